@@ -9,20 +9,40 @@ fn create_test_config() -> AppConfig {
         whatsapp_access_token: "test_token".to_string(),
         whatsapp_phone_number_id: "test_phone_id".to_string(),
         whatsapp_webhook_verify_token: "test_verify_token".to_string(),
+        whatsapp_app_secret: "test_app_secret".to_string(),
         whatsapp_api_base_url: "https://graph.facebook.com/v18.0".to_string(),
         bitsacco_api_base_url: "https://api.bitsacco.com".to_string(),
         bitsacco_api_token: "test_bitsacco_token".to_string(),
+        bitsacco_callback_secret: "test_callback_secret".to_string(),
         server_host: "127.0.0.1".to_string(),
         server_port: 8080,
         rust_log: "debug".to_string(),
         rate_limit_requests_per_minute: 60,
+        rate_limit_money_commands_per_minute: 5,
         max_message_length: 4096,
         btc_api_base_url: "https://api.coingecko.com/api/v3".to_string(),
         btc_api_key: Some("test_btc_key".to_string()),
+        coinbase_api_base_url: "https://api.coinbase.com/v2".to_string(),
+        kraken_api_base_url: "https://api.kraken.com".to_string(),
+        binance_api_base_url: "https://api.binance.com".to_string(),
+        btc_price_sources: vec!["coingecko".to_string()],
+        invoice_store_path: "".to_string(),
+        mpesa_status_query_delay_secs: 60,
+        limits_config_path: "".to_string(),
+        templates_config_path: "".to_string(),
+        pin_store_path: "".to_string(),
+        bitsacco_web_app_url: "https://app.bitsacco.com".to_string(),
+        message_dedup_retention_secs: 86400,
+        admin_api_token: "".to_string(),
+        outbound_queue_path: "".to_string(),
         whatsapp_media_base_url: "https://graph.facebook.com/v18.0".to_string(),
         twilio_account_sid: "test_account_sid".to_string(),
         twilio_auth_token: "test_auth_token".to_string(),
         twilio_whatsapp_number: "+1234567890".to_string(),
+        twilio_api_base_url: "https://api.twilio.com".to_string(),
+        twilio_sms_number: "".to_string(),
+        messaging_provider: "meta".to_string(),
+        twilio_webhook_url: "".to_string(),
     }
 }
 
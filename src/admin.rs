@@ -0,0 +1,110 @@
+//! Operator endpoints under `/admin`
+//!
+//! Every request must carry `Authorization: Bearer <ADMIN_API_TOKEN>`. The
+//! endpoints are disabled when no token is configured.
+
+use crate::{
+    delivery::DeliveryRecord,
+    error::{AppError, Result},
+    outbound::OutboundMessage,
+    rate_limit::RateLimitBan,
+    service_window::ServiceWindowMetrics,
+    types::AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::Json,
+};
+use ring::hmac;
+use serde::Deserialize;
+use tracing::warn;
+
+/// Check the admin bearer token
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<()> {
+    let expected = &state.config.admin_api_token;
+    if expected.is_empty() {
+        warn!("Admin request received but no admin token is configured");
+        return Err(AppError::Unauthorized);
+    }
+    let token = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AppError::Unauthorized)?;
+
+    // Compare MACs rather than the strings so the check runs in constant time
+    let key = hmac::Key::new(hmac::HMAC_SHA256, expected.as_bytes());
+    let tag = hmac::sign(&key, expected.as_bytes());
+    hmac::verify(&key, token.as_bytes(), tag.as_ref()).map_err(|_| {
+        warn!("Admin request with an invalid token");
+        AppError::Unauthorized
+    })
+}
+
+/// Senders currently banned by the rate limiter
+pub async fn list_rate_limit_bans(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<RateLimitBan>>> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.rate_limiter.bans(chrono::Utc::now()).await))
+}
+
+/// WhatsApp messages given up on after failing to send
+pub async fn list_dead_letters(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<OutboundMessage>>> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.outbox.dead_letters().await))
+}
+
+/// Queue a dead-lettered message to be sent again
+pub async fn replay_dead_letter(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<OutboundMessage>> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.outbox.replay(id, chrono::Utc::now()).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveryQuery {
+    pub to: String,
+}
+
+/// Delivery history of one outbound message
+pub async fn get_delivery(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(message_id): Path<String>,
+) -> Result<Json<DeliveryRecord>> {
+    require_admin(&state, &headers)?;
+    state
+        .deliveries
+        .get(&message_id)
+        .await
+        .map(Json)
+        .ok_or_else(|| AppError::DataNotFound(format!("No delivery record for message {}", message_id)))
+}
+
+/// Delivery histories of the messages sent to a phone number, most recent first
+pub async fn list_deliveries(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<DeliveryQuery>,
+) -> Result<Json<Vec<DeliveryRecord>>> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.deliveries.for_recipient(&query.to).await))
+}
+
+/// Open service windows, and free-form sends made or blocked by them
+pub async fn service_window_metrics(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ServiceWindowMetrics>> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.service_windows.metrics(chrono::Utc::now()).await))
+}
@@ -0,0 +1,348 @@
+//! Bitcoin price alerts
+//!
+//! Users subscribe with `alert btc above 10000000 KES` and are messaged when
+//! the aggregated BTC price crosses the threshold. After firing, an alert is
+//! disarmed until the price moves back past the threshold by the hysteresis
+//! band, so a price hovering around the line does not send a stream of
+//! messages. Alerts are kept in memory and capped per user.
+
+use crate::{
+    channel::Messenger,
+    cache::AppCache,
+    error::AppError,
+    money::{Currency, Money},
+    services::btc::BtcService,
+    templates::{ProactiveMessage, TemplateMessage, TemplateParameter},
+};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+/// Most alerts a single user may hold at once
+pub const MAX_ALERTS_PER_USER: usize = 5;
+
+/// How far back past the threshold the price must move, in basis points of
+/// the threshold, before a fired alert can fire again
+pub const HYSTERESIS_BPS: i64 = 100;
+
+/// How often the background monitor checks prices
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AlertError {
+    #[error("You already have {0} price alerts. Remove one with `alert remove <id>` first.")]
+    LimitReached(usize),
+
+    #[error("Price alerts must be set in KES or USD")]
+    UnsupportedCurrency,
+
+    #[error("Alert price must be greater than 0")]
+    NonPositiveThreshold,
+}
+
+impl From<AlertError> for AppError {
+    fn from(err: AlertError) -> Self {
+        AppError::Validation(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertDirection {
+    Above,
+    Below,
+}
+
+impl fmt::Display for AlertDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertDirection::Above => f.write_str("above"),
+            AlertDirection::Below => f.write_str("below"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriceAlert {
+    pub id: u64,
+    pub phone_number: String,
+    pub direction: AlertDirection,
+    pub threshold: Money,
+    /// Whether the alert will fire on the next crossing
+    pub armed: bool,
+    pub created_at: DateTime<Utc>,
+    pub last_triggered: Option<DateTime<Utc>>,
+}
+
+impl PriceAlert {
+    fn is_crossed(&self, price: Money) -> bool {
+        match self.direction {
+            AlertDirection::Above => price.minor() >= self.threshold.minor(),
+            AlertDirection::Below => price.minor() <= self.threshold.minor(),
+        }
+    }
+
+    /// The price is far enough back on the untriggered side to re-arm
+    fn is_reset(&self, price: Money) -> bool {
+        let band = i128::from(self.threshold.minor()) * i128::from(HYSTERESIS_BPS) / 10_000;
+        let threshold = i128::from(self.threshold.minor());
+        let price = i128::from(price.minor());
+        match self.direction {
+            AlertDirection::Above => price <= threshold - band,
+            AlertDirection::Below => price >= threshold + band,
+        }
+    }
+
+    /// Update the armed state for a new price, returning whether it fired
+    fn observe(&mut self, price: Money, now: DateTime<Utc>) -> bool {
+        if self.armed && self.is_crossed(price) {
+            self.armed = false;
+            self.last_triggered = Some(now);
+            true
+        } else {
+            if !self.armed && self.is_reset(price) {
+                self.armed = true;
+            }
+            false
+        }
+    }
+}
+
+impl fmt::Display for PriceAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} BTC {} {}", self.id, self.direction, self.threshold)
+    }
+}
+
+/// In-memory store of price alerts, keyed by phone number
+#[derive(Debug, Clone)]
+pub struct AlertStore {
+    alerts: Arc<RwLock<HashMap<String, Vec<PriceAlert>>>>,
+    next_id: Arc<AtomicU64>,
+    max_per_user: usize,
+}
+
+impl Default for AlertStore {
+    fn default() -> Self {
+        Self::new(MAX_ALERTS_PER_USER)
+    }
+}
+
+impl AlertStore {
+    pub fn new(max_per_user: usize) -> Self {
+        Self {
+            alerts: Arc::new(RwLock::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            max_per_user,
+        }
+    }
+
+    pub async fn add(
+        &self,
+        phone_number: &str,
+        direction: AlertDirection,
+        threshold: Money,
+    ) -> Result<PriceAlert, AlertError> {
+        if threshold.currency() == Currency::Btc {
+            return Err(AlertError::UnsupportedCurrency);
+        }
+        if !threshold.is_positive() {
+            return Err(AlertError::NonPositiveThreshold);
+        }
+
+        let mut alerts = self.alerts.write().await;
+        let user_alerts = alerts.entry(phone_number.to_string()).or_default();
+        if user_alerts.len() >= self.max_per_user {
+            return Err(AlertError::LimitReached(self.max_per_user));
+        }
+
+        let alert = PriceAlert {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            phone_number: phone_number.to_string(),
+            direction,
+            threshold,
+            armed: true,
+            created_at: Utc::now(),
+            last_triggered: None,
+        };
+        user_alerts.push(alert.clone());
+        Ok(alert)
+    }
+
+    pub async fn list(&self, phone_number: &str) -> Vec<PriceAlert> {
+        self.alerts
+            .read()
+            .await
+            .get(phone_number)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Remove one of the user's alerts; other users' alerts are never touched
+    pub async fn remove(&self, phone_number: &str, id: u64) -> Option<PriceAlert> {
+        let mut alerts = self.alerts.write().await;
+        let user_alerts = alerts.get_mut(phone_number)?;
+        let index = user_alerts.iter().position(|alert| alert.id == id)?;
+        let removed = user_alerts.remove(index);
+        if user_alerts.is_empty() {
+            alerts.remove(phone_number);
+        }
+        Some(removed)
+    }
+
+    /// Currencies that at least one alert is set in
+    pub async fn currencies(&self) -> Vec<Currency> {
+        let mut currencies: Vec<Currency> = self
+            .alerts
+            .read()
+            .await
+            .values()
+            .flatten()
+            .map(|alert| alert.threshold.currency())
+            .collect();
+        currencies.sort();
+        currencies.dedup();
+        currencies
+    }
+
+    /// Feed a new price to every alert in its currency, returning those that fired
+    pub async fn evaluate(&self, price: Money) -> Vec<PriceAlert> {
+        let now = Utc::now();
+        let mut fired = Vec::new();
+        for alert in self.alerts.write().await.values_mut().flatten() {
+            if alert.threshold.currency() == price.currency() && alert.observe(price, now) {
+                fired.push(alert.clone());
+            }
+        }
+        fired
+    }
+}
+
+/// Poll the BTC price and message users whose alerts fire, until the process exits
+pub async fn run_alert_monitor(
+    alerts: AlertStore,
+    btc_service: BtcService,
+    messenger: Messenger,
+    cache: AppCache,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        for currency in alerts.currencies().await {
+            let price = match btc_service
+                .get_btc_price(&currency.code().to_lowercase(), &cache)
+                .await
+                .and_then(|price| Ok(Money::from_f64(price.price, currency)?))
+            {
+                Ok(price) => price,
+                Err(e) => {
+                    warn!("Skipping BTC/{} alerts, no price: {}", currency, e);
+                    continue;
+                }
+            };
+
+            for alert in alerts.evaluate(price).await {
+                info!("Price alert {} fired at {}", alert.id, price);
+                let message = format!(
+                    "🔔 *Bitcoin Price Alert*\n\nBTC is now {} (alert #{}: {} {}).\n\nThis alert will fire again if the price moves back and crosses {} again. Send `alert remove {}` to stop it.",
+                    price, alert.id, alert.direction, alert.threshold, alert.threshold, alert.id
+                );
+                let template = TemplateMessage::new("btc_price_alert").body(vec![
+                    TemplateParameter::money(price),
+                    TemplateParameter::text(alert.id),
+                    TemplateParameter::text(alert.direction),
+                    TemplateParameter::money(alert.threshold),
+                ]);
+                let notification = ProactiveMessage::new(message, template);
+                if let Err(e) = messenger.send_proactive(&alert.phone_number, &notification).await {
+                    error!("Failed to send price alert {}: {}", alert.id, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kes(major: i64) -> Money {
+        Money::from_major(major, Currency::Kes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_alert_fires_once_until_reset() {
+        let store = AlertStore::default();
+        let alert = store
+            .add("+254712345678", AlertDirection::Above, kes(10_000_000))
+            .await
+            .unwrap();
+
+        assert!(store.evaluate(kes(9_900_000)).await.is_empty());
+        let fired = store.evaluate(kes(10_000_000)).await;
+        assert_eq!(fired.iter().map(|a| a.id).collect::<Vec<_>>(), vec![alert.id]);
+
+        // Hovering around the threshold does not re-fire
+        assert!(store.evaluate(kes(9_950_000)).await.is_empty());
+        assert!(store.evaluate(kes(10_050_000)).await.is_empty());
+
+        // Dropping 1% below re-arms it
+        assert!(store.evaluate(kes(9_900_000)).await.is_empty());
+        assert_eq!(store.evaluate(kes(10_010_000)).await.len(), 1);
+
+        // Prices in other currencies are ignored
+        assert!(store.evaluate(Money::from_major(1, Currency::Usd).unwrap()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_below_alert() {
+        let store = AlertStore::default();
+        store
+            .add("+254712345678", AlertDirection::Below, Money::from_major(60_000, Currency::Usd).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(store.evaluate(Money::from_major(59_000, Currency::Usd).unwrap()).await.len(), 1);
+        assert!(store.evaluate(Money::from_major(58_000, Currency::Usd).unwrap()).await.is_empty());
+        assert_eq!(store.currencies().await, vec![Currency::Usd]);
+    }
+
+    #[tokio::test]
+    async fn test_per_user_cap_and_remove() {
+        let store = AlertStore::new(2);
+        let first = store.add("+254712345678", AlertDirection::Above, kes(1)).await.unwrap();
+        store.add("+254712345678", AlertDirection::Above, kes(2)).await.unwrap();
+        assert_eq!(
+            store.add("+254712345678", AlertDirection::Above, kes(3)).await,
+            Err(AlertError::LimitReached(2))
+        );
+        // The cap is per user
+        assert!(store.add("+254700000000", AlertDirection::Above, kes(3)).await.is_ok());
+
+        // Users can only remove their own alerts
+        assert!(store.remove("+254700000000", first.id).await.is_none());
+        assert_eq!(store.remove("+254712345678", first.id).await.map(|a| a.id), Some(first.id));
+        assert_eq!(store.list("+254712345678").await.len(), 1);
+        assert!(store.add("+254712345678", AlertDirection::Above, kes(3)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_thresholds() {
+        let store = AlertStore::default();
+        assert_eq!(
+            store.add("+254712345678", AlertDirection::Above, Money::from_minor(1, Currency::Btc)).await,
+            Err(AlertError::UnsupportedCurrency)
+        );
+        assert_eq!(
+            store.add("+254712345678", AlertDirection::Below, kes(0)).await,
+            Err(AlertError::NonPositiveThreshold)
+        );
+    }
+}
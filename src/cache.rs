@@ -6,7 +6,7 @@
 // - Memory-efficient storage
 // - Thread-safe operations
 
-use crate::types::{BitSaccoBtcBalance, BitSaccoSavings, BitSaccoUser, BtcPrice};
+use crate::types::{BitSaccoBtcBalance, BitSaccoSavings, BitSaccoUser, BtcPrice, PriceHistory};
 use moka::future::Cache;
 use std::sync::Arc;
 use std::time::Duration;
@@ -16,6 +16,7 @@ use std::time::Duration;
 pub struct CacheConfig {
     pub user_cache_ttl: Duration,
     pub btc_price_cache_ttl: Duration,
+    pub price_history_cache_ttl: Duration,
     pub savings_cache_ttl: Duration,
     pub max_capacity: u64,
 }
@@ -25,6 +26,7 @@ impl Default for CacheConfig {
         Self {
             user_cache_ttl: Duration::from_secs(300), // 5 minutes
             btc_price_cache_ttl: Duration::from_secs(60), // 1 minute
+            price_history_cache_ttl: Duration::from_secs(300), // 5 minutes
             savings_cache_ttl: Duration::from_secs(180), // 3 minutes
             max_capacity: 1000,
         }
@@ -36,6 +38,7 @@ impl Default for CacheConfig {
 pub struct AppCache {
     user_cache: Arc<Cache<String, BitSaccoUser>>,
     btc_price_cache: Arc<Cache<String, BtcPrice>>,
+    price_history_cache: Arc<Cache<String, PriceHistory>>,
     savings_cache: Arc<Cache<String, Vec<BitSaccoSavings>>>,
     btc_balance_cache: Arc<Cache<String, BitSaccoBtcBalance>>,
 }
@@ -56,6 +59,13 @@ impl AppCache {
                 .build(),
         );
 
+        let price_history_cache = Arc::new(
+            Cache::builder()
+                .time_to_live(config.price_history_cache_ttl)
+                .max_capacity(20) // A few ranges per currency
+                .build(),
+        );
+
         let savings_cache = Arc::new(
             Cache::builder()
                 .time_to_live(config.savings_cache_ttl)
@@ -73,6 +83,7 @@ impl AppCache {
         Self {
             user_cache,
             btc_price_cache,
+            price_history_cache,
             savings_cache,
             btc_balance_cache,
         }
@@ -98,6 +109,16 @@ impl AppCache {
         self.btc_price_cache.insert(currency.to_string(), price).await;
     }
 
+    /// Get a price history from cache, keyed like `usd:7d`
+    pub async fn get_price_history(&self, key: &str) -> Option<PriceHistory> {
+        self.price_history_cache.get(key).await
+    }
+
+    /// Store a price history in cache
+    pub async fn set_price_history(&self, key: &str, history: PriceHistory) {
+        self.price_history_cache.insert(key.to_string(), history).await;
+    }
+
     /// Get user savings from cache or return None if not found
     pub async fn get_savings(&self, user_id: &str) -> Option<Vec<BitSaccoSavings>> {
         self.savings_cache.get(user_id).await
@@ -137,6 +158,7 @@ impl AppCache {
     pub async fn clear_all(&self) {
         self.user_cache.invalidate_all();
         self.btc_price_cache.invalidate_all();
+        self.price_history_cache.invalidate_all();
         self.savings_cache.invalidate_all();
         self.btc_balance_cache.invalidate_all();
     }
@@ -203,6 +225,7 @@ mod tests {
             price: 50000.0,
             change_24h: 2.5,
             last_updated: chrono::Utc::now().to_rfc3339(),
+            sources: vec!["coingecko".to_string()],
         };
 
         // Test cache miss
@@ -0,0 +1,501 @@
+//! Messaging providers behind one interface
+//!
+//! Users reach the bot through Meta's WhatsApp Cloud API or through Twilio.
+//! Each provider is a `MessagingChannel` that sends messages and turns its
+//! webhooks into provider-neutral `InboundMessage`s. The command pipeline and
+//! the notification workers only send through `Messenger`, which picks the
+//! provider for each user: the one they last wrote in on, or
+//! `MESSAGING_PROVIDER` for users not heard from since the process started.
+//! `Messenger` also checks every send against the user's service window on
+//! that channel.
+
+use crate::{
+    config::AppConfig,
+    error::{AppError, Result},
+    interactive::InteractiveMessage,
+    money::Money,
+    service_window::ServiceWindows,
+    services::{conversion::ExchangeRate, twilio::TwilioService, whatsapp::{format_balance, WhatsAppService}},
+    templates::ProactiveMessage,
+    types::{WhatsAppAudio, WhatsAppStatus, WhatsAppVoice},
+};
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<SentMessage>> + Send + 'a>>;
+
+/// A message accepted by a provider
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SentMessage {
+    /// Name of the channel that accepted it
+    pub channel: &'static str,
+    /// The provider's ID for the message
+    pub id: String,
+}
+
+/// An image or audio clip to send
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboundMedia {
+    pub data: Vec<u8>,
+    pub file_name: String,
+    pub mime_type: String,
+    pub caption: Option<String>,
+}
+
+impl OutboundMedia {
+    pub fn png(data: Vec<u8>, file_name: impl Into<String>, caption: Option<&str>) -> Self {
+        Self {
+            data,
+            file_name: file_name.into(),
+            mime_type: "image/png".to_string(),
+            caption: caption.map(str::to_string),
+        }
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.mime_type.starts_with("audio/")
+    }
+}
+
+/// A webhook request exactly as received.
+///
+/// Providers sign the raw bytes, so the body must not pass through an
+/// extractor before verification: re-serializing changes key order,
+/// whitespace and escapes, and the signature no longer matches.
+#[derive(Debug, Clone, Copy)]
+pub struct InboundRequest<'a> {
+    /// URL the provider posted to, as it was configured with the provider
+    pub url: &'a str,
+    pub headers: &'a HeaderMap,
+    pub body: &'a [u8],
+}
+
+#[derive(Debug, Clone)]
+pub enum InboundContent {
+    Text(String),
+    /// A tapped button or list row, as the text it stands for
+    Reply(String),
+    Voice(WhatsAppVoice),
+    Audio(WhatsAppAudio),
+    /// A message type the bot does not handle, such as a sticker
+    Unsupported(String),
+}
+
+/// A user's message, whichever provider it came through
+#[derive(Debug, Clone)]
+pub struct InboundMessage {
+    /// The provider's ID for the message
+    pub id: String,
+    /// Sender in E.164 format, with the leading '+'
+    pub from: String,
+    pub sent_at: DateTime<Utc>,
+    pub content: InboundContent,
+}
+
+/// Everything one webhook delivered
+#[derive(Debug, Default)]
+pub struct Inbound {
+    pub messages: Vec<InboundMessage>,
+    /// Delivery reports for messages we sent
+    pub statuses: Vec<WhatsAppStatus>,
+}
+
+/// A provider users can message the bot through
+pub trait MessagingChannel: Send + Sync + std::fmt::Debug {
+    /// Stable lowercase name, as used in `MESSAGING_PROVIDER`
+    fn name(&self) -> &'static str;
+
+    fn send_text<'a>(&'a self, to: &'a str, text: &'a str) -> SendFuture<'a>;
+
+    fn send_media<'a>(&'a self, to: &'a str, media: OutboundMedia) -> SendFuture<'a>;
+
+    /// Send choices, as their text version where the provider has no
+    /// interactive messages
+    fn send_interactive<'a>(&'a self, to: &'a str, message: &'a InteractiveMessage) -> SendFuture<'a>;
+
+    /// Send a notification the user did not ask for. `window_open` says
+    /// whether free-form messages still reach the user on this channel;
+    /// when it does not, providers must send something that does, such as a
+    /// template. Providers with no such route refuse the send.
+    fn send_proactive<'a>(&'a self, to: &'a str, message: &'a ProactiveMessage, window_open: bool) -> SendFuture<'a> {
+        if window_open {
+            return self.send_text(to, &message.text);
+        }
+        Box::pin(async move { Err(AppError::ServiceWindowClosed(to.to_string())) })
+    }
+
+    /// Check a webhook request was signed by the provider
+    fn verify_inbound(&self, request: &InboundRequest<'_>) -> Result<()>;
+
+    /// Parse the body of a verified webhook request
+    fn normalize_inbound(&self, body: &[u8]) -> Result<Inbound>;
+}
+
+/// Sends to each user through the channel they use
+#[derive(Debug, Clone)]
+pub struct Messenger {
+    default: Arc<dyn MessagingChannel>,
+    channels: Vec<Arc<dyn MessagingChannel>>,
+    /// Channel each user last wrote in on
+    users: Arc<RwLock<HashMap<String, &'static str>>>,
+    /// When each user last wrote on each channel, if tracked
+    windows: Option<ServiceWindows>,
+}
+
+impl Messenger {
+    /// Send everything through `default` until users write in on other channels
+    pub fn new(default: Arc<dyn MessagingChannel>) -> Self {
+        Self {
+            channels: vec![default.clone()],
+            default,
+            users: Arc::default(),
+            windows: None,
+        }
+    }
+
+    pub fn with_channel(mut self, channel: Arc<dyn MessagingChannel>) -> Self {
+        if self.channel(channel.name()).is_none() {
+            self.channels.push(channel);
+        }
+        self
+    }
+
+    /// Refuse free-form messages to users whose service window on their
+    /// channel has closed, and send proactive messages to them another way.
+    /// Without windows everything is sent as free-form.
+    pub fn with_service_windows(mut self, windows: ServiceWindows) -> Self {
+        self.windows = Some(windows);
+        self
+    }
+
+    /// Both providers, defaulting to the one named in `config.messaging_provider`
+    pub fn from_config(config: &AppConfig, whatsapp_service: WhatsAppService, twilio_service: TwilioService) -> Result<Self> {
+        let whatsapp: Arc<dyn MessagingChannel> = Arc::new(whatsapp_service);
+        let twilio: Arc<dyn MessagingChannel> = Arc::new(twilio_service);
+        let default = match config.messaging_provider.trim().to_lowercase().as_str() {
+            "meta" => whatsapp.clone(),
+            "twilio" => twilio.clone(),
+            other => return Err(AppError::Internal(format!("Unknown messaging provider: {}", other))),
+        };
+        Ok(Self::new(default).with_channel(whatsapp).with_channel(twilio))
+    }
+
+    pub fn channel(&self, name: &str) -> Option<Arc<dyn MessagingChannel>> {
+        self.channels.iter().find(|channel| channel.name() == name).cloned()
+    }
+
+    /// Reply to `phone_number` on `channel` from now on
+    pub async fn record_inbound(&self, phone_number: &str, channel: &'static str) {
+        self.users.write().await.insert(phone_number.to_string(), channel);
+    }
+
+    /// The channel `phone_number` last wrote in on, or the default
+    pub async fn channel_for(&self, phone_number: &str) -> Arc<dyn MessagingChannel> {
+        self.users
+            .read()
+            .await
+            .get(phone_number)
+            .and_then(|name| self.channel(name))
+            .unwrap_or_else(|| self.default.clone())
+    }
+
+    /// Refuse a free-form message to `to` on `channel` once their service
+    /// window there has closed
+    pub async fn check_window(&self, channel: &'static str, to: &str) -> Result<()> {
+        let Some(windows) = &self.windows else {
+            return Ok(());
+        };
+        if windows.admit_free_form(channel, to, Utc::now()).await {
+            return Ok(());
+        }
+        warn!("Not sending free-form message to {} on {}: service window closed", to, channel);
+        Err(AppError::ServiceWindowClosed(to.to_string()))
+    }
+
+    pub async fn send_message(&self, to: &str, message: &str) -> Result<SentMessage> {
+        let channel = self.channel_for(to).await;
+        self.check_window(channel.name(), to).await?;
+        channel.send_text(to, message).await
+    }
+
+    pub async fn send_media(&self, to: &str, media: OutboundMedia) -> Result<SentMessage> {
+        let channel = self.channel_for(to).await;
+        self.check_window(channel.name(), to).await?;
+        channel.send_media(to, media).await
+    }
+
+    pub async fn send_interactive(&self, to: &str, message: &InteractiveMessage) -> Result<SentMessage> {
+        let channel = self.channel_for(to).await;
+        self.check_window(channel.name(), to).await?;
+        channel.send_interactive(to, message).await
+    }
+
+    /// Send a notification the user did not ask for: as text while their
+    /// service window is open, otherwise the way their channel reaches users
+    /// outside it
+    pub async fn send_proactive(&self, to: &str, message: &ProactiveMessage) -> Result<SentMessage> {
+        let channel = self.channel_for(to).await;
+        let window_open = match &self.windows {
+            Some(windows) => windows.admit_proactive(channel.name(), to, Utc::now()).await,
+            None => true,
+        };
+        if !window_open {
+            info!("Service window with {} on {} has closed; sending {} another way", to, channel.name(), message.template.name);
+        }
+        channel.send_proactive(to, message, window_open).await
+    }
+
+    pub async fn send_help_message(&self, to: &str) -> Result<()> {
+        let help_text = r#"🤖 *BitSacco WhatsApp Bot Help*
+
+*Basic Commands:*
+• `help` - Show this help message
+• `balance` - Check your total balance in sats
+• `savings` - View your savings details
+• `bitcoin` - Get current Bitcoin price
+• `bitcoin <24h|7d|30d|1y> [KES|USD]` - Bitcoin price chart
+
+*Personal Savings:*
+• `deposit <amount> KES [mpesa|lightning]` - Make a deposit
+• `withdraw <amount> KES [mpesa [phone]]` - Withdraw to M-Pesa
+• `withdraw <amount> KES lightning <destination>` - Withdraw over Lightning
+• `history` - View transaction history
+
+*Chama Management:*
+• `chama` - View your chama groups
+• `create chama <name>` - Create a new chama
+• `contribute chama <id> <amount> <currency>` - Contribute to chama
+• `shares balance` - View your chama shares
+
+*Membership Shares:*
+• `membership` - View your BitSacco membership shares
+• `buy shares <count> [mpesa|lightning]` - Purchase membership shares
+• `share history` - View share purchase history
+
+*Lightning Network:*
+• `lightning deposit <amount> KES` - Deposit via Lightning
+• `lightning withdraw <invoice>` - Pay a Lightning invoice
+• `lightning withdraw <amount> KES <address>` - Send to a Lightning address or LNURL
+
+*Price Alerts:*
+• `alert btc above <price> <KES|USD>` - Notify me when BTC rises past a price
+• `alert btc below <price> <KES|USD>` - Notify me when BTC falls past a price
+• `alerts` - List your price alerts
+• `alert remove <id>` - Remove a price alert
+
+*Security:*
+• `set pin` - Require a PIN for withdrawals, transfers and share purchases
+• `change pin` / `remove pin` - Change or turn off your PIN
+• `reset pin` - Reset a forgotten PIN in the BitSacco web app
+
+*Examples:*
+• `deposit 100 KES mpesa`
+• `deposit 50 KES lightning`
+• `buy shares 10 mpesa`
+• `create chama Investment Club`
+• `contribute chama CH123 50 USD`
+
+*Guided Commands:*
+Send just `deposit`, `withdraw`, `transfer`, `buy shares` or `contribute chama` and the bot will ask for the missing details. Reply `back` to change an answer or `cancel` to stop.
+
+*Note:* All balances are displayed in Satoshis (sats) for Bitcoin precision.
+
+*Voice Commands:*
+🎤 You can also send voice messages with commands like:
+• "Help" - Get help
+• "Balance" - Check balance
+• "Bitcoin price" - Get BTC price
+• "Deposit 100 dollars" - Make a deposit
+• "Create chama My Group" - Create a chama
+
+*Examples:*
+• `deposit 100 USD`
+• `withdraw 50 KES`
+• `transfer 25 USD +254712345678`
+• `create chama Investment Group`
+• `contribute chama CH123 50 USD`
+• `shares balance`
+
+*Security Note:*
+Withdrawals, transfers, share purchases and chama contributions must be confirmed by replying `YES` within 2 minutes. Reply `NO` to cancel.
+All transactions are secure and encrypted. Your data is protected by BitSacco's enterprise-grade security.
+
+Need more help? Visit https://bitsacco.com or contact support."#;
+
+        self.send_message(to, help_text).await?;
+        Ok(())
+    }
+
+    pub async fn send_balance_message(
+        &self,
+        to: &str,
+        savings: &[Money],
+        btc_balance: Money,
+        rates: &[ExchangeRate],
+    ) -> Result<()> {
+        let balance_text = format_balance(savings, btc_balance, rates)?;
+
+        self.send_message(to, &balance_text).await?;
+        Ok(())
+    }
+
+    pub async fn send_error_message(&self, to: &str, error: &str) -> Result<()> {
+        let error_text = format!(
+            r#"❌ *Error*
+
+{}
+
+Please try again or contact support if the problem persists.
+
+For help, send `help`"#,
+            error
+        );
+
+        self.send_message(to, &error_text).await?;
+        Ok(())
+    }
+
+    pub async fn send_success_message(&self, to: &str, message: &str) -> Result<()> {
+        let success_text = format!(
+            r#"✅ *Success*
+
+{}
+
+Thank you for using BitSacco!"#,
+            message
+        );
+
+        self.send_message(to, &success_text).await?;
+        Ok(())
+    }
+
+    pub async fn send_btc_price_message(
+        &self,
+        to: &str,
+        price: f64,
+        change_24h: f64,
+        currency: &str,
+        sources: &[String],
+    ) -> Result<()> {
+        let change_emoji = if change_24h >= 0.0 { "📈" } else { "📉" };
+        let change_sign = if change_24h >= 0.0 { "+" } else { "" };
+
+        let price_text = format!(
+            r#"₿ *Bitcoin Price Update*
+
+*Current Price:* {:.2} {}
+*24h Change:* {} {}{:.2}%
+
+*Last Updated:* {}
+
+Sources: {}"#,
+            price,
+            currency,
+            change_emoji,
+            change_sign,
+            change_24h,
+            chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
+            sources.join(", ")
+        );
+
+        self.send_message(to, &price_text).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records who each message was sent to
+    #[derive(Debug)]
+    struct RecordingChannel {
+        name: &'static str,
+        sent: Mutex<Vec<String>>,
+    }
+
+    impl RecordingChannel {
+        fn new(name: &'static str) -> Arc<Self> {
+            Arc::new(Self { name, sent: Mutex::new(Vec::new()) })
+        }
+
+        fn sent(&self) -> Vec<String> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    impl MessagingChannel for RecordingChannel {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn send_text<'a>(&'a self, to: &'a str, _text: &'a str) -> SendFuture<'a> {
+            self.sent.lock().unwrap().push(to.to_string());
+            Box::pin(async move { Ok(SentMessage { channel: self.name, id: "id".to_string() }) })
+        }
+
+        fn send_media<'a>(&'a self, to: &'a str, _media: OutboundMedia) -> SendFuture<'a> {
+            self.send_text(to, "")
+        }
+
+        fn send_interactive<'a>(&'a self, to: &'a str, message: &'a InteractiveMessage) -> SendFuture<'a> {
+            self.send_text(to, &message.fallback_text)
+        }
+
+        fn verify_inbound(&self, _request: &InboundRequest<'_>) -> Result<()> {
+            Ok(())
+        }
+
+        fn normalize_inbound(&self, _body: &[u8]) -> Result<Inbound> {
+            Ok(Inbound::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replies_follow_the_users_channel() {
+        let meta = RecordingChannel::new("meta");
+        let twilio = RecordingChannel::new("twilio");
+        let messenger = Messenger::new(meta.clone()).with_channel(twilio.clone());
+
+        messenger.send_message("+254700000001", "Hi").await.unwrap();
+        messenger.record_inbound("+254712345678", "twilio").await;
+        let sent = messenger.send_message("+254712345678", "Hi").await.unwrap();
+        assert_eq!(sent.channel, "twilio");
+
+        // Channels that are not registered fall back to the default
+        messenger.record_inbound("+254700000002", "telegram").await;
+        messenger.send_message("+254700000002", "Hi").await.unwrap();
+
+        assert_eq!(meta.sent(), vec!["+254700000001", "+254700000002"]);
+        assert_eq!(twilio.sent(), vec!["+254712345678"]);
+    }
+
+    #[tokio::test]
+    async fn test_sends_check_the_window_on_the_users_channel() {
+        let meta = RecordingChannel::new("meta");
+        let twilio = RecordingChannel::new("twilio");
+        let windows = ServiceWindows::default();
+        let messenger = Messenger::new(meta.clone())
+            .with_channel(twilio.clone())
+            .with_service_windows(windows.clone());
+
+        // A message on Meta does not open the Twilio window for the number
+        windows.record_inbound("meta", "+254712345678", Utc::now()).await;
+        messenger.record_inbound("+254712345678", "twilio").await;
+        assert!(matches!(
+            messenger.send_message("+254712345678", "Hi").await,
+            Err(AppError::ServiceWindowClosed(_))
+        ));
+
+        windows.record_inbound("twilio", "+254712345678", Utc::now()).await;
+        messenger.send_message("+254712345678", "Hi").await.unwrap();
+        assert!(meta.sent().is_empty());
+        assert_eq!(twilio.sent(), vec!["+254712345678"]);
+        assert_eq!(windows.metrics(Utc::now()).await.blocked_sends, 1);
+    }
+}
@@ -0,0 +1,231 @@
+//! PNG line charts for price history replies
+//!
+//! Charts are rasterised directly into an RGB buffer and encoded with the
+//! `png` crate. They carry no text: the high, low and change are sent as the
+//! image caption, which WhatsApp renders more legibly than a bitmap font.
+
+use crate::{
+    error::{AppError, Result},
+    types::PriceHistory,
+};
+
+pub const CHART_WIDTH: u32 = 800;
+pub const CHART_HEIGHT: u32 = 400;
+
+const PADDING: u32 = 24;
+const GRID_LINES: u32 = 4;
+const BACKGROUND: Rgb = [255, 255, 255];
+const GRID: Rgb = [226, 230, 236];
+const RISING: Rgb = [22, 163, 74];
+const FALLING: Rgb = [220, 38, 38];
+const RISING_FILL: Rgb = [220, 252, 231];
+const FALLING_FILL: Rgb = [254, 226, 226];
+
+type Rgb = [u8; 3];
+
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32, background: Rgb) -> Self {
+        let pixels = background
+            .iter()
+            .copied()
+            .cycle()
+            .take((width * height * 3) as usize)
+            .collect();
+        Self { width, height, pixels }
+    }
+
+    fn set(&mut self, x: i64, y: i64, color: Rgb) {
+        if x < 0 || y < 0 || x >= i64::from(self.width) || y >= i64::from(self.height) {
+            return;
+        }
+        let offset = ((y as u32 * self.width + x as u32) * 3) as usize;
+        self.pixels[offset..offset + 3].copy_from_slice(&color);
+    }
+
+    fn hline(&mut self, y: i64, color: Rgb) {
+        for x in 0..i64::from(self.width) {
+            self.set(x, y, color);
+        }
+    }
+
+    fn vline(&mut self, x: i64, from_y: i64, to_y: i64, color: Rgb) {
+        for y in from_y..=to_y {
+            self.set(x, y, color);
+        }
+    }
+
+    /// Bresenham line stamped with a square brush of the given radius
+    fn line(&mut self, from: (i64, i64), to: (i64, i64), radius: i64, color: Rgb) {
+        let (mut x, mut y) = from;
+        let dx = (to.0 - x).abs();
+        let dy = -(to.1 - y).abs();
+        let sx = if x < to.0 { 1 } else { -1 };
+        let sy = if y < to.1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        loop {
+            for bx in -radius..=radius {
+                for by in -radius..=radius {
+                    self.set(x + bx, y + by, color);
+                }
+            }
+            if (x, y) == to {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    fn encode(self) -> Result<Vec<u8>> {
+        encode_png(self.width, self.height, png::ColorType::Rgb, &self.pixels)
+    }
+}
+
+/// Encode 8-bit pixel data as a PNG
+pub(crate) fn encode_png(width: u32, height: u32, color: png::ColorType, pixels: &[u8]) -> Result<Vec<u8>> {
+    let encode_error = |e: png::EncodingError| AppError::Internal(format!("Failed to encode PNG: {}", e));
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(encode_error)?;
+    writer.write_image_data(pixels).map_err(encode_error)?;
+    writer.finish().map_err(encode_error)?;
+    Ok(out)
+}
+
+/// Render `(x, y)` points as a filled line chart, green when the series ends
+/// higher than it started and red otherwise
+pub fn render_line_chart(points: &[(f64, f64)], width: u32, height: u32) -> Result<Vec<u8>> {
+    if points.len() < 2 {
+        return Err(AppError::Validation("Not enough data to draw a chart".to_string()));
+    }
+    if points.iter().any(|(x, y)| !x.is_finite() || !y.is_finite()) {
+        return Err(AppError::Validation("Chart data must be finite".to_string()));
+    }
+    if width <= 2 * PADDING || height <= 2 * PADDING {
+        return Err(AppError::Validation("Chart is too small".to_string()));
+    }
+
+    let (min_x, max_x) = bounds(points.iter().map(|p| p.0));
+    let (min_y, max_y) = bounds(points.iter().map(|p| p.1));
+    // Keep a flat series off the edges
+    let margin = ((max_y - min_y) * 0.05).max(max_y.abs() * 0.001).max(f64::EPSILON);
+    let (min_y, max_y) = (min_y - margin, max_y + margin);
+
+    let plot_w = f64::from(width - 2 * PADDING);
+    let plot_h = f64::from(height - 2 * PADDING);
+    let top = i64::from(PADDING);
+    let bottom = i64::from(height - PADDING);
+    let to_pixel = |(x, y): (f64, f64)| -> (i64, i64) {
+        let px = if max_x > min_x { (x - min_x) / (max_x - min_x) } else { 0.5 };
+        let py = (y - min_y) / (max_y - min_y);
+        (
+            (f64::from(PADDING) + px * plot_w).round() as i64,
+            (f64::from(PADDING) + (1.0 - py) * plot_h).round() as i64,
+        )
+    };
+
+    let rising = points[points.len() - 1].1 >= points[0].1;
+    let (line, fill) = if rising { (RISING, RISING_FILL) } else { (FALLING, FALLING_FILL) };
+
+    let mut canvas = Canvas::new(width, height, BACKGROUND);
+    for i in 0..=GRID_LINES {
+        canvas.hline(top + (bottom - top) * i64::from(i) / i64::from(GRID_LINES), GRID);
+    }
+
+    let pixels: Vec<(i64, i64)> = points.iter().copied().map(to_pixel).collect();
+    for pair in pixels.windows(2) {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        for x in x0.min(x1)..=x0.max(x1) {
+            let t = if x1 == x0 { 0.0 } else { (x - x0) as f64 / (x1 - x0) as f64 };
+            let y = (y0 as f64 + t * (y1 - y0) as f64).round() as i64;
+            canvas.vline(x, y, bottom, fill);
+        }
+    }
+    for pair in pixels.windows(2) {
+        canvas.line(pair[0], pair[1], 1, line);
+    }
+
+    canvas.encode()
+}
+
+fn bounds(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)))
+}
+
+/// Chart of a price history, time on the x axis
+pub fn render_price_chart(history: &PriceHistory) -> Result<Vec<u8>> {
+    let points: Vec<(f64, f64)> = history
+        .points
+        .iter()
+        .map(|p| (p.timestamp.timestamp() as f64, p.price))
+        .collect();
+    render_line_chart(&points, CHART_WIDTH, CHART_HEIGHT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(png_data: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let decoder = png::Decoder::new(png_data);
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        buf.truncate(info.buffer_size());
+        (info, buf)
+    }
+
+    fn pixel(info: &png::OutputInfo, data: &[u8], x: u32, y: u32) -> Rgb {
+        let offset = ((y * info.width + x) * 3) as usize;
+        [data[offset], data[offset + 1], data[offset + 2]]
+    }
+
+    #[test]
+    fn test_renders_png() {
+        let points = [(0.0, 100.0), (1.0, 120.0), (2.0, 110.0), (3.0, 130.0)];
+        let png_data = render_line_chart(&points, 200, 100).unwrap();
+        assert_eq!(&png_data[..8], b"\x89PNG\r\n\x1a\n");
+
+        let (info, data) = decode(&png_data);
+        assert_eq!((info.width, info.height), (200, 100));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        // The last point is the highest, so the line ends at the top right in green
+        assert_eq!(pixel(&info, &data, 200 - PADDING, PADDING + 2), RISING);
+        assert_eq!(pixel(&info, &data, 2, 2), BACKGROUND);
+    }
+
+    #[test]
+    fn test_falling_series_is_red() {
+        let points = [(0.0, 130.0), (1.0, 100.0)];
+        let (info, data) = decode(&render_line_chart(&points, 200, 100).unwrap());
+        assert_eq!(pixel(&info, &data, PADDING, PADDING + 2), FALLING);
+    }
+
+    #[test]
+    fn test_flat_series() {
+        let points = [(0.0, 100.0), (1.0, 100.0)];
+        assert!(render_line_chart(&points, 200, 100).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_bad_input() {
+        assert!(render_line_chart(&[(0.0, 1.0)], 200, 100).is_err());
+        assert!(render_line_chart(&[(0.0, 1.0), (1.0, f64::NAN)], 200, 100).is_err());
+        assert!(render_line_chart(&[(0.0, 1.0), (1.0, 2.0)], 40, 40).is_err());
+    }
+}
@@ -8,6 +8,7 @@ pub struct AppConfig {
     pub whatsapp_access_token: String,
     pub whatsapp_phone_number_id: String,
     pub whatsapp_webhook_verify_token: String,
+    pub whatsapp_app_secret: String,
     pub whatsapp_api_base_url: String,
     pub whatsapp_media_base_url: String,
     
@@ -15,10 +16,22 @@ pub struct AppConfig {
     pub twilio_account_sid: String,
     pub twilio_auth_token: String,
     pub twilio_whatsapp_number: String,
+    pub twilio_api_base_url: String,
+    /// Number critical notifications are sent from by SMS when WhatsApp fails
+    /// to deliver them; Twilio WhatsApp is used instead when empty
+    pub twilio_sms_number: String,
+    /// Provider users are messaged through until they write in on another:
+    /// `meta` for the WhatsApp Cloud API or `twilio`
+    pub messaging_provider: String,
+    /// Public URL of `/webhook/twilio` exactly as configured in Twilio, which
+    /// signs it into `X-Twilio-Signature`; Twilio webhooks are refused when empty
+    pub twilio_webhook_url: String,
 
     // BitSacco API Configuration
     pub bitsacco_api_base_url: String,
     pub bitsacco_api_token: String,
+    /// Shared secret BitSacco signs callbacks with; callbacks are refused when empty
+    pub bitsacco_callback_secret: String,
 
     // Server Configuration
     pub server_host: String,
@@ -27,11 +40,45 @@ pub struct AppConfig {
 
     // Security Configuration
     pub rate_limit_requests_per_minute: u32,
+    pub rate_limit_money_commands_per_minute: u32,
     pub max_message_length: usize,
 
     // BTC Service Configuration (CoinGecko - no API key required)
     pub btc_api_base_url: String,
     pub btc_api_key: Option<String>,
+
+    // Additional BTC price sources, and which sources are enabled
+    pub coinbase_api_base_url: String,
+    pub kraken_api_base_url: String,
+    pub binance_api_base_url: String,
+    pub btc_price_sources: Vec<String>,
+
+    // Lightning deposits awaiting settlement; not persisted when empty
+    pub invoice_store_path: String,
+
+    // Seconds to wait for an M-Pesa callback before querying the STK Push status
+    pub mpesa_status_query_delay_secs: u64,
+
+    // JSON file of per-user transaction limits; built-in defaults when empty
+    pub limits_config_path: String,
+
+    // JSON list of approved message templates; built-in templates when empty
+    pub templates_config_path: String,
+
+    // Transaction PINs; not persisted when empty
+    pub pin_store_path: String,
+
+    // BitSacco web app, where users reset a forgotten PIN
+    pub bitsacco_web_app_url: String,
+
+    // Seconds a WhatsApp message ID is remembered to drop redeliveries
+    pub message_dedup_retention_secs: u64,
+
+    // Bearer token for the /admin endpoints; they are disabled when empty
+    pub admin_api_token: String,
+
+    // WhatsApp messages queued for retry and dead letters; not persisted when empty
+    pub outbound_queue_path: String,
 }
 
 impl AppConfig {
@@ -46,6 +93,8 @@ impl AppConfig {
                 .context("WHATSAPP_PHONE_NUMBER_ID must be set")?,
             whatsapp_webhook_verify_token: env::var("WHATSAPP_WEBHOOK_VERIFY_TOKEN")
                 .context("WHATSAPP_WEBHOOK_VERIFY_TOKEN must be set")?,
+            whatsapp_app_secret: env::var("WHATSAPP_APP_SECRET")
+                .context("WHATSAPP_APP_SECRET must be set")?,
             whatsapp_api_base_url: env::var("WHATSAPP_API_BASE_URL")
                 .unwrap_or_else(|_| "https://graph.facebook.com/v18.0".to_string()),
             whatsapp_media_base_url: env::var("WHATSAPP_MEDIA_BASE_URL")
@@ -58,11 +107,21 @@ impl AppConfig {
                 .unwrap_or_else(|_| "".to_string()),
             twilio_whatsapp_number: env::var("TWILIO_WHATSAPP_NUMBER")
                 .unwrap_or_else(|_| "".to_string()),
+            twilio_api_base_url: env::var("TWILIO_API_BASE_URL")
+                .unwrap_or_else(|_| "https://api.twilio.com".to_string()),
+            twilio_sms_number: env::var("TWILIO_SMS_NUMBER")
+                .unwrap_or_else(|_| "".to_string()),
+            messaging_provider: env::var("MESSAGING_PROVIDER")
+                .unwrap_or_else(|_| "meta".to_string()),
+            twilio_webhook_url: env::var("TWILIO_WEBHOOK_URL")
+                .unwrap_or_else(|_| "".to_string()),
 
             bitsacco_api_base_url: env::var("BITSACCO_API_BASE_URL")
                 .unwrap_or_else(|_| "https://api.bitsacco.com".to_string()),
             bitsacco_api_token: env::var("BITSACCO_API_TOKEN")
                 .context("BITSACCO_API_TOKEN must be set")?,
+            bitsacco_callback_secret: env::var("BITSACCO_CALLBACK_SECRET")
+                .unwrap_or_else(|_| "".to_string()),
 
             server_host: env::var("SERVER_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
             server_port: env::var("SERVER_PORT")
@@ -75,6 +134,10 @@ impl AppConfig {
                 .unwrap_or_else(|_| "60".to_string())
                 .parse()
                 .context("Invalid RATE_LIMIT_REQUESTS_PER_MINUTE")?,
+            rate_limit_money_commands_per_minute: env::var("RATE_LIMIT_MONEY_COMMANDS_PER_MINUTE")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()
+                .context("Invalid RATE_LIMIT_MONEY_COMMANDS_PER_MINUTE")?,
             max_message_length: env::var("MAX_MESSAGE_LENGTH")
                 .unwrap_or_else(|_| "4096".to_string())
                 .parse()
@@ -83,6 +146,46 @@ impl AppConfig {
             btc_api_base_url: env::var("BTC_API_BASE_URL")
                 .unwrap_or_else(|_| "https://api.coingecko.com/api/v3".to_string()),
             btc_api_key: env::var("BTC_API_KEY").ok(),
+
+            coinbase_api_base_url: env::var("COINBASE_API_BASE_URL")
+                .unwrap_or_else(|_| "https://api.coinbase.com/v2".to_string()),
+            kraken_api_base_url: env::var("KRAKEN_API_BASE_URL")
+                .unwrap_or_else(|_| "https://api.kraken.com".to_string()),
+            binance_api_base_url: env::var("BINANCE_API_BASE_URL")
+                .unwrap_or_else(|_| "https://api.binance.com".to_string()),
+            btc_price_sources: env::var("BTC_PRICE_SOURCES")
+                .unwrap_or_else(|_| "coingecko,coinbase,kraken,binance".to_string())
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+
+            invoice_store_path: env::var("INVOICE_STORE_PATH")
+                .unwrap_or_else(|_| "data/lightning_invoices.json".to_string()),
+
+            mpesa_status_query_delay_secs: env::var("MPESA_STATUS_QUERY_DELAY_SECS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .context("Invalid MPESA_STATUS_QUERY_DELAY_SECS")?,
+
+            limits_config_path: env::var("LIMITS_CONFIG_PATH").unwrap_or_else(|_| "".to_string()),
+
+            templates_config_path: env::var("TEMPLATES_CONFIG_PATH").unwrap_or_else(|_| "".to_string()),
+
+            pin_store_path: env::var("PIN_STORE_PATH").unwrap_or_else(|_| "data/pins.json".to_string()),
+
+            bitsacco_web_app_url: env::var("BITSACCO_WEB_APP_URL")
+                .unwrap_or_else(|_| "https://app.bitsacco.com".to_string()),
+
+            message_dedup_retention_secs: env::var("MESSAGE_DEDUP_RETENTION_SECS")
+                .unwrap_or_else(|_| "86400".to_string())
+                .parse()
+                .context("Invalid MESSAGE_DEDUP_RETENTION_SECS")?,
+
+            admin_api_token: env::var("ADMIN_API_TOKEN").unwrap_or_else(|_| "".to_string()),
+
+            outbound_queue_path: env::var("OUTBOUND_QUEUE_PATH")
+                .unwrap_or_else(|_| "data/outbound_queue.json".to_string()),
         };
 
         // Validate configuration
@@ -104,11 +207,15 @@ impl AppConfig {
             anyhow::bail!("WhatsApp webhook verify token cannot be empty");
         }
 
+        if self.whatsapp_app_secret.is_empty() {
+            anyhow::bail!("WhatsApp app secret cannot be empty");
+        }
+
         if self.bitsacco_api_token.is_empty() {
             anyhow::bail!("BitSacco API token cannot be empty");
         }
 
-        if self.rate_limit_requests_per_minute == 0 {
+        if self.rate_limit_requests_per_minute == 0 || self.rate_limit_money_commands_per_minute == 0 {
             anyhow::bail!("Rate limit must be greater than 0");
         }
 
@@ -116,6 +223,10 @@ impl AppConfig {
             anyhow::bail!("Max message length must be greater than 0");
         }
 
+        if self.btc_price_sources.is_empty() {
+            anyhow::bail!("At least one BTC price source must be enabled");
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,270 @@
+//! Two-step confirmation for money-moving commands
+//!
+//! Withdrawals, transfers, share purchases and chama contributions are not
+//! executed straight from the parser. Instead they are parked here, keyed by
+//! the sender's phone number, and only run once the user replies `YES`.
+//! Each user may have at most one pending action, and unconfirmed actions
+//! expire after a short window.
+
+use crate::{lightning::LightningWithdrawal, money::Money, types::BotCommand, withdrawal::WithdrawalPlan};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// How long a pending action waits for a `YES` before it expires
+pub const DEFAULT_CONFIRMATION_TTL: Duration = Duration::from_secs(120);
+
+/// A money-moving command waiting for the user's confirmation
+#[derive(Debug, Clone)]
+pub struct PendingAction {
+    pub command: BotCommand,
+    pub summary: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PendingAction {
+    pub fn is_expired(&self) -> bool {
+        Utc::now() >= self.expires_at
+    }
+}
+
+/// Result of asking the store to hold a new action
+#[derive(Debug, Clone)]
+pub enum BeginOutcome {
+    /// The action was stored and now awaits confirmation
+    Started(PendingAction),
+    /// Another unexpired action is already pending for this user
+    AlreadyPending(PendingAction),
+}
+
+/// Result of a `YES` reply
+#[derive(Debug, Clone)]
+pub enum ConfirmOutcome {
+    Confirmed(PendingAction),
+    Expired(PendingAction),
+    NothingPending,
+}
+
+/// In-memory store of pending actions, one per phone number
+#[derive(Debug, Clone)]
+pub struct PendingActionStore {
+    actions: Arc<RwLock<HashMap<String, PendingAction>>>,
+    ttl: Duration,
+}
+
+impl Default for PendingActionStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONFIRMATION_TTL)
+    }
+}
+
+impl PendingActionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            actions: Arc::new(RwLock::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    /// Confirmation window in whole minutes, for user-facing prompts
+    pub fn ttl_minutes(&self) -> u64 {
+        self.ttl.as_secs().div_ceil(60).max(1)
+    }
+
+    /// Hold a command until the user confirms it.
+    ///
+    /// Expired entries are dropped first, so a stale action never blocks a new one.
+    pub async fn begin(&self, phone_number: &str, command: BotCommand, summary: String) -> BeginOutcome {
+        let mut actions = self.actions.write().await;
+        actions.retain(|_, action| !action.is_expired());
+
+        if let Some(existing) = actions.get(phone_number) {
+            return BeginOutcome::AlreadyPending(existing.clone());
+        }
+
+        let created_at = Utc::now();
+        let expires_at = created_at
+            + chrono::Duration::from_std(self.ttl).unwrap_or_else(|_| chrono::Duration::seconds(120));
+        let action = PendingAction {
+            command,
+            summary,
+            created_at,
+            expires_at,
+        };
+        actions.insert(phone_number.to_string(), action.clone());
+        BeginOutcome::Started(action)
+    }
+
+    /// Take the pending action for a user, if any, so it can be executed
+    pub async fn confirm(&self, phone_number: &str) -> ConfirmOutcome {
+        match self.actions.write().await.remove(phone_number) {
+            Some(action) if action.is_expired() => ConfirmOutcome::Expired(action),
+            Some(action) => ConfirmOutcome::Confirmed(action),
+            None => ConfirmOutcome::NothingPending,
+        }
+    }
+
+    /// Drop the pending action for a user, returning it if it was still live
+    pub async fn cancel(&self, phone_number: &str) -> Option<PendingAction> {
+        self.actions
+            .write()
+            .await
+            .remove(phone_number)
+            .filter(|action| !action.is_expired())
+    }
+
+    /// Look at the pending action for a user without consuming it
+    pub async fn get(&self, phone_number: &str) -> Option<PendingAction> {
+        self.actions
+            .read()
+            .await
+            .get(phone_number)
+            .filter(|action| !action.is_expired())
+            .cloned()
+    }
+}
+
+/// Build the one-line summary echoed back before a money-moving command runs.
+///
+/// `mpesa_phone` is the number M-Pesa withdrawals will be paid out to.
+/// Returns `None` for commands that do not need confirmation.
+pub fn describe_action(command: &BotCommand, mpesa_phone: &str) -> Option<String> {
+    match command {
+        BotCommand::Withdraw { amount, method, destination } => {
+            let now = Utc::now();
+            let summary = match WithdrawalPlan::resolve(*amount, method.as_deref(), destination.as_deref(), now) {
+                Ok(plan) => plan.summary(mpesa_phone, now),
+                Err(_) => format!("Withdraw {} via {}", amount, method_label(method.as_deref().unwrap_or("mpesa"))),
+            };
+            Some(summary)
+        }
+        BotCommand::LightningWithdraw { amount, destination } => {
+            let now = Utc::now();
+            let summary = match LightningWithdrawal::resolve(destination, *amount, now) {
+                Ok(withdrawal) => withdrawal.summary(now),
+                Err(_) => format!("Withdraw via Lightning to {}", destination),
+            };
+            Some(summary)
+        }
+        BotCommand::Transfer { amount, recipient } => {
+            Some(format!("Transfer {} to {}", amount, recipient))
+        }
+        BotCommand::BuyShares { count, method } => Some(format!(
+            "Buy {} membership share{} via {}",
+            count,
+            if *count == 1 { "" } else { "s" },
+            method_label(method.as_deref().unwrap_or("mpesa"))
+        )),
+        BotCommand::ContributeChama { chama_id, amount } => {
+            Some(format!("Contribute {} to chama {}", amount, chama_id))
+        }
+        _ => None,
+    }
+}
+
+/// Fee and limit details shown under the summary, for commands whose cost
+/// depends on how they are paid out. `fee` is BitSacco's quote, if any.
+pub fn describe_costs(command: &BotCommand, fee: Option<Money>) -> Option<String> {
+    match command {
+        BotCommand::Withdraw { amount, method, destination } => {
+            WithdrawalPlan::resolve(*amount, method.as_deref(), destination.as_deref(), Utc::now())
+                .and_then(|plan| plan.cost_preview(fee))
+                .ok()
+        }
+        _ => None,
+    }
+}
+
+fn method_label(method: &str) -> &str {
+    match method {
+        "mpesa" => "M-Pesa",
+        "lightning" => "Lightning",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::{Currency, Money};
+
+    fn withdrawal() -> BotCommand {
+        BotCommand::Withdraw {
+            amount: Money::from_major(500, Currency::Kes).unwrap(),
+            method: None,
+            destination: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_begin_and_confirm() {
+        let store = PendingActionStore::default();
+        let outcome = store
+            .begin("+254712345678", withdrawal(), "Withdraw 500.00 KES".to_string())
+            .await;
+        assert!(matches!(outcome, BeginOutcome::Started(_)));
+
+        match store.confirm("+254712345678").await {
+            ConfirmOutcome::Confirmed(action) => assert_eq!(action.command, withdrawal()),
+            other => panic!("expected confirmed action, got {:?}", other),
+        }
+        assert!(matches!(store.confirm("+254712345678").await, ConfirmOutcome::NothingPending));
+    }
+
+    #[tokio::test]
+    async fn test_one_pending_action_per_user() {
+        let store = PendingActionStore::default();
+        store.begin("+254712345678", withdrawal(), "first".to_string()).await;
+
+        let outcome = store.begin("+254712345678", withdrawal(), "second".to_string()).await;
+        match outcome {
+            BeginOutcome::AlreadyPending(existing) => assert_eq!(existing.summary, "first"),
+            other => panic!("expected existing action, got {:?}", other),
+        }
+
+        // Other users are unaffected
+        let outcome = store.begin("+254700000000", withdrawal(), "other".to_string()).await;
+        assert!(matches!(outcome, BeginOutcome::Started(_)));
+    }
+
+    #[tokio::test]
+    async fn test_expired_action_is_not_executed() {
+        let store = PendingActionStore::new(Duration::ZERO);
+        store.begin("+254712345678", withdrawal(), "stale".to_string()).await;
+
+        assert!(store.get("+254712345678").await.is_none());
+        assert!(matches!(store.confirm("+254712345678").await, ConfirmOutcome::Expired(_)));
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let store = PendingActionStore::default();
+        store.begin("+254712345678", withdrawal(), "cancel me".to_string()).await;
+
+        let cancelled = store.cancel("+254712345678").await;
+        assert_eq!(cancelled.map(|a| a.summary), Some("cancel me".to_string()));
+        assert!(store.get("+254712345678").await.is_none());
+    }
+
+    #[test]
+    fn test_describe_action() {
+        let summary = describe_action(&withdrawal(), "+254712345678").unwrap();
+        assert_eq!(summary, "Withdraw 500.00 KES via M-Pesa to +254712345678");
+        let fee = Money::from_major(15, Currency::Kes).unwrap();
+        assert!(describe_costs(&withdrawal(), Some(fee)).unwrap().starts_with("Fee: 15.00 KES\nM-Pesa limits:"));
+        assert!(describe_action(&BotCommand::Balance, "+254712345678").is_none());
+        assert!(describe_costs(&BotCommand::Balance, None).is_none());
+
+        let lightning = BotCommand::LightningWithdraw {
+            amount: Some(Money::from_major(500, Currency::Kes).unwrap()),
+            destination: "alice@wallet.com".to_string(),
+        };
+        assert_eq!(
+            describe_action(&lightning, "+254712345678").unwrap(),
+            "Withdraw 500.00 KES via Lightning to alice@wallet.com"
+        );
+    }
+}
@@ -0,0 +1,694 @@
+//! Guided multi-turn conversations for incomplete commands
+//!
+//! `BotCommand::parse` only understands fully-specified commands. When a user
+//! sends something like `deposit` or `withdraw 500`, a conversation session is
+//! opened that asks for each missing detail in turn ("How much?", "M-Pesa or
+//! Lightning?", "Which chama?") and produces a complete `BotCommand` once every
+//! slot is filled. Users can reply `back` to revisit the previous answer or
+//! `cancel` to abandon the flow at any step. Setting, changing and removing
+//! the transaction PIN use the same mechanism, so a PIN is never typed as
+//! part of a longer command.
+
+use crate::{
+    interactive::{Choice, InteractiveMessage},
+    lightning::LightningDestination,
+    money::{Currency, Money, MoneyError},
+    pin::Pin,
+    types::BotCommand,
+    validation::{validate_amount, validate_phone_number},
+};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// How long a session stays open without a reply
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(300);
+
+/// Currency assumed when the user answers with a bare number
+const DEFAULT_CURRENCY: Currency = Currency::Kes;
+
+/// Commands that can be completed through a guided conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuidedFlow {
+    Deposit,
+    Withdraw,
+    Transfer,
+    ContributeChama,
+    BuyShares,
+    LightningDeposit,
+    LightningWithdraw,
+    SetPin,
+    ChangePin,
+    RemovePin,
+}
+
+impl GuidedFlow {
+    /// Slots this flow needs, in the order they are asked for
+    pub fn slots(&self) -> &'static [Slot] {
+        match self {
+            GuidedFlow::Deposit => &[Slot::Amount, Slot::Method],
+            GuidedFlow::Withdraw => &[Slot::Amount, Slot::Method, Slot::Destination],
+            GuidedFlow::Transfer => &[Slot::Amount, Slot::Recipient],
+            GuidedFlow::ContributeChama => &[Slot::Chama, Slot::Amount],
+            GuidedFlow::BuyShares => &[Slot::ShareCount, Slot::Method],
+            GuidedFlow::LightningDeposit => &[Slot::Amount],
+            GuidedFlow::LightningWithdraw => &[Slot::Destination, Slot::Amount],
+            GuidedFlow::SetPin => &[Slot::NewPin, Slot::ConfirmPin],
+            GuidedFlow::ChangePin => &[Slot::CurrentPin, Slot::NewPin, Slot::ConfirmPin],
+            GuidedFlow::RemovePin => &[Slot::CurrentPin],
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            GuidedFlow::Deposit => "Deposit",
+            GuidedFlow::Withdraw => "Withdrawal",
+            GuidedFlow::Transfer => "Transfer",
+            GuidedFlow::ContributeChama => "Chama Contribution",
+            GuidedFlow::BuyShares => "Share Purchase",
+            GuidedFlow::LightningDeposit => "Lightning Deposit",
+            GuidedFlow::LightningWithdraw => "Lightning Withdrawal",
+            GuidedFlow::SetPin => "Set PIN",
+            GuidedFlow::ChangePin => "Change PIN",
+            GuidedFlow::RemovePin => "Remove PIN",
+        }
+    }
+}
+
+/// A piece of information a guided flow asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Amount,
+    Method,
+    Recipient,
+    Chama,
+    ShareCount,
+    Destination,
+    CurrentPin,
+    NewPin,
+    ConfirmPin,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlotValue {
+    Amount(Money),
+    Method(String),
+    Recipient(String),
+    Chama(String),
+    ShareCount(u32),
+    Destination(String),
+    Pin(Pin),
+}
+
+/// What the bot should do next in a conversation
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConversationStep {
+    /// Ask the user for this slot
+    Ask(Slot),
+    /// Every slot is filled and the command can be resumed
+    Complete(BotCommand),
+}
+
+/// A chama the user can pick by number
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChamaOption {
+    pub id: String,
+    pub name: String,
+}
+
+/// Per-user state of a guided conversation
+#[derive(Debug, Clone)]
+pub struct ConversationSession {
+    pub flow: GuidedFlow,
+    pub filled: Vec<(Slot, SlotValue)>,
+    pub chama_options: Vec<ChamaOption>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ConversationSession {
+    pub fn new(flow: GuidedFlow) -> Self {
+        Self {
+            flow,
+            filled: Vec::new(),
+            chama_options: Vec::new(),
+            expires_at: Utc::now(),
+        }
+    }
+
+    /// Open a session for an incomplete command such as `deposit` or
+    /// `withdraw 500`, pre-filling whatever the message already contains.
+    /// Returns `None` when the message does not start a guided flow.
+    pub fn start(message: &str) -> Option<Self> {
+        let message = message.trim().to_lowercase();
+        let parts: Vec<&str> = message.split_whitespace().collect();
+
+        let (flow, rest) = match parts.as_slice() {
+            ["lightning", "deposit", rest @ ..] => (GuidedFlow::LightningDeposit, rest),
+            ["deposit", rest @ ..] => (GuidedFlow::Deposit, rest),
+            ["withdraw", rest @ ..] => (GuidedFlow::Withdraw, rest),
+            ["transfer", rest @ ..] => (GuidedFlow::Transfer, rest),
+            ["buy", "shares", rest @ ..] => (GuidedFlow::BuyShares, rest),
+            // PINs are always asked for on their own, never read from the opening message
+            ["set" | "create", "pin", ..] => return Some(Self::new(GuidedFlow::SetPin)),
+            ["change", "pin", ..] => return Some(Self::new(GuidedFlow::ChangePin)),
+            ["remove" | "disable", "pin", ..] => return Some(Self::new(GuidedFlow::RemovePin)),
+            ["contribute", "chama", rest @ ..] => {
+                let mut session = Self::new(GuidedFlow::ContributeChama);
+                if let Some((chama_id, amount)) = rest.split_first() {
+                    session.filled.push((Slot::Chama, SlotValue::Chama(chama_id.to_string())));
+                    if let Ok(amount) = parse_amount(&amount.join(" ")) {
+                        session.filled.push((Slot::Amount, SlotValue::Amount(amount)));
+                    }
+                }
+                return Some(session);
+            }
+            ["lightning", "withdraw", rest @ ..] => {
+                // The destination, if given, comes last: `lightning withdraw 500 KES alice@wallet.com`
+                let mut session = Self::new(GuidedFlow::LightningWithdraw);
+                let mut rest = rest;
+                if let Some((last, amount)) = rest.split_last() {
+                    if LightningDestination::parse(last).is_ok() {
+                        session.filled.push((Slot::Destination, SlotValue::Destination(last.to_string())));
+                        rest = amount;
+                    }
+                }
+                if let Ok(amount) = parse_amount(&rest.join(" ")) {
+                    session.filled.push((Slot::Amount, SlotValue::Amount(amount)));
+                }
+                return Some(session);
+            }
+            _ => return None,
+        };
+
+        let mut session = Self::new(flow);
+        // A trailing method, as in `withdraw 500 KES lightning`, fills the method slot
+        let mut rest = rest;
+        if flow.slots().contains(&Slot::Method) {
+            if let Some((method, before)) = rest.split_last().and_then(|(last, before)| Some((parse_method(last)?, before))) {
+                session.filled.push((Slot::Method, method));
+                rest = before;
+            }
+        }
+        let rest = rest.join(" ");
+        if !rest.is_empty() {
+            let prefill = match flow {
+                GuidedFlow::BuyShares => parse_share_count(&rest).map(|v| (Slot::ShareCount, v)),
+                _ => parse_amount(&rest).ok().map(|v| (Slot::Amount, SlotValue::Amount(v))),
+            };
+            session.filled.extend(prefill);
+        }
+        Some(session)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now() >= self.expires_at
+    }
+
+    fn value(&self, slot: Slot) -> Option<&SlotValue> {
+        self.filled.iter().find(|(s, _)| *s == slot).map(|(_, v)| v)
+    }
+
+    /// Whether a slot still has to be asked for. Invoices that carry an
+    /// amount make the amount slot unnecessary, and plain withdrawals only
+    /// need a destination when paid out over Lightning; M-Pesa goes to the
+    /// user's registered number.
+    fn needs(&self, slot: Slot) -> bool {
+        if self.flow == GuidedFlow::Withdraw {
+            return match slot {
+                Slot::Destination => matches!(self.value(Slot::Method), Some(SlotValue::Method(method)) if method == "lightning"),
+                _ => true,
+            };
+        }
+        match (slot, self.value(Slot::Destination)) {
+            (Slot::Amount, Some(SlotValue::Destination(destination))) => {
+                LightningDestination::parse(destination).is_ok_and(|d| d.needs_amount())
+            }
+            _ => true,
+        }
+    }
+
+    /// The next missing slot, or the finished command
+    pub fn next_step(&self) -> ConversationStep {
+        if let Some(slot) = self
+            .flow
+            .slots()
+            .iter()
+            .find(|slot| self.value(**slot).is_none() && self.needs(**slot))
+        {
+            return ConversationStep::Ask(*slot);
+        }
+
+        let amount = match self.value(Slot::Amount) {
+            Some(SlotValue::Amount(amount)) => *amount,
+            _ => Money::zero(DEFAULT_CURRENCY),
+        };
+        let pin = |slot| match self.value(slot) {
+            Some(SlotValue::Pin(pin)) => pin.clone(),
+            _ => Pin::default(),
+        };
+        let method = match self.value(Slot::Method) {
+            Some(SlotValue::Method(method)) => Some(method.clone()),
+            _ => None,
+        };
+
+        let command = match self.flow {
+            GuidedFlow::Deposit => BotCommand::Deposit { amount, method },
+            GuidedFlow::Withdraw => BotCommand::Withdraw {
+                amount,
+                method,
+                destination: match self.value(Slot::Destination) {
+                    Some(SlotValue::Destination(destination)) => Some(destination.clone()),
+                    _ => None,
+                },
+            },
+            GuidedFlow::Transfer => BotCommand::Transfer {
+                amount,
+                recipient: match self.value(Slot::Recipient) {
+                    Some(SlotValue::Recipient(recipient)) => recipient.clone(),
+                    _ => String::new(),
+                },
+            },
+            GuidedFlow::ContributeChama => BotCommand::ContributeChama {
+                chama_id: match self.value(Slot::Chama) {
+                    Some(SlotValue::Chama(chama_id)) => chama_id.clone(),
+                    _ => String::new(),
+                },
+                amount,
+            },
+            GuidedFlow::BuyShares => BotCommand::BuyShares {
+                count: match self.value(Slot::ShareCount) {
+                    Some(SlotValue::ShareCount(count)) => *count,
+                    _ => 0,
+                },
+                method,
+            },
+            GuidedFlow::LightningDeposit => BotCommand::LightningDeposit { amount },
+            GuidedFlow::LightningWithdraw => BotCommand::LightningWithdraw {
+                amount: match self.value(Slot::Amount) {
+                    Some(SlotValue::Amount(amount)) => Some(*amount),
+                    _ => None,
+                },
+                destination: match self.value(Slot::Destination) {
+                    Some(SlotValue::Destination(destination)) => destination.clone(),
+                    _ => String::new(),
+                },
+            },
+            GuidedFlow::SetPin => BotCommand::SetPin { pin: pin(Slot::NewPin) },
+            GuidedFlow::ChangePin => BotCommand::ChangePin {
+                current: pin(Slot::CurrentPin),
+                pin: pin(Slot::NewPin),
+            },
+            GuidedFlow::RemovePin => BotCommand::RemovePin { current: pin(Slot::CurrentPin) },
+        };
+        ConversationStep::Complete(command)
+    }
+
+    /// Fill the current slot from the user's reply.
+    /// On failure returns a hint explaining what was expected.
+    pub fn answer(&mut self, input: &str) -> std::result::Result<(), String> {
+        let ConversationStep::Ask(slot) = self.next_step() else {
+            return Ok(());
+        };
+        let input = input.trim();
+
+        let value = match slot {
+            Slot::Amount => {
+                let amount = parse_amount(input)?;
+                validate_amount(&amount).map_err(|e| e.to_string())?;
+                SlotValue::Amount(amount)
+            }
+            Slot::Method => parse_method(input)
+                .ok_or_else(|| "Please reply `mpesa` or `lightning`.".to_string())?,
+            Slot::Recipient => {
+                validate_phone_number(input).map_err(|e| e.to_string())?;
+                SlotValue::Recipient(input.to_string())
+            }
+            Slot::Chama => self
+                .pick_chama(input)
+                .ok_or_else(|| "Please reply with the number or ID of one of your chamas.".to_string())?,
+            Slot::ShareCount => parse_share_count(input)
+                .ok_or_else(|| "Please reply with a whole number of shares, e.g. `10`.".to_string())?,
+            Slot::Destination => {
+                LightningDestination::parse(input).map_err(|e| e.to_string())?;
+                SlotValue::Destination(input.to_string())
+            }
+            Slot::CurrentPin => SlotValue::Pin(Pin::parse(input).map_err(|e| e.to_string())?),
+            Slot::NewPin => {
+                let pin = Pin::parse(input).map_err(|e| e.to_string())?;
+                pin.check_strength().map_err(|e| e.to_string())?;
+                SlotValue::Pin(pin)
+            }
+            Slot::ConfirmPin => {
+                let pin = Pin::parse(input).map_err(|e| e.to_string())?;
+                if self.value(Slot::NewPin) != Some(&SlotValue::Pin(pin.clone())) {
+                    // Start the new PIN over rather than guess which one was mistyped
+                    self.filled.retain(|(slot, _)| *slot != Slot::NewPin);
+                    return Err("The PINs did not match. Please choose your new PIN again.".to_string());
+                }
+                SlotValue::Pin(pin)
+            }
+        };
+
+        self.filled.push((slot, value));
+        Ok(())
+    }
+
+    /// Forget the most recent answer. Returns `false` if nothing was filled yet.
+    pub fn back(&mut self) -> bool {
+        self.filled.pop().is_some()
+    }
+
+    fn pick_chama(&self, input: &str) -> Option<SlotValue> {
+        if let Ok(index) = input.parse::<usize>() {
+            return index
+                .checked_sub(1)
+                .and_then(|i| self.chama_options.get(i))
+                .map(|option| SlotValue::Chama(option.id.clone()));
+        }
+        self.chama_options
+            .iter()
+            .find(|option| option.id.eq_ignore_ascii_case(input) || option.name.eq_ignore_ascii_case(input))
+            .map(|option| SlotValue::Chama(option.id.clone()))
+    }
+
+    /// Question to send for a slot, including step progress and controls
+    pub fn prompt(&self, slot: Slot) -> String {
+        let question = match slot {
+            Slot::Amount => format!("How much? Reply with an amount, e.g. `500` or `500 {}`.", DEFAULT_CURRENCY),
+            Slot::Method => "M-Pesa or Lightning? Reply `mpesa` or `lightning`.".to_string(),
+            Slot::Recipient => "Who should receive it? Reply with their phone number, e.g. `+254712345678`.".to_string(),
+            Slot::ShareCount => "How many shares would you like to buy?".to_string(),
+            Slot::Destination => "Where should it go? Reply with a Lightning invoice (lnbc...), Lightning address (name@wallet.com) or LNURL.".to_string(),
+            Slot::CurrentPin => "Reply with your current PIN.".to_string(),
+            Slot::NewPin => "Choose a 4 to 6 digit PIN. Avoid easy ones like `1234` or `0000`.".to_string(),
+            Slot::ConfirmPin => "Send the same PIN again to confirm it.".to_string(),
+            Slot::Chama => format!(
+                "Which chama? Reply with the number or chama ID:\n{}",
+                self.chama_options
+                    .iter()
+                    .enumerate()
+                    .map(|(i, option)| format!("{}. {} ({})", i + 1, option.name, option.id))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+        };
+
+        self.framed(&question)
+    }
+
+    /// The prompt for `slot` with its choices as buttons or a list, for slots
+    /// that have a fixed set of answers
+    pub fn interactive_prompt(&self, slot: Slot) -> Option<InteractiveMessage> {
+        match slot {
+            Slot::Method => Some(InteractiveMessage::buttons(
+                self.framed("M-Pesa or Lightning?"),
+                vec![
+                    Choice::new("mpesa", "M-Pesa"),
+                    Choice::new("lightning", "Lightning"),
+                    Choice::new("cancel", "Cancel"),
+                ],
+                self.prompt(slot),
+            )),
+            Slot::Chama => Some(InteractiveMessage::list(
+                self.framed("Which chama?"),
+                "Choose chama",
+                self.chama_options
+                    .iter()
+                    .enumerate()
+                    .map(|(i, option)| Choice::new((i + 1).to_string(), option.name.clone()).with_description(option.id.clone()))
+                    .collect(),
+                self.prompt(slot),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Add the flow title, step count and navigation hint to a question
+    fn framed(&self, question: &str) -> String {
+        let total = self.flow.slots().iter().filter(|slot| self.needs(**slot)).count();
+        let step = self.filled.len() + 1;
+        format!(
+            "📝 *{}* (step {} of {})\n\n{}\n\nReply `back` to change your last answer or `cancel` to stop.",
+            self.flow.title(),
+            step.min(total),
+            total,
+            question
+        )
+    }
+}
+
+/// Parse `500`, `500 KES` or `KES 500` into an amount.
+/// On failure returns a hint explaining what was expected.
+fn parse_amount(input: &str) -> std::result::Result<Money, String> {
+    let hint = || "Please reply with an amount, e.g. `500` or `500 KES`.".to_string();
+    let parts: Vec<&str> = input.split_whitespace().collect();
+    let (amount, currency) = match parts.as_slice() {
+        [amount] => (*amount, DEFAULT_CURRENCY),
+        [first, second] => match (second.parse::<Currency>(), first.parse::<Currency>()) {
+            (Ok(currency), _) => (*first, currency),
+            (_, Ok(currency)) => (*second, currency),
+            (Err(e), Err(_)) => return Err(e.to_string()),
+        },
+        _ => return Err(hint()),
+    };
+    Money::parse(amount, currency).map_err(|e| match e {
+        MoneyError::InvalidAmount(_) => hint(),
+        other => other.to_string(),
+    })
+}
+
+fn parse_method(input: &str) -> Option<SlotValue> {
+    match input.to_lowercase().as_str() {
+        "mpesa" | "m-pesa" | "m pesa" => Some(SlotValue::Method("mpesa".to_string())),
+        "lightning" | "ln" => Some(SlotValue::Method("lightning".to_string())),
+        _ => None,
+    }
+}
+
+fn parse_share_count(input: &str) -> Option<SlotValue> {
+    input
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|count| *count > 0)
+        .map(SlotValue::ShareCount)
+}
+
+/// In-memory store of open conversations, one per phone number
+#[derive(Debug, Clone)]
+pub struct ConversationStore {
+    sessions: Arc<RwLock<HashMap<String, ConversationSession>>>,
+    ttl: Duration,
+}
+
+impl Default for ConversationStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_TTL)
+    }
+}
+
+impl ConversationStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    /// Get the open session for a user, ignoring expired ones
+    pub async fn get(&self, phone_number: &str) -> Option<ConversationSession> {
+        self.sessions
+            .read()
+            .await
+            .get(phone_number)
+            .filter(|session| !session.is_expired())
+            .cloned()
+    }
+
+    /// Store a session and push its expiry forward by the TTL
+    pub async fn save(&self, phone_number: &str, mut session: ConversationSession) {
+        session.expires_at = Utc::now()
+            + chrono::Duration::from_std(self.ttl).unwrap_or_else(|_| chrono::Duration::seconds(300));
+        let mut sessions = self.sessions.write().await;
+        sessions.retain(|_, session| !session.is_expired());
+        sessions.insert(phone_number.to_string(), session);
+    }
+
+    /// Close the session for a user
+    pub async fn end(&self, phone_number: &str) -> Option<ConversationSession> {
+        self.sessions.write().await.remove(phone_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_detects_incomplete_commands() {
+        let session = ConversationSession::start("deposit").unwrap();
+        assert_eq!(session.flow, GuidedFlow::Deposit);
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::Amount));
+
+        let session = ConversationSession::start("withdraw 500").unwrap();
+        assert_eq!(session.flow, GuidedFlow::Withdraw);
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::Method));
+
+        assert!(ConversationSession::start("balance").is_none());
+    }
+
+    #[test]
+    fn test_deposit_flow_completes() {
+        let mut session = ConversationSession::start("deposit").unwrap();
+        assert!(session.answer("abc").is_err());
+        session.answer("500").unwrap();
+        assert!(session.answer("paypal").is_err());
+        assert!(ConversationSession::start("deposit").unwrap().answer("500.123").is_err());
+        session.answer("Lightning").unwrap();
+
+        assert_eq!(
+            session.next_step(),
+            ConversationStep::Complete(BotCommand::Deposit {
+                amount: Money::from_major(500, Currency::Kes).unwrap(),
+                method: Some("lightning".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_back_revisits_previous_slot() {
+        let mut session = ConversationSession::start("transfer").unwrap();
+        session.answer("100 KES").unwrap();
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::Recipient));
+
+        assert!(session.back());
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::Amount));
+        assert!(!session.back());
+    }
+
+    #[test]
+    fn test_chama_picked_by_number() {
+        let mut session = ConversationSession::start("contribute chama").unwrap();
+        session.chama_options = vec![
+            ChamaOption { id: "CH1".to_string(), name: "Investment Club".to_string() },
+            ChamaOption { id: "CH2".to_string(), name: "Family".to_string() },
+        ];
+        assert!(session.answer("3").is_err());
+        session.answer("2").unwrap();
+        session.answer("250").unwrap();
+
+        assert_eq!(
+            session.next_step(),
+            ConversationStep::Complete(BotCommand::ContributeChama {
+                chama_id: "CH2".to_string(),
+                amount: Money::from_major(250, Currency::Kes).unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_interactive_prompts_reply_with_typed_answers() {
+        let mut session = ConversationSession::start("contribute chama").unwrap();
+        session.chama_options = vec![
+            ChamaOption { id: "7".to_string(), name: "Investment Club".to_string() },
+            ChamaOption { id: "CH2".to_string(), name: "Family".to_string() },
+        ];
+        let list = session.interactive_prompt(Slot::Chama).unwrap();
+        assert_eq!(list.fallback_text, session.prompt(Slot::Chama));
+        assert_eq!(list.choices[1].title, "Family");
+        // Row IDs are positions, so numeric chama IDs cannot be mistaken for one
+        session.answer(&list.choices[0].id).unwrap();
+        assert_eq!(session.filled[0].1, SlotValue::Chama("7".to_string()));
+
+        let mut session = ConversationSession::start("deposit 500").unwrap();
+        let buttons = session.interactive_prompt(Slot::Method).unwrap();
+        assert!(buttons.validate().is_ok());
+        session.answer(&buttons.choices[1].id).unwrap();
+        assert_eq!(session.filled[1].1, SlotValue::Method("lightning".to_string()));
+
+        assert!(session.interactive_prompt(Slot::Amount).is_none());
+    }
+
+    #[test]
+    fn test_lightning_withdraw_asks_for_destination() {
+        let mut session = ConversationSession::start("lightning withdraw 500").unwrap();
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::Destination));
+        assert!(session.answer("not an address").is_err());
+        session.answer("alice@wallet.com").unwrap();
+        assert_eq!(
+            session.next_step(),
+            ConversationStep::Complete(BotCommand::LightningWithdraw {
+                amount: Some(Money::from_major(500, Currency::Kes).unwrap()),
+                destination: "alice@wallet.com".to_string(),
+            })
+        );
+
+        // A destination without an amount asks for one
+        let session = ConversationSession::start("lightning withdraw alice@wallet.com").unwrap();
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::Amount));
+    }
+
+    #[test]
+    fn test_withdraw_asks_for_destination_only_for_lightning() {
+        let mut session = ConversationSession::start("withdraw 500").unwrap();
+        session.answer("mpesa").unwrap();
+        assert_eq!(
+            session.next_step(),
+            ConversationStep::Complete(BotCommand::Withdraw {
+                amount: Money::from_major(500, Currency::Kes).unwrap(),
+                method: Some("mpesa".to_string()),
+                destination: None,
+            })
+        );
+
+        let mut session = ConversationSession::start("withdraw 500 kes lightning").unwrap();
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::Destination));
+        session.answer("alice@wallet.com").unwrap();
+        assert_eq!(
+            session.next_step(),
+            ConversationStep::Complete(BotCommand::Withdraw {
+                amount: Money::from_major(500, Currency::Kes).unwrap(),
+                method: Some("lightning".to_string()),
+                destination: Some("alice@wallet.com".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_set_pin_flow() {
+        let mut session = ConversationSession::start("set pin").unwrap();
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::NewPin));
+        assert!(session.answer("12").is_err());
+        assert!(session.answer("1234").is_err());
+        session.answer("2580").unwrap();
+
+        // A mismatch goes back to choosing the PIN
+        assert!(session.answer("2581").is_err());
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::NewPin));
+        session.answer("2580").unwrap();
+        session.answer("2580").unwrap();
+        assert_eq!(
+            session.next_step(),
+            ConversationStep::Complete(BotCommand::SetPin { pin: Pin::parse("2580").unwrap() })
+        );
+
+        // Never pre-filled from the opening message
+        let session = ConversationSession::start("change pin 2580").unwrap();
+        assert_eq!(session.next_step(), ConversationStep::Ask(Slot::CurrentPin));
+    }
+
+    #[tokio::test]
+    async fn test_store_expires_sessions() {
+        let store = ConversationStore::new(Duration::ZERO);
+        store.save("+254712345678", ConversationSession::new(GuidedFlow::Deposit)).await;
+        assert!(store.get("+254712345678").await.is_none());
+
+        let store = ConversationStore::default();
+        store.save("+254712345678", ConversationSession::new(GuidedFlow::Deposit)).await;
+        assert!(store.get("+254712345678").await.is_some());
+        assert!(store.end("+254712345678").await.is_some());
+        assert!(store.get("+254712345678").await.is_none());
+    }
+}
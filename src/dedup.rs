@@ -0,0 +1,90 @@
+//! Dropping redelivered WhatsApp messages
+//!
+//! Meta retries webhook deliveries it considers failed, and a retried
+//! delivery carries the same message ID as the original. Every message is
+//! handled on its own task, so without this check a retry could run a
+//! deposit or withdrawal twice. Each ID is remembered for a retention window,
+//! and anything seen again within it is skipped.
+
+use moka::future::Cache;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long message IDs are remembered by default
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most IDs kept at once; the oldest are evicted first beyond this
+const MAX_TRACKED_MESSAGES: u64 = 100_000;
+
+/// Message IDs seen within the retention window
+#[derive(Debug, Clone)]
+pub struct MessageDeduplicator {
+    seen: Arc<Cache<String, ()>>,
+}
+
+impl Default for MessageDeduplicator {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETENTION)
+    }
+}
+
+impl MessageDeduplicator {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            seen: Arc::new(
+                Cache::builder()
+                    .time_to_live(retention)
+                    .max_capacity(MAX_TRACKED_MESSAGES)
+                    .build(),
+            ),
+        }
+    }
+
+    /// Record a message ID, returning `true` only the first time it is seen
+    /// within the retention window. Concurrent deliveries of the same ID
+    /// are resolved atomically, so exactly one of them gets `true`.
+    pub async fn first_delivery(&self, message_id: &str) -> bool {
+        self.seen
+            .entry(message_id.to_string())
+            .or_insert(())
+            .await
+            .is_fresh()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_repeated_ids_are_detected() {
+        let dedup = MessageDeduplicator::default();
+        assert!(dedup.first_delivery("wamid.1").await);
+        assert!(!dedup.first_delivery("wamid.1").await);
+        assert!(dedup.first_delivery("wamid.2").await);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_deliveries() {
+        let dedup = MessageDeduplicator::default();
+        let deliveries = (0..8).map(|_| {
+            let dedup = dedup.clone();
+            tokio::spawn(async move { dedup.first_delivery("wamid.1").await })
+        });
+        let mut first = 0;
+        for delivery in deliveries {
+            if delivery.await.unwrap() {
+                first += 1;
+            }
+        }
+        assert_eq!(first, 1);
+    }
+
+    #[tokio::test]
+    async fn test_ids_are_forgotten_after_retention() {
+        let dedup = MessageDeduplicator::new(Duration::from_millis(50));
+        assert!(dedup.first_delivery("wamid.1").await);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(dedup.first_delivery("wamid.1").await);
+    }
+}
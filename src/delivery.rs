@@ -0,0 +1,309 @@
+//! Delivery status of outbound WhatsApp messages
+//!
+//! Every message the Cloud API accepts is recorded by its ID, and the status
+//! callbacks Meta sends to `/webhook` (sent, delivered, read, failed) are
+//! added to its history. Critical notifications, such as deposit
+//! confirmations, keep their text so that when WhatsApp reports them as
+//! failed they can be sent again by SMS, or through Twilio's WhatsApp sender
+//! when no SMS number is configured. Histories are kept for
+//! `RETENTION` and can be looked up by admins.
+
+use crate::{
+    error::Result,
+    services::twilio::TwilioService,
+    types::WhatsAppStatus,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+/// How long delivery histories are kept
+pub const RETENTION: chrono::Duration = chrono::Duration::days(7);
+
+/// Where a message has got to. Later stages never go back to earlier ones,
+/// whatever order the callbacks arrive in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Accepted by the Cloud API, no callback yet
+    Accepted,
+    Sent,
+    Delivered,
+    Read,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "sent" => Some(DeliveryStatus::Sent),
+            "delivered" => Some(DeliveryStatus::Delivered),
+            "read" => Some(DeliveryStatus::Read),
+            "failed" => Some(DeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            DeliveryStatus::Accepted => "accepted",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Read => "read",
+            DeliveryStatus::Failed => "failed",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeliveryEvent {
+    pub status: DeliveryStatus,
+    pub at: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+/// A critical notification sent again on another channel
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FallbackDelivery {
+    pub channel: String,
+    pub message_id: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Everything known about one outbound message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeliveryRecord {
+    pub message_id: String,
+    pub to: String,
+    pub status: DeliveryStatus,
+    pub history: Vec<DeliveryEvent>,
+    /// Text of a critical notification, kept so it can be sent again
+    #[serde(skip)]
+    pub critical_body: Option<String>,
+    pub critical: bool,
+    pub fallback: Option<FallbackDelivery>,
+}
+
+impl DeliveryRecord {
+    fn sent_at(&self) -> DateTime<Utc> {
+        self.history.first().map(|event| event.at).unwrap_or_else(Utc::now)
+    }
+}
+
+/// Delivery records by message ID
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryTracker {
+    records: Arc<RwLock<HashMap<String, DeliveryRecord>>>,
+}
+
+impl DeliveryTracker {
+    /// Record a message the Cloud API accepted. `critical_body` is the text
+    /// of a notification that must reach the user one way or another.
+    pub async fn record_sent(&self, message_id: &str, to: &str, critical_body: Option<&str>, now: DateTime<Utc>) {
+        let mut records = self.records.write().await;
+        records.retain(|_, record| now - record.sent_at() < RETENTION);
+        records.insert(
+            message_id.to_string(),
+            DeliveryRecord {
+                message_id: message_id.to_string(),
+                to: to.to_string(),
+                status: DeliveryStatus::Accepted,
+                history: vec![DeliveryEvent {
+                    status: DeliveryStatus::Accepted,
+                    at: now,
+                    error: None,
+                }],
+                critical: critical_body.is_some(),
+                critical_body: critical_body.map(str::to_string),
+                fallback: None,
+            },
+        );
+    }
+
+    /// Add a status callback to a message's history. Returns the record when
+    /// it is a critical notification that has just failed and still needs
+    /// sending another way.
+    pub async fn apply_status(&self, status: &WhatsAppStatus) -> Option<DeliveryRecord> {
+        let Some(new_status) = DeliveryStatus::parse(&status.status) else {
+            debug!("Ignoring unknown delivery status {} for {}", status.status, status.id);
+            return None;
+        };
+        let mut records = self.records.write().await;
+        let Some(record) = records.get_mut(&status.id) else {
+            debug!("Delivery status {} for untracked message {}", new_status, status.id);
+            return None;
+        };
+
+        let at = status
+            .timestamp
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+            .unwrap_or_else(Utc::now);
+        let error = status.errors.as_ref().and_then(|errors| errors.first()).map(|error| {
+            format!("{} ({})", error.title, error.code)
+        });
+        record.history.push(DeliveryEvent { status: new_status, at, error: error.clone() });
+
+        let was_failed = record.status == DeliveryStatus::Failed;
+        record.status = record.status.max(new_status);
+        if new_status == DeliveryStatus::Failed && !was_failed {
+            warn!(
+                "WhatsApp message {} to {} failed: {}",
+                record.message_id,
+                record.to,
+                error.as_deref().unwrap_or("no reason given")
+            );
+            if record.critical && record.fallback.is_none() {
+                return Some(record.clone());
+            }
+        }
+        None
+    }
+
+    /// Note that a failed notification was sent on another channel
+    pub async fn record_fallback(&self, message_id: &str, fallback: FallbackDelivery) {
+        if let Some(record) = self.records.write().await.get_mut(message_id) {
+            record.fallback = Some(fallback);
+        }
+    }
+
+    pub async fn get(&self, message_id: &str) -> Option<DeliveryRecord> {
+        self.records.read().await.get(message_id).cloned()
+    }
+
+    /// Messages sent to a phone number, most recent first
+    pub async fn for_recipient(&self, to: &str) -> Vec<DeliveryRecord> {
+        let mut records: Vec<DeliveryRecord> = self
+            .records
+            .read()
+            .await
+            .values()
+            .filter(|record| record.to == to)
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| std::cmp::Reverse(record.sent_at()));
+        records
+    }
+}
+
+/// Send a failed critical notification again by SMS, or through Twilio's
+/// WhatsApp sender when no SMS number is configured
+pub async fn send_fallback(
+    tracker: &DeliveryTracker,
+    twilio_service: &TwilioService,
+    record: &DeliveryRecord,
+) -> Result<Option<FallbackDelivery>> {
+    let Some(body) = &record.critical_body else {
+        return Ok(None);
+    };
+    let (channel, response) = if twilio_service.can_send_sms() {
+        ("sms", twilio_service.send_sms(&record.to, body).await?)
+    } else if twilio_service.is_configured() {
+        ("twilio_whatsapp", twilio_service.send_message(&record.to, body).await?)
+    } else {
+        warn!("No fallback channel configured for failed message {}", record.message_id);
+        return Ok(None);
+    };
+
+    let fallback = FallbackDelivery {
+        channel: channel.to_string(),
+        message_id: response.sid,
+        at: Utc::now(),
+    };
+    info!(
+        "Sent failed message {} to {} again via {} ({})",
+        record.message_id, record.to, channel, fallback.message_id
+    );
+    tracker.record_fallback(&record.message_id, fallback.clone()).await;
+    Ok(Some(fallback))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::WhatsAppStatusError;
+
+    fn status(id: &str, status: &str, timestamp: i64) -> WhatsAppStatus {
+        WhatsAppStatus {
+            id: id.to_string(),
+            status: status.to_string(),
+            timestamp: timestamp.to_string(),
+            recipient_id: "254712345678".to_string(),
+            errors: (status == "failed").then(|| {
+                vec![WhatsAppStatusError {
+                    code: 131026,
+                    title: "Message undeliverable".to_string(),
+                }]
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_follows_callbacks() {
+        let tracker = DeliveryTracker::default();
+        let now = Utc::now();
+        tracker.record_sent("wamid.1", "+254712345678", None, now).await;
+
+        assert!(tracker.apply_status(&status("wamid.1", "sent", 1)).await.is_none());
+        assert!(tracker.apply_status(&status("wamid.1", "read", 3)).await.is_none());
+        // A late "delivered" does not undo "read"
+        assert!(tracker.apply_status(&status("wamid.1", "delivered", 2)).await.is_none());
+        // Unknown IDs and statuses are ignored
+        assert!(tracker.apply_status(&status("wamid.other", "read", 3)).await.is_none());
+        assert!(tracker.apply_status(&status("wamid.1", "deleted", 4)).await.is_none());
+
+        let record = tracker.get("wamid.1").await.unwrap();
+        assert_eq!(record.status, DeliveryStatus::Read);
+        let statuses: Vec<_> = record.history.iter().map(|event| event.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                DeliveryStatus::Accepted,
+                DeliveryStatus::Sent,
+                DeliveryStatus::Read,
+                DeliveryStatus::Delivered
+            ]
+        );
+        assert_eq!(record.history[1].at, Utc.timestamp_opt(1, 0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_only_critical_failures_need_fallback() {
+        let tracker = DeliveryTracker::default();
+        let now = Utc::now();
+        tracker.record_sent("wamid.1", "+254712345678", None, now).await;
+        tracker
+            .record_sent("wamid.2", "+254712345678", Some("Deposit received"), now)
+            .await;
+
+        assert!(tracker.apply_status(&status("wamid.1", "failed", 1)).await.is_none());
+        let failed = tracker.apply_status(&status("wamid.2", "failed", 1)).await.unwrap();
+        assert_eq!(failed.critical_body.as_deref(), Some("Deposit received"));
+        assert_eq!(
+            failed.history.last().unwrap().error.as_deref(),
+            Some("Message undeliverable (131026)")
+        );
+
+        // A repeated failure callback does not send it again
+        assert!(tracker.apply_status(&status("wamid.2", "failed", 2)).await.is_none());
+        assert_eq!(tracker.for_recipient("+254712345678").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_old_records_are_dropped() {
+        let tracker = DeliveryTracker::default();
+        let now = Utc::now();
+        tracker.record_sent("wamid.1", "+254712345678", None, now - RETENTION).await;
+        tracker.record_sent("wamid.2", "+254712345678", None, now).await;
+        assert!(tracker.get("wamid.1").await.is_none());
+        assert!(tracker.get("wamid.2").await.is_some());
+    }
+}
@@ -33,7 +33,6 @@ pub enum AppError {
     BtcService(String),
 
     #[error("Rate limit exceeded")]
-    #[allow(dead_code)]
     RateLimit,
 
     #[error("Unauthorized access")]
@@ -48,6 +47,9 @@ pub enum AppError {
     #[error("Insufficient funds")]
     InsufficientFunds,
 
+    #[error("Limit reached: {0}")]
+    LimitExceeded(String),
+
     #[error("Invalid command: {0}")]
     InvalidCommand(String),
 
@@ -74,6 +76,15 @@ pub enum AppError {
 
     #[error("Invalid input: {0}")]
     InvalidInput(String),
+
+    /// Free-form message to a user who has not written in 24 hours
+    #[error("Service window closed for {0}")]
+    ServiceWindowClosed(String),
+
+    /// The first attempt failed and the outbox will retry the message, so it
+    /// must not be sent again
+    #[error("Queued for retry: {0}")]
+    Queued(String),
 }
 
 impl IntoResponse for AppError {
@@ -95,6 +106,7 @@ impl IntoResponse for AppError {
             AppError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             AppError::UserNotFound => (StatusCode::NOT_FOUND, "User not found".to_string()),
             AppError::InsufficientFunds => (StatusCode::BAD_REQUEST, "Insufficient funds".to_string()),
+            AppError::LimitExceeded(msg) => (StatusCode::FORBIDDEN, format!("Limit reached: {}", msg)),
             AppError::InvalidCommand(msg) => (StatusCode::BAD_REQUEST, format!("Invalid command: {}", msg)),
             AppError::VoiceProcessing(msg) => (StatusCode::BAD_REQUEST, format!("Voice processing error: {}", msg)),
             AppError::Network(msg) => (StatusCode::BAD_GATEWAY, format!("Network error: {}", msg)),
@@ -104,6 +116,11 @@ impl IntoResponse for AppError {
             AppError::Timeout(msg) => (StatusCode::REQUEST_TIMEOUT, format!("Timeout: {}", msg)),
             AppError::DataNotFound(msg) => (StatusCode::NOT_FOUND, format!("Data not found: {}", msg)),
             AppError::InvalidInput(msg) => (StatusCode::BAD_REQUEST, format!("Invalid input: {}", msg)),
+            AppError::ServiceWindowClosed(to) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("{} has not messaged in 24 hours; only templates can be sent", to),
+            ),
+            AppError::Queued(msg) => (StatusCode::ACCEPTED, format!("Queued for retry: {}", msg)),
         };
 
         let body = Json(json!({
@@ -132,6 +149,7 @@ impl AppError {
             AppError::Unauthorized => "Authentication required. Please contact support.".to_string(),
             AppError::UserNotFound => "User account not found. Please register with BitSacco first.".to_string(),
             AppError::InsufficientFunds => "Insufficient funds for this transaction. Please check your balance.".to_string(),
+            AppError::LimitExceeded(msg) => format!("Transaction limit reached. {}", msg),
             AppError::InvalidCommand(msg) => format!("Unknown command: {}. Type 'help' to see available commands.", msg),
             AppError::VoiceProcessing(_) => "Voice message processing failed. Please try sending a text message.".to_string(),
             AppError::Network(msg) => format!("Network error: {}. Please check your connection.", msg),
@@ -141,6 +159,8 @@ impl AppError {
             AppError::Timeout(msg) => format!("Request timed out: {}. Please try again.", msg),
             AppError::DataNotFound(msg) => format!("Data not found: {}. Please check your input.", msg),
             AppError::InvalidInput(msg) => format!("Invalid input: {}. Please check your message format.", msg),
+            AppError::ServiceWindowClosed(_) => "This message can no longer be delivered on WhatsApp.".to_string(),
+            AppError::Queued(_) => "Your message will be sent shortly.".to_string(),
         }
     }
 
@@ -150,7 +170,10 @@ impl AppError {
             AppError::Config(_) | AppError::Internal(_) => ErrorSeverity::Critical,
             AppError::WhatsApp(_) | AppError::BitSacco(_) | AppError::BtcService(_) => ErrorSeverity::High,
             AppError::Network(_) | AppError::Timeout(_) | AppError::ServiceUnavailable(_) => ErrorSeverity::Medium,
-            AppError::Validation(_) | AppError::InvalidCommand(_) | AppError::InvalidInput(_) => ErrorSeverity::Low,
+            AppError::Validation(_)
+            | AppError::InvalidCommand(_)
+            | AppError::InvalidInput(_)
+            | AppError::LimitExceeded(_) => ErrorSeverity::Low,
             _ => ErrorSeverity::Medium,
         }
     }
@@ -167,8 +190,12 @@ impl AppError {
             AppError::Http(_) | AppError::Network(_) | AppError::Timeout(_) => ErrorCategory::Network,
             AppError::WhatsApp(_) | AppError::BitSacco(_) | AppError::BtcService(_) => ErrorCategory::ExternalApi,
             AppError::Validation(_) | AppError::InvalidCommand(_) | AppError::InvalidInput(_) => ErrorCategory::UserInput,
-            AppError::UserNotFound | AppError::InsufficientFunds | AppError::PermissionDenied(_) => ErrorCategory::Business,
+            AppError::UserNotFound
+            | AppError::InsufficientFunds
+            | AppError::LimitExceeded(_)
+            | AppError::PermissionDenied(_) => ErrorCategory::Business,
             AppError::VoiceProcessing(_) => ErrorCategory::Media,
+            AppError::ServiceWindowClosed(_) | AppError::Queued(_) => ErrorCategory::ExternalApi,
             _ => ErrorCategory::System,
         }
     }
@@ -0,0 +1,230 @@
+//! Interactive reply buttons and list messages
+//!
+//! Choices such as "M-Pesa or Lightning?", chama pickers and YES/NO
+//! confirmations are sent as WhatsApp interactive messages so they can be
+//! tapped instead of typed. Each option's ID is the text the user would
+//! otherwise type, so a tapped reply goes through the same command parsing
+//! as a typed one. Every message also carries a plain-text version, sent on
+//! channels without interactive messages, such as Twilio, and whenever the
+//! choices do not fit WhatsApp's limits.
+
+use crate::{
+    error::{AppError, Result},
+    types::{
+        WhatsAppInteractiveAction, WhatsAppInteractiveBody, WhatsAppInteractiveContent, WhatsAppListRow,
+        WhatsAppListSection, WhatsAppReplyButton, WhatsAppReplyOption,
+    },
+};
+
+/// WhatsApp's limits on interactive messages
+pub const MAX_BUTTONS: usize = 3;
+pub const MAX_LIST_ROWS: usize = 10;
+const MAX_BODY_CHARS: usize = 1024;
+const MAX_BUTTON_TITLE_CHARS: usize = 20;
+const MAX_ROW_TITLE_CHARS: usize = 24;
+const MAX_ROW_DESCRIPTION_CHARS: usize = 72;
+
+/// One tappable option
+#[derive(Debug, Clone, PartialEq)]
+pub struct Choice {
+    /// Sent back as the reply; the text the user would otherwise type
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+}
+
+impl Choice {
+    pub fn new(id: impl Into<String>, title: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            title: title.into(),
+            description: None,
+        }
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InteractiveKind {
+    /// Up to three reply buttons under the message
+    Buttons,
+    /// A list of up to ten rows opened with `button`
+    List { button: String },
+}
+
+/// A message with choices, and the text sent in its place where
+/// interactive messages are not available
+#[derive(Debug, Clone, PartialEq)]
+pub struct InteractiveMessage {
+    pub body: String,
+    pub kind: InteractiveKind,
+    pub choices: Vec<Choice>,
+    pub fallback_text: String,
+}
+
+impl InteractiveMessage {
+    pub fn buttons(body: impl Into<String>, choices: Vec<Choice>, fallback_text: impl Into<String>) -> Self {
+        Self {
+            body: body.into(),
+            kind: InteractiveKind::Buttons,
+            choices,
+            fallback_text: fallback_text.into(),
+        }
+    }
+
+    pub fn list(
+        body: impl Into<String>,
+        button: impl Into<String>,
+        choices: Vec<Choice>,
+        fallback_text: impl Into<String>,
+    ) -> Self {
+        Self {
+            body: body.into(),
+            kind: InteractiveKind::List { button: button.into() },
+            choices,
+            fallback_text: fallback_text.into(),
+        }
+    }
+
+    /// YES/NO buttons under a confirmation request whose text already
+    /// explains the typed replies
+    pub fn yes_no(body: impl Into<String>) -> Self {
+        let body = body.into();
+        Self::buttons(body.clone(), vec![Choice::new("yes", "Yes"), Choice::new("no", "No")], body)
+    }
+
+    /// Check the message fits WhatsApp's limits. Titles are shortened rather
+    /// than rejected; too many choices or too long a body are rejected.
+    pub fn validate(&self) -> Result<()> {
+        let max_choices = match self.kind {
+            InteractiveKind::Buttons => MAX_BUTTONS,
+            InteractiveKind::List { .. } => MAX_LIST_ROWS,
+        };
+        if self.choices.is_empty() || self.choices.len() > max_choices {
+            return Err(AppError::Validation(format!(
+                "Interactive message needs 1 to {} choices, got {}",
+                max_choices,
+                self.choices.len()
+            )));
+        }
+        if self.body.chars().count() > MAX_BODY_CHARS {
+            return Err(AppError::Validation("Interactive message body too long".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The `interactive` object of a Cloud API send request
+    pub fn to_content(&self) -> WhatsAppInteractiveContent {
+        let body = WhatsAppInteractiveBody { text: self.body.clone() };
+        match &self.kind {
+            InteractiveKind::Buttons => WhatsAppInteractiveContent {
+                r#type: "button".to_string(),
+                body,
+                action: WhatsAppInteractiveAction {
+                    button: None,
+                    buttons: Some(
+                        self.choices
+                            .iter()
+                            .map(|choice| WhatsAppReplyButton {
+                                r#type: "reply".to_string(),
+                                reply: WhatsAppReplyOption {
+                                    id: choice.id.clone(),
+                                    title: shorten(&choice.title, MAX_BUTTON_TITLE_CHARS),
+                                },
+                            })
+                            .collect(),
+                    ),
+                    sections: None,
+                },
+            },
+            InteractiveKind::List { button } => WhatsAppInteractiveContent {
+                r#type: "list".to_string(),
+                body,
+                action: WhatsAppInteractiveAction {
+                    button: Some(shorten(button, MAX_BUTTON_TITLE_CHARS)),
+                    buttons: None,
+                    sections: Some(vec![WhatsAppListSection {
+                        title: shorten(button, MAX_ROW_TITLE_CHARS),
+                        rows: self
+                            .choices
+                            .iter()
+                            .map(|choice| WhatsAppListRow {
+                                id: choice.id.clone(),
+                                title: shorten(&choice.title, MAX_ROW_TITLE_CHARS),
+                                description: choice
+                                    .description
+                                    .as_deref()
+                                    .map(|description| shorten(description, MAX_ROW_DESCRIPTION_CHARS)),
+                            })
+                            .collect(),
+                    }]),
+                },
+            },
+        }
+    }
+}
+
+/// Cut `text` to `max` characters, marking the cut with an ellipsis
+fn shorten(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut short: String = text.chars().take(max - 1).collect();
+    short.push('…');
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buttons_content() {
+        let message = InteractiveMessage::yes_no("Send 500 KES?");
+        assert!(message.validate().is_ok());
+        assert_eq!(message.fallback_text, "Send 500 KES?");
+
+        let json = serde_json::to_value(message.to_content()).unwrap();
+        assert_eq!(json["type"], "button");
+        assert_eq!(json["body"]["text"], "Send 500 KES?");
+        assert_eq!(json["action"]["buttons"][0]["type"], "reply");
+        assert_eq!(json["action"]["buttons"][0]["reply"]["id"], "yes");
+        assert_eq!(json["action"]["buttons"][1]["reply"]["title"], "No");
+        assert!(json["action"].get("sections").is_none());
+    }
+
+    #[test]
+    fn test_list_content_shortens_titles() {
+        let message = InteractiveMessage::list(
+            "Which chama?",
+            "Choose chama",
+            vec![Choice::new("CH1", "The Very Long Named Investment Club").with_description("CH1")],
+            "Which chama? Reply with the number or chama ID",
+        );
+        assert!(message.validate().is_ok());
+
+        let json = serde_json::to_value(message.to_content()).unwrap();
+        assert_eq!(json["type"], "list");
+        assert_eq!(json["action"]["button"], "Choose chama");
+        let row = &json["action"]["sections"][0]["rows"][0];
+        assert_eq!(row["id"], "CH1");
+        assert_eq!(row["title"], "The Very Long Named Inv…");
+        assert_eq!(row["title"].as_str().unwrap().chars().count(), MAX_ROW_TITLE_CHARS);
+        assert_eq!(row["description"], "CH1");
+    }
+
+    #[test]
+    fn test_too_many_choices_rejected() {
+        let choices = |n: usize| (0..n).map(|i| Choice::new(i.to_string(), i.to_string())).collect::<Vec<_>>();
+        assert!(InteractiveMessage::buttons("Pick", choices(MAX_BUTTONS + 1), "Pick").validate().is_err());
+        assert!(InteractiveMessage::buttons("Pick", choices(0), "Pick").validate().is_err());
+        assert!(InteractiveMessage::list("Pick", "Choose", choices(MAX_LIST_ROWS), "Pick").validate().is_ok());
+        assert!(InteractiveMessage::list("Pick", "Choose", choices(MAX_LIST_ROWS + 1), "Pick")
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,74 @@
+//! JSON files mirroring in-memory stores
+//!
+//! The PIN store, the outbox and the Lightning invoice tracker keep their
+//! state in memory and write all of it out after every change, so a restart
+//! picks up where the previous run stopped.
+
+use crate::error::{AppError, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::Path;
+use tracing::error;
+
+/// Read the value saved at `path`, or `None` if nothing has been saved yet
+pub async fn read<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the value saved at `path` for a store that can safely start empty.
+/// A file that does not parse is moved to `<name>.json.corrupt` for
+/// inspection rather than overwritten by the next save.
+pub async fn read_or_default<T: DeserializeOwned + Default>(path: &Path, what: &str) -> T {
+    match read(path).await {
+        Ok(value) => value.unwrap_or_default(),
+        Err(AppError::Json(e)) => {
+            let corrupt = path.with_extension("json.corrupt");
+            error!("{} {} is unreadable ({}); moved to {}", what, path.display(), e, corrupt.display());
+            if let Err(e) = tokio::fs::rename(path, &corrupt).await {
+                error!("Failed to move unreadable {} {}: {}", what, path.display(), e);
+            }
+            T::default()
+        }
+        Err(e) => {
+            error!("Failed to read {} {}: {}", what, path.display(), e);
+            T::default()
+        }
+    }
+}
+
+/// Save `value` to `path` via a temporary file, so a crash mid-write never
+/// leaves a truncated file
+pub async fn write<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let data = serde_json::to_vec_pretty(value)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip_and_corrupt_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("store.json");
+
+        assert_eq!(read::<Vec<u32>>(&path).await.unwrap(), None);
+        write(&path, &vec![1u32, 2, 3]).await.unwrap();
+        assert_eq!(read::<Vec<u32>>(&path).await.unwrap(), Some(vec![1, 2, 3]));
+
+        std::fs::write(&path, b"{not json").unwrap();
+        assert!(read::<Vec<u32>>(&path).await.is_err());
+        assert_eq!(read_or_default::<Vec<u32>>(&path, "Test store").await, Vec::<u32>::new());
+        assert!(!path.exists());
+        assert!(path.with_extension("json.corrupt").exists());
+    }
+}
@@ -1,11 +1,34 @@
+pub mod admin;
+pub mod alerts;
 pub mod cache;
+pub mod channel;
+pub mod chart;
 pub mod config;
+pub mod confirmation;
+pub mod conversation;
+pub mod dedup;
+pub mod delivery;
 pub mod error;
+pub mod interactive;
+pub mod json_store;
+pub mod lightning;
+pub mod limits;
+pub mod money;
 pub mod monitoring;
+pub mod mpesa;
+pub mod outbound;
+pub mod pin;
+pub mod qr;
+pub mod rate_limit;
+pub mod service_window;
 pub mod services;
+pub mod settlement;
+pub mod templates;
+pub mod timezone;
 pub mod types;
 pub mod validation;
 pub mod webhook;
+pub mod withdrawal;
 
 pub use config::AppConfig;
 pub use error::{AppError, Result};
@@ -0,0 +1,725 @@
+//! Lightning withdrawal destinations
+//!
+//! `lightning withdraw` accepts a BOLT11 invoice, a Lightning address
+//! (`name@wallet.com`) or an LNURL-pay string. Invoices are decoded locally so
+//! the network, amount, expiry and description can be checked and shown to the
+//! user before anything is sent to BitSacco. The invoice signature is not
+//! verified here; the paying node does that when it routes the payment.
+//! Lightning addresses and LNURLs are only checked for shape; BitSacco resolves
+//! them to an invoice when it pays.
+
+use crate::{
+    error::AppError,
+    money::{Money, Sats},
+};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use thiserror::Error;
+
+/// Network BitSacco pays out on; invoices for any other network are refused
+pub const PAYOUT_NETWORK: Network = Network::Bitcoin;
+
+/// Expiry BOLT11 assumes when an invoice has no `x` field
+const DEFAULT_EXPIRY_SECS: u64 = 3600;
+
+/// Longest invoice description echoed back to the user
+const MAX_DESCRIPTION_CHARS: usize = 100;
+
+const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+/// 5-bit groups in a BOLT11 timestamp and signature
+const TIMESTAMP_LEN: usize = 7;
+const SIGNATURE_LEN: usize = 104;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LightningError {
+    #[error("That Lightning invoice is not valid ({0})")]
+    InvalidInvoice(String),
+
+    #[error("That invoice is for {0}, but BitSacco pays out on {PAYOUT_NETWORK}")]
+    WrongNetwork(Network),
+
+    #[error("That invoice expired at {}. Ask the recipient for a new one.", .0.format("%Y-%m-%d %H:%M UTC"))]
+    Expired(DateTime<Utc>),
+
+    #[error("Please say how much to send, e.g. `lightning withdraw 500 KES <invoice or address>`")]
+    AmountRequired,
+
+    #[error("That invoice is for {invoice}, but you asked to send {requested}. Send `lightning withdraw <invoice>` to pay the invoice amount.")]
+    AmountMismatch { invoice: Sats, requested: Money },
+
+    #[error("Invoices for a fraction of a satoshi are not supported")]
+    SubSatoshiAmount,
+
+    #[error("`{0}` is not a valid Lightning address")]
+    InvalidAddress(String),
+
+    #[error("That LNURL is not valid ({0})")]
+    InvalidLnurl(String),
+
+    #[error("Please send a Lightning invoice (lnbc...), Lightning address (name@wallet.com) or LNURL")]
+    UnrecognisedDestination,
+}
+
+impl From<LightningError> for AppError {
+    fn from(err: LightningError) -> Self {
+        AppError::Validation(err.to_string())
+    }
+}
+
+/// Bitcoin network an invoice is payable on, from its human-readable prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Bitcoin,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    /// Invoice prefix after `ln`, longest first so `bcrt` is not read as `bc`
+    const PREFIXES: [(&'static str, Network); 4] = [
+        ("bcrt", Network::Regtest),
+        ("bc", Network::Bitcoin),
+        ("tbs", Network::Signet),
+        ("tb", Network::Testnet),
+    ];
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Bitcoin => f.write_str("Bitcoin mainnet"),
+            Network::Testnet => f.write_str("testnet"),
+            Network::Signet => f.write_str("signet"),
+            Network::Regtest => f.write_str("regtest"),
+        }
+    }
+}
+
+/// The fields of a BOLT11 invoice the bot checks and shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bolt11Invoice {
+    /// The invoice as sent, lower case and without a `lightning:` prefix
+    pub encoded: String,
+    pub network: Network,
+    pub amount_msat: Option<u64>,
+    pub timestamp: DateTime<Utc>,
+    pub expiry_secs: u64,
+    /// Hex payment hash
+    pub payment_hash: String,
+    pub description: Option<String>,
+    /// Hex SHA-256 of a description too long to embed
+    pub description_hash: Option<String>,
+    /// Hex public key of the payee, when the invoice names it
+    pub payee: Option<String>,
+}
+
+impl Bolt11Invoice {
+    pub fn parse(invoice: &str) -> Result<Self, LightningError> {
+        let invalid = |reason: &str| LightningError::InvalidInvoice(reason.to_string());
+        let encoded = strip_scheme(invoice).to_lowercase();
+        let (hrp, data) = bech32_decode(&encoded).map_err(|reason| invalid(&reason))?;
+
+        let hrp = hrp.strip_prefix("ln").ok_or_else(|| invalid("missing ln prefix"))?;
+        let (network, amount) = Network::PREFIXES
+            .iter()
+            .find_map(|(prefix, network)| hrp.strip_prefix(prefix).map(|amount| (*network, amount)))
+            .ok_or_else(|| invalid("unknown network"))?;
+        let amount_msat = parse_amount_msat(amount)?;
+
+        if data.len() < TIMESTAMP_LEN + SIGNATURE_LEN {
+            return Err(invalid("too short"));
+        }
+        let timestamp = i64::try_from(read_uint(&data[..TIMESTAMP_LEN]))
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .ok_or_else(|| invalid("bad timestamp"))?;
+
+        let mut invoice = Bolt11Invoice {
+            encoded: encoded.clone(),
+            network,
+            amount_msat,
+            timestamp,
+            expiry_secs: DEFAULT_EXPIRY_SECS,
+            payment_hash: String::new(),
+            description: None,
+            description_hash: None,
+            payee: None,
+        };
+
+        let mut fields = &data[TIMESTAMP_LEN..data.len() - SIGNATURE_LEN];
+        while !fields.is_empty() {
+            if fields.len() < 3 {
+                return Err(invalid("truncated field"));
+            }
+            let tag = BECH32_CHARSET[usize::from(fields[0])];
+            let len = usize::from(fields[1]) * 32 + usize::from(fields[2]);
+            let value = fields.get(3..3 + len).ok_or_else(|| invalid("truncated field"))?;
+            fields = &fields[3 + len..];
+
+            // Fields of an unexpected length are skipped, as BOLT11 requires
+            match (tag, len) {
+                (b'p', 52) => invoice.payment_hash = hex::encode(from_5bit(value)),
+                (b'h', 52) => invoice.description_hash = Some(hex::encode(from_5bit(value))),
+                (b'n', 53) => invoice.payee = Some(hex::encode(from_5bit(value))),
+                (b'd', _) => {
+                    let description = String::from_utf8(from_5bit(value))
+                        .map_err(|_| invalid("description is not UTF-8"))?;
+                    invoice.description = Some(description);
+                }
+                (b'x', 1..=12) => invoice.expiry_secs = read_uint(value),
+                _ => {}
+            }
+        }
+
+        if invoice.payment_hash.is_empty() {
+            return Err(invalid("no payment hash"));
+        }
+        if invoice.description.is_none() && invoice.description_hash.is_none() {
+            return Err(invalid("no description"));
+        }
+        Ok(invoice)
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        let expiry = i64::try_from(self.expiry_secs).unwrap_or(i64::MAX);
+        self.timestamp
+            .checked_add_signed(chrono::Duration::try_seconds(expiry).unwrap_or(chrono::Duration::MAX))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires_at()
+    }
+
+    /// Whole-satoshi amount, `None` for an amountless invoice
+    pub fn amount(&self) -> Result<Option<Sats>, LightningError> {
+        match self.amount_msat {
+            None => Ok(None),
+            Some(msat) if !msat.is_multiple_of(1000) => Err(LightningError::SubSatoshiAmount),
+            Some(msat) => Ok(Some(Sats(msat / 1000))),
+        }
+    }
+
+    /// Description made safe to echo back: control characters removed and
+    /// long text shortened
+    pub fn display_description(&self) -> Option<String> {
+        let description: String = self
+            .description
+            .as_deref()?
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let description = description.trim();
+        if description.is_empty() {
+            return None;
+        }
+        if description.chars().count() > MAX_DESCRIPTION_CHARS {
+            let short: String = description.chars().take(MAX_DESCRIPTION_CHARS - 1).collect();
+            return Some(format!("{}…", short.trim_end()));
+        }
+        Some(description.to_string())
+    }
+
+    /// The invoice shortened to its first and last characters
+    pub fn short(&self) -> String {
+        let chars: Vec<char> = self.encoded.chars().collect();
+        if chars.len() <= 24 {
+            return self.encoded.clone();
+        }
+        let head: String = chars[..14].iter().collect();
+        let tail: String = chars[chars.len() - 6..].iter().collect();
+        format!("{}…{}", head, tail)
+    }
+}
+
+/// Where a Lightning withdrawal is paid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LightningDestination {
+    Invoice(Bolt11Invoice),
+    /// Lightning address, lower case
+    Address(String),
+    /// LNURL-pay string and the URL it encodes
+    Lnurl { encoded: String, url: String },
+}
+
+impl LightningDestination {
+    /// Decode an invoice, Lightning address or LNURL, optionally prefixed
+    /// with `lightning:`
+    pub fn parse(input: &str) -> Result<Self, LightningError> {
+        let value = strip_scheme(input.trim()).to_lowercase();
+        if value.starts_with("lnurl") {
+            return parse_lnurl(&value);
+        }
+        if value.starts_with("ln") {
+            return Bolt11Invoice::parse(&value).map(LightningDestination::Invoice);
+        }
+        if value.contains('@') {
+            return parse_address(&value);
+        }
+        Err(LightningError::UnrecognisedDestination)
+    }
+
+    /// Whether a word is meant as a destination rather than an amount or
+    /// currency, so malformed destinations get a specific error
+    pub fn looks_like(input: &str) -> bool {
+        let value = strip_scheme(input.trim()).to_lowercase();
+        value.starts_with("ln") || value.contains('@')
+    }
+
+    /// Whether the user has to say how much to send, i.e. anything but an
+    /// invoice that carries its own amount
+    pub fn needs_amount(&self) -> bool {
+        match self {
+            LightningDestination::Invoice(invoice) => invoice.amount_msat.is_none(),
+            _ => true,
+        }
+    }
+
+    /// Destination kind as named in the BitSacco API
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LightningDestination::Invoice(_) => "bolt11",
+            LightningDestination::Address(_) => "lightning_address",
+            LightningDestination::Lnurl { .. } => "lnurl",
+        }
+    }
+
+    /// The destination string BitSacco pays to
+    pub fn encoded(&self) -> &str {
+        match self {
+            LightningDestination::Invoice(invoice) => &invoice.encoded,
+            LightningDestination::Address(address) => address,
+            LightningDestination::Lnurl { encoded, .. } => encoded,
+        }
+    }
+}
+
+impl fmt::Display for LightningDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LightningDestination::Invoice(invoice) => write!(f, "invoice {}", invoice.short()),
+            LightningDestination::Address(address) => f.write_str(address),
+            LightningDestination::Lnurl { url, .. } => {
+                write!(f, "LNURL at {}", url_host(url).unwrap_or(url))
+            }
+        }
+    }
+}
+
+/// A Lightning withdrawal whose destination and amount have been checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LightningWithdrawal {
+    pub destination: LightningDestination,
+    pub amount: Money,
+}
+
+impl LightningWithdrawal {
+    /// Decode `destination` and settle the amount to send.
+    ///
+    /// An invoice that carries an amount is paid for exactly that amount; any
+    /// amount the user gave must match it. Amountless invoices, addresses
+    /// and LNURLs need an amount from the user.
+    pub fn resolve(
+        destination: &str,
+        requested: Option<Money>,
+        now: DateTime<Utc>,
+    ) -> Result<Self, LightningError> {
+        let destination = LightningDestination::parse(destination)?;
+        let amount = match &destination {
+            LightningDestination::Invoice(invoice) => {
+                if invoice.network != PAYOUT_NETWORK {
+                    return Err(LightningError::WrongNetwork(invoice.network));
+                }
+                if invoice.is_expired(now) {
+                    return Err(LightningError::Expired(invoice.expires_at()));
+                }
+                match (invoice.amount()?, requested) {
+                    (Some(sats), None) => sats_to_money(sats)?,
+                    (Some(sats), Some(requested)) => {
+                        let invoice_amount = sats_to_money(sats)?;
+                        if requested != invoice_amount {
+                            return Err(LightningError::AmountMismatch { invoice: sats, requested });
+                        }
+                        invoice_amount
+                    }
+                    (None, Some(requested)) => requested,
+                    (None, None) => return Err(LightningError::AmountRequired),
+                }
+            }
+            LightningDestination::Address(_) | LightningDestination::Lnurl { .. } => {
+                requested.ok_or(LightningError::AmountRequired)?
+            }
+        };
+        Ok(Self { destination, amount })
+    }
+
+    /// One-line description for the confirmation prompt
+    pub fn summary(&self, now: DateTime<Utc>) -> String {
+        let mut summary = format!("Withdraw {} via Lightning to {}", self.amount, self.destination);
+        if let LightningDestination::Invoice(invoice) = &self.destination {
+            let mut details = Vec::new();
+            if let Some(description) = invoice.display_description() {
+                details.push(format!("\"{}\"", description));
+            }
+            let minutes = (invoice.expires_at() - now).num_minutes().max(0);
+            details.push(format!("expires in {} min", minutes));
+            summary.push_str(&format!(" ({})", details.join(", ")));
+        }
+        summary
+    }
+}
+
+fn sats_to_money(sats: Sats) -> Result<Money, LightningError> {
+    sats.to_money()
+        .map_err(|_| LightningError::InvalidInvoice("amount too large".to_string()))
+}
+
+fn strip_scheme(input: &str) -> &str {
+    input
+        .get(..10)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("lightning:"))
+        .map_or(input, |_| &input[10..])
+}
+
+/// Amount part of an invoice prefix, e.g. `2500u`, in millisatoshis
+fn parse_amount_msat(amount: &str) -> Result<Option<u64>, LightningError> {
+    if amount.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || LightningError::InvalidInvoice("bad amount".to_string());
+    let (digits, multiplier) = match amount.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&amount[..i], Some(c)),
+        _ => (amount, None),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let value: u64 = digits.parse().map_err(|_| invalid())?;
+    let msat = match multiplier {
+        None => value.checked_mul(100_000_000_000),
+        Some('m') => value.checked_mul(100_000_000),
+        Some('u') => value.checked_mul(100_000),
+        Some('n') => value.checked_mul(100),
+        Some('p') if value.is_multiple_of(10) => Some(value / 10),
+        Some('p') => return Err(LightningError::SubSatoshiAmount),
+        Some(_) => return Err(invalid()),
+    };
+    msat.filter(|msat| *msat > 0).map(Some).ok_or_else(invalid)
+}
+
+fn parse_address(address: &str) -> Result<LightningDestination, LightningError> {
+    let invalid = || LightningError::InvalidAddress(address.to_string());
+    let (user, domain) = address.split_once('@').ok_or_else(invalid)?;
+    let user_ok = !user.is_empty()
+        && user
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"-_.+".contains(&b));
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        });
+    if !user_ok || !domain_ok {
+        return Err(invalid());
+    }
+    Ok(LightningDestination::Address(address.to_string()))
+}
+
+fn parse_lnurl(lnurl: &str) -> Result<LightningDestination, LightningError> {
+    let (hrp, data) = bech32_decode(lnurl).map_err(LightningError::InvalidLnurl)?;
+    if hrp != "lnurl" {
+        return Err(LightningError::InvalidLnurl("unknown prefix".to_string()));
+    }
+    let url = String::from_utf8(from_5bit(&data))
+        .map_err(|_| LightningError::InvalidLnurl("not a URL".to_string()))?;
+    let secure = match url_host(&url) {
+        Some(host) if url.starts_with("https://") => !host.is_empty(),
+        // Tor hidden services are encrypted end to end without TLS
+        Some(host) => url.starts_with("http://") && host.ends_with(".onion"),
+        None => false,
+    };
+    if !secure {
+        return Err(LightningError::InvalidLnurl("LNURLs must use https".to_string()));
+    }
+    Ok(LightningDestination::Lnurl { encoded: lnurl.to_string(), url })
+}
+
+/// Host part of an absolute URL
+fn url_host(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    rest.split(['/', '?', '#']).next()
+}
+
+fn bech32_polymod(values: impl Iterator<Item = u8>) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a_57b2, 0x2650_8e6d, 0x1ea1_19fa, 0x3d42_33dd, 0x2a14_62b3];
+    let mut chk: u32 = 1;
+    for value in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x01ff_ffff) << 5) ^ u32::from(value);
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> impl Iterator<Item = u8> + '_ {
+    hrp.bytes().map(|b| b >> 5).chain(std::iter::once(0)).chain(hrp.bytes().map(|b| b & 31))
+}
+
+/// Decode lower-case bech32 into its prefix and 5-bit data, without the
+/// checksum. Invoices and LNURLs exceed bech32's 90 character limit, so no
+/// length limit is applied.
+fn bech32_decode(input: &str) -> Result<(String, Vec<u8>), String> {
+    if input.bytes().any(|b| b.is_ascii_uppercase()) {
+        return Err("mixed case".to_string());
+    }
+    let (hrp, data) = input.rsplit_once('1').ok_or_else(|| "missing separator".to_string())?;
+    if hrp.is_empty() || !hrp.bytes().all(|b| (33..=126).contains(&b)) {
+        return Err("bad prefix".to_string());
+    }
+    if data.len() < 6 {
+        return Err("too short".to_string());
+    }
+    let values = data
+        .bytes()
+        .map(|b| BECH32_CHARSET.iter().position(|c| *c == b).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| "invalid character".to_string())?;
+    if bech32_polymod(hrp_expand(hrp).chain(values.iter().copied())) != 1 {
+        return Err("checksum mismatch".to_string());
+    }
+    Ok((hrp.to_string(), values[..values.len() - 6].to_vec()))
+}
+
+/// Regroup 5-bit values into bytes, dropping the trailing padding bits
+fn from_5bit(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() * 5 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for value in data {
+        acc = (acc << 5) | u32::from(*value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    bytes
+}
+
+/// Big-endian unsigned integer from 5-bit values
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |acc, value| (acc << 5) | u64::from(*value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Currency;
+    use chrono::TimeZone;
+
+    /// "Please send $3 for a cup of coffee to the same peer, within one minute" (BOLT11 test vector)
+    const COFFEE: &str = "lnbc2500u1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpu9qrsgquk0rl77nj30yxdy8j9vdx85fkpmdla2087ne0xh8nhedh8w27kyke0lp53ut353s06fv3qfegext0eh0ymjpf39tuven09sam30g4vgpfna3rh";
+
+    fn to_5bit(bytes: &[u8]) -> Vec<u8> {
+        let mut values = Vec::new();
+        let mut acc: u32 = 0;
+        let mut bits = 0;
+        for byte in bytes {
+            acc = (acc << 8) | u32::from(*byte);
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                values.push(((acc >> bits) & 31) as u8);
+            }
+        }
+        if bits > 0 {
+            values.push(((acc << (5 - bits)) & 31) as u8);
+        }
+        values
+    }
+
+    fn bech32_encode(hrp: &str, data: &[u8]) -> String {
+        let checksum_input: Vec<u8> = hrp_expand(hrp).chain(data.iter().copied()).chain([0; 6]).collect();
+        let polymod = bech32_polymod(checksum_input.into_iter()) ^ 1;
+        let checksum = (0..6).map(|i| ((polymod >> (5 * (5 - i))) & 31) as u8);
+        let body: String = data
+            .iter()
+            .copied()
+            .chain(checksum)
+            .map(|v| BECH32_CHARSET[usize::from(v)] as char)
+            .collect();
+        format!("{}1{}", hrp, body)
+    }
+
+    fn field(tag: u8, value: &[u8]) -> Vec<u8> {
+        let tag = BECH32_CHARSET.iter().position(|c| *c == tag).unwrap() as u8;
+        let mut out = vec![tag, (value.len() / 32) as u8, (value.len() % 32) as u8];
+        out.extend_from_slice(value);
+        out
+    }
+
+    /// Unsigned invoice with the given prefix, timestamp and description
+    fn invoice(hrp: &str, timestamp: u64, description: &str, expiry: Option<u64>) -> String {
+        let mut data: Vec<u8> = (0..TIMESTAMP_LEN).rev().map(|i| ((timestamp >> (5 * i)) & 31) as u8).collect();
+        data.extend(field(b'p', &to_5bit(&[7; 32])));
+        data.extend(field(b'd', &to_5bit(description.as_bytes())));
+        if let Some(expiry) = expiry {
+            data.extend(field(b'x', &[(expiry >> 5) as u8, (expiry & 31) as u8]));
+        }
+        data.extend([0; SIGNATURE_LEN]);
+        bech32_encode(hrp, &data)
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn test_decodes_spec_invoice() {
+        let invoice = Bolt11Invoice::parse(COFFEE).unwrap();
+        assert_eq!(invoice.network, Network::Bitcoin);
+        assert_eq!(invoice.amount_msat, Some(250_000_000));
+        assert_eq!(invoice.amount(), Ok(Some(Sats(250_000))));
+        assert_eq!(invoice.timestamp, at(1_496_314_658));
+        assert_eq!(invoice.expiry_secs, 60);
+        assert_eq!(invoice.description.as_deref(), Some("1 cup coffee"));
+        assert_eq!(
+            invoice.payment_hash,
+            "0001020304050607080900010203040506070809000102030405060708090102"
+        );
+
+        // Case-insensitive and accepts the URI scheme
+        let upper = format!("LIGHTNING:{}", COFFEE.to_uppercase());
+        assert_eq!(Bolt11Invoice::parse(&upper).unwrap().encoded, COFFEE);
+    }
+
+    #[test]
+    fn test_rejects_corrupted_invoice() {
+        let mut corrupted = COFFEE.to_string();
+        corrupted.replace_range(40..41, "q");
+        assert!(matches!(
+            Bolt11Invoice::parse(&corrupted),
+            Err(LightningError::InvalidInvoice(_))
+        ));
+        assert!(Bolt11Invoice::parse("lnbc1qqqqqq").is_err());
+    }
+
+    #[test]
+    fn test_amount_multipliers() {
+        assert_eq!(parse_amount_msat(""), Ok(None));
+        assert_eq!(parse_amount_msat("1"), Ok(Some(100_000_000_000)));
+        assert_eq!(parse_amount_msat("20m"), Ok(Some(2_000_000_000)));
+        assert_eq!(parse_amount_msat("2500u"), Ok(Some(250_000_000)));
+        assert_eq!(parse_amount_msat("10n"), Ok(Some(1_000)));
+        assert_eq!(parse_amount_msat("10p"), Ok(Some(1)));
+        assert_eq!(parse_amount_msat("11p"), Err(LightningError::SubSatoshiAmount));
+        assert!(parse_amount_msat("u").is_err());
+        assert!(parse_amount_msat("5x").is_err());
+    }
+
+    #[test]
+    fn test_resolve_invoice_checks() {
+        let now = at(1_700_000_000);
+        let fresh = invoice("lnbc10u", 1_700_000_000 - 60, "Coffee", None);
+
+        let withdrawal = LightningWithdrawal::resolve(&fresh, None, now).unwrap();
+        assert_eq!(withdrawal.amount, Money::from_minor(1_000, Currency::Btc));
+        let summary = withdrawal.summary(now);
+        assert!(summary.contains("\"Coffee\""), "{}", summary);
+        assert!(summary.contains("expires in 59 min"), "{}", summary);
+
+        // A matching BTC amount is fine; anything else is a mismatch
+        let same = Money::from_minor(1_000, Currency::Btc);
+        assert!(LightningWithdrawal::resolve(&fresh, Some(same), now).is_ok());
+        let kes = Money::from_major(500, Currency::Kes).unwrap();
+        assert!(matches!(
+            LightningWithdrawal::resolve(&fresh, Some(kes), now),
+            Err(LightningError::AmountMismatch { .. })
+        ));
+
+        let expired = invoice("lnbc10u", 1_700_000_000 - 120, "Coffee", Some(60));
+        assert!(matches!(
+            LightningWithdrawal::resolve(&expired, None, now),
+            Err(LightningError::Expired(_))
+        ));
+
+        let testnet = invoice("lntb10u", 1_700_000_000, "Coffee", None);
+        assert_eq!(
+            LightningWithdrawal::resolve(&testnet, None, now),
+            Err(LightningError::WrongNetwork(Network::Testnet))
+        );
+
+        let regtest = invoice("lnbcrt10u", 1_700_000_000, "Coffee", None);
+        assert_eq!(Bolt11Invoice::parse(&regtest).unwrap().network, Network::Regtest);
+
+        let amountless = invoice("lnbc", 1_700_000_000, "Tip", None);
+        assert_eq!(
+            LightningWithdrawal::resolve(&amountless, None, now),
+            Err(LightningError::AmountRequired)
+        );
+        assert_eq!(LightningWithdrawal::resolve(&amountless, Some(kes), now).unwrap().amount, kes);
+    }
+
+    #[test]
+    fn test_description_is_sanitised() {
+        let long = "x".repeat(300);
+        let parsed = Bolt11Invoice::parse(&invoice("lnbc", 0, &long, None)).unwrap();
+        let shown = parsed.display_description().unwrap();
+        assert_eq!(shown.chars().count(), MAX_DESCRIPTION_CHARS);
+        assert!(shown.ends_with('…'));
+
+        let parsed = Bolt11Invoice::parse(&invoice("lnbc", 0, "a\nb\u{7}", None)).unwrap();
+        assert_eq!(parsed.display_description().as_deref(), Some("a b"));
+    }
+
+    #[test]
+    fn test_lightning_address() {
+        let kes = Money::from_major(500, Currency::Kes).unwrap();
+        let withdrawal = LightningWithdrawal::resolve("Alice@Wallet.com", Some(kes), Utc::now()).unwrap();
+        assert_eq!(withdrawal.destination, LightningDestination::Address("alice@wallet.com".to_string()));
+        assert_eq!(withdrawal.summary(Utc::now()), "Withdraw 500.00 KES via Lightning to alice@wallet.com");
+
+        assert_eq!(
+            LightningWithdrawal::resolve("alice@wallet.com", None, Utc::now()),
+            Err(LightningError::AmountRequired)
+        );
+        for bad in ["@wallet.com", "alice@wallet", "alice@-wallet.com", "al ice@wallet.com", "a@b@c.com"] {
+            assert!(matches!(
+                LightningDestination::parse(bad),
+                Err(LightningError::InvalidAddress(_))
+            ), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_lnurl() {
+        let url = "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df";
+        let lnurl = bech32_encode("lnurl", &to_5bit(url.as_bytes()));
+        let destination = LightningDestination::parse(&lnurl.to_uppercase()).unwrap();
+        assert_eq!(destination, LightningDestination::Lnurl { encoded: lnurl.clone(), url: url.to_string() });
+        assert_eq!(destination.to_string(), "LNURL at service.com");
+        assert_eq!(destination.kind(), "lnurl");
+
+        let plain_http = bech32_encode("lnurl", &to_5bit(b"http://service.com/pay"));
+        assert!(matches!(LightningDestination::parse(&plain_http), Err(LightningError::InvalidLnurl(_))));
+        let onion = bech32_encode("lnurl", &to_5bit(b"http://abc.onion/pay"));
+        assert!(LightningDestination::parse(&onion).is_ok());
+    }
+
+    #[test]
+    fn test_unrecognised_destination() {
+        assert_eq!(LightningDestination::parse("hello"), Err(LightningError::UnrecognisedDestination));
+        assert!(LightningDestination::looks_like("lnbc1..."));
+        assert!(LightningDestination::looks_like("alice@wallet.com"));
+        assert!(!LightningDestination::looks_like("500"));
+    }
+}
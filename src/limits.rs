@@ -0,0 +1,387 @@
+//! Per-user transaction limits and velocity checks
+//!
+//! Every money-moving command is checked against caps for its kind and
+//! currency: a per-transaction maximum and rolling 24-hour and 7-day totals,
+//! worked out from the user's BitSacco transaction history. Withdrawals are
+//! also capped by count per hour. Commands in a currency without a rule for
+//! their kind are refused, so a missing rule never means "no limit"; a rule
+//! with no caps set allows them uncapped.
+//!
+//! The defaults below can be replaced with a JSON file named by
+//! `LIMITS_CONFIG_PATH`, in the same shape as [`LimitsConfig`].
+
+use crate::{
+    error::{AppError, Result},
+    lightning::LightningWithdrawal,
+    money::{Currency, Money},
+    types::{BitSaccoTransaction, BotCommand},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use tracing::info;
+
+const DAY: chrono::Duration = chrono::Duration::hours(24);
+const WEEK: chrono::Duration = chrono::Duration::days(7);
+const HOUR: chrono::Duration = chrono::Duration::hours(1);
+
+/// Withdrawals allowed per rolling hour unless configured otherwise
+const DEFAULT_MAX_WITHDRAWALS_PER_HOUR: u32 = 5;
+
+/// Kinds of transaction with their own limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKind {
+    Deposit,
+    Withdrawal,
+    Transfer,
+    ChamaContribution,
+}
+
+impl LimitKind {
+    /// The limited kind and amount of a command, if it has one. Lightning
+    /// withdrawals are resolved so invoices count for the amount they carry;
+    /// one that cannot be resolved is an error, since its amount is unknown.
+    pub fn of(command: &BotCommand) -> Result<Option<(Self, Money)>> {
+        let limited = match command {
+            BotCommand::Deposit { amount, .. } | BotCommand::LightningDeposit { amount } => {
+                (LimitKind::Deposit, *amount)
+            }
+            BotCommand::Withdraw { amount, .. } => (LimitKind::Withdrawal, *amount),
+            BotCommand::LightningWithdraw { amount, destination } => {
+                let withdrawal = LightningWithdrawal::resolve(destination, *amount, Utc::now())?;
+                (LimitKind::Withdrawal, withdrawal.amount)
+            }
+            BotCommand::Transfer { amount, .. } => (LimitKind::Transfer, *amount),
+            BotCommand::ContributeChama { amount, .. } => (LimitKind::ChamaContribution, *amount),
+            _ => return Ok(None),
+        };
+        Ok(Some(limited))
+    }
+
+    /// `BitSaccoTransaction::type` of transactions of this kind
+    fn transaction_type(&self) -> &'static str {
+        match self {
+            LimitKind::Deposit => "deposit",
+            LimitKind::Withdrawal => "withdrawal",
+            LimitKind::Transfer => "transfer",
+            LimitKind::ChamaContribution => "chama_contribution",
+        }
+    }
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitKind::Deposit => write!(f, "deposit"),
+            LimitKind::Withdrawal => write!(f, "withdrawal"),
+            LimitKind::Transfer => write!(f, "transfer"),
+            LimitKind::ChamaContribution => write!(f, "chama contribution"),
+        }
+    }
+}
+
+/// Caps for one kind of transaction in one currency, in whole units, or in
+/// sats for BTC
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitRule {
+    pub kind: LimitKind,
+    pub currency: Currency,
+    #[serde(default)]
+    pub per_transaction: Option<i64>,
+    #[serde(default)]
+    pub daily: Option<i64>,
+    #[serde(default)]
+    pub weekly: Option<i64>,
+}
+
+impl LimitRule {
+    fn new(kind: LimitKind, currency: Currency, per_transaction: i64, daily: i64, weekly: i64) -> Self {
+        Self {
+            kind,
+            currency,
+            per_transaction: Some(per_transaction),
+            daily: Some(daily),
+            weekly: Some(weekly),
+        }
+    }
+
+    fn cap(&self, cap: i64) -> Result<Money> {
+        match self.currency {
+            Currency::Btc => Ok(Money::from_minor(cap, Currency::Btc)),
+            currency => Ok(Money::from_major(cap, currency)?),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitsConfig {
+    #[serde(default = "default_max_withdrawals_per_hour")]
+    pub max_withdrawals_per_hour: u32,
+    #[serde(default)]
+    pub rules: Vec<LimitRule>,
+}
+
+fn default_max_withdrawals_per_hour() -> u32 {
+    DEFAULT_MAX_WITHDRAWALS_PER_HOUR
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        use Currency::{Btc, Kes, Usd};
+        use LimitKind::*;
+        Self {
+            max_withdrawals_per_hour: DEFAULT_MAX_WITHDRAWALS_PER_HOUR,
+            rules: vec![
+                LimitRule::new(Deposit, Kes, 250_000, 500_000, 1_500_000),
+                LimitRule::new(Withdrawal, Kes, 150_000, 300_000, 1_000_000),
+                LimitRule::new(Transfer, Kes, 100_000, 200_000, 500_000),
+                LimitRule::new(ChamaContribution, Kes, 250_000, 500_000, 1_500_000),
+                LimitRule::new(Deposit, Usd, 2_000, 4_000, 12_000),
+                LimitRule::new(Withdrawal, Usd, 1_000, 2_000, 7_000),
+                LimitRule::new(Transfer, Usd, 700, 1_500, 4_000),
+                LimitRule::new(ChamaContribution, Usd, 2_000, 4_000, 12_000),
+                // Lightning withdrawals of invoices are in BTC; caps are in sats
+                LimitRule::new(Deposit, Btc, 2_000_000, 4_000_000, 12_000_000),
+                LimitRule::new(Withdrawal, Btc, 1_000_000, 2_000_000, 7_000_000),
+                LimitRule::new(Transfer, Btc, 700_000, 1_500_000, 4_000_000),
+                LimitRule::new(ChamaContribution, Btc, 2_000_000, 4_000_000, 12_000_000),
+            ],
+        }
+    }
+}
+
+/// Checks commands against the configured limits
+#[derive(Debug, Clone, Default)]
+pub struct LimitsEngine {
+    config: Arc<LimitsConfig>,
+}
+
+impl LimitsEngine {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self { config: Arc::new(config) }
+    }
+
+    /// Limits from a JSON file, or the defaults when `path` is empty
+    pub async fn load(path: &str) -> Result<Self> {
+        if path.is_empty() {
+            return Ok(Self::default());
+        }
+        let data = tokio::fs::read(path).await?;
+        let config: LimitsConfig = serde_json::from_slice(&data)?;
+        info!("Loaded {} transaction limit rules from {}", config.rules.len(), path);
+        Ok(Self::new(config))
+    }
+
+    fn rule(&self, kind: LimitKind, currency: Currency) -> Option<&LimitRule> {
+        self.config
+            .rules
+            .iter()
+            .find(|rule| rule.kind == kind && rule.currency == currency)
+    }
+
+    /// Check a `kind` transaction of `amount` against the user's `history`.
+    /// Failed transactions do not count towards any limit.
+    pub fn check(
+        &self,
+        kind: LimitKind,
+        amount: Money,
+        history: &[BitSaccoTransaction],
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let recent: Vec<(DateTime<Utc>, &BitSaccoTransaction)> = history
+            .iter()
+            .filter(|tx| tx.r#type == kind.transaction_type() && counts(tx))
+            .filter_map(|tx| Some((created_at(tx)?, tx)))
+            .filter(|(at, _)| *at > now - WEEK)
+            .collect();
+
+        if kind == LimitKind::Withdrawal {
+            self.check_withdrawal_count(&recent, now)?;
+        }
+
+        let Some(rule) = self.rule(kind, amount.currency()) else {
+            return Err(AppError::LimitExceeded(format!(
+                "{}s in {} are not supported.",
+                kind,
+                amount.currency()
+            )));
+        };
+        if let Some(cap) = rule.per_transaction {
+            let cap = rule.cap(cap)?;
+            if amount.minor() > cap.minor() {
+                return Err(AppError::LimitExceeded(format!(
+                    "A single {} can be at most {}.",
+                    kind, cap
+                )));
+            }
+        }
+        for (cap, window, label) in [(rule.daily, DAY, "24 hours"), (rule.weekly, WEEK, "7 days")] {
+            let Some(cap) = cap else {
+                continue;
+            };
+            let cap = rule.cap(cap)?;
+            let used = Money::sum(
+                recent
+                    .iter()
+                    .filter(|(at, tx)| *at > now - window && tx.amount.currency() == amount.currency())
+                    .map(|(_, tx)| tx.amount),
+                amount.currency(),
+            )?;
+            if used.minor() + amount.minor() > cap.minor() {
+                let remaining = cap.checked_sub(used).unwrap_or(Money::zero(amount.currency()));
+                let remaining = if remaining.is_positive() { remaining } else { Money::zero(amount.currency()) };
+                return Err(AppError::LimitExceeded(format!(
+                    "You can make up to {} in {}s every {}. You have {} left.",
+                    cap, kind, label, remaining
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_withdrawal_count(&self, recent: &[(DateTime<Utc>, &BitSaccoTransaction)], now: DateTime<Utc>) -> Result<()> {
+        let max = self.config.max_withdrawals_per_hour;
+        let in_last_hour: Vec<DateTime<Utc>> =
+            recent.iter().map(|(at, _)| *at).filter(|at| *at > now - HOUR).collect();
+        if (in_last_hour.len() as u64) < u64::from(max) {
+            return Ok(());
+        }
+        let next_slot = in_last_hour.iter().min().map(|oldest| *oldest + HOUR - now).unwrap_or(HOUR);
+        let minutes = (next_slot.num_seconds() + 59) / 60;
+        Err(AppError::LimitExceeded(format!(
+            "You can make up to {} withdrawals an hour and have none left. Please try again in {} minute{}.",
+            max,
+            minutes,
+            if minutes == 1 { "" } else { "s" }
+        )))
+    }
+}
+
+/// Whether a transaction uses up allowance; failed ones do not
+fn counts(tx: &BitSaccoTransaction) -> bool {
+    !matches!(tx.status.as_str(), "failed" | "cancelled" | "canceled" | "expired")
+}
+
+fn created_at(tx: &BitSaccoTransaction) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(&tx.created_at)
+        .ok()
+        .map(|at| at.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kes(major: i64) -> Money {
+        Money::from_major(major, Currency::Kes).unwrap()
+    }
+
+    fn tx(kind: &str, amount: Money, status: &str, at: DateTime<Utc>) -> BitSaccoTransaction {
+        BitSaccoTransaction {
+            id: format!("tx-{}", at.timestamp_millis()),
+            user_id: "user123".to_string(),
+            r#type: kind.to_string(),
+            amount,
+            status: status.to_string(),
+            payment_method: Some("mpesa".to_string()),
+            external_reference: None,
+            chama_id: None,
+            description: None,
+            created_at: at.to_rfc3339(),
+            updated_at: at.to_rfc3339(),
+        }
+    }
+
+    #[test]
+    fn test_per_transaction_cap() {
+        let engine = LimitsEngine::default();
+        let now = Utc::now();
+        assert!(engine.check(LimitKind::Withdrawal, kes(150_000), &[], now).is_ok());
+        let err = engine.check(LimitKind::Withdrawal, kes(150_001), &[], now).unwrap_err();
+        assert!(matches!(err, AppError::LimitExceeded(_)));
+        assert!(err.to_string().contains("at most 150000.00 KES"));
+    }
+
+    #[test]
+    fn test_daily_cap_reports_remaining_allowance() {
+        let engine = LimitsEngine::default();
+        let now = Utc::now();
+        let history = vec![
+            tx("withdrawal", kes(140_000), "completed", now - chrono::Duration::hours(3)),
+            tx("withdrawal", kes(140_000), "completed", now - chrono::Duration::hours(5)),
+            // Failed, older than a day, other kinds and other currencies do not count
+            tx("withdrawal", kes(100_000), "failed", now - chrono::Duration::hours(1)),
+            tx("withdrawal", kes(100_000), "completed", now - chrono::Duration::hours(30)),
+            tx("deposit", kes(100_000), "completed", now - chrono::Duration::hours(2)),
+            tx("withdrawal", Money::from_major(100, Currency::Usd).unwrap(), "completed", now),
+        ];
+        assert!(engine.check(LimitKind::Withdrawal, kes(20_000), &history, now).is_ok());
+        let err = engine.check(LimitKind::Withdrawal, kes(20_001), &history, now).unwrap_err();
+        assert!(err.to_string().contains("every 24 hours. You have 20000.00 KES left."), "{}", err);
+    }
+
+    #[test]
+    fn test_weekly_cap() {
+        let engine = LimitsEngine::default();
+        let now = Utc::now();
+        let history: Vec<_> = (2..=6)
+            .map(|day| tx("transfer", kes(90_000), "completed", now - chrono::Duration::days(day)))
+            .collect();
+        assert!(engine.check(LimitKind::Transfer, kes(50_000), &history, now).is_ok());
+        let err = engine.check(LimitKind::Transfer, kes(50_001), &history, now).unwrap_err();
+        assert!(err.to_string().contains("every 7 days"), "{}", err);
+    }
+
+    #[test]
+    fn test_hourly_withdrawal_count() {
+        let uncapped = |kind| LimitRule { kind, currency: Currency::Kes, per_transaction: None, daily: None, weekly: None };
+        let engine = LimitsEngine::new(LimitsConfig {
+            max_withdrawals_per_hour: 2,
+            rules: vec![uncapped(LimitKind::Withdrawal), uncapped(LimitKind::Transfer)],
+        });
+        let now = Utc::now();
+        let history = vec![
+            tx("withdrawal", kes(100), "pending", now - chrono::Duration::minutes(50)),
+            tx("withdrawal", kes(100), "completed", now - chrono::Duration::minutes(10)),
+        ];
+        let err = engine.check(LimitKind::Withdrawal, kes(100), &history, now).unwrap_err();
+        assert!(err.to_string().contains("try again in 10 minutes"), "{}", err);
+        assert!(engine.check(LimitKind::Withdrawal, kes(100), &history[1..], now).is_ok());
+        // Other kinds are not counted
+        assert!(engine.check(LimitKind::Transfer, kes(100), &history, now).is_ok());
+    }
+
+    #[test]
+    fn test_btc_caps_and_config_file_shape() {
+        let engine = LimitsEngine::default();
+        let sats = |sats| Money::from_minor(sats, Currency::Btc);
+        assert!(engine.check(LimitKind::Withdrawal, sats(1_000_000), &[], Utc::now()).is_ok());
+        let err = engine.check(LimitKind::Withdrawal, sats(1_000_001), &[], Utc::now()).unwrap_err();
+        assert!(err.to_string().contains("at most 0.01000000 BTC"), "{}", err);
+
+        let config: LimitsConfig = serde_json::from_str(
+            r#"{"rules": [{"kind": "chama_contribution", "currency": "KES", "daily": 1000}]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.max_withdrawals_per_hour, DEFAULT_MAX_WITHDRAWALS_PER_HOUR);
+        let engine = LimitsEngine::new(config);
+        assert!(engine.check(LimitKind::ChamaContribution, kes(1_000), &[], Utc::now()).is_ok());
+        assert!(engine.check(LimitKind::ChamaContribution, kes(1_001), &[], Utc::now()).is_err());
+        // Kinds and currencies the file has no rule for are refused
+        let err = engine.check(LimitKind::Withdrawal, kes(1), &[], Utc::now()).unwrap_err();
+        assert!(err.to_string().contains("withdrawals in KES are not supported"), "{}", err);
+    }
+
+    #[test]
+    fn test_limit_kind_of_command() {
+        let withdraw = BotCommand::Withdraw { amount: kes(500), method: None, destination: None };
+        assert_eq!(LimitKind::of(&withdraw).unwrap(), Some((LimitKind::Withdrawal, kes(500))));
+        assert_eq!(LimitKind::of(&BotCommand::Balance).unwrap(), None);
+
+        // A Lightning payment whose amount cannot be worked out is not let through
+        let no_amount = BotCommand::LightningWithdraw { amount: None, destination: "alice@example.com".to_string() };
+        assert!(LimitKind::of(&no_amount).is_err());
+    }
+}
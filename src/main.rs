@@ -1,37 +1,59 @@
 use anyhow::Result;
 use axum::{
     extract::State,
-    http::StatusCode,
     response::Json,
     routing::{get, post},
     Router,
 };
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-mod cache;
-mod config;
-mod error;
-mod monitoring;
-mod services;
-mod types;
-mod validation;
-mod webhook;
-
-use cache::AppCache;
-use config::AppConfig;
-use error::AppError;
-use monitoring::{ComponentHealth, HealthStatus, MonitoringService, SystemMetrics};
-use services::{bitsacco::BitSaccoService, btc::BtcService, twilio::TwilioService, voice::VoiceService, whatsapp::WhatsAppService};
-use types::AppState;
-use webhook::{handle_webhook, health_check, send_message};
+use bitsacco_whatsapp_bot::{
+    admin,
+    alerts::{self, AlertStore},
+    cache::{self, AppCache},
+    channel::Messenger,
+    config::AppConfig,
+    confirmation::PendingActionStore,
+    conversation::ConversationStore,
+    dedup::MessageDeduplicator,
+    delivery::DeliveryTracker,
+    error::AppError,
+    limits::LimitsEngine,
+    monitoring::{ComponentHealth, HealthStatus, MonitoringService, SystemMetrics},
+    mpesa::{self, StkPushTracker},
+    outbound::{self, Outbox},
+    pin::PinStore,
+    rate_limit::{RateLimitPolicy, RateLimiter},
+    service_window::ServiceWindows,
+    settlement::{self, InvoiceTracker},
+    services::{bitsacco::BitSaccoService, btc::BtcService, conversion::ConversionService, twilio::TwilioService, voice::VoiceService, whatsapp::WhatsAppService},
+    templates::TemplateRegistry,
+    types::AppState,
+    webhook::{
+        handle_lightning_callback, handle_mpesa_callback, handle_pin_reset_callback, handle_twilio_webhook, handle_webhook,
+        health_check, send_message,
+    },
+};
+
+/// Span for a request's log lines. It records the path without the query
+/// string, which carries the M-Pesa callback secret.
+fn request_span(request: &axum::http::Request<axum::body::Body>) -> tracing::Span {
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        version = ?request.version(),
+    )
+}
 
 /// Get system metrics endpoint
-async fn get_metrics(State(state): State<AppState>) -> Result<Json<SystemMetrics>, AppError> {
+async fn get_metrics(State(_state): State<AppState>) -> Result<Json<SystemMetrics>, AppError> {
     // In a real implementation, you would get metrics from the monitoring service
     // For now, we'll return a mock response
     let metrics = SystemMetrics {
@@ -54,7 +76,7 @@ async fn get_metrics(State(state): State<AppState>) -> Result<Json<SystemMetrics
 }
 
 /// Get detailed health status endpoint
-async fn get_detailed_health(State(state): State<AppState>) -> Result<Json<HealthStatus>, AppError> {
+async fn get_detailed_health(State(_state): State<AppState>) -> Result<Json<HealthStatus>, AppError> {
     // In a real implementation, you would get health from the monitoring service
     // For now, we'll return a mock response
     let mut components = HashMap::new();
@@ -104,33 +126,110 @@ async fn main() -> Result<()> {
     monitoring.start_monitoring().await;
 
     // Initialize services
-    let whatsapp_service = WhatsAppService::new(&config)?;
+    let outbox = Outbox::load(&config.outbound_queue_path).await;
+    let deliveries = DeliveryTracker::default();
+    let service_windows = ServiceWindows::default();
+    let templates = TemplateRegistry::load(&config.templates_config_path).await?;
+    let whatsapp_service = WhatsAppService::new(&config)?
+        .with_outbox(outbox.clone())
+        .with_delivery_tracker(deliveries.clone())
+        .with_templates(templates.clone());
     let bitsacco_service = BitSaccoService::new(&config)?;
     let btc_service = BtcService::new(&config)?;
+    let conversion_service = ConversionService::new(btc_service.clone());
     let voice_service = VoiceService::new(&config)?;
-    let twilio_service = TwilioService::new(config.clone());
+    let twilio_service = TwilioService::new(config.clone()).with_templates(templates);
+    let messenger = Messenger::from_config(&config, whatsapp_service.clone(), twilio_service.clone())?
+        .with_service_windows(service_windows.clone());
+    let lightning_invoices = InvoiceTracker::load(&config.invoice_store_path).await;
+    let mpesa_deposits = StkPushTracker::new(Duration::from_secs(config.mpesa_status_query_delay_secs));
+    let limits = LimitsEngine::load(&config.limits_config_path).await?;
+    let pins = PinStore::load(&config.pin_store_path).await?;
+    let processed_messages = MessageDeduplicator::new(Duration::from_secs(config.message_dedup_retention_secs));
+    let rate_limiter = RateLimiter::new(RateLimitPolicy {
+        messages_per_minute: config.rate_limit_requests_per_minute,
+        money_commands_per_minute: config.rate_limit_money_commands_per_minute,
+    });
 
     let app_state = AppState {
         config,
         whatsapp_service,
         bitsacco_service,
         btc_service,
+        conversion_service,
         voice_service,
         cache,
         twilio_service,
+        messenger,
+        pending_actions: PendingActionStore::default(),
+        conversations: ConversationStore::default(),
+        price_alerts: AlertStore::default(),
+        lightning_invoices,
+        mpesa_deposits,
+        limits,
+        pins,
+        processed_messages,
+        rate_limiter,
+        outbox,
+        deliveries,
+        service_windows,
     };
 
+    // Push price alerts in the background
+    tokio::spawn(alerts::run_alert_monitor(
+        app_state.price_alerts.clone(),
+        app_state.btc_service.clone(),
+        app_state.messenger.clone(),
+        app_state.cache.clone(),
+        alerts::DEFAULT_POLL_INTERVAL,
+    ));
+
+    // Watch Lightning deposits until they settle or expire
+    tokio::spawn(settlement::run_settlement_watcher(
+        app_state.lightning_invoices.clone(),
+        app_state.bitsacco_service.clone(),
+        app_state.messenger.clone(),
+        settlement::DEFAULT_POLL_INTERVAL,
+    ));
+
+    // Query M-Pesa for deposits whose STK Push callback is late
+    tokio::spawn(mpesa::run_stk_query_watcher(
+        app_state.mpesa_deposits.clone(),
+        app_state.bitsacco_service.clone(),
+        app_state.messenger.clone(),
+        app_state.cache.clone(),
+        mpesa::DEFAULT_POLL_INTERVAL,
+    ));
+
+    // Retry WhatsApp messages that failed to send
+    tokio::spawn(outbound::run_outbox_worker(
+        app_state.outbox.clone(),
+        app_state.whatsapp_service.clone(),
+        app_state.service_windows.clone(),
+        outbound::DEFAULT_POLL_INTERVAL,
+    ));
+
     // Build application
     let app = Router::new()
         .route("/webhook", post(handle_webhook))
+        .route("/webhook/twilio", post(handle_twilio_webhook))
         .route("/send", post(send_message))
+        .route("/callbacks/lightning", post(handle_lightning_callback))
+        .route("/callbacks/mpesa", post(handle_mpesa_callback))
+        .route("/callbacks/pin-reset", post(handle_pin_reset_callback))
         .route("/health", get(health_check))
         .route("/metrics", get(get_metrics))
         .route("/health/detailed", get(get_detailed_health))
+        .route("/admin/rate-limits/bans", get(admin::list_rate_limit_bans))
+        .route("/admin/outbound/dead-letters", get(admin::list_dead_letters))
+        .route("/admin/outbound/dead-letters/{id}/replay", post(admin::replay_dead_letter))
+        .route("/admin/deliveries", get(admin::list_deliveries))
+        .route("/admin/deliveries/{message_id}", get(admin::get_delivery))
+        .route("/admin/service-windows/metrics", get(admin::service_window_metrics))
         .with_state(app_state)
         .layer(
             ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
+                .layer(TraceLayer::new_for_http().make_span_with(request_span))
                 .layer(CorsLayer::permissive()),
         );
 
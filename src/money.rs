@@ -0,0 +1,516 @@
+//! Fixed-point money types
+//!
+//! Ledger amounts are held as an integer count of the currency's minor unit
+//! (cents for KES/USD, satoshis for BTC) so that sums and comparisons are
+//! exact. Floating point only appears at the edges: when a JSON number is
+//! read from or written to an external API.
+
+use crate::error::AppError;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Satoshis in one bitcoin
+pub const SATS_PER_BTC: u64 = 100_000_000;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MoneyError {
+    #[error("Invalid amount format: {0}")]
+    InvalidAmount(String),
+
+    #[error("Unsupported currency: {0}")]
+    UnsupportedCurrency(String),
+
+    #[error("{currency} amounts cannot have more than {max} decimal places")]
+    TooPrecise { currency: Currency, max: u32 },
+
+    #[error("Cannot combine {0} and {1} amounts")]
+    CurrencyMismatch(Currency, Currency),
+
+    #[error("Amount is out of range")]
+    Overflow,
+}
+
+impl From<MoneyError> for AppError {
+    fn from(err: MoneyError) -> Self {
+        AppError::Validation(err.to_string())
+    }
+}
+
+/// Currencies the bot can hold balances in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Currency {
+    Kes,
+    Usd,
+    Btc,
+}
+
+impl Currency {
+    /// ISO 4217 style code, as used by the BitSacco API
+    pub fn code(&self) -> &'static str {
+        match self {
+            Currency::Kes => "KES",
+            Currency::Usd => "USD",
+            Currency::Btc => "BTC",
+        }
+    }
+
+    /// Number of decimal places in one major unit
+    pub fn minor_units(&self) -> u32 {
+        match self {
+            Currency::Kes | Currency::Usd => 2,
+            Currency::Btc => 8,
+        }
+    }
+
+    fn scale(&self) -> i64 {
+        10_i64.pow(self.minor_units())
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = MoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_str() {
+            "KES" => Ok(Currency::Kes),
+            "USD" => Ok(Currency::Usd),
+            "BTC" => Ok(Currency::Btc),
+            other => Err(MoneyError::UnsupportedCurrency(other.to_string())),
+        }
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(de::Error::custom)
+    }
+}
+
+/// An exact amount of a given currency, stored in minor units
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Money {
+    minor: i64,
+    currency: Currency,
+}
+
+impl Money {
+    pub fn from_minor(minor: i64, currency: Currency) -> Self {
+        Self { minor, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Self::from_minor(0, currency)
+    }
+
+    /// Whole major units, e.g. `Money::from_major(500, Currency::Kes)` is 500.00 KES
+    pub fn from_major(major: i64, currency: Currency) -> Result<Self, MoneyError> {
+        major
+            .checked_mul(currency.scale())
+            .map(|minor| Self::from_minor(minor, currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// Parse a decimal string such as `"1,250.50"` without going through `f64`.
+    ///
+    /// More decimal places than the currency supports is an error rather than
+    /// being silently rounded.
+    pub fn parse(amount: &str, currency: Currency) -> Result<Self, MoneyError> {
+        let invalid = || MoneyError::InvalidAmount(amount.to_string());
+        let cleaned = amount.trim().replace(',', "");
+        let (negative, digits) = match cleaned.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, cleaned.strip_prefix('+').unwrap_or(&cleaned)),
+        };
+
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(invalid());
+        }
+        if !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let places = currency.minor_units();
+        if fraction.len() > places as usize {
+            return Err(MoneyError::TooPrecise { currency, max: places });
+        }
+
+        let whole: i64 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| MoneyError::Overflow)? };
+        let fraction: i64 = if fraction.is_empty() {
+            0
+        } else {
+            let padded = format!("{:0<width$}", fraction, width = places as usize);
+            padded.parse().map_err(|_| invalid())?
+        };
+
+        let minor = whole
+            .checked_mul(currency.scale())
+            .and_then(|m| m.checked_add(fraction))
+            .ok_or(MoneyError::Overflow)?;
+        Ok(Self::from_minor(if negative { -minor } else { minor }, currency))
+    }
+
+    /// Parse an amount and a currency code as typed by a user, e.g. `("500", "kes")`
+    pub fn parse_with_code(amount: &str, currency: &str) -> Result<Self, MoneyError> {
+        Self::parse(amount, currency.parse()?)
+    }
+
+    /// Convert a JSON number from an external API, rounding to the nearest minor unit
+    pub fn from_f64(value: f64, currency: Currency) -> Result<Self, MoneyError> {
+        let minor = (value * currency.scale() as f64).round();
+        if !minor.is_finite() || minor.abs() >= i64::MAX as f64 {
+            return Err(MoneyError::Overflow);
+        }
+        Ok(Self::from_minor(minor as i64, currency))
+    }
+
+    /// Lossy conversion for JSON payloads and rate maths; never use for ledger sums
+    pub fn to_f64(&self) -> f64 {
+        self.minor as f64 / self.currency.scale() as f64
+    }
+
+    pub fn minor(&self) -> i64 {
+        self.minor
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.minor > 0
+    }
+
+    pub fn checked_add(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(&other)?;
+        self.minor
+            .checked_add(other.minor)
+            .map(|minor| Self::from_minor(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(&other)?;
+        self.minor
+            .checked_sub(other.minor)
+            .map(|minor| Self::from_minor(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_mul(self, factor: i64) -> Result<Money, MoneyError> {
+        self.minor
+            .checked_mul(factor)
+            .map(|minor| Self::from_minor(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// Sum amounts that must all be in `currency`
+    pub fn sum<I>(amounts: I, currency: Currency) -> Result<Money, MoneyError>
+    where
+        I: IntoIterator<Item = Money>,
+    {
+        amounts
+            .into_iter()
+            .try_fold(Money::zero(currency), Money::checked_add)
+    }
+
+    /// Sum amounts per currency, ordered by currency
+    pub fn totals_by_currency<I>(amounts: I) -> Result<Vec<Money>, MoneyError>
+    where
+        I: IntoIterator<Item = Money>,
+    {
+        let mut totals: Vec<Money> = Vec::new();
+        for amount in amounts {
+            match totals.iter_mut().find(|t| t.currency == amount.currency) {
+                Some(total) => *total = total.checked_add(amount)?,
+                None => totals.push(amount),
+            }
+        }
+        totals.sort_by_key(|t| t.currency);
+        Ok(totals)
+    }
+
+    /// The amount without its currency code, e.g. `"500.00"`
+    pub fn amount_str(&self) -> String {
+        let scale = self.currency.scale().unsigned_abs();
+        let abs = self.minor.unsigned_abs();
+        let sign = if self.minor < 0 { "-" } else { "" };
+        format!(
+            "{}{}.{:0width$}",
+            sign,
+            abs / scale,
+            abs % scale,
+            width = self.currency.minor_units() as usize
+        )
+    }
+
+    fn same_currency(&self, other: &Money) -> Result<(), MoneyError> {
+        if self.currency == other.currency {
+            Ok(())
+        } else {
+            Err(MoneyError::CurrencyMismatch(self.currency, other.currency))
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.amount_str(), self.currency)
+    }
+}
+
+/// Render per-currency totals as `"1000.00 KES + 500.00 USD"`
+pub fn format_totals(totals: &[Money]) -> String {
+    if totals.is_empty() {
+        return Money::zero(Currency::Kes).to_string();
+    }
+    totals
+        .iter()
+        .map(Money::to_string)
+        .collect::<Vec<_>>()
+        .join(" + ")
+}
+
+/// A bitcoin amount in satoshis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Sats(pub u64);
+
+impl Sats {
+    pub fn checked_add(self, other: Sats) -> Option<Sats> {
+        self.0.checked_add(other.0).map(Sats)
+    }
+
+    pub fn checked_sub(self, other: Sats) -> Option<Sats> {
+        self.0.checked_sub(other.0).map(Sats)
+    }
+
+    pub fn to_money(self) -> Result<Money, MoneyError> {
+        i64::try_from(self.0)
+            .map(|minor| Money::from_minor(minor, Currency::Btc))
+            .map_err(|_| MoneyError::Overflow)
+    }
+
+    /// With thousands separators, e.g. `"5,000 sats"`
+    pub fn grouped(&self) -> String {
+        let digits = self.0.to_string();
+        let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                out.push(',');
+            }
+            out.push(digit);
+        }
+        format!("{} sats", out)
+    }
+}
+
+impl TryFrom<Money> for Sats {
+    type Error = MoneyError;
+
+    fn try_from(money: Money) -> Result<Self, Self::Error> {
+        if money.currency != Currency::Btc {
+            return Err(MoneyError::CurrencyMismatch(money.currency, Currency::Btc));
+        }
+        u64::try_from(money.minor)
+            .map(Sats)
+            .map_err(|_| MoneyError::InvalidAmount(money.to_string()))
+    }
+}
+
+impl fmt::Display for Sats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sats", self.0)
+    }
+}
+
+/// Amount as it appears on the wire: a JSON number or a decimal string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WireAmount {
+    Number(f64),
+    Text(String),
+}
+
+impl WireAmount {
+    fn into_money<E: de::Error>(self, currency: Currency) -> Result<Money, E> {
+        match self {
+            WireAmount::Number(value) => Money::from_f64(value, currency),
+            WireAmount::Text(text) => Money::parse(&text, currency),
+        }
+        .map_err(de::Error::custom)
+    }
+}
+
+/// Generates serde adapters for payloads that carry an amount and a sibling
+/// `currency` field. Used with `#[serde(flatten, with = "...")]`.
+macro_rules! money_fields {
+    ($($module:ident => $field:literal),+ $(,)?) => {
+        $(
+            pub mod $module {
+                use super::*;
+
+                #[derive(Serialize)]
+                struct Out {
+                    #[serde(rename = $field)]
+                    amount: f64,
+                    currency: Currency,
+                }
+
+                #[derive(Deserialize)]
+                struct In {
+                    #[serde(rename = $field)]
+                    amount: WireAmount,
+                    currency: Currency,
+                }
+
+                pub fn serialize<S: Serializer>(money: &Money, serializer: S) -> Result<S::Ok, S::Error> {
+                    Out { amount: money.to_f64(), currency: money.currency() }.serialize(serializer)
+                }
+
+                pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Money, D::Error> {
+                    let wire = In::deserialize(deserializer)?;
+                    wire.amount.into_money(wire.currency)
+                }
+            }
+        )+
+    };
+}
+
+/// Serde adapters keyed by the JSON name of the amount field
+pub mod fields {
+    use super::*;
+
+    money_fields! {
+        balance => "balance",
+        total_savings => "total_savings",
+        total_contribution => "total_contribution",
+        total_investment => "total_investment",
+        fee => "fee",
+    }
+}
+
+/// `Money` serializes as `{"amount": 500.0, "currency": "KES"}` so it can be
+/// flattened into the BitSacco payloads that already use that shape.
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Out {
+            amount: f64,
+            currency: Currency,
+        }
+        Out { amount: self.to_f64(), currency: self.currency }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct In {
+            amount: WireAmount,
+            currency: Currency,
+        }
+        let wire = In::deserialize(deserializer)?;
+        wire.amount.into_money(wire.currency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_is_exact() {
+        let a = Money::parse("0.1", Currency::Kes).unwrap();
+        let b = Money::parse("0.2", Currency::Kes).unwrap();
+        assert_eq!(a.checked_add(b).unwrap(), Money::parse("0.3", Currency::Kes).unwrap());
+        assert_eq!(Money::parse("1,250.5", Currency::Kes).unwrap().minor(), 125_050);
+        assert_eq!(Money::parse(".5", Currency::Usd).unwrap().minor(), 50);
+        assert_eq!(Money::parse("0.00000001", Currency::Btc).unwrap().minor(), 1);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_input() {
+        assert!(matches!(
+            Money::parse("100.123", Currency::Kes),
+            Err(MoneyError::TooPrecise { max: 2, .. })
+        ));
+        assert!(Money::parse("abc", Currency::Kes).is_err());
+        assert!(Money::parse("1e5", Currency::Kes).is_err());
+        assert!(Money::parse(".", Currency::Kes).is_err());
+        assert!(Money::parse("99999999999999999999", Currency::Kes).is_err());
+        assert!(Money::parse_with_code("10", "XYZ").is_err());
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let kes = Money::from_major(10, Currency::Kes).unwrap();
+        let usd = Money::from_major(10, Currency::Usd).unwrap();
+        assert!(matches!(kes.checked_add(usd), Err(MoneyError::CurrencyMismatch(..))));
+        assert!(Money::from_minor(i64::MAX, Currency::Kes).checked_add(kes).is_err());
+        assert_eq!(kes.checked_mul(3).unwrap().to_string(), "30.00 KES");
+
+        let totals = Money::totals_by_currency([kes, usd, kes]).unwrap();
+        assert_eq!(totals, vec![kes.checked_mul(2).unwrap(), usd]);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Money::from_minor(50_050, Currency::Kes).to_string(), "500.50 KES");
+        assert_eq!(Money::from_minor(-5, Currency::Usd).to_string(), "-0.05 USD");
+        assert_eq!(Money::from_minor(1_000, Currency::Btc).to_string(), "0.00001000 BTC");
+        assert_eq!(Sats(1_000).to_string(), "1000 sats");
+        assert_eq!(Sats(5_000).grouped(), "5,000 sats");
+        assert_eq!(Sats(1_234_567).grouped(), "1,234,567 sats");
+        assert_eq!(Sats(999).grouped(), "999 sats");
+    }
+
+    #[test]
+    fn test_sats_conversion() {
+        let btc = Money::parse("0.0001", Currency::Btc).unwrap();
+        assert_eq!(Sats::try_from(btc).unwrap(), Sats(10_000));
+        assert_eq!(Sats(10_000).to_money().unwrap(), btc);
+        assert!(Sats::try_from(Money::from_major(1, Currency::Kes).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let money: Money = serde_json::from_value(json!({"amount": 1000.1, "currency": "KES"})).unwrap();
+        assert_eq!(money.minor(), 100_010);
+        assert_eq!(serde_json::to_value(money).unwrap(), json!({"amount": 1000.1, "currency": "KES"}));
+
+        let money: Money = serde_json::from_value(json!({"amount": "0.3", "currency": "USD"})).unwrap();
+        assert_eq!(money.minor(), 30);
+    }
+
+    #[test]
+    fn test_serde_renamed_field() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Balance {
+            user_id: String,
+            #[serde(flatten, with = "crate::money::fields::balance")]
+            balance: Money,
+        }
+
+        let wire = json!({"user_id": "user123", "balance": 0.0005, "currency": "BTC"});
+        let parsed: Balance = serde_json::from_value(wire.clone()).unwrap();
+        assert_eq!(Sats::try_from(parsed.balance).unwrap(), Sats(50_000));
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), wire);
+    }
+}
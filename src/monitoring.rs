@@ -6,7 +6,6 @@
 //! - Health check endpoints
 //! - Alerting capabilities
 
-use crate::error::{AppError, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -335,7 +334,6 @@ impl MonitoringService {
         info!("Starting monitoring service");
         
         let metrics = self.metrics.clone();
-        let health_status = self.health_status.clone();
         let alert_config = self.alert_config.clone();
         let start_time = self.start_time;
         
@@ -0,0 +1,582 @@
+//! M-Pesa STK Push results
+//!
+//! Safaricom's Daraja API reports the outcome of an STK Push to
+//! `/callbacks/mpesa`. The callback is matched to the pending deposit that
+//! `create_mpesa_deposit` recorded under its `CheckoutRequestID`, checked
+//! against it, and turned into a status update and a message for the user.
+//! Daraja callbacks are not signed, so the route also requires the shared
+//! callback secret in its URL.
+//!
+//! Some callbacks never arrive. Every STK Push the bot starts is also tracked
+//! here, and a background watcher queries its status with exponential backoff
+//! until the deposit completes, fails, or the checks run out and the user is
+//! asked to look at their M-Pesa messages instead.
+
+use crate::{
+    channel::Messenger,
+    cache::AppCache,
+    error::{AppError, Result},
+    money::{Currency, Money},
+    outbound::sent_or_queued,
+    services::bitsacco::BitSaccoService,
+    templates::{ProactiveMessage, TemplateMessage, TemplateParameter},
+    types::{BitSaccoTransaction, MpesaStkCallback, MpesaStkQueryResponse, TransactionStatusUpdate},
+};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+/// Daraja result code for a successful payment
+const RESULT_SUCCESS: i64 = 0;
+
+/// Daraja result code for a payment it is still processing
+const RESULT_PROCESSING: i64 = 4999;
+
+/// How often the background watcher looks for STK Pushes due a status query
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Status queries made for one STK Push before falling back to asking the
+/// user to check their M-Pesa messages
+pub const MAX_STATUS_QUERIES: u32 = 5;
+
+/// How an STK Push ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StkOutcome {
+    Completed {
+        /// Only callbacks carry the receipt number; status queries do not
+        receipt: Option<String>,
+        amount: Option<Money>,
+    },
+    Failed {
+        code: i64,
+        reason: String,
+    },
+}
+
+impl StkOutcome {
+    pub fn from_callback(callback: &MpesaStkCallback) -> Result<Self> {
+        if callback.result_code != RESULT_SUCCESS {
+            return Ok(StkOutcome::Failed {
+                code: callback.result_code,
+                reason: failure_reason(callback.result_code, &callback.result_desc),
+            });
+        }
+
+        let receipt = metadata_value(callback, "MpesaReceiptNumber")
+            .and_then(|value| value.as_str().map(str::to_string))
+            .filter(|receipt| !receipt.is_empty())
+            .ok_or_else(|| AppError::Validation("Successful M-Pesa callback has no receipt number".to_string()))?;
+        let amount = metadata_value(callback, "Amount")
+            .and_then(|value| value.as_f64())
+            .map(|amount| Money::from_f64(amount, Currency::Kes))
+            .transpose()?;
+        Ok(StkOutcome::Completed { receipt: Some(receipt), amount })
+    }
+
+    /// Outcome of a status query, or `None` while M-Pesa is still processing
+    pub fn from_query(response: &MpesaStkQueryResponse) -> Option<Self> {
+        let code = response.result_code.as_ref().and_then(|value| {
+            value
+                .as_i64()
+                .or_else(|| value.as_str().and_then(|code| code.trim().parse().ok()))
+        })?;
+        match code {
+            RESULT_SUCCESS => Some(StkOutcome::Completed { receipt: None, amount: None }),
+            RESULT_PROCESSING => None,
+            code => Some(StkOutcome::Failed {
+                code,
+                reason: failure_reason(code, response.result_desc.as_deref().unwrap_or_default()),
+            }),
+        }
+    }
+
+    pub fn status_update(&self) -> TransactionStatusUpdate {
+        match self {
+            StkOutcome::Completed { receipt, .. } => TransactionStatusUpdate {
+                status: "completed".to_string(),
+                external_receipt: receipt.clone(),
+                failure_reason: None,
+            },
+            StkOutcome::Failed { reason, .. } => TransactionStatusUpdate {
+                status: "failed".to_string(),
+                external_receipt: None,
+                failure_reason: Some(reason.clone()),
+            },
+        }
+    }
+
+    /// Message telling the user how their deposit went
+    pub fn message(&self, transaction: &BitSaccoTransaction) -> String {
+        match self {
+            StkOutcome::Completed { receipt: Some(receipt), .. } => format!(
+                "✅ *M-Pesa Deposit Received!*\n\nAmount: {}\nM-Pesa Receipt: {}\nTransaction ID: {}\n\nSend `balance` to see your updated balance.",
+                transaction.amount, receipt, transaction.id
+            ),
+            StkOutcome::Completed { receipt: None, .. } => format!(
+                "✅ *M-Pesa Deposit Received!*\n\nAmount: {}\nTransaction ID: {}\n\nYour M-Pesa confirmation SMS has the receipt number. Send `balance` to see your updated balance.",
+                transaction.amount, transaction.id
+            ),
+            StkOutcome::Failed { reason, .. } => format!(
+                "❌ *M-Pesa Deposit Not Completed*\n\nAmount: {}\nReason: {}\n\nSend `deposit {}` to try again.",
+                transaction.amount, reason, transaction.amount
+            ),
+        }
+    }
+
+    /// The deposit message, with the template sent in its place once the
+    /// user's service window has closed
+    pub fn notification(&self, transaction: &BitSaccoTransaction) -> ProactiveMessage {
+        let template = match self {
+            StkOutcome::Completed { .. } => TemplateMessage::new("mpesa_deposit_received").body(vec![
+                TemplateParameter::money(transaction.amount),
+                TemplateParameter::text(&transaction.id),
+            ]),
+            StkOutcome::Failed { reason, .. } => TemplateMessage::new("mpesa_deposit_failed").body(vec![
+                TemplateParameter::money(transaction.amount),
+                TemplateParameter::text(reason),
+            ]),
+        };
+        ProactiveMessage::new(self.message(transaction), template)
+    }
+}
+
+/// User-facing reason for a failed STK Push
+pub fn failure_reason(result_code: i64, result_desc: &str) -> String {
+    match result_code {
+        1 => "Your M-Pesa balance was not enough for this deposit.".to_string(),
+        1032 => "You cancelled the M-Pesa prompt.".to_string(),
+        1037 => "We could not reach your phone, or the M-Pesa prompt timed out.".to_string(),
+        2001 => "The M-Pesa PIN entered was incorrect.".to_string(),
+        1019 => "The M-Pesa request expired before it was completed.".to_string(),
+        1001 => "Another M-Pesa transaction was in progress. Please wait a moment.".to_string(),
+        _ if !result_desc.trim().is_empty() => result_desc.trim().to_string(),
+        _ => format!("M-Pesa returned error code {}.", result_code),
+    }
+}
+
+/// Check an outcome for `checkout_request_id` belongs to `transaction` and
+/// that the transaction is an M-Pesa deposit for the amount that was paid
+pub fn validate_outcome(
+    transaction: &BitSaccoTransaction,
+    checkout_request_id: &str,
+    outcome: &StkOutcome,
+) -> Result<()> {
+    if transaction.external_reference.as_deref() != Some(checkout_request_id) {
+        return Err(AppError::Validation(format!(
+            "Transaction {} does not belong to checkout request {}",
+            transaction.id, checkout_request_id
+        )));
+    }
+    if transaction.r#type != "deposit" || transaction.payment_method.as_deref() != Some("mpesa") {
+        return Err(AppError::Validation(format!(
+            "Transaction {} is not an M-Pesa deposit",
+            transaction.id
+        )));
+    }
+    if let StkOutcome::Completed { amount: Some(paid), .. } = outcome {
+        if *paid != transaction.amount {
+            return Err(AppError::Validation(format!(
+                "M-Pesa paid {} for transaction {} of {}",
+                paid, transaction.id, transaction.amount
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Record the outcome against a pending deposit and tell the user. A failed
+/// notification is logged rather than returned, since the deposit itself
+/// has already been updated.
+pub async fn complete_deposit(
+    bitsacco_service: &BitSaccoService,
+    messenger: &Messenger,
+    cache: &AppCache,
+    phone_number: &str,
+    transaction: &BitSaccoTransaction,
+    outcome: &StkOutcome,
+) -> Result<()> {
+    bitsacco_service
+        .update_transaction_status(&transaction.id, &outcome.status_update())
+        .await?;
+    cache.invalidate_savings(&transaction.user_id).await;
+
+    if let Err(e) = messenger
+        .send_proactive(phone_number, &outcome.notification(transaction).critical())
+        .await
+    {
+        error!("Failed to notify {} of M-Pesa deposit {}: {}", phone_number, transaction.id, e);
+    }
+    Ok(())
+}
+
+fn metadata_value<'a>(callback: &'a MpesaStkCallback, name: &str) -> Option<&'a serde_json::Value> {
+    callback
+        .callback_metadata
+        .as_ref()?
+        .items
+        .iter()
+        .find(|item| item.name == name)?
+        .value
+        .as_ref()
+}
+
+/// An STK Push waiting for its result
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingStkPush {
+    pub checkout_request_id: String,
+    pub transaction_id: String,
+    pub phone_number: String,
+    pub amount: Money,
+    /// Status queries made so far
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+impl PendingStkPush {
+    /// Track a deposit returned by `create_mpesa_deposit`, if it carries a
+    /// checkout request to query
+    pub fn new(phone_number: &str, transaction: &BitSaccoTransaction) -> Option<Self> {
+        if transaction.payment_method.as_deref() != Some("mpesa") {
+            return None;
+        }
+        Some(Self {
+            checkout_request_id: transaction.external_reference.clone().filter(|id| !id.is_empty())?,
+            transaction_id: transaction.id.clone(),
+            phone_number: phone_number.to_string(),
+            amount: transaction.amount,
+            attempts: 0,
+            created_at: Utc::now(),
+        })
+    }
+
+    /// When the next status query is due: `first_check_after` after the push,
+    /// then doubling the wait after every query that found no result
+    pub fn next_check_at(&self, first_check_after: chrono::Duration) -> DateTime<Utc> {
+        let waits = (1i32 << (self.attempts + 1).min(16)) - 1;
+        self.created_at + first_check_after * waits
+    }
+}
+
+/// STK Pushes awaiting a result, by checkout request id
+#[derive(Debug, Clone)]
+pub struct StkPushTracker {
+    pushes: Arc<RwLock<HashMap<String, PendingStkPush>>>,
+    first_check_after: chrono::Duration,
+}
+
+impl StkPushTracker {
+    /// Tracker that first queries a push's status `first_check_after` it
+    /// was sent, giving the callback a chance to arrive
+    pub fn new(first_check_after: Duration) -> Self {
+        Self {
+            pushes: Arc::new(RwLock::new(HashMap::new())),
+            first_check_after: chrono::Duration::from_std(first_check_after)
+                .unwrap_or(chrono::Duration::MAX),
+        }
+    }
+
+    pub async fn track(&self, push: PendingStkPush) {
+        self.pushes
+            .write()
+            .await
+            .insert(push.checkout_request_id.clone(), push);
+    }
+
+    pub async fn outstanding(&self) -> Vec<PendingStkPush> {
+        self.pushes.read().await.values().cloned().collect()
+    }
+
+    /// Pushes whose next status query is due at `now`
+    pub async fn due(&self, now: DateTime<Utc>) -> Vec<PendingStkPush> {
+        self.pushes
+            .read()
+            .await
+            .values()
+            .filter(|push| push.next_check_at(self.first_check_after) <= now)
+            .cloned()
+            .collect()
+    }
+
+    /// Stop tracking a push. Only the first caller gets it back.
+    pub async fn take(&self, checkout_request_id: &str) -> Option<PendingStkPush> {
+        self.pushes.write().await.remove(checkout_request_id)
+    }
+
+    /// Count a status query that found no result. Once the queries run out
+    /// the push is no longer tracked and is returned for the fallback message.
+    pub async fn record_attempt(&self, checkout_request_id: &str) -> Option<PendingStkPush> {
+        let mut pushes = self.pushes.write().await;
+        let push = pushes.get_mut(checkout_request_id)?;
+        push.attempts += 1;
+        if push.attempts < MAX_STATUS_QUERIES {
+            return None;
+        }
+        pushes.remove(checkout_request_id)
+    }
+}
+
+/// Sent when a deposit's result could not be confirmed either way
+pub fn unconfirmed_message(push: &PendingStkPush) -> String {
+    format!(
+        "⏳ *M-Pesa Deposit Unconfirmed*\n\nWe have not been able to confirm your M-Pesa deposit of {} (Transaction ID: {}).\n\nPlease check your M-Pesa messages. If you received a confirmation SMS, your deposit will reflect shortly. If not, no money was taken and you can send `deposit {}` to try again.",
+        push.amount, push.transaction_id, push.amount
+    )
+}
+
+/// Query the status of STK Pushes whose callback is late, until the process exits
+pub async fn run_stk_query_watcher(
+    tracker: StkPushTracker,
+    bitsacco_service: BitSaccoService,
+    messenger: Messenger,
+    cache: AppCache,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        for push in tracker.due(Utc::now()).await {
+            if let Err(e) = check_stk_push(&tracker, &bitsacco_service, &messenger, &cache, &push).await {
+                error!("Failed to update M-Pesa deposit {}: {}", push.transaction_id, e);
+            }
+        }
+    }
+}
+
+/// Query one STK Push and settle its deposit if M-Pesa has a result
+pub async fn check_stk_push(
+    tracker: &StkPushTracker,
+    bitsacco_service: &BitSaccoService,
+    messenger: &Messenger,
+    cache: &AppCache,
+    push: &PendingStkPush,
+) -> Result<()> {
+    let outcome = match bitsacco_service.get_transaction_by_reference(&push.checkout_request_id).await {
+        Ok(transaction) if transaction.status != "pending" => {
+            // The callback got there first
+            tracker.take(&push.checkout_request_id).await;
+            return Ok(());
+        }
+        Ok(transaction) => match bitsacco_service.query_mpesa_stk_status(&push.checkout_request_id).await {
+            Ok(response) => StkOutcome::from_query(&response).map(|outcome| (transaction, outcome)),
+            Err(e) => {
+                warn!("M-Pesa status query for {} failed: {}", push.checkout_request_id, e);
+                None
+            }
+        },
+        Err(e) => {
+            warn!("Failed to look up M-Pesa deposit {}: {}", push.transaction_id, e);
+            None
+        }
+    };
+
+    let Some((transaction, outcome)) = outcome else {
+        return record_failed_check(tracker, messenger, push).await;
+    };
+    if let Err(e) = validate_outcome(&transaction, &push.checkout_request_id, &outcome) {
+        record_failed_check(tracker, messenger, push).await?;
+        return Err(e);
+    }
+    // Claim the push so a callback arriving now does not complete it too
+    let Some(claimed) = tracker.take(&push.checkout_request_id).await else {
+        return Ok(());
+    };
+    info!("M-Pesa checkout {} resolved by status query", push.checkout_request_id);
+    let bitsacco_service = bitsacco_service.for_stk_push(&push.checkout_request_id);
+    if let Err(e) = complete_deposit(&bitsacco_service, messenger, cache, &push.phone_number, &transaction, &outcome).await {
+        // The deposit is still pending; keep querying it
+        tracker.track(claimed).await;
+        record_failed_check(tracker, messenger, push).await?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Count a status check that did not settle the push, and tell the user to
+/// check their M-Pesa messages once the checks run out
+async fn record_failed_check(tracker: &StkPushTracker, messenger: &Messenger, push: &PendingStkPush) -> Result<()> {
+    let Some(push) = tracker.record_attempt(&push.checkout_request_id).await else {
+        return Ok(());
+    };
+    info!("Giving up on M-Pesa checkout {} after {} queries", push.checkout_request_id, push.attempts);
+    let notification = ProactiveMessage::new(
+        unconfirmed_message(&push),
+        TemplateMessage::new("mpesa_deposit_unconfirmed").body(vec![
+            TemplateParameter::money(push.amount),
+            TemplateParameter::text(&push.transaction_id),
+        ]),
+    );
+    sent_or_queued(messenger.send_proactive(&push.phone_number, &notification).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MpesaCallback;
+    use serde_json::json;
+
+    fn callback(body: serde_json::Value) -> MpesaStkCallback {
+        serde_json::from_value::<MpesaCallback>(body).unwrap().body.stk_callback
+    }
+
+    fn success() -> MpesaStkCallback {
+        callback(json!({
+            "Body": {"stkCallback": {
+                "MerchantRequestID": "29115-34620561-1",
+                "CheckoutRequestID": "ws_CO_191220191020363925",
+                "ResultCode": 0,
+                "ResultDesc": "The service request is processed successfully.",
+                "CallbackMetadata": {"Item": [
+                    {"Name": "Amount", "Value": 500.00},
+                    {"Name": "MpesaReceiptNumber", "Value": "NLJ7RT61SV"},
+                    {"Name": "Balance"},
+                    {"Name": "TransactionDate", "Value": 20191219102115u64},
+                    {"Name": "PhoneNumber", "Value": 254708374149u64}
+                ]}
+            }}
+        }))
+    }
+
+    fn transaction() -> BitSaccoTransaction {
+        BitSaccoTransaction {
+            id: "tx1".to_string(),
+            user_id: "user123".to_string(),
+            r#type: "deposit".to_string(),
+            amount: Money::from_major(500, Currency::Kes).unwrap(),
+            status: "pending".to_string(),
+            payment_method: Some("mpesa".to_string()),
+            external_reference: Some("ws_CO_191220191020363925".to_string()),
+            chama_id: None,
+            description: None,
+            created_at: "2026-10-16T00:00:00Z".to_string(),
+            updated_at: "2026-10-16T00:00:00Z".to_string(),
+        }
+    }
+
+    fn query(result_code: serde_json::Value) -> MpesaStkQueryResponse {
+        serde_json::from_value(json!({
+            "CheckoutRequestID": "ws_CO_191220191020363925",
+            "ResultCode": result_code,
+            "ResultDesc": "Request cancelled by user"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_successful_callback() {
+        let callback = success();
+        let outcome = StkOutcome::from_callback(&callback).unwrap();
+        assert_eq!(
+            outcome,
+            StkOutcome::Completed {
+                receipt: Some("NLJ7RT61SV".to_string()),
+                amount: Some(Money::from_major(500, Currency::Kes).unwrap()),
+            }
+        );
+        assert!(validate_outcome(&transaction(), &callback.checkout_request_id, &outcome).is_ok());
+        assert!(outcome.message(&transaction()).contains("NLJ7RT61SV"));
+        assert_eq!(outcome.status_update().status, "completed");
+    }
+
+    #[test]
+    fn test_failed_callbacks() {
+        let cancelled = callback(json!({
+            "Body": {"stkCallback": {
+                "MerchantRequestID": "29115-34620561-1",
+                "CheckoutRequestID": "ws_CO_191220191020363925",
+                "ResultCode": 1032,
+                "ResultDesc": "Request cancelled by user"
+            }}
+        }));
+        let outcome = StkOutcome::from_callback(&cancelled).unwrap();
+        assert_eq!(
+            outcome,
+            StkOutcome::Failed { code: 1032, reason: "You cancelled the M-Pesa prompt.".to_string() }
+        );
+        assert_eq!(outcome.status_update().failure_reason.as_deref(), Some("You cancelled the M-Pesa prompt."));
+        assert!(failure_reason(1, "").contains("balance was not enough"));
+        assert_eq!(failure_reason(9999, "Something odd"), "Something odd");
+    }
+
+    #[test]
+    fn test_validation_against_transaction() {
+        let callback = success();
+        let outcome = StkOutcome::from_callback(&callback).unwrap();
+        let checkout = &callback.checkout_request_id;
+
+        let mut other = transaction();
+        other.external_reference = Some("ws_CO_other".to_string());
+        assert!(validate_outcome(&other, checkout, &outcome).is_err());
+
+        let mut withdrawal = transaction();
+        withdrawal.r#type = "withdrawal".to_string();
+        assert!(validate_outcome(&withdrawal, checkout, &outcome).is_err());
+
+        let mut larger = transaction();
+        larger.amount = Money::from_major(1_000, Currency::Kes).unwrap();
+        assert!(validate_outcome(&larger, checkout, &outcome).is_err());
+    }
+
+    #[test]
+    fn test_success_without_receipt_is_rejected() {
+        let mut callback = success();
+        callback.callback_metadata = None;
+        assert!(StkOutcome::from_callback(&callback).is_err());
+    }
+
+    #[test]
+    fn test_query_outcomes() {
+        assert_eq!(
+            StkOutcome::from_query(&query(json!("0"))),
+            Some(StkOutcome::Completed { receipt: None, amount: None })
+        );
+        assert_eq!(
+            StkOutcome::from_query(&query(json!(1032))),
+            Some(StkOutcome::Failed { code: 1032, reason: "You cancelled the M-Pesa prompt.".to_string() })
+        );
+        assert_eq!(StkOutcome::from_query(&query(json!("4999"))), None);
+        assert_eq!(StkOutcome::from_query(&query(json!(null))), None);
+
+        let completed = StkOutcome::Completed { receipt: None, amount: None };
+        assert!(completed.message(&transaction()).contains("confirmation SMS"));
+        assert_eq!(completed.status_update().external_receipt, None);
+    }
+
+    #[test]
+    fn test_query_backoff() {
+        let mut push = PendingStkPush::new("+254712345678", &transaction()).unwrap();
+        let minute = chrono::Duration::minutes(1);
+        assert_eq!(push.next_check_at(minute), push.created_at + minute);
+        push.attempts = 1;
+        assert_eq!(push.next_check_at(minute), push.created_at + minute * 3);
+        push.attempts = 2;
+        assert_eq!(push.next_check_at(minute), push.created_at + minute * 7);
+
+        let mut lightning = transaction();
+        lightning.payment_method = Some("lightning".to_string());
+        assert!(PendingStkPush::new("+254712345678", &lightning).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tracker_gives_up_after_max_queries() {
+        let tracker = StkPushTracker::new(Duration::from_secs(60));
+        let push = PendingStkPush::new("+254712345678", &transaction()).unwrap();
+        let checkout = push.checkout_request_id.clone();
+        tracker.track(push).await;
+
+        assert!(tracker.due(Utc::now()).await.is_empty());
+        assert_eq!(tracker.due(Utc::now() + chrono::Duration::minutes(1)).await.len(), 1);
+
+        for _ in 1..MAX_STATUS_QUERIES {
+            assert!(tracker.record_attempt(&checkout).await.is_none());
+        }
+        let exhausted = tracker.record_attempt(&checkout).await.unwrap();
+        assert_eq!(exhausted.attempts, MAX_STATUS_QUERIES);
+        assert!(unconfirmed_message(&exhausted).contains("check your M-Pesa messages"));
+        assert!(tracker.outstanding().await.is_empty());
+        assert!(tracker.take(&checkout).await.is_none());
+    }
+}
@@ -0,0 +1,536 @@
+//! Retrying WhatsApp messages that could not be sent
+//!
+//! `WhatsAppService::send_message` makes one attempt straight away. When the
+//! attempt fails for a reason that may pass, such as a 429, a 5xx or one of
+//! Meta's throttling error codes, the message is put in the outbox and
+//! retried in the background with exponential backoff and jitter. Messages
+//! that fail for good, or run out of attempts, are moved to dead-letter
+//! storage, where admins can list them and replay them once the cause is
+//! fixed. Template sends are queued the same way. A send that was queued
+//! returns `AppError::Queued`, which callers count as sent so the message
+//! does not go out twice. Only the most recent
+//! `MAX_DEAD_LETTERS` are kept. The outbox is saved to disk so queued
+//! messages survive restarts.
+
+use crate::{
+    error::{AppError, Result},
+    json_store,
+    service_window::ServiceWindows,
+    services::whatsapp::{self, WhatsAppService},
+    templates::TemplateMessage,
+};
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+/// How often the outbox is checked for messages due a retry
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Attempts, including the first, before a message is dead-lettered
+pub const MAX_ATTEMPTS: u32 = 6;
+
+/// Dead letters kept for admins; older ones are dropped as new ones arrive
+pub const MAX_DEAD_LETTERS: usize = 1000;
+
+const BASE_DELAY: chrono::Duration = chrono::Duration::seconds(2);
+
+const MAX_DELAY: chrono::Duration = chrono::Duration::minutes(10);
+
+/// Meta error codes that mean "try again later": unknown and temporary
+/// errors, the API and spam rate limits, throughput and pair rate limits,
+/// and service unavailability
+const RETRYABLE_META_CODES: &[i64] = &[1, 2, 4, 80007, 130429, 131000, 131016, 131048, 131056, 133004];
+
+/// Whether a failed send is worth retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// `retry_after` is the delay Meta asked for, if any
+    Transient { retry_after: Option<Duration> },
+    Permanent,
+}
+
+/// A failed send attempt and whether it may be retried
+#[derive(Debug)]
+pub struct SendFailure {
+    pub error: AppError,
+    pub kind: FailureKind,
+}
+
+impl SendFailure {
+    /// Network errors never reached Meta and are always worth retrying
+    pub fn network(error: AppError) -> Self {
+        Self {
+            error,
+            kind: FailureKind::Transient { retry_after: None },
+        }
+    }
+}
+
+/// Classify an error response from the Cloud API. Meta's error code decides
+/// where it is known; otherwise 429s and 5xxs are retried and the rest are not.
+pub fn classify_response(status: u16, body: &str, retry_after: Option<Duration>) -> FailureKind {
+    let code = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body["error"]["code"].as_i64());
+    let transient = match code {
+        Some(code) if RETRYABLE_META_CODES.contains(&code) => true,
+        Some(_) if status < 500 && status != 429 => false,
+        _ => status == 429 || status >= 500,
+    };
+    if transient {
+        FailureKind::Transient { retry_after }
+    } else {
+        FailureKind::Permanent
+    }
+}
+
+/// The error for a failed first attempt: `Queued` when the outbox is
+/// retrying the message, so callers do not send it a second time
+pub fn queued_or(queued: Option<OutboundMessage>, error: AppError) -> AppError {
+    match queued {
+        Some(message) if message.dead_at.is_none() => AppError::Queued(error.to_string()),
+        _ => error,
+    }
+}
+
+/// Count a message the outbox took for retry as sent
+pub fn sent_or_queued<T>(result: Result<T>) -> Result<()> {
+    match result {
+        Ok(_) | Err(AppError::Queued(_)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Delay before the next attempt after `attempts` failed ones: doubling from
+/// `BASE_DELAY` up to `MAX_DELAY`, with the upper half scaled by `jitter`
+/// (0 to 1) so messages that failed together are not retried together
+pub fn backoff(attempts: u32, jitter: f64) -> chrono::Duration {
+    let doubled = BASE_DELAY
+        .checked_mul(1 << attempts.saturating_sub(1).min(16))
+        .unwrap_or(MAX_DELAY)
+        .min(MAX_DELAY);
+    let half = doubled.num_milliseconds() / 2;
+    chrono::Duration::milliseconds(half + (half as f64 * jitter.clamp(0.0, 1.0)) as i64)
+}
+
+fn random_jitter() -> f64 {
+    let mut bytes = [0u8; 4];
+    match SystemRandom::new().fill(&mut bytes) {
+        Ok(()) => f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX),
+        Err(_) => 0.5,
+    }
+}
+
+/// A message waiting for a retry, or dead-lettered
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboundMessage {
+    pub id: u64,
+    pub to: String,
+    /// The text sent, or for a template its free-form version, if any
+    pub body: String,
+    /// The template sent instead of `body`, for messages sent outside the
+    /// user's service window
+    #[serde(default)]
+    pub template: Option<TemplateMessage>,
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    /// When the message was given up on; set only on dead letters
+    pub dead_at: Option<DateTime<Utc>>,
+    /// Whether the message needs a fallback if WhatsApp fails to deliver it
+    #[serde(default)]
+    pub critical: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OutboxState {
+    next_id: u64,
+    pending: Vec<OutboundMessage>,
+    dead_letters: Vec<OutboundMessage>,
+}
+
+/// Messages waiting for a retry and those given up on, optionally mirrored
+/// to a JSON file
+#[derive(Debug, Clone, Default)]
+pub struct Outbox {
+    state: Arc<RwLock<OutboxState>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+impl Outbox {
+    /// Outbox backed by `path`, resuming retries queued before a restart. An
+    /// empty path keeps the outbox in memory only.
+    pub async fn load(path: &str) -> Self {
+        if path.is_empty() {
+            return Self::default();
+        }
+        let path = PathBuf::from(path);
+        let state: OutboxState = json_store::read_or_default(&path, "Outbox").await;
+        info!(
+            "Loaded {} queued and {} dead-lettered WhatsApp messages",
+            state.pending.len(),
+            state.dead_letters.len()
+        );
+
+        Self {
+            state: Arc::new(RwLock::new(state)),
+            path: Some(Arc::new(path)),
+        }
+    }
+
+    /// Record a failed first attempt, queueing the message for a retry or
+    /// dead-lettering it straight away when the failure is permanent
+    pub async fn record_first_failure(
+        &self,
+        to: &str,
+        body: &str,
+        critical: bool,
+        failure: &SendFailure,
+        now: DateTime<Utc>,
+    ) -> OutboundMessage {
+        self.record_new(to, body, None, critical, failure, now).await
+    }
+
+    /// Record a failed first attempt at sending `template`, whose free-form
+    /// version is `text`
+    pub async fn record_first_template_failure(
+        &self,
+        to: &str,
+        template: &TemplateMessage,
+        text: Option<&str>,
+        critical: bool,
+        failure: &SendFailure,
+        now: DateTime<Utc>,
+    ) -> OutboundMessage {
+        self.record_new(to, text.unwrap_or_default(), Some(template.clone()), critical, failure, now)
+            .await
+    }
+
+    async fn record_new(
+        &self,
+        to: &str,
+        body: &str,
+        template: Option<TemplateMessage>,
+        critical: bool,
+        failure: &SendFailure,
+        now: DateTime<Utc>,
+    ) -> OutboundMessage {
+        let mut state = self.state.write().await;
+        state.next_id += 1;
+        let message = OutboundMessage {
+            id: state.next_id,
+            to: to.to_string(),
+            body: body.to_string(),
+            template,
+            attempts: 0,
+            created_at: now,
+            next_attempt_at: now,
+            last_error: None,
+            dead_at: None,
+            critical,
+        };
+        let message = Self::apply_failure(&mut state, message, failure, now);
+        self.persist(&state).await;
+        message
+    }
+
+    /// Record a failed retry of a queued message
+    pub async fn record_failure(&self, id: u64, failure: &SendFailure, now: DateTime<Utc>) -> Option<OutboundMessage> {
+        let mut state = self.state.write().await;
+        let index = state.pending.iter().position(|message| message.id == id)?;
+        let message = state.pending.remove(index);
+        let message = Self::apply_failure(&mut state, message, failure, now);
+        self.persist(&state).await;
+        Some(message)
+    }
+
+    fn apply_failure(
+        state: &mut OutboxState,
+        mut message: OutboundMessage,
+        failure: &SendFailure,
+        now: DateTime<Utc>,
+    ) -> OutboundMessage {
+        message.attempts += 1;
+        message.last_error = Some(failure.error.to_string());
+        match failure.kind {
+            FailureKind::Transient { retry_after } if message.attempts < MAX_ATTEMPTS => {
+                let delay = backoff(message.attempts, random_jitter());
+                let requested = retry_after
+                    .and_then(|after| chrono::Duration::from_std(after).ok())
+                    .unwrap_or(delay);
+                message.next_attempt_at = now + delay.max(requested);
+                info!(
+                    "Retrying WhatsApp message {} to {} at {} (attempt {} failed: {})",
+                    message.id, message.to, message.next_attempt_at, message.attempts, failure.error
+                );
+                state.pending.push(message.clone());
+            }
+            _ => {
+                warn!(
+                    "Dead-lettering WhatsApp message {} to {} after {} attempts: {}",
+                    message.id, message.to, message.attempts, failure.error
+                );
+                message.dead_at = Some(now);
+                state.dead_letters.push(message.clone());
+                let excess = state.dead_letters.len().saturating_sub(MAX_DEAD_LETTERS);
+                state.dead_letters.drain(..excess);
+            }
+        }
+        message
+    }
+
+    /// Forget a queued message once it has been sent
+    pub async fn delivered(&self, id: u64) {
+        let mut state = self.state.write().await;
+        let before = state.pending.len();
+        state.pending.retain(|message| message.id != id);
+        if state.pending.len() != before {
+            self.persist(&state).await;
+        }
+    }
+
+    /// Queued messages whose next attempt is due at `now`
+    pub async fn due(&self, now: DateTime<Utc>) -> Vec<OutboundMessage> {
+        self.state
+            .read()
+            .await
+            .pending
+            .iter()
+            .filter(|message| message.next_attempt_at <= now)
+            .cloned()
+            .collect()
+    }
+
+    pub async fn pending(&self) -> Vec<OutboundMessage> {
+        self.state.read().await.pending.clone()
+    }
+
+    pub async fn dead_letters(&self) -> Vec<OutboundMessage> {
+        self.state.read().await.dead_letters.clone()
+    }
+
+    /// Move a dead letter back into the queue with a fresh set of attempts,
+    /// due straight away
+    pub async fn replay(&self, id: u64, now: DateTime<Utc>) -> Result<OutboundMessage> {
+        let mut state = self.state.write().await;
+        let index = state
+            .dead_letters
+            .iter()
+            .position(|message| message.id == id)
+            .ok_or_else(|| AppError::DataNotFound(format!("No dead-lettered message {}", id)))?;
+        let mut message = state.dead_letters.remove(index);
+        message.attempts = 0;
+        message.next_attempt_at = now;
+        message.dead_at = None;
+        state.pending.push(message.clone());
+        self.persist(&state).await;
+        info!("Replaying dead-lettered WhatsApp message {} to {}", message.id, message.to);
+        Ok(message)
+    }
+
+    async fn persist(&self, state: &OutboxState) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = json_store::write(path, state).await {
+            error!("Failed to save outbox to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Retry every message that is due at `now`. Text messages to users whose
+/// service window has closed since they were queued are dead-lettered;
+/// templates are sent regardless.
+pub async fn flush_outbox(outbox: &Outbox, whatsapp_service: &WhatsAppService, windows: &ServiceWindows, now: DateTime<Utc>) {
+    for message in outbox.due(now).await {
+        let attempt = match &message.template {
+            Some(template) => whatsapp_service.attempt_template(&message.to, template).await,
+            None if !windows.admit_free_form(whatsapp::CHANNEL, &message.to, now).await => {
+                let failure = SendFailure {
+                    error: AppError::ServiceWindowClosed(message.to.clone()),
+                    kind: FailureKind::Permanent,
+                };
+                outbox.record_failure(message.id, &failure, now).await;
+                continue;
+            }
+            None => whatsapp_service.attempt_text(&message.to, &message.body).await,
+        };
+        match attempt {
+            Ok(response) => {
+                info!("Sent queued WhatsApp message {} to {}", message.id, message.to);
+                whatsapp_service
+                    .track_sent(&message.to, &response, message.critical.then_some(message.body.as_str()))
+                    .await;
+                outbox.delivered(message.id).await;
+            }
+            Err(failure) => {
+                outbox.record_failure(message.id, &failure, now).await;
+            }
+        }
+    }
+}
+
+/// Retry queued messages until the process exits
+pub async fn run_outbox_worker(outbox: Outbox, whatsapp_service: WhatsAppService, windows: ServiceWindows, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        flush_outbox(&outbox, &whatsapp_service, &windows, Utc::now()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(kind: FailureKind) -> SendFailure {
+        SendFailure {
+            error: AppError::WhatsApp("API error 503".to_string()),
+            kind,
+        }
+    }
+
+    const TRANSIENT: FailureKind = FailureKind::Transient { retry_after: None };
+
+    #[test]
+    fn test_classify_response() {
+        let meta_error = |code: i64| format!(r#"{{"error":{{"message":"x","type":"OAuthException","code":{}}}}}"#, code);
+
+        assert_eq!(classify_response(503, "", None), TRANSIENT);
+        assert_eq!(
+            classify_response(429, "", Some(Duration::from_secs(30))),
+            FailureKind::Transient { retry_after: Some(Duration::from_secs(30)) }
+        );
+        assert_eq!(classify_response(400, "", None), FailureKind::Permanent);
+
+        // Meta reports throttling as a 400 with a rate limit code
+        assert_eq!(classify_response(400, &meta_error(131056), None), TRANSIENT);
+        assert_eq!(classify_response(400, &meta_error(130429), None), TRANSIENT);
+        assert_eq!(classify_response(400, &meta_error(131026), None), FailureKind::Permanent);
+        assert_eq!(classify_response(401, &meta_error(190), None), FailureKind::Permanent);
+        assert_eq!(classify_response(500, &meta_error(131026), None), TRANSIENT);
+    }
+
+    #[test]
+    fn test_backoff_doubles_with_jitter() {
+        assert_eq!(backoff(1, 0.0), chrono::Duration::seconds(1));
+        assert_eq!(backoff(1, 1.0), chrono::Duration::seconds(2));
+        assert_eq!(backoff(3, 1.0), chrono::Duration::seconds(8));
+        assert_eq!(backoff(30, 1.0), MAX_DELAY);
+        let jittered = backoff(3, 0.5);
+        assert!(jittered > chrono::Duration::seconds(4) && jittered < chrono::Duration::seconds(8));
+    }
+
+    #[tokio::test]
+    async fn test_queued_sends_count_as_sent() {
+        let outbox = Outbox::default();
+        let now = Utc::now();
+        let retried = outbox
+            .record_first_failure("+254712345678", "Deposit received", false, &failure(TRANSIENT), now)
+            .await;
+        let error = queued_or(Some(retried), failure(TRANSIENT).error);
+        assert!(matches!(error, AppError::Queued(_)));
+        assert!(sent_or_queued::<()>(Err(error)).is_ok());
+
+        // A dead letter will not be retried on its own
+        let dead = outbox
+            .record_first_failure("+254712345678", "Deposit received", false, &failure(FailureKind::Permanent), now)
+            .await;
+        let error = queued_or(Some(dead), failure(FailureKind::Permanent).error);
+        assert!(matches!(error, AppError::WhatsApp(_)));
+        assert!(sent_or_queued::<()>(Err(error)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_dead_lettered() {
+        let outbox = Outbox::default();
+        let now = Utc::now();
+        let queued = outbox
+            .record_first_failure("+254712345678", "Deposit received", false, &failure(TRANSIENT), now)
+            .await;
+        assert_eq!(queued.attempts, 1);
+        assert!(queued.next_attempt_at > now);
+        assert!(outbox.due(now).await.is_empty());
+        assert_eq!(outbox.due(now + MAX_DELAY).await.len(), 1);
+
+        for _ in 1..MAX_ATTEMPTS {
+            outbox.record_failure(queued.id, &failure(TRANSIENT), now).await.unwrap();
+        }
+        assert!(outbox.pending().await.is_empty());
+        let dead = outbox.dead_letters().await;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].attempts, MAX_ATTEMPTS);
+        assert_eq!(dead[0].dead_at, Some(now));
+    }
+
+    #[tokio::test]
+    async fn test_permanent_failure_and_replay() {
+        let outbox = Outbox::default();
+        let now = Utc::now();
+        let message = outbox
+            .record_first_failure("+254712345678", "Hi", false, &failure(FailureKind::Permanent), now)
+            .await;
+        assert!(outbox.pending().await.is_empty());
+        assert_eq!(outbox.dead_letters().await.len(), 1);
+
+        let replayed = outbox.replay(message.id, now).await.unwrap();
+        assert_eq!(replayed.attempts, 0);
+        assert!(outbox.dead_letters().await.is_empty());
+        assert_eq!(outbox.due(now).await, vec![replayed]);
+        assert!(outbox.replay(message.id, now).await.is_err());
+
+        outbox.delivered(message.id).await;
+        assert!(outbox.pending().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_keeps_only_recent_dead_letters() {
+        let outbox = Outbox::default();
+        let now = Utc::now();
+        for n in 0..MAX_DEAD_LETTERS + 2 {
+            outbox
+                .record_first_failure("+254712345678", &n.to_string(), false, &failure(FailureKind::Permanent), now)
+                .await;
+        }
+        let dead = outbox.dead_letters().await;
+        assert_eq!(dead.len(), MAX_DEAD_LETTERS);
+        assert_eq!(dead[0].body, "2");
+    }
+
+    #[tokio::test]
+    async fn test_respects_retry_after() {
+        let outbox = Outbox::default();
+        let now = Utc::now();
+        let kind = FailureKind::Transient { retry_after: Some(Duration::from_secs(120)) };
+        let message = outbox.record_first_failure("+254712345678", "Hi", false, &failure(kind), now).await;
+        assert_eq!(message.next_attempt_at, now + chrono::Duration::seconds(120));
+    }
+
+    #[tokio::test]
+    async fn test_persists_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.json");
+        let path = path.to_str().unwrap();
+        let now = Utc::now();
+
+        let outbox = Outbox::load(path).await;
+        outbox.record_first_failure("+254712345678", "Queued", false, &failure(TRANSIENT), now).await;
+        outbox
+            .record_first_failure("+254712345678", "Dead", false, &failure(FailureKind::Permanent), now)
+            .await;
+
+        let reloaded = Outbox::load(path).await;
+        assert_eq!(reloaded.pending().await[0].body, "Queued");
+        assert_eq!(reloaded.dead_letters().await[0].body, "Dead");
+        // IDs keep counting after a restart
+        let next = reloaded.record_first_failure("+254712345678", "Next", false, &failure(TRANSIENT), now).await;
+        assert_eq!(next.id, 3);
+    }
+}
@@ -0,0 +1,414 @@
+//! Optional transaction PIN
+//!
+//! Users can set a 4 to 6 digit PIN with `set pin`. Once set, withdrawals,
+//! transfers and share purchases are confirmed by replying with the PIN
+//! instead of `YES`, so an unlocked phone alone is not enough to move money.
+//! PINs are stored only as salted PBKDF2-HMAC-SHA256 hashes. Too many wrong
+//! attempts lock the PIN for a while; a forgotten PIN is reset from the
+//! BitSacco web app, which tells the bot through `/callbacks/pin-reset`.
+
+use crate::{
+    error::{AppError, Result},
+    json_store,
+};
+use chrono::{DateTime, Utc};
+use ring::{
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+
+/// Wrong attempts allowed before the PIN is locked
+pub const MAX_PIN_ATTEMPTS: u32 = 3;
+
+/// How long a PIN stays locked after too many wrong attempts
+pub const LOCKOUT: chrono::Duration = chrono::Duration::minutes(30);
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// A PIN as typed by the user. Its `Debug` output is redacted so it never
+/// ends up in logs alongside the command that carries it.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Pin(String);
+
+impl Pin {
+    /// Whether a message could be a PIN: 4 to 6 digits
+    pub fn looks_like(input: &str) -> bool {
+        let input = input.trim();
+        (4..=6).contains(&input.len()) && input.bytes().all(|b| b.is_ascii_digit())
+    }
+
+    pub fn parse(input: &str) -> Result<Self> {
+        if !Self::looks_like(input) {
+            return Err(AppError::Validation("A PIN must be 4 to 6 digits".to_string()));
+        }
+        Ok(Self(input.trim().to_string()))
+    }
+
+    /// Reject PINs that are easy to guess: one repeated digit, or a run
+    /// such as 1234 or 9876
+    pub fn check_strength(&self) -> Result<()> {
+        let digits: Vec<i32> = self.0.bytes().map(|b| i32::from(b - b'0')).collect();
+        let steps: Vec<i32> = digits.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        if steps.iter().all(|step| *step == 0) || steps.iter().all(|step| *step == 1) || steps.iter().all(|step| *step == -1) {
+            return Err(AppError::Validation(
+                "That PIN is too easy to guess. Avoid repeated digits and runs like 1234".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pin(****)")
+    }
+}
+
+/// Result of checking a PIN
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinCheck {
+    Correct,
+    Incorrect { attempts_left: u32 },
+    LockedOut { until: DateTime<Utc> },
+    NotSet,
+}
+
+/// Stored hash and lockout state for one user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PinRecord {
+    pub phone_number: String,
+    /// Hex-encoded salt and PBKDF2 output
+    pub salt: String,
+    pub hash: String,
+    pub iterations: u32,
+    pub failed_attempts: u32,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl PinRecord {
+    fn new(phone_number: &str, pin: &Pin, iterations: NonZeroU32) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| AppError::Internal("Failed to generate PIN salt".to_string()))?;
+        let mut hash = [0u8; HASH_LEN];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, pin.0.as_bytes(), &mut hash);
+        Ok(Self {
+            phone_number: phone_number.to_string(),
+            salt: hex::encode(salt),
+            hash: hex::encode(hash),
+            iterations: iterations.get(),
+            failed_attempts: 0,
+            locked_until: None,
+            updated_at: Utc::now(),
+        })
+    }
+
+    fn matches(&self, pin: &Pin) -> bool {
+        let (Ok(salt), Ok(hash), Some(iterations)) =
+            (hex::decode(&self.salt), hex::decode(&self.hash), NonZeroU32::new(self.iterations))
+        else {
+            return false;
+        };
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, pin.0.as_bytes(), &hash).is_ok()
+    }
+}
+
+/// PIN records by phone number, optionally mirrored to a JSON file
+#[derive(Debug, Clone)]
+pub struct PinStore {
+    records: Arc<RwLock<HashMap<String, PinRecord>>>,
+    path: Option<Arc<PathBuf>>,
+    /// Held while saving, so saves run one at a time
+    save_lock: Arc<Mutex<()>>,
+    iterations: NonZeroU32,
+}
+
+impl Default for PinStore {
+    fn default() -> Self {
+        Self {
+            records: Arc::new(RwLock::new(HashMap::new())),
+            path: None,
+            save_lock: Arc::new(Mutex::new(())),
+            iterations: NonZeroU32::new(PBKDF2_ITERATIONS).expect("iterations are non-zero"),
+        }
+    }
+}
+
+impl PinStore {
+    /// Store backed by `path`, keeping PINs set before a restart. An empty
+    /// path keeps them in memory only.
+    ///
+    /// A store that exists but cannot be read is an error rather than an
+    /// empty store: starting without it would silently drop PIN protection
+    /// from every user who set one.
+    pub async fn load(path: &str) -> Result<Self> {
+        if path.is_empty() {
+            return Ok(Self::default());
+        }
+        let path = PathBuf::from(path);
+        let records: Vec<PinRecord> = json_store::read(&path)
+            .await
+            .map_err(|e| AppError::Internal(format!("PIN store {} is unreadable: {}", path.display(), e)))?
+            .unwrap_or_default();
+        info!("Loaded {} transaction PINs", records.len());
+
+        Ok(Self {
+            records: Arc::new(RwLock::new(
+                records.into_iter().map(|record| (record.phone_number.clone(), record)).collect(),
+            )),
+            path: Some(Arc::new(path)),
+            ..Self::default()
+        })
+    }
+
+    pub async fn has_pin(&self, phone_number: &str) -> bool {
+        self.records.read().await.contains_key(phone_number)
+    }
+
+    /// When a locked PIN can be tried again, if it is locked at `now`
+    pub async fn locked_until(&self, phone_number: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.records
+            .read()
+            .await
+            .get(phone_number)
+            .and_then(|record| record.locked_until)
+            .filter(|until| *until > now)
+    }
+
+    /// Set or replace a user's PIN. The PIN is hashed off the async
+    /// runtime, without holding the store's lock.
+    pub async fn set(&self, phone_number: &str, pin: &Pin) -> Result<()> {
+        pin.check_strength()?;
+        let (phone, pin, iterations) = (phone_number.to_string(), pin.clone(), self.iterations);
+        let record = tokio::task::spawn_blocking(move || PinRecord::new(&phone, &pin, iterations))
+            .await
+            .map_err(|e| AppError::Internal(format!("PIN hashing failed: {}", e)))??;
+        self.records.write().await.insert(phone_number.to_string(), record);
+        self.persist().await;
+        Ok(())
+    }
+
+    /// Remove a user's PIN, returning whether they had one
+    pub async fn remove(&self, phone_number: &str) -> bool {
+        let removed = self.records.write().await.remove(phone_number).is_some();
+        if removed {
+            self.persist().await;
+        }
+        removed
+    }
+
+    /// Check a PIN, counting wrong attempts and locking the PIN after
+    /// `MAX_PIN_ATTEMPTS` of them in a row. The PIN is hashed off the async
+    /// runtime against a copy of the record; the lock is only taken again to
+    /// update the counts.
+    pub async fn verify(&self, phone_number: &str, pin: &Pin, now: DateTime<Utc>) -> PinCheck {
+        loop {
+            let Some(record) = self.records.read().await.get(phone_number).cloned() else {
+                return PinCheck::NotSet;
+            };
+            if let Some(until) = record.locked_until.filter(|until| *until > now) {
+                return PinCheck::LockedOut { until };
+            }
+            let hash = record.hash.clone();
+            let candidate = pin.clone();
+            let matched = tokio::task::spawn_blocking(move || record.matches(&candidate))
+                .await
+                .unwrap_or_else(|e| {
+                    error!("PIN check for {} failed: {}", phone_number, e);
+                    false
+                });
+
+            let check = {
+                let mut records = self.records.write().await;
+                let Some(record) = records.get_mut(phone_number) else {
+                    return PinCheck::NotSet;
+                };
+                // The PIN was changed while this one was being checked
+                if record.hash != hash {
+                    continue;
+                }
+                match record.locked_until {
+                    Some(until) if until > now => return PinCheck::LockedOut { until },
+                    Some(_) => {
+                        record.locked_until = None;
+                        record.failed_attempts = 0;
+                    }
+                    None => {}
+                }
+
+                let check = if matched {
+                    record.failed_attempts = 0;
+                    PinCheck::Correct
+                } else {
+                    record.failed_attempts += 1;
+                    if record.failed_attempts >= MAX_PIN_ATTEMPTS {
+                        let until = now + LOCKOUT;
+                        warn!("PIN for {} locked until {} after {} wrong attempts", phone_number, until, record.failed_attempts);
+                        record.locked_until = Some(until);
+                        PinCheck::LockedOut { until }
+                    } else {
+                        PinCheck::Incorrect { attempts_left: MAX_PIN_ATTEMPTS - record.failed_attempts }
+                    }
+                };
+                record.updated_at = now;
+                check
+            };
+            self.persist().await;
+            return check;
+        }
+    }
+
+    /// Save every record. Each save snapshots the records only once it is
+    /// its turn, so the file always ends up holding the latest state.
+    async fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _saving = self.save_lock.lock().await;
+        let records: Vec<PinRecord> = self.records.read().await.values().cloned().collect();
+        if let Err(e) = json_store::write(path, &records).await {
+            error!("Failed to save PINs to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHONE: &str = "+254712345678";
+
+    /// Few iterations keep the tests fast; the hashing is the same
+    fn store() -> PinStore {
+        PinStore {
+            iterations: NonZeroU32::new(10).unwrap(),
+            ..PinStore::default()
+        }
+    }
+
+    fn pin(input: &str) -> Pin {
+        Pin::parse(input).unwrap()
+    }
+
+    #[test]
+    fn test_pin_format_and_strength() {
+        assert!(Pin::looks_like("2580"));
+        assert!(Pin::looks_like(" 258013 "));
+        assert!(!Pin::looks_like("258"));
+        assert!(!Pin::looks_like("2580134"));
+        assert!(!Pin::looks_like("25a0"));
+
+        assert!(pin("2580").check_strength().is_ok());
+        assert!(pin("0000").check_strength().is_err());
+        assert!(pin("123456").check_strength().is_err());
+        assert!(pin("9876").check_strength().is_err());
+        assert_eq!(format!("{:?}", pin("2580")), "Pin(****)");
+    }
+
+    #[tokio::test]
+    async fn test_set_and_verify() {
+        let store = store();
+        assert_eq!(store.verify(PHONE, &pin("2580"), Utc::now()).await, PinCheck::NotSet);
+        assert!(store.set(PHONE, &pin("1111")).await.is_err());
+
+        store.set(PHONE, &pin("2580")).await.unwrap();
+        assert!(store.has_pin(PHONE).await);
+        assert_eq!(store.verify(PHONE, &pin("2580"), Utc::now()).await, PinCheck::Correct);
+        assert_eq!(
+            store.verify(PHONE, &pin("2581"), Utc::now()).await,
+            PinCheck::Incorrect { attempts_left: 2 }
+        );
+
+        // A correct PIN clears earlier mistakes
+        assert_eq!(store.verify(PHONE, &pin("2580"), Utc::now()).await, PinCheck::Correct);
+        assert_eq!(
+            store.verify(PHONE, &pin("2581"), Utc::now()).await,
+            PinCheck::Incorrect { attempts_left: 2 }
+        );
+
+        // The stored record never holds the PIN itself
+        let record = store.records.read().await.get(PHONE).cloned().unwrap();
+        assert!(!record.hash.contains("2580"));
+        assert_eq!(record.hash.len(), HASH_LEN * 2);
+    }
+
+    #[tokio::test]
+    async fn test_lockout_after_repeated_failures() {
+        let store = store();
+        store.set(PHONE, &pin("2580")).await.unwrap();
+        let now = Utc::now();
+
+        store.verify(PHONE, &pin("1111"), now).await;
+        store.verify(PHONE, &pin("1111"), now).await;
+        let locked = store.verify(PHONE, &pin("1111"), now).await;
+        assert_eq!(locked, PinCheck::LockedOut { until: now + LOCKOUT });
+
+        // Even the right PIN is refused until the lockout ends
+        assert!(matches!(store.verify(PHONE, &pin("2580"), now).await, PinCheck::LockedOut { .. }));
+        assert!(store.locked_until(PHONE, now).await.is_some());
+
+        let later = now + LOCKOUT + chrono::Duration::seconds(1);
+        assert_eq!(store.locked_until(PHONE, later).await, None);
+        assert_eq!(store.verify(PHONE, &pin("2580"), later).await, PinCheck::Correct);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_attempts_are_all_counted() {
+        let store = store();
+        store.set(PHONE, &pin("2580")).await.unwrap();
+        let now = Utc::now();
+
+        let wrong = pin("1111");
+        let checks = tokio::join!(
+            store.verify(PHONE, &wrong, now),
+            store.verify(PHONE, &wrong, now),
+            store.verify(PHONE, &wrong, now),
+        );
+        assert!([checks.0, checks.1, checks.2].contains(&PinCheck::LockedOut { until: now + LOCKOUT }));
+        assert!(store.locked_until(PHONE, now).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_persists_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.json");
+        let path = path.to_str().unwrap();
+
+        let store = PinStore {
+            iterations: NonZeroU32::new(10).unwrap(),
+            ..PinStore::load(path).await.unwrap()
+        };
+        store.set(PHONE, &pin("2580")).await.unwrap();
+        store.set("+254700000001", &pin("3691")).await.unwrap();
+        assert!(store.remove("+254700000001").await);
+        assert!(!store.remove("+254700000001").await);
+
+        let reloaded = PinStore::load(path).await.unwrap();
+        assert!(reloaded.has_pin(PHONE).await);
+        assert!(!reloaded.has_pin("+254700000001").await);
+        assert_eq!(reloaded.verify(PHONE, &pin("2580"), Utc::now()).await, PinCheck::Correct);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_store_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.json");
+        std::fs::write(&path, b"{not json").unwrap();
+
+        assert!(PinStore::load(path.to_str().unwrap()).await.is_err());
+        // The file is left alone for the operator to inspect
+        assert_eq!(std::fs::read(&path).unwrap(), b"{not json");
+    }
+}
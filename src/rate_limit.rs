@@ -0,0 +1,307 @@
+//! Per-sender rate limiting
+//!
+//! Every sender, keyed by `wa_id`, gets two token buckets: one for all
+//! incoming messages and a smaller one for commands that move money or start
+//! an M-Pesa prompt. A message that finds its bucket empty is dropped. The
+//! sender is told to slow down once per episode rather than once per
+//! message, and a sender who keeps going past the limit is banned for a while.
+//! Current bans are listed for admins at `/admin/rate-limits/bans`.
+
+use crate::{
+    error::{AppError, Result},
+    types::BotCommand,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+/// Rejected messages within `STRIKE_WINDOW` that earn a ban
+const STRIKES_BEFORE_BAN: u32 = 20;
+
+const STRIKE_WINDOW: chrono::Duration = chrono::Duration::minutes(10);
+
+const BAN_DURATION: chrono::Duration = chrono::Duration::minutes(15);
+
+/// Senders idle this long have full buckets and are forgotten
+const IDLE_TIMEOUT: chrono::Duration = chrono::Duration::minutes(10);
+
+/// Which budget a message is charged to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandClass {
+    Query,
+    Money,
+}
+
+impl CommandClass {
+    /// Commands that move money or trigger a payment prompt use the money
+    /// budget; everything else only the message budget
+    pub fn of(command: &BotCommand) -> Self {
+        match command {
+            BotCommand::Deposit { .. }
+            | BotCommand::Withdraw { .. }
+            | BotCommand::Transfer { .. }
+            | BotCommand::ContributeChama { .. }
+            | BotCommand::BuyShares { .. }
+            | BotCommand::LightningDeposit { .. }
+            | BotCommand::LightningWithdraw { .. } => CommandClass::Money,
+            _ => CommandClass::Query,
+        }
+    }
+}
+
+/// Budgets, in messages per minute
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitPolicy {
+    pub messages_per_minute: u32,
+    pub money_commands_per_minute: u32,
+}
+
+/// A sender banned for repeatedly exceeding their budget
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RateLimitBan {
+    pub wa_id: String,
+    pub banned_until: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    refilled_at: DateTime<Utc>,
+}
+
+impl TokenBucket {
+    fn full(per_minute: u32, now: DateTime<Utc>) -> Self {
+        Self {
+            tokens: f64::from(per_minute),
+            capacity: f64::from(per_minute),
+            refilled_at: now,
+        }
+    }
+
+    fn try_take(&mut self, now: DateTime<Utc>) -> bool {
+        let elapsed = (now - self.refilled_at).num_milliseconds().max(0) as f64 / 60_000.0;
+        self.tokens = (self.tokens + elapsed * self.capacity).min(self.capacity);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Sender {
+    messages: TokenBucket,
+    money: TokenBucket,
+    strikes: u32,
+    first_strike: Option<DateTime<Utc>>,
+    notified: bool,
+    banned_until: Option<DateTime<Utc>>,
+    last_seen: DateTime<Utc>,
+}
+
+impl Sender {
+    fn new(policy: &RateLimitPolicy, now: DateTime<Utc>) -> Self {
+        Self {
+            messages: TokenBucket::full(policy.messages_per_minute, now),
+            money: TokenBucket::full(policy.money_commands_per_minute, now),
+            strikes: 0,
+            first_strike: None,
+            notified: false,
+            banned_until: None,
+            last_seen: now,
+        }
+    }
+
+    fn is_banned(&self, now: DateTime<Utc>) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    /// Forget strikes and the slow-down notice once their window has passed
+    fn expire_strikes(&mut self, now: DateTime<Utc>) {
+        if self.banned_until.is_some_and(|until| until <= now)
+            || self.first_strike.is_some_and(|first| now - first >= STRIKE_WINDOW)
+        {
+            self.banned_until = None;
+            self.strikes = 0;
+            self.first_strike = None;
+            self.notified = false;
+        }
+    }
+}
+
+/// Token buckets and bans for every recent sender
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    senders: Arc<RwLock<HashMap<String, Sender>>>,
+    policy: RateLimitPolicy,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        Self {
+            senders: Arc::new(RwLock::new(HashMap::new())),
+            policy,
+        }
+    }
+
+    /// Charge one message to a sender's budget for `class`, failing with
+    /// `AppError::RateLimit` when the budget is spent or the sender is banned
+    pub async fn check(&self, wa_id: &str, class: CommandClass, now: DateTime<Utc>) -> Result<()> {
+        let mut senders = self.senders.write().await;
+        senders.retain(|_, sender| sender.is_banned(now) || now - sender.last_seen < IDLE_TIMEOUT);
+
+        let sender = senders
+            .entry(wa_id.to_string())
+            .or_insert_with(|| Sender::new(&self.policy, now));
+        sender.last_seen = now;
+        sender.expire_strikes(now);
+        if sender.is_banned(now) {
+            return Err(AppError::RateLimit);
+        }
+
+        let allowed = match class {
+            CommandClass::Query => sender.messages.try_take(now),
+            CommandClass::Money => sender.money.try_take(now),
+        };
+        if allowed {
+            return Ok(());
+        }
+
+        sender.strikes += 1;
+        sender.first_strike.get_or_insert(now);
+        if sender.strikes >= STRIKES_BEFORE_BAN {
+            let until = now + BAN_DURATION;
+            warn!("Banning {} until {} after {} messages over the rate limit", wa_id, until, sender.strikes);
+            sender.banned_until = Some(until);
+        }
+        Err(AppError::RateLimit)
+    }
+
+    /// Whether a limited sender still needs to be told to slow down. Returns
+    /// `true` once per episode of limited messages.
+    pub async fn take_notice(&self, wa_id: &str) -> bool {
+        match self.senders.write().await.get_mut(wa_id) {
+            Some(sender) if !sender.notified => {
+                sender.notified = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Senders currently banned, soonest to be released first
+    pub async fn bans(&self, now: DateTime<Utc>) -> Vec<RateLimitBan> {
+        let mut bans: Vec<RateLimitBan> = self
+            .senders
+            .read()
+            .await
+            .iter()
+            .filter_map(|(wa_id, sender)| {
+                let until = sender.banned_until.filter(|until| *until > now)?;
+                Some(RateLimitBan {
+                    wa_id: wa_id.clone(),
+                    banned_until: until,
+                })
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.banned_until);
+        bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHONE: &str = "+254712345678";
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitPolicy {
+            messages_per_minute: 10,
+            money_commands_per_minute: 2,
+        })
+    }
+
+    #[test]
+    fn test_command_classes() {
+        assert_eq!(CommandClass::of(&BotCommand::parse("withdraw 500 KES")), CommandClass::Money);
+        assert_eq!(CommandClass::of(&BotCommand::parse("deposit 500 KES mpesa")), CommandClass::Money);
+        assert_eq!(CommandClass::of(&BotCommand::parse("balance")), CommandClass::Query);
+        assert_eq!(CommandClass::of(&BotCommand::Confirm), CommandClass::Query);
+    }
+
+    #[tokio::test]
+    async fn test_budgets_are_separate_and_refill() {
+        let limiter = limiter();
+        let now = Utc::now();
+
+        assert!(limiter.check(PHONE, CommandClass::Money, now).await.is_ok());
+        assert!(limiter.check(PHONE, CommandClass::Money, now).await.is_ok());
+        assert!(matches!(
+            limiter.check(PHONE, CommandClass::Money, now).await,
+            Err(AppError::RateLimit)
+        ));
+        // Queries and other senders are unaffected
+        assert!(limiter.check(PHONE, CommandClass::Query, now).await.is_ok());
+        assert!(limiter.check("+254700000001", CommandClass::Money, now).await.is_ok());
+
+        // Two money commands a minute means one token every 30 seconds
+        let later = now + chrono::Duration::seconds(30);
+        assert!(limiter.check(PHONE, CommandClass::Money, later).await.is_ok());
+        assert!(limiter.check(PHONE, CommandClass::Money, later).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_single_notice_per_episode() {
+        let limiter = limiter();
+        let now = Utc::now();
+        for _ in 0..2 {
+            limiter.check(PHONE, CommandClass::Money, now).await.unwrap();
+        }
+
+        assert!(limiter.check(PHONE, CommandClass::Money, now).await.is_err());
+        assert!(limiter.take_notice(PHONE).await);
+        assert!(limiter.check(PHONE, CommandClass::Money, now).await.is_err());
+        assert!(!limiter.take_notice(PHONE).await);
+
+        // A new episode after the strike window gets a new notice
+        let later = now + STRIKE_WINDOW;
+        limiter.check(PHONE, CommandClass::Money, later).await.unwrap();
+        limiter.check(PHONE, CommandClass::Money, later).await.unwrap();
+        assert!(limiter.check(PHONE, CommandClass::Money, later).await.is_err());
+        assert!(limiter.take_notice(PHONE).await);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_offenders_are_banned() {
+        let limiter = limiter();
+        let now = Utc::now();
+        for _ in 0..10 + STRIKES_BEFORE_BAN {
+            let _ = limiter.check(PHONE, CommandClass::Query, now).await;
+        }
+
+        let bans = limiter.bans(now).await;
+        assert_eq!(
+            bans,
+            vec![RateLimitBan {
+                wa_id: PHONE.to_string(),
+                banned_until: now + BAN_DURATION,
+            }]
+        );
+
+        // Refilled buckets do not help while banned
+        let during = now + chrono::Duration::minutes(5);
+        assert!(limiter.check(PHONE, CommandClass::Query, during).await.is_err());
+
+        let after = now + BAN_DURATION;
+        assert!(limiter.check(PHONE, CommandClass::Query, after).await.is_ok());
+        assert!(limiter.bans(after).await.is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+//! WhatsApp customer service windows
+//!
+//! Free-form messages can only be sent to a user within 24 hours of their
+//! last message to us; after that only approved templates are delivered.
+//! Meta and Twilio each enforce the window for messages sent through them,
+//! so windows are kept per channel: a message on Twilio does not open the
+//! Cloud API window for the same number. The tracker is fed the timestamp
+//! of every inbound message, and every outbound send consults it through
+//! `Messenger`: free-form sends to a closed window are blocked before they
+//! reach the provider, and proactive notifications go out another way.
+//! Sends by route are counted and listed for admins at
+//! `/admin/service-windows/metrics`.
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// How long after a user's last message free-form replies are allowed
+pub const WINDOW: chrono::Duration = chrono::Duration::hours(24);
+
+/// Sends by route since the process started
+#[derive(Debug, Default)]
+struct SendCounters {
+    free_form: AtomicU64,
+    template: AtomicU64,
+    blocked: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServiceWindowMetrics {
+    pub open_windows: usize,
+    pub free_form_sends: u64,
+    /// Proactive notifications sent outside the window, as templates or
+    /// through another transport
+    pub template_sends: u64,
+    /// Free-form sends refused because the user's window had closed
+    pub blocked_sends: u64,
+}
+
+/// A channel name and a phone number
+type WindowKey = (&'static str, String);
+
+/// When each user last messaged us, by channel and phone number
+#[derive(Debug, Clone, Default)]
+pub struct ServiceWindows {
+    last_inbound: Arc<RwLock<HashMap<WindowKey, DateTime<Utc>>>>,
+    counters: Arc<SendCounters>,
+}
+
+impl ServiceWindows {
+    /// Note a message from `phone_number` on `channel` sent at `at`, opening
+    /// or extending their window on that channel
+    pub async fn record_inbound(&self, channel: &'static str, phone_number: &str, at: DateTime<Utc>) {
+        let mut last_inbound = self.last_inbound.write().await;
+        last_inbound.retain(|_, last| at - *last < WINDOW);
+        let last = last_inbound.entry((channel, phone_number.to_string())).or_insert(at);
+        *last = (*last).max(at);
+    }
+
+    /// Whether free-form messages can be sent to `phone_number` on `channel`
+    /// at `now`. Users not heard from there since the process started are
+    /// treated as closed.
+    pub async fn is_open(&self, channel: &'static str, phone_number: &str, now: DateTime<Utc>) -> bool {
+        self.last_inbound
+            .read()
+            .await
+            .get(&(channel, phone_number.to_string()))
+            .is_some_and(|last| now - *last < WINDOW)
+    }
+
+    /// Whether a free-form message may be sent to `phone_number` on
+    /// `channel` at `now`, counting it as sent or blocked
+    pub async fn admit_free_form(&self, channel: &'static str, phone_number: &str, now: DateTime<Utc>) -> bool {
+        let open = self.is_open(channel, phone_number, now).await;
+        let counter = if open { &self.counters.free_form } else { &self.counters.blocked };
+        counter.fetch_add(1, Ordering::Relaxed);
+        open
+    }
+
+    /// Whether a proactive notification to `phone_number` on `channel` can
+    /// go out as free-form text at `now`, counting it as a free-form or a
+    /// template send
+    pub async fn admit_proactive(&self, channel: &'static str, phone_number: &str, now: DateTime<Utc>) -> bool {
+        let open = self.is_open(channel, phone_number, now).await;
+        let counter = if open { &self.counters.free_form } else { &self.counters.template };
+        counter.fetch_add(1, Ordering::Relaxed);
+        open
+    }
+
+    pub async fn metrics(&self, now: DateTime<Utc>) -> ServiceWindowMetrics {
+        ServiceWindowMetrics {
+            open_windows: self
+                .last_inbound
+                .read()
+                .await
+                .values()
+                .filter(|last| now - **last < WINDOW)
+                .count(),
+            free_form_sends: self.counters.free_form.load(Ordering::Relaxed),
+            template_sends: self.counters.template.load(Ordering::Relaxed),
+            blocked_sends: self.counters.blocked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// When an inbound message was sent, from its Unix `timestamp`. Timestamps
+/// that cannot be parsed, or lie in the future, count as `now`.
+pub fn inbound_time(timestamp: &str, now: DateTime<Utc>) -> DateTime<Utc> {
+    timestamp
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+        .map_or(now, |at| at.min(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_window_closes_after_24_hours() {
+        let windows = ServiceWindows::default();
+        let now = Utc::now();
+        assert!(!windows.is_open("meta", "+254712345678", now).await);
+
+        windows.record_inbound("meta", "+254712345678", now).await;
+        assert!(windows.is_open("meta", "+254712345678", now + chrono::Duration::hours(23)).await);
+        assert!(!windows.is_open("meta", "+254712345678", now + WINDOW).await);
+        assert!(!windows.is_open("meta", "+254700000001", now).await);
+        // Each provider has its own window
+        assert!(!windows.is_open("twilio", "+254712345678", now).await);
+
+        // An older message does not shorten the window
+        windows.record_inbound("meta", "+254712345678", now - chrono::Duration::hours(2)).await;
+        assert!(windows.is_open("meta", "+254712345678", now + chrono::Duration::hours(23)).await);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_blocked_sends() {
+        let windows = ServiceWindows::default();
+        let now = Utc::now();
+        windows.record_inbound("twilio", "+254712345678", now).await;
+
+        assert!(windows.admit_free_form("twilio", "+254712345678", now).await);
+        assert!(!windows.admit_free_form("twilio", "+254700000001", now).await);
+        assert!(!windows.admit_free_form("meta", "+254712345678", now).await);
+        assert!(!windows.admit_proactive("twilio", "+254712345678", now + WINDOW).await);
+
+        assert_eq!(
+            windows.metrics(now).await,
+            ServiceWindowMetrics {
+                open_windows: 1,
+                free_form_sends: 1,
+                template_sends: 1,
+                blocked_sends: 2,
+            }
+        );
+        assert_eq!(windows.metrics(now + WINDOW).await.open_windows, 0);
+    }
+
+    #[test]
+    fn test_inbound_time() {
+        let now = Utc::now();
+        assert_eq!(inbound_time("1718362915", now), Utc.timestamp_opt(1718362915, 0).unwrap());
+        assert_eq!(inbound_time("not a time", now), now);
+        let future = (now + chrono::Duration::hours(1)).timestamp().to_string();
+        assert_eq!(inbound_time(&future, now), now);
+    }
+}
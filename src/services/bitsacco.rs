@@ -1,15 +1,23 @@
 use crate::{
     config::AppConfig,
     error::{AppError, Result},
+    lightning::{LightningDestination, LightningWithdrawal},
+    money::{Currency, Money},
     types::{
         BitSaccoBtcBalance, BitSaccoChama, BitSaccoChamaContribution, BitSaccoChamaShare, 
-        BitSaccoSavings, BitSaccoTransaction, BitSaccoUser, MpesaStkPushRequest, MpesaStkPushResponse,
+        BitSaccoSavings, BitSaccoTransaction, BitSaccoUser, MpesaStkPushRequest, MpesaStkPushResponse, MpesaStkQueryResponse,
         BitSaccoMembershipShare, BitSaccoSharePurchase, LightningPaymentRequest, LightningPaymentResponse,
-        WithdrawalRequest, WithdrawalResponse,
+        LightningPaymentStatus, LightningWithdrawalRequest, TransactionStatusUpdate, WithdrawalQuote, WithdrawalRequest,
+        WithdrawalResponse,
     },
 };
 use reqwest::Client;
+use ring::{digest, hmac};
 use serde_json::json;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
 use tracing::{error, info, warn};
 
 #[derive(Debug, Clone)]
@@ -17,6 +25,21 @@ pub struct BitSaccoService {
     client: Client,
     base_url: String,
     api_token: String,
+    callback_secret: String,
+    /// WhatsApp message or M-Pesa payment this copy of the service acts
+    /// for, if any
+    scope: Option<Arc<str>>,
+    /// POST requests made for it so far
+    post_sequence: Arc<AtomicU32>,
+}
+
+/// Idempotency key for the `sequence`th POST made while handling a message
+/// or payment: the hex SHA-256 of `<scope>:<sequence>:<endpoint>`. Handling
+/// it again makes the same calls in the same order, so each call gets the
+/// same key as before and BitSacco can drop the duplicate.
+fn idempotency_key(scope: &str, sequence: u32, endpoint: &str) -> String {
+    let input = format!("{}:{}:{}", scope, sequence, endpoint);
+    hex::encode(digest::digest(&digest::SHA256, input.as_bytes()).as_ref())
 }
 
 impl BitSaccoService {
@@ -33,9 +56,34 @@ impl BitSaccoService {
             client,
             base_url: config.bitsacco_api_base_url.clone(),
             api_token: config.bitsacco_api_token.clone(),
+            callback_secret: config.bitsacco_callback_secret.clone(),
+            scope: None,
+            post_sequence: Arc::new(AtomicU32::new(0)),
         })
     }
 
+    /// Copy of the service for handling one WhatsApp message. Its POST
+    /// requests carry an `Idempotency-Key` derived from the message ID.
+    pub fn for_message(&self, message_id: &str) -> Self {
+        self.scoped(message_id)
+    }
+
+    /// Copy of the service for applying the result of an M-Pesa STK Push,
+    /// whether it came from Daraja's callback or a status query. Its POST
+    /// requests carry an `Idempotency-Key` derived from the
+    /// `CheckoutRequestID`, so a result applied by both is applied once.
+    pub fn for_stk_push(&self, checkout_request_id: &str) -> Self {
+        self.scoped(&format!("mpesa:{}", checkout_request_id))
+    }
+
+    fn scoped(&self, scope: &str) -> Self {
+        Self {
+            scope: Some(Arc::from(scope)),
+            post_sequence: Arc::new(AtomicU32::new(0)),
+            ..self.clone()
+        }
+    }
+
     async fn make_request<T>(&self, endpoint: &str) -> Result<T>
     where
         T: serde::de::DeserializeOwned,
@@ -84,11 +132,17 @@ impl BitSaccoService {
 
         info!("Making POST request to BitSacco API: {}", endpoint);
 
-        let response = self
+        let mut request = self
             .client
             .post(&url)
             .header("Authorization", format!("Bearer {}", self.api_token))
-            .header("Content-Type", "application/json")
+            .header("Content-Type", "application/json");
+        if let Some(scope) = &self.scope {
+            let sequence = self.post_sequence.fetch_add(1, Ordering::SeqCst);
+            request = request.header("Idempotency-Key", idempotency_key(scope, sequence, endpoint));
+        }
+
+        let response = request
             .json(payload)
             .send()
             .await
@@ -175,7 +229,6 @@ impl BitSaccoService {
         Ok(balance)
     }
 
-    #[allow(dead_code)]
     pub async fn get_user_transactions(&self, user_id: &str) -> Result<Vec<BitSaccoTransaction>> {
         let endpoint = format!("users/{}/transactions", user_id);
         self.make_request(&endpoint).await
@@ -184,11 +237,10 @@ impl BitSaccoService {
     pub async fn create_deposit(
         &self,
         user_id: &str,
-        amount: f64,
-        currency: &str,
+        amount: Money,
     ) -> Result<BitSaccoTransaction> {
         // For KES deposits, use M-Pesa STK Push
-        if currency.to_uppercase() == "KES" {
+        if amount.currency() == Currency::Kes {
             return self.create_mpesa_deposit(user_id, amount).await;
         }
 
@@ -196,8 +248,8 @@ impl BitSaccoService {
         let payload = json!({
             "user_id": user_id,
             "type": "deposit",
-            "amount": amount,
-            "currency": currency,
+            "amount": amount.to_f64(),
+            "currency": amount.currency(),
             "status": "pending"
         });
 
@@ -208,8 +260,15 @@ impl BitSaccoService {
     pub async fn create_mpesa_deposit(
         &self,
         user_id: &str,
-        amount: f64,
+        amount: Money,
     ) -> Result<BitSaccoTransaction> {
+        if amount.currency() != Currency::Kes {
+            return Err(AppError::Validation(format!(
+                "M-Pesa deposits must be in KES, got {}",
+                amount.currency()
+            )));
+        }
+
         // First, get user details to get phone number
         let user = self.get_user_by_id(user_id).await?;
         
@@ -217,9 +276,8 @@ impl BitSaccoService {
         let stk_request = MpesaStkPushRequest {
             phone_number: user.phone_number.clone(),
             amount,
-            currency: "KES".to_string(),
             account_reference: format!("BITSACCO_{}", user_id),
-            transaction_desc: format!("BitSacco deposit of {} KES", amount),
+            transaction_desc: format!("BitSacco deposit of {}", amount),
         };
 
         // Send STK Push request to BitSacco API
@@ -228,8 +286,8 @@ impl BitSaccoService {
         // Create transaction record
         let payload = json!({
             "user_id": user_id,
-            "amount": amount,
-            "currency": "KES",
+            "amount": amount.to_f64(),
+            "currency": amount.currency(),
             "type": "deposit",
             "status": "pending",
             "payment_method": "mpesa",
@@ -245,8 +303,14 @@ impl BitSaccoService {
         self.make_post_request("transactions", &payload).await
     }
 
+    /// Ask M-Pesa where an STK Push stands, for when its callback never came
+    pub async fn query_mpesa_stk_status(&self, checkout_request_id: &str) -> Result<MpesaStkQueryResponse> {
+        let payload = json!({ "checkout_request_id": checkout_request_id });
+        self.make_post_request("mpesa/stk-query", &payload).await
+    }
+
     /// Get user by ID (helper method for M-Pesa integration)
-    async fn get_user_by_id(&self, user_id: &str) -> Result<BitSaccoUser> {
+    pub async fn get_user_by_id(&self, user_id: &str) -> Result<BitSaccoUser> {
         let endpoint = format!("users/{}", user_id);
         self.make_request(&endpoint).await
     }
@@ -254,14 +318,13 @@ impl BitSaccoService {
     pub async fn create_withdrawal(
         &self,
         user_id: &str,
-        amount: f64,
-        currency: &str,
+        amount: Money,
     ) -> Result<BitSaccoTransaction> {
         let payload = json!({
             "user_id": user_id,
             "type": "withdrawal",
-            "amount": amount,
-            "currency": currency,
+            "amount": amount.to_f64(),
+            "currency": amount.currency(),
             "status": "pending"
         });
 
@@ -271,15 +334,14 @@ impl BitSaccoService {
     pub async fn create_transfer(
         &self,
         user_id: &str,
-        amount: f64,
-        currency: &str,
+        amount: Money,
         recipient_phone: &str,
     ) -> Result<BitSaccoTransaction> {
         let payload = json!({
             "user_id": user_id,
             "type": "transfer",
-            "amount": amount,
-            "currency": currency,
+            "amount": amount.to_f64(),
+            "currency": amount.currency(),
             "recipient_phone": recipient_phone,
             "status": "pending"
         });
@@ -287,10 +349,10 @@ impl BitSaccoService {
         self.make_post_request("transactions", &payload).await
     }
 
-    pub async fn get_total_savings(&self, user_id: &str, cache: &crate::cache::AppCache) -> Result<f64> {
+    /// Total savings per currency; savings in different currencies are never added together
+    pub async fn get_total_savings(&self, user_id: &str, cache: &crate::cache::AppCache) -> Result<Vec<Money>> {
         let savings = self.get_user_savings(user_id, cache).await?;
-        let total: f64 = savings.iter().map(|s| s.amount).sum();
-        Ok(total)
+        Ok(Money::totals_by_currency(savings.iter().map(|s| s.amount))?)
     }
 
     pub async fn health_check(&self) -> Result<()> {
@@ -333,15 +395,16 @@ impl BitSaccoService {
         &self,
         user_id: &str,
         chama_id: &str,
-        amount: f64,
-        currency: &str,
+        amount: Money,
     ) -> Result<BitSaccoChamaContribution> {
+        // Assuming 1 share = 10 units of the contribution currency
+        let share_price = Money::from_major(10, amount.currency())?;
         let payload = json!({
             "user_id": user_id,
             "chama_id": chama_id,
-            "amount": amount,
-            "currency": currency,
-            "shares_purchased": (amount / 10.0) as i32, // Assuming 1 share = $10
+            "amount": amount.to_f64(),
+            "currency": amount.currency(),
+            "shares_purchased": amount.minor() / share_price.minor(),
             "status": "pending"
         });
 
@@ -404,13 +467,11 @@ impl BitSaccoService {
     pub async fn create_lightning_payment(
         &self,
         user_id: &str,
-        amount: f64,
-        currency: &str,
+        amount: Money,
         description: &str,
     ) -> Result<LightningPaymentResponse> {
         let payload = LightningPaymentRequest {
             amount,
-            currency: currency.to_string(),
             description: description.to_string(),
             user_id: user_id.to_string(),
         };
@@ -418,19 +479,97 @@ impl BitSaccoService {
         self.make_post_request("lightning/create-payment", &payload).await
     }
 
+    /// Pay a checked Lightning withdrawal to an invoice, Lightning address or LNURL
+    pub async fn create_lightning_withdrawal(
+        &self,
+        user_id: &str,
+        withdrawal: &LightningWithdrawal,
+    ) -> Result<WithdrawalResponse> {
+        let payment_hash = match &withdrawal.destination {
+            LightningDestination::Invoice(invoice) => Some(invoice.payment_hash.clone()),
+            _ => None,
+        };
+        let payload = LightningWithdrawalRequest {
+            user_id: user_id.to_string(),
+            amount: withdrawal.amount,
+            destination_type: withdrawal.destination.kind().to_string(),
+            destination: withdrawal.destination.encoded().to_string(),
+            payment_hash,
+        };
+
+        self.make_post_request("lightning/withdraw", &payload).await
+    }
+
+    /// Current status of a Lightning payment by its payment hash
+    pub async fn get_lightning_payment_status(&self, payment_hash: &str) -> Result<LightningPaymentStatus> {
+        let endpoint = format!("lightning/payments/{}", payment_hash);
+        self.make_request(&endpoint).await
+    }
+
+    /// Look up a transaction by the payment provider's reference, e.g. the
+    /// M-Pesa `CheckoutRequestID` recorded by `create_mpesa_deposit`
+    pub async fn get_transaction_by_reference(&self, reference: &str) -> Result<BitSaccoTransaction> {
+        let endpoint = format!("transactions/reference/{}", reference);
+        self.make_request(&endpoint).await
+    }
+
+    pub async fn update_transaction_status(
+        &self,
+        transaction_id: &str,
+        update: &TransactionStatusUpdate,
+    ) -> Result<BitSaccoTransaction> {
+        let endpoint = format!("transactions/{}/status", transaction_id);
+        self.make_post_request(&endpoint, update).await
+    }
+
+    /// Verify the `X-BitSacco-Signature` header of a callback against its raw
+    /// body. Callbacks are refused outright when no secret is configured.
+    pub fn verify_callback_signature(&self, payload: &[u8], signature: &str) -> Result<()> {
+        if self.callback_secret.is_empty() {
+            warn!("BitSacco callback received but no callback secret is configured");
+            return Err(AppError::Unauthorized);
+        }
+        let provided_signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+        let Ok(provided_bytes) = hex::decode(provided_signature) else {
+            warn!("BitSacco callback signature is not valid hex");
+            return Err(AppError::Unauthorized);
+        };
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.callback_secret.as_bytes());
+        hmac::verify(&key, payload, &provided_bytes).map_err(|_| {
+            warn!("BitSacco callback signature verification failed");
+            AppError::Unauthorized
+        })
+    }
+
+    /// Check the `token` query parameter of an M-Pesa callback URL. Daraja
+    /// does not sign its callbacks, so the callback URL registered with it
+    /// carries the shared callback secret instead.
+    pub fn verify_callback_token(&self, token: &str) -> Result<()> {
+        if self.callback_secret.is_empty() {
+            warn!("M-Pesa callback received but no callback secret is configured");
+            return Err(AppError::Unauthorized);
+        }
+        // Compare MACs rather than the strings so the check runs in constant time
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.callback_secret.as_bytes());
+        let expected = hmac::sign(&key, self.callback_secret.as_bytes());
+        hmac::verify(&key, token.as_bytes(), expected.as_ref()).map_err(|_| {
+            warn!("M-Pesa callback token verification failed");
+            AppError::Unauthorized
+        })
+    }
+
     // Withdrawal Methods
     pub async fn create_withdrawal_enhanced(
         &self,
         user_id: &str,
-        amount: f64,
-        currency: &str,
+        amount: Money,
         payment_method: &str,
         phone_number: Option<&str>,
     ) -> Result<WithdrawalResponse> {
         let payload = WithdrawalRequest {
             user_id: user_id.to_string(),
             amount,
-            currency: currency.to_string(),
             payment_method: payment_method.to_string(),
             phone_number: phone_number.map(|s| s.to_string()),
             description: None,
@@ -439,14 +578,26 @@ impl BitSaccoService {
         self.make_post_request("withdrawals", &payload).await
     }
 
+    /// Fee BitSacco would charge to pay out `amount` by `payment_method`,
+    /// for the confirmation prompt
+    pub async fn quote_withdrawal(&self, user_id: &str, amount: Money, payment_method: &str) -> Result<WithdrawalQuote> {
+        let endpoint = format!(
+            "withdrawals/quote?user_id={}&amount={}&currency={}&payment_method={}",
+            user_id,
+            amount.amount_str(),
+            amount.currency().code(),
+            payment_method
+        );
+        self.make_request(&endpoint).await
+    }
+
     // Enhanced Deposit with Lightning Support
     pub async fn create_lightning_deposit(
         &self,
         user_id: &str,
-        amount: f64,
-        currency: &str,
+        amount: Money,
     ) -> Result<LightningPaymentResponse> {
-        let description = format!("BitSacco deposit of {} {}", amount, currency);
-        self.create_lightning_payment(user_id, amount, currency, &description).await
+        let description = format!("BitSacco deposit of {}", amount);
+        self.create_lightning_payment(user_id, amount, &description).await
     }
 }
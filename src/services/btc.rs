@@ -1,77 +1,76 @@
 use crate::{
     config::AppConfig,
     error::{AppError, Result},
-    types::BtcPrice,
+    money::Currency,
+    services::price_sources::{self, aggregate, PriceSource, SourceQuote, MAX_DEVIATION},
+    types::{BtcPrice, PriceHistory, PriceRange},
 };
+use chrono::{DateTime, Utc};
 use reqwest::Client;
-// BTC service for fetching Bitcoin prices
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
+use tokio::{sync::RwLock, task::JoinSet};
+// BTC service for fetching Bitcoin prices from several sources
 use tracing::{error, info, warn};
 
+/// Consecutive failures before a source is skipped for `SOURCE_COOLDOWN`
+const FAILURE_THRESHOLD: u32 = 3;
+const SOURCE_COOLDOWN: chrono::Duration = chrono::Duration::minutes(2);
+
+/// Recent track record of one price source
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SourceHealth {
+    pub consecutive_failures: u32,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub cooling_down_until: Option<DateTime<Utc>>,
+}
+
+impl SourceHealth {
+    pub fn is_available(&self, now: DateTime<Utc>) -> bool {
+        self.cooling_down_until.is_none_or(|until| now >= until)
+    }
+
+    fn record_success(&mut self, now: DateTime<Utc>) {
+        self.consecutive_failures = 0;
+        self.last_success = Some(now);
+        self.cooling_down_until = None;
+    }
+
+    fn record_failure(&mut self, now: DateTime<Utc>, reason: String) {
+        self.consecutive_failures += 1;
+        self.last_error = Some(reason);
+        if self.consecutive_failures >= FAILURE_THRESHOLD {
+            self.cooling_down_until = Some(now + SOURCE_COOLDOWN);
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BtcService {
-    client: Client,
-    base_url: String,
-    api_key: Option<String>,
+    sources: Vec<Arc<dyn PriceSource>>,
+    health: Arc<RwLock<HashMap<&'static str, SourceHealth>>>,
 }
 
 impl BtcService {
     pub fn new(config: &AppConfig) -> Result<Self> {
         let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(30))
+            .timeout(std::time::Duration::from_secs(10))
             .pool_max_idle_per_host(10)
             .pool_idle_timeout(std::time::Duration::from_secs(90))
-            .connect_timeout(std::time::Duration::from_secs(10))
+            .connect_timeout(std::time::Duration::from_secs(5))
             .build()
             .map_err(|e| AppError::Internal(format!("Failed to create HTTP client: {}", e)))?;
 
-        Ok(Self {
-            client,
-            base_url: config.btc_api_base_url.clone(),
-            api_key: config.btc_api_key.clone(),
-        })
+        Ok(Self::with_sources(price_sources::from_config(config, &client)?))
     }
 
-    async fn make_request<T>(&self, endpoint: &str) -> Result<T>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        let mut url = format!("{}/{}", self.base_url, endpoint);
-
-        // Add API key if available
-        if let Some(api_key) = &self.api_key {
-            url = format!("{}?api_key={}", url, api_key);
-        }
-
-        info!("Making request to BTC API: {}", endpoint);
-
-        let response = self
-            .client
-            .get(&url)
-            .header("Content-Type", "application/json")
-            .send()
-            .await
-            .map_err(|e| AppError::BtcService(format!("Request failed: {}", e)))?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-
-            error!("BTC API error: {} - {}", status, error_text);
-            return Err(AppError::BtcService(format!(
-                "API error {}: {}",
-                status, error_text
-            )));
+    /// Service over an explicit set of sources, e.g. custom adapters
+    pub fn with_sources(sources: Vec<Arc<dyn PriceSource>>) -> Self {
+        Self {
+            sources,
+            health: Arc::new(RwLock::new(HashMap::new())),
         }
-
-        let data: T = response
-            .json()
-            .await
-            .map_err(|e| AppError::BtcService(format!("Failed to parse response: {}", e)))?;
-
-        Ok(data)
     }
 
     pub async fn get_btc_price(&self, currency: &str, cache: &crate::cache::AppCache) -> Result<BtcPrice> {
@@ -81,45 +80,180 @@ impl BtcService {
             return Ok(cached_price);
         }
 
-        // If not in cache, fetch from API
-        let price = self.get_btc_price_from_coinbase(currency).await?;
-        
+        // If not in cache, query the price sources
+        let price = self.fetch_aggregate_price(currency.parse()?).await?;
+
         // Store in cache
         cache.set_btc_price(currency, price.clone()).await;
         tracing::debug!("BTC price cached for currency: {}", currency);
-        
+
         Ok(price)
     }
 
-    async fn get_btc_price_from_coinbase(&self, currency: &str) -> Result<BtcPrice> {
-        // CoinGecko API endpoint for BTC price (no API key required)
-        let endpoint = format!("simple/price?ids=bitcoin&vs_currencies={}&include_24hr_change=true", 
-                              currency.to_lowercase());
+    /// Query every available source for `currency` and take the median
+    async fn fetch_aggregate_price(&self, currency: Currency) -> Result<BtcPrice> {
+        let candidates: Vec<_> = self
+            .sources
+            .iter()
+            .filter(|source| source.supports(currency))
+            .cloned()
+            .collect();
+        if candidates.is_empty() {
+            return Err(AppError::BtcService(format!(
+                "No BTC price source supports {}",
+                currency
+            )));
+        }
+
+        let now = Utc::now();
+        let mut selected: Vec<_> = {
+            let health = self.health.read().await;
+            candidates
+                .iter()
+                .filter(|source| health.get(source.name()).is_none_or(|h| h.is_available(now)))
+                .cloned()
+                .collect()
+        };
+        if selected.is_empty() {
+            warn!("All BTC/{} price sources are cooling down; trying them anyway", currency);
+            selected = candidates;
+        }
+
+        let mut requests = JoinSet::new();
+        for source in selected {
+            requests.spawn(async move {
+                let result = source.fetch(currency).await;
+                (source.name(), result)
+            });
+        }
 
-        let response: serde_json::Value = self.make_request(&endpoint).await?;
+        let mut quotes: Vec<SourceQuote> = Vec::new();
+        let mut failures: Vec<(&'static str, String)> = Vec::new();
+        while let Some(joined) = requests.join_next().await {
+            match joined {
+                Ok((_, Ok(quote))) => quotes.push(quote),
+                Ok((name, Err(e))) => {
+                    warn!("BTC price source {} failed: {}", name, e);
+                    failures.push((name, e.to_string()));
+                }
+                Err(e) => error!("BTC price request task failed: {}", e),
+            }
+        }
 
-        if let Some(bitcoin_data) = response.get("bitcoin") {
-            let price = bitcoin_data
-                .get(&currency.to_lowercase())
-                .and_then(|v| v.as_f64())
-                .ok_or_else(|| AppError::BtcService("Price not found in response".to_string()))?;
+        let result = aggregate(&quotes, MAX_DEVIATION);
 
-            let change_24h = bitcoin_data
-                .get(&format!("{}_24h_change", currency.to_lowercase()))
-                .and_then(|v| v.as_f64())
-                .unwrap_or(0.0);
+        let now = Utc::now();
+        let mut health = self.health.write().await;
+        for (name, reason) in failures {
+            health.entry(name).or_default().record_failure(now, reason);
+        }
+        match &result {
+            Ok(aggregated) => {
+                for name in &aggregated.sources {
+                    health.entry(name).or_default().record_success(now);
+                }
+                for name in &aggregated.rejected {
+                    warn!("BTC price from {} rejected as an outlier", name);
+                    health
+                        .entry(name)
+                        .or_default()
+                        .record_failure(now, "Quote rejected as an outlier".to_string());
+                }
+            }
+            Err(e) => {
+                for quote in &quotes {
+                    health.entry(quote.source).or_default().record_failure(now, e.to_string());
+                }
+            }
+        }
 
-            Ok(BtcPrice {
-                currency: currency.to_uppercase(),
-                price,
-                change_24h,
-                last_updated: chrono::Utc::now().to_rfc3339(),
-            })
-        } else {
-            Err(AppError::BtcService(
-                "Bitcoin data not found in response".to_string(),
-            ))
+        let aggregated = result?;
+        info!(
+            "BTC/{} price {:.2} from {}",
+            currency,
+            aggregated.price,
+            aggregated.sources.join(", ")
+        );
+        Ok(BtcPrice {
+            currency: currency.code().to_string(),
+            price: aggregated.price,
+            change_24h: aggregated.change_24h,
+            last_updated: now.to_rfc3339(),
+            sources: aggregated.sources.iter().map(|s| s.to_string()).collect(),
+        })
+    }
+
+    /// Price history over `range`, from the first healthy source that has it
+    pub async fn get_price_history(
+        &self,
+        currency: &str,
+        range: PriceRange,
+        cache: &crate::cache::AppCache,
+    ) -> Result<PriceHistory> {
+        let currency: Currency = currency.parse()?;
+        let key = format!("{}:{}", currency.code().to_lowercase(), range.label());
+        if let Some(cached) = cache.get_price_history(&key).await {
+            tracing::debug!("BTC price history found in cache: {}", key);
+            return Ok(cached);
+        }
+
+        let now = Utc::now();
+        let mut candidates: Vec<_> = self.sources.iter().collect();
+        {
+            // Healthy sources first, keeping configuration order otherwise
+            let health = self.health.read().await;
+            candidates.sort_by_key(|source| !health.get(source.name()).is_none_or(|h| h.is_available(now)));
+        }
+
+        let mut last_error = None;
+        for source in candidates {
+            let Some(request) = source.fetch_history(currency, range) else {
+                continue;
+            };
+            match request.await {
+                Ok(points) if points.len() >= 2 => {
+                    self.health.write().await.entry(source.name()).or_default().record_success(Utc::now());
+                    let history = PriceHistory {
+                        currency: currency.code().to_string(),
+                        range: range.label().to_string(),
+                        points,
+                        source: source.name().to_string(),
+                    };
+                    cache.set_price_history(&key, history.clone()).await;
+                    return Ok(history);
+                }
+                Ok(_) => {
+                    warn!("BTC price source {} returned too little history", source.name());
+                    last_error = Some(AppError::BtcService(format!("{} returned too little history", source.name())));
+                }
+                Err(e) => {
+                    warn!("BTC price history from {} failed: {}", source.name(), e);
+                    self.health
+                        .write()
+                        .await
+                        .entry(source.name())
+                        .or_default()
+                        .record_failure(Utc::now(), e.to_string());
+                    last_error = Some(e);
+                }
+            }
         }
+
+        Err(last_error.unwrap_or_else(|| {
+            AppError::BtcService(format!("No BTC price source has {} history", currency))
+        }))
+    }
+
+    /// Health of every configured source, in configuration order
+    pub async fn source_health(&self) -> Vec<(&'static str, SourceHealth)> {
+        let health = self.health.read().await;
+        self.sources
+            .iter()
+            .map(|source| {
+                let name = source.name();
+                (name, health.get(name).cloned().unwrap_or_default())
+            })
+            .collect()
     }
 
     pub async fn get_btc_price_usd(&self, cache: &crate::cache::AppCache) -> Result<BtcPrice> {
@@ -145,3 +279,95 @@ impl BtcService {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::{AppCache, CacheConfig},
+        services::price_sources::PriceFuture,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug)]
+    struct FixedSource {
+        name: &'static str,
+        price: Option<f64>,
+        calls: AtomicUsize,
+    }
+
+    impl FixedSource {
+        fn new(name: &'static str, price: Option<f64>) -> Arc<Self> {
+            Arc::new(Self { name, price, calls: AtomicUsize::new(0) })
+        }
+    }
+
+    impl PriceSource for FixedSource {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn supports(&self, currency: Currency) -> bool {
+            currency == Currency::Usd
+        }
+
+        fn fetch(&self, _currency: Currency) -> PriceFuture<'_> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                self.price
+                    .map(|price| SourceQuote { source: self.name, price, change_24h: None })
+                    .ok_or_else(|| AppError::BtcService("down".to_string()))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_price_reports_contributing_sources() {
+        let service = BtcService::with_sources(vec![
+            FixedSource::new("a", Some(50_000.0)),
+            FixedSource::new("b", Some(50_200.0)),
+            FixedSource::new("c", Some(90_000.0)),
+        ]);
+        let cache = AppCache::new(CacheConfig::default());
+
+        let price = service.get_btc_price_usd(&cache).await.unwrap();
+        assert_eq!(price.currency, "USD");
+        assert_eq!(price.price, 50_100.0);
+        assert_eq!(price.sources, vec!["a", "b"]);
+
+        let health = service.source_health().await;
+        assert_eq!(health[2].1.consecutive_failures, 1);
+        assert!(health[0].1.last_success.is_some());
+
+        assert!(service.get_btc_price("kes", &cache).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failing_source_is_skipped_after_threshold() {
+        let down = FixedSource::new("down", None);
+        let up = FixedSource::new("up", Some(50_000.0));
+        let service = BtcService::with_sources(vec![down.clone(), up.clone()]);
+
+        for _ in 0..FAILURE_THRESHOLD {
+            let price = service.fetch_aggregate_price(Currency::Usd).await.unwrap();
+            assert_eq!(price.sources, vec!["up"]);
+        }
+        assert_eq!(down.calls.load(Ordering::SeqCst), FAILURE_THRESHOLD as usize);
+
+        // Cooling down: the failing source is no longer queried
+        service.fetch_aggregate_price(Currency::Usd).await.unwrap();
+        assert_eq!(down.calls.load(Ordering::SeqCst), FAILURE_THRESHOLD as usize);
+        assert_eq!(up.calls.load(Ordering::SeqCst), FAILURE_THRESHOLD as usize + 1);
+
+        let health = service.source_health().await;
+        assert!(!health[0].1.is_available(Utc::now()));
+    }
+
+    #[tokio::test]
+    async fn test_all_sources_down() {
+        let service = BtcService::with_sources(vec![FixedSource::new("down", None)]);
+        for _ in 0..=FAILURE_THRESHOLD {
+            assert!(service.fetch_aggregate_price(Currency::Usd).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Fiat ↔ sats conversion at the cached BTC price
+//!
+//! Rates come from `BtcService::get_btc_price` and are held as a fixed-point
+//! fiat price of one bitcoin, so conversions are integer maths on minor units.
+//! A rate older than the allowed age is refused rather than used silently.
+
+use crate::{
+    cache::AppCache,
+    error::{AppError, Result},
+    money::{Currency, Money, Sats, SATS_PER_BTC},
+    services::btc::BtcService,
+    types::BtcPrice,
+};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use tracing::warn;
+
+/// Oldest price a conversion may be based on
+pub const DEFAULT_MAX_RATE_AGE: chrono::Duration = chrono::Duration::minutes(5);
+
+/// The price of one bitcoin in a fiat currency at a point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExchangeRate {
+    price_per_btc: Money,
+    as_of: DateTime<Utc>,
+}
+
+impl ExchangeRate {
+    pub fn new(price_per_btc: Money, as_of: DateTime<Utc>) -> Result<Self> {
+        if price_per_btc.currency() == Currency::Btc {
+            return Err(AppError::BtcService(
+                "Exchange rate must be quoted in a fiat currency".to_string(),
+            ));
+        }
+        if !price_per_btc.is_positive() {
+            return Err(AppError::BtcService(format!(
+                "Invalid BTC price: {}",
+                price_per_btc
+            )));
+        }
+        Ok(Self { price_per_btc, as_of })
+    }
+
+    /// Build a rate from a price quote returned by the BTC service
+    pub fn from_price(price: &BtcPrice) -> Result<Self> {
+        let currency: Currency = price.currency.parse()?;
+        let price_per_btc = Money::from_f64(price.price, currency)?;
+        let as_of = DateTime::parse_from_rfc3339(&price.last_updated)
+            .map_err(|e| AppError::BtcService(format!("Invalid price timestamp: {}", e)))?
+            .with_timezone(&Utc);
+        Self::new(price_per_btc, as_of)
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.price_per_btc.currency()
+    }
+
+    pub fn price_per_btc(&self) -> Money {
+        self.price_per_btc
+    }
+
+    pub fn as_of(&self) -> DateTime<Utc> {
+        self.as_of
+    }
+
+    pub fn is_fresh(&self, now: DateTime<Utc>, max_age: chrono::Duration) -> bool {
+        now.signed_duration_since(self.as_of) <= max_age
+    }
+
+    /// Convert an amount to sats, rounding to the nearest satoshi.
+    ///
+    /// BTC amounts pass straight through; other currencies must match the rate.
+    pub fn to_sats(&self, amount: Money) -> Result<Sats> {
+        if amount.currency() == Currency::Btc {
+            return Ok(Sats::try_from(amount)?);
+        }
+        self.check_currency(amount.currency())?;
+
+        let sats = div_round(
+            i128::from(amount.minor()) * i128::from(SATS_PER_BTC),
+            i128::from(self.price_per_btc.minor()),
+        );
+        u64::try_from(sats)
+            .map(Sats)
+            .map_err(|_| AppError::Validation(format!("Cannot convert {} to sats", amount)))
+    }
+
+    /// Convert sats to the rate's currency, rounding to the nearest minor unit
+    pub fn to_fiat(&self, sats: Sats) -> Result<Money> {
+        let minor = div_round(
+            i128::from(sats.0) * i128::from(self.price_per_btc.minor()),
+            i128::from(SATS_PER_BTC),
+        );
+        i64::try_from(minor)
+            .map(|minor| Money::from_minor(minor, self.currency()))
+            .map_err(|_| AppError::Validation(format!("Cannot convert {} to {}", sats, self.currency())))
+    }
+
+    fn check_currency(&self, currency: Currency) -> Result<()> {
+        if currency == self.currency() {
+            Ok(())
+        } else {
+            Err(AppError::Internal(format!(
+                "No {} rate available to convert {}",
+                currency,
+                self.currency()
+            )))
+        }
+    }
+}
+
+impl fmt::Display for ExchangeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "1 BTC = {} (as of {})",
+            self.price_per_btc,
+            self.as_of.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+    }
+}
+
+/// Round-half-away-from-zero integer division
+fn div_round(numerator: i128, denominator: i128) -> i128 {
+    let half = denominator / 2;
+    if numerator >= 0 {
+        (numerator + half) / denominator
+    } else {
+        (numerator - half) / denominator
+    }
+}
+
+/// Look up the rate for `currency` in a list fetched by `ConversionService::rates`
+pub fn rate_for(rates: &[ExchangeRate], currency: Currency) -> Option<&ExchangeRate> {
+    rates.iter().find(|rate| rate.currency() == currency)
+}
+
+/// Render the rates a reply was based on, one per line
+pub fn format_rates(rates: &[ExchangeRate]) -> String {
+    rates
+        .iter()
+        .map(|rate| format!("Rate: {}", rate))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug, Clone)]
+pub struct ConversionService {
+    btc_service: BtcService,
+    max_age: chrono::Duration,
+}
+
+impl ConversionService {
+    pub fn new(btc_service: BtcService) -> Self {
+        Self {
+            btc_service,
+            max_age: DEFAULT_MAX_RATE_AGE,
+        }
+    }
+
+    pub fn with_max_age(mut self, max_age: chrono::Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Current BTC price in `currency`, failing if none is fresh enough to use
+    pub async fn rate(&self, currency: Currency, cache: &AppCache) -> Result<ExchangeRate> {
+        let price = self
+            .btc_service
+            .get_btc_price(&currency.code().to_lowercase(), cache)
+            .await
+            .map_err(|e| {
+                warn!("No BTC/{} price available: {}", currency, e);
+                AppError::ServiceUnavailable(format!(
+                    "The BTC/{} price is unavailable right now, so amounts can't be converted to sats. Please try again shortly.",
+                    currency
+                ))
+            })?;
+
+        let rate = ExchangeRate::from_price(&price)?;
+        if !rate.is_fresh(Utc::now(), self.max_age) {
+            warn!("BTC/{} price from {} is stale", currency, rate.as_of());
+            return Err(AppError::ServiceUnavailable(format!(
+                "The latest BTC/{} price is from {} and too old to use. Please try again shortly.",
+                currency,
+                rate.as_of().format("%Y-%m-%d %H:%M:%S UTC")
+            )));
+        }
+        Ok(rate)
+    }
+
+    /// Rates for each distinct fiat currency in `currencies`, in order
+    pub async fn rates<I>(&self, currencies: I, cache: &AppCache) -> Result<Vec<ExchangeRate>>
+    where
+        I: IntoIterator<Item = Currency>,
+    {
+        let mut rates: Vec<ExchangeRate> = Vec::new();
+        for currency in currencies {
+            if currency == Currency::Btc || rate_for(&rates, currency).is_some() {
+                continue;
+            }
+            rates.push(self.rate(currency, cache).await?);
+        }
+        Ok(rates)
+    }
+
+    pub async fn fiat_to_sats(&self, amount: Money, cache: &AppCache) -> Result<Sats> {
+        if amount.currency() == Currency::Btc {
+            return Ok(Sats::try_from(amount)?);
+        }
+        self.rate(amount.currency(), cache).await?.to_sats(amount)
+    }
+
+    pub async fn sats_to_fiat(&self, sats: Sats, currency: Currency, cache: &AppCache) -> Result<Money> {
+        if currency == Currency::Btc {
+            return Ok(sats.to_money()?);
+        }
+        self.rate(currency, cache).await?.to_fiat(sats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kes_rate() -> ExchangeRate {
+        // 1 BTC = 8,000,000.00 KES
+        ExchangeRate::new(Money::from_major(8_000_000, Currency::Kes).unwrap(), Utc::now()).unwrap()
+    }
+
+    #[test]
+    fn test_fiat_to_sats() {
+        let rate = kes_rate();
+        assert_eq!(rate.to_sats(Money::from_major(80, Currency::Kes).unwrap()).unwrap(), Sats(1_000));
+        assert_eq!(rate.to_sats(Money::from_major(8_000_000, Currency::Kes).unwrap()).unwrap(), Sats(SATS_PER_BTC));
+        // 0.01 KES is 0.125 sats
+        assert_eq!(rate.to_sats(Money::from_minor(1, Currency::Kes)).unwrap(), Sats(0));
+        assert_eq!(rate.to_sats(Money::from_minor(4, Currency::Kes)).unwrap(), Sats(1));
+        assert_eq!(rate.to_sats(Money::from_minor(5_000, Currency::Btc)).unwrap(), Sats(5_000));
+        assert!(rate.to_sats(Money::from_major(1, Currency::Usd).unwrap()).is_err());
+        assert!(rate.to_sats(Money::from_major(-1, Currency::Kes).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_sats_to_fiat() {
+        let rate = kes_rate();
+        assert_eq!(rate.to_fiat(Sats(1_000)).unwrap().to_string(), "80.00 KES");
+        assert_eq!(rate.to_fiat(Sats(1)).unwrap().to_string(), "0.08 KES");
+        assert_eq!(rate.to_fiat(Sats(SATS_PER_BTC)).unwrap(), rate.price_per_btc());
+    }
+
+    #[test]
+    fn test_from_price() {
+        let price = BtcPrice {
+            currency: "USD".to_string(),
+            price: 61_234.56,
+            change_24h: 0.0,
+            last_updated: "2026-10-16T12:00:00+00:00".to_string(),
+            sources: vec!["coingecko".to_string()],
+        };
+        let rate = ExchangeRate::from_price(&price).unwrap();
+        assert_eq!(rate.price_per_btc().minor(), 6_123_456);
+        assert_eq!(rate.to_string(), "1 BTC = 61234.56 USD (as of 2026-10-16 12:00:00 UTC)");
+
+        let zero = BtcPrice { price: 0.0, ..price.clone() };
+        assert!(ExchangeRate::from_price(&zero).is_err());
+        let bad_time = BtcPrice { last_updated: "yesterday".to_string(), ..price };
+        assert!(ExchangeRate::from_price(&bad_time).is_err());
+    }
+
+    #[test]
+    fn test_freshness() {
+        let rate = kes_rate();
+        let now = rate.as_of();
+        assert!(rate.is_fresh(now, DEFAULT_MAX_RATE_AGE));
+        assert!(rate.is_fresh(now + chrono::Duration::minutes(5), DEFAULT_MAX_RATE_AGE));
+        assert!(!rate.is_fresh(now + chrono::Duration::minutes(6), DEFAULT_MAX_RATE_AGE));
+    }
+}
@@ -1,5 +1,7 @@
 pub mod bitsacco;
 pub mod btc;
+pub mod conversion;
+pub mod price_sources;
 pub mod twilio;
 pub mod voice;
 pub mod whatsapp;
@@ -0,0 +1,524 @@
+//! BTC price sources
+//!
+//! Each exchange or aggregator is a `PriceSource` that returns a single spot
+//! quote. `BtcService` queries the enabled sources together and combines their
+//! quotes with `aggregate`, so one source being down or wrong does not decide
+//! the price users see.
+
+use crate::{
+    config::AppConfig,
+    error::{AppError, Result},
+    money::Currency,
+    types::{PricePoint, PriceRange},
+};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+use std::{future::Future, pin::Pin, sync::Arc};
+use tracing::{debug, error};
+
+/// Largest relative distance from the median before a quote is discarded
+pub const MAX_DEVIATION: f64 = 0.05;
+
+pub type PriceFuture<'a> = Pin<Box<dyn Future<Output = Result<SourceQuote>> + Send + 'a>>;
+pub type HistoryFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<PricePoint>>> + Send + 'a>>;
+
+/// A spot price reported by one source
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceQuote {
+    pub source: &'static str,
+    pub price: f64,
+    /// Percentage change over 24 hours, if the source reports it
+    pub change_24h: Option<f64>,
+}
+
+/// A provider of BTC spot prices
+pub trait PriceSource: Send + Sync + std::fmt::Debug {
+    /// Stable lowercase name, as used in `BTC_PRICE_SOURCES`
+    fn name(&self) -> &'static str;
+
+    /// Whether the source quotes BTC in `currency`
+    fn supports(&self, currency: Currency) -> bool;
+
+    fn fetch(&self, currency: Currency) -> PriceFuture<'_>;
+
+    /// Prices over `range`, oldest first, or `None` if the source has no history
+    fn fetch_history(&self, _currency: Currency, _range: PriceRange) -> Option<HistoryFuture<'_>> {
+        None
+    }
+}
+
+/// Build the sources named in `config.btc_price_sources`, in that order
+pub fn from_config(config: &AppConfig, client: &Client) -> Result<Vec<Arc<dyn PriceSource>>> {
+    config
+        .btc_price_sources
+        .iter()
+        .map(|name| -> Result<Arc<dyn PriceSource>> {
+            match name.trim().to_lowercase().as_str() {
+                "coingecko" => Ok(Arc::new(CoinGecko {
+                    client: client.clone(),
+                    base_url: config.btc_api_base_url.clone(),
+                    api_key: config.btc_api_key.clone(),
+                })),
+                "coinbase" => Ok(Arc::new(Coinbase {
+                    client: client.clone(),
+                    base_url: config.coinbase_api_base_url.clone(),
+                })),
+                "kraken" => Ok(Arc::new(Kraken {
+                    client: client.clone(),
+                    base_url: config.kraken_api_base_url.clone(),
+                })),
+                "binance" => Ok(Arc::new(Binance {
+                    client: client.clone(),
+                    base_url: config.binance_api_base_url.clone(),
+                })),
+                other => Err(AppError::Internal(format!("Unknown BTC price source: {}", other))),
+            }
+        })
+        .collect()
+}
+
+async fn get_json<T>(source: &str, request: reqwest::RequestBuilder) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let response = request
+        .send()
+        .await
+        .map_err(|e| AppError::BtcService(format!("{} request failed: {}", source, e)))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+
+        error!("{} price API error: {} - {}", source, status, error_text);
+        return Err(AppError::BtcService(format!(
+            "{} API error {}: {}",
+            source, status, error_text
+        )));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|e| AppError::BtcService(format!("Failed to parse {} response: {}", source, e)))
+}
+
+fn point(source: &str, timestamp: Option<DateTime<Utc>>, price: Option<f64>) -> Result<PricePoint> {
+    match (timestamp, price) {
+        (Some(timestamp), Some(price)) if price.is_finite() && price > 0.0 => Ok(PricePoint { timestamp, price }),
+        _ => Err(AppError::BtcService(format!("{} returned an invalid history entry", source))),
+    }
+}
+
+/// Close price of a candle given as a JSON array, e.g. Binance klines and Kraken OHLC
+fn candle_close(source: &str, candle: &[serde_json::Value], timestamp: Option<DateTime<Utc>>) -> Result<PricePoint> {
+    let close = candle
+        .get(4)
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.parse::<f64>().ok());
+    point(source, timestamp, close)
+}
+
+fn parse_price(source: &str, value: &str) -> Result<f64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|price| price.is_finite() && *price > 0.0)
+        .ok_or_else(|| AppError::BtcService(format!("{} returned an invalid price: {}", source, value)))
+}
+
+/// CoinGecko `simple/price`
+#[derive(Debug)]
+pub struct CoinGecko {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl PriceSource for CoinGecko {
+    fn name(&self) -> &'static str {
+        "coingecko"
+    }
+
+    fn supports(&self, currency: Currency) -> bool {
+        currency != Currency::Btc
+    }
+
+    fn fetch(&self, currency: Currency) -> PriceFuture<'_> {
+        Box::pin(async move {
+            let code = currency.code().to_lowercase();
+            let url = format!("{}/simple/price", self.base_url);
+            let mut request = self.client.get(&url).query(&[
+                ("ids", "bitcoin"),
+                ("vs_currencies", code.as_str()),
+                ("include_24hr_change", "true"),
+            ]);
+            if let Some(api_key) = &self.api_key {
+                request = request.header("x-cg-demo-api-key", api_key);
+            }
+
+            let response: serde_json::Value = get_json(self.name(), request).await?;
+            let bitcoin = response
+                .get("bitcoin")
+                .ok_or_else(|| AppError::BtcService("Bitcoin data not found in response".to_string()))?;
+            let price = bitcoin
+                .get(&code)
+                .and_then(|v| v.as_f64())
+                .filter(|price| *price > 0.0)
+                .ok_or_else(|| AppError::BtcService("Price not found in response".to_string()))?;
+            let change_24h = bitcoin
+                .get(format!("{}_24h_change", code))
+                .and_then(|v| v.as_f64());
+
+            Ok(SourceQuote { source: self.name(), price, change_24h })
+        })
+    }
+
+    fn fetch_history(&self, currency: Currency, range: PriceRange) -> Option<HistoryFuture<'_>> {
+        if !self.supports(currency) {
+            return None;
+        }
+        Some(Box::pin(async move {
+            let url = format!("{}/coins/bitcoin/market_chart", self.base_url);
+            let days = range.days().to_string();
+            let code = currency.code().to_lowercase();
+            let mut request = self
+                .client
+                .get(&url)
+                .query(&[("vs_currency", code.as_str()), ("days", days.as_str())]);
+            if let Some(api_key) = &self.api_key {
+                request = request.header("x-cg-demo-api-key", api_key);
+            }
+
+            // {"prices": [[unix_ms, price], ...]}
+            #[derive(Deserialize)]
+            struct MarketChart {
+                prices: Vec<(i64, f64)>,
+            }
+            let chart: MarketChart = get_json(self.name(), request).await?;
+            chart
+                .prices
+                .into_iter()
+                .map(|(ms, price)| point(self.name(), DateTime::from_timestamp_millis(ms), Some(price)))
+                .collect()
+        }))
+    }
+}
+
+/// Coinbase `prices/BTC-{currency}/spot`
+#[derive(Debug)]
+pub struct Coinbase {
+    client: Client,
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseSpot {
+    data: CoinbaseSpotData,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseSpotData {
+    amount: String,
+}
+
+impl PriceSource for Coinbase {
+    fn name(&self) -> &'static str {
+        "coinbase"
+    }
+
+    fn supports(&self, currency: Currency) -> bool {
+        currency != Currency::Btc
+    }
+
+    fn fetch(&self, currency: Currency) -> PriceFuture<'_> {
+        Box::pin(async move {
+            let url = format!("{}/prices/BTC-{}/spot", self.base_url, currency.code());
+            let spot: CoinbaseSpot = get_json(self.name(), self.client.get(&url)).await?;
+            let price = parse_price(self.name(), &spot.data.amount)?;
+
+            // The spot endpoint has no 24h change
+            Ok(SourceQuote { source: self.name(), price, change_24h: None })
+        })
+    }
+}
+
+/// Kraken public `Ticker`; only quotes USD among our currencies
+#[derive(Debug)]
+pub struct Kraken {
+    client: Client,
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+struct KrakenTicker {
+    error: Vec<String>,
+    #[serde(default)]
+    result: std::collections::HashMap<String, KrakenPair>,
+}
+
+#[derive(Deserialize)]
+struct KrakenPair {
+    /// Last trade as `[price, lot volume]`
+    c: Vec<String>,
+}
+
+impl PriceSource for Kraken {
+    fn name(&self) -> &'static str {
+        "kraken"
+    }
+
+    fn supports(&self, currency: Currency) -> bool {
+        currency == Currency::Usd
+    }
+
+    fn fetch(&self, currency: Currency) -> PriceFuture<'_> {
+        Box::pin(async move {
+            let url = format!("{}/0/public/Ticker", self.base_url);
+            let pair = format!("XBT{}", currency.code());
+            let ticker: KrakenTicker =
+                get_json(self.name(), self.client.get(&url).query(&[("pair", pair.as_str())])).await?;
+            if !ticker.error.is_empty() {
+                return Err(AppError::BtcService(format!("kraken error: {}", ticker.error.join(", "))));
+            }
+
+            // Kraken keys the result by its own pair name (e.g. XXBTZUSD)
+            let last = ticker
+                .result
+                .values()
+                .next()
+                .and_then(|pair| pair.c.first())
+                .ok_or_else(|| AppError::BtcService("Price not found in response".to_string()))?;
+            let price = parse_price(self.name(), last)?;
+
+            Ok(SourceQuote { source: self.name(), price, change_24h: None })
+        })
+    }
+
+    fn fetch_history(&self, currency: Currency, range: PriceRange) -> Option<HistoryFuture<'_>> {
+        if !self.supports(currency) {
+            return None;
+        }
+        Some(Box::pin(async move {
+            // Candle size in minutes, chosen to give a few dozen points per range
+            let interval = match range {
+                PriceRange::Day => 60,
+                PriceRange::Week => 240,
+                PriceRange::Month => 1440,
+                PriceRange::Year => 10080,
+            };
+            let since = Utc::now() - chrono::Duration::days(i64::from(range.days()));
+            let url = format!("{}/0/public/OHLC", self.base_url);
+            let pair = format!("XBT{}", currency.code());
+            let query = [
+                ("pair", pair),
+                ("interval", interval.to_string()),
+                ("since", since.timestamp().to_string()),
+            ];
+
+            // {"error": [], "result": {"XXBTZUSD": [[time, open, high, low, close, ...]], "last": n}}
+            let response: serde_json::Value = get_json(self.name(), self.client.get(&url).query(&query)).await?;
+            if let Some(errors) = response["error"].as_array().filter(|e| !e.is_empty()) {
+                return Err(AppError::BtcService(format!("kraken error: {:?}", errors)));
+            }
+            let candles = response["result"]
+                .as_object()
+                .and_then(|result| result.iter().find(|(key, _)| *key != "last"))
+                .and_then(|(_, candles)| candles.as_array())
+                .ok_or_else(|| AppError::BtcService("History not found in response".to_string()))?;
+            candles
+                .iter()
+                .map(|candle| {
+                    let candle = candle.as_array().map(Vec::as_slice).unwrap_or_default();
+                    let timestamp = candle
+                        .first()
+                        .and_then(|t| t.as_i64())
+                        .and_then(|t| DateTime::from_timestamp(t, 0));
+                    candle_close(self.name(), candle, timestamp)
+                })
+                .collect()
+        }))
+    }
+}
+
+/// Binance `ticker/24hr` against USDT, which stands in for USD
+#[derive(Debug)]
+pub struct Binance {
+    client: Client,
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinanceTicker {
+    last_price: String,
+    price_change_percent: String,
+}
+
+impl PriceSource for Binance {
+    fn name(&self) -> &'static str {
+        "binance"
+    }
+
+    fn supports(&self, currency: Currency) -> bool {
+        currency == Currency::Usd
+    }
+
+    fn fetch(&self, _currency: Currency) -> PriceFuture<'_> {
+        Box::pin(async move {
+            let url = format!("{}/api/v3/ticker/24hr", self.base_url);
+            let ticker: BinanceTicker =
+                get_json(self.name(), self.client.get(&url).query(&[("symbol", "BTCUSDT")])).await?;
+            let price = parse_price(self.name(), &ticker.last_price)?;
+
+            Ok(SourceQuote {
+                source: self.name(),
+                price,
+                change_24h: ticker.price_change_percent.parse().ok(),
+            })
+        })
+    }
+
+    fn fetch_history(&self, currency: Currency, range: PriceRange) -> Option<HistoryFuture<'_>> {
+        if !self.supports(currency) {
+            return None;
+        }
+        Some(Box::pin(async move {
+            let (interval, limit) = match range {
+                PriceRange::Day => ("1h", "24"),
+                PriceRange::Week => ("4h", "42"),
+                PriceRange::Month => ("1d", "30"),
+                PriceRange::Year => ("1w", "52"),
+            };
+            let url = format!("{}/api/v3/klines", self.base_url);
+            let query = [("symbol", "BTCUSDT"), ("interval", interval), ("limit", limit)];
+
+            // [[open_time_ms, "open", "high", "low", "close", ...], ...]
+            let klines: Vec<Vec<serde_json::Value>> =
+                get_json(self.name(), self.client.get(&url).query(&query)).await?;
+            klines
+                .iter()
+                .map(|kline| {
+                    let timestamp = kline
+                        .first()
+                        .and_then(|t| t.as_i64())
+                        .and_then(DateTime::from_timestamp_millis);
+                    candle_close(self.name(), kline, timestamp)
+                })
+                .collect()
+        }))
+    }
+}
+
+/// Quotes combined into one price
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    pub price: f64,
+    pub change_24h: f64,
+    /// Sources whose quotes were used
+    pub sources: Vec<&'static str>,
+    /// Sources whose quotes were discarded as outliers
+    pub rejected: Vec<&'static str>,
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+/// Median of the quotes after discarding any further than `max_deviation`
+/// (relative) from the median of all of them.
+///
+/// With only two quotes that disagree there is no majority to trust, so both
+/// are rejected.
+pub fn aggregate(quotes: &[SourceQuote], max_deviation: f64) -> Result<Aggregate> {
+    let mut prices: Vec<f64> = quotes.iter().map(|q| q.price).collect();
+    let centre = median(&mut prices)
+        .ok_or_else(|| AppError::BtcService("No BTC price source returned a quote".to_string()))?;
+
+    let (accepted, rejected): (Vec<&SourceQuote>, Vec<&SourceQuote>) = quotes
+        .iter()
+        .partition(|q| ((q.price - centre) / centre).abs() <= max_deviation);
+    if accepted.is_empty() {
+        return Err(AppError::BtcService(format!(
+            "BTC price sources disagree: {}",
+            quotes
+                .iter()
+                .map(|q| format!("{} {:.2}", q.source, q.price))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )));
+    }
+
+    let mut prices: Vec<f64> = accepted.iter().map(|q| q.price).collect();
+    let mut changes: Vec<f64> = accepted.iter().filter_map(|q| q.change_24h).collect();
+    let aggregate = Aggregate {
+        price: median(&mut prices).unwrap_or(centre),
+        change_24h: median(&mut changes).unwrap_or(0.0),
+        sources: accepted.iter().map(|q| q.source).collect(),
+        rejected: rejected.iter().map(|q| q.source).collect(),
+    };
+    debug!("Aggregated BTC price {:?}", aggregate);
+    Ok(aggregate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(source: &'static str, price: f64, change_24h: Option<f64>) -> SourceQuote {
+        SourceQuote { source, price, change_24h }
+    }
+
+    #[test]
+    fn test_aggregate_median() {
+        let quotes = [
+            quote("coingecko", 50_100.0, Some(2.0)),
+            quote("coinbase", 50_000.0, None),
+            quote("kraken", 49_900.0, None),
+            quote("binance", 50_050.0, Some(1.0)),
+        ];
+        let result = aggregate(&quotes, MAX_DEVIATION).unwrap();
+        assert_eq!(result.price, 50_025.0);
+        assert_eq!(result.change_24h, 1.5);
+        assert_eq!(result.sources.len(), 4);
+        assert!(result.rejected.is_empty());
+    }
+
+    #[test]
+    fn test_aggregate_rejects_outliers() {
+        let quotes = [
+            quote("coingecko", 50_000.0, None),
+            quote("coinbase", 50_200.0, None),
+            quote("kraken", 5_000.0, None),
+        ];
+        let result = aggregate(&quotes, MAX_DEVIATION).unwrap();
+        assert_eq!(result.price, 50_100.0);
+        assert_eq!(result.sources, vec!["coingecko", "coinbase"]);
+        assert_eq!(result.rejected, vec!["kraken"]);
+        assert_eq!(result.change_24h, 0.0);
+    }
+
+    #[test]
+    fn test_aggregate_single_and_conflicting() {
+        let single = aggregate(&[quote("coinbase", 50_000.0, None)], MAX_DEVIATION).unwrap();
+        assert_eq!(single.price, 50_000.0);
+        assert_eq!(single.sources, vec!["coinbase"]);
+
+        assert!(aggregate(&[], MAX_DEVIATION).is_err());
+        let split = [quote("coinbase", 50_000.0, None), quote("kraken", 70_000.0, None)];
+        assert!(aggregate(&split, MAX_DEVIATION).is_err());
+    }
+}
@@ -5,17 +5,30 @@
 //! - Media file handling
 //! - Webhook verification
 //! - Error handling and retry logic
+//!
+//! Incoming messages arrive at `/webhook/twilio` as form-encoded PascalCase
+//! fields, signed with `X-Twilio-Signature`: the base64 HMAC-SHA1, keyed with
+//! the auth token, of the webhook URL followed by every parameter name and
+//! value sorted by name.
 
 use crate::{
+    channel::{Inbound, InboundContent, InboundMessage, InboundRequest, MessagingChannel, OutboundMedia, SendFuture, SentMessage},
     config::AppConfig,
     error::{AppError, Result},
-    types::WhatsAppSendResponse,
+    interactive::InteractiveMessage,
+    templates::{ProactiveMessage, TemplateRegistry, TwilioContent},
+    validation::normalize_twilio_address,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use reqwest::Client;
+use ring::hmac;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tracing::{error, info, warn};
 
+/// Name of the Twilio channel, as used in `MESSAGING_PROVIDER`
+pub const CHANNEL: &str = "twilio";
+
 /// Twilio WhatsApp message request
 #[derive(Debug, Serialize)]
 pub struct TwilioMessageRequest {
@@ -38,20 +51,28 @@ pub struct TwilioMessageResponse {
     pub error_message: Option<String>,
 }
 
-/// Twilio webhook payload
+/// Twilio incoming message webhook, as posted to `/webhook/twilio`
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
 pub struct TwilioWebhookPayload {
     pub message_sid: String,
     pub account_sid: String,
     pub messaging_service_sid: Option<String>,
+    /// Sender, as `whatsapp:+254712345678`
     pub from: String,
     pub to: String,
+    #[serde(default)]
     pub body: String,
     pub num_media: Option<String>,
+    #[serde(rename = "MediaContentType0")]
     pub media_content_type: Option<String>,
+    #[serde(rename = "MediaUrl0")]
     pub media_url: Option<String>,
-    pub status: String,
-    pub api_version: String,
+    /// Payload of a tapped quick reply button
+    pub button_payload: Option<String>,
+    #[serde(rename = "SmsStatus")]
+    pub status: Option<String>,
+    pub api_version: Option<String>,
 }
 
 /// Twilio WhatsApp service
@@ -59,6 +80,8 @@ pub struct TwilioWebhookPayload {
 pub struct TwilioService {
     client: Client,
     config: AppConfig,
+    /// Templates, with the Content SIDs of those approved through Twilio
+    templates: TemplateRegistry,
 }
 
 impl TwilioService {
@@ -69,14 +92,24 @@ impl TwilioService {
             .build()
             .expect("Failed to create HTTP client");
 
-        Self { client, config }
+        Self {
+            client,
+            config,
+            templates: TemplateRegistry::default(),
+        }
+    }
+
+    /// Use `templates` instead of the built-in templates
+    pub fn with_templates(mut self, templates: TemplateRegistry) -> Self {
+        self.templates = templates;
+        self
     }
 
     /// Send a text message via Twilio WhatsApp
-    pub async fn send_message(&self, to: &str, message: &str) -> Result<WhatsAppSendResponse> {
+    pub async fn send_message(&self, to: &str, message: &str) -> Result<TwilioMessageResponse> {
         let url = format!(
-            "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
-            self.config.twilio_account_sid
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            self.config.twilio_api_base_url, self.config.twilio_account_sid
         );
 
         let mut form_data = HashMap::new();
@@ -102,17 +135,111 @@ impl TwilioService {
         let twilio_response: TwilioMessageResponse = response
             .json()
             .await
-            .map_err(|e| AppError::Http(e))?;
+            .map_err(AppError::Http)?;
 
         info!("Message sent via Twilio: {}", twilio_response.sid);
 
-        Ok(WhatsAppSendResponse {
-            messaging_product: "whatsapp".to_string(),
-            contacts: vec![],
-            messages: vec![crate::types::WhatsAppMessageResponse {
-                id: twilio_response.sid,
-            }],
-        })
+        Ok(twilio_response)
+    }
+
+    /// Send the text version of an interactive message; Twilio WhatsApp
+    /// sessions do not support reply buttons or lists
+    pub async fn send_interactive(&self, to: &str, message: &InteractiveMessage) -> Result<TwilioMessageResponse> {
+        self.send_message(to, &message.fallback_text).await
+    }
+
+    /// Send a template approved through Twilio Content, which WhatsApp
+    /// delivers outside the service window
+    pub async fn send_content_message(&self, to: &str, content: &TwilioContent) -> Result<TwilioMessageResponse> {
+        let url = format!(
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            self.config.twilio_api_base_url, self.config.twilio_account_sid
+        );
+
+        let mut form_data = HashMap::new();
+        form_data.insert("To", format!("whatsapp:{}", to));
+        form_data.insert("From", format!("whatsapp:{}", self.config.twilio_whatsapp_number));
+        form_data.insert("ContentSid", content.sid.clone());
+        form_data.insert("ContentVariables", content.variables.clone());
+
+        let response = self
+            .client
+            .post(&url)
+            .basic_auth(&self.config.twilio_account_sid, Some(&self.config.twilio_auth_token))
+            .form(&form_data)
+            .send()
+            .await
+            .map_err(|e| AppError::Network(format!("Failed to send Twilio template: {}", e)))?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            error!("Twilio template API error: {}", error_text);
+            return Err(AppError::WhatsApp(format!("Twilio template API error: {}", error_text)));
+        }
+
+        let twilio_response: TwilioMessageResponse = response
+            .json()
+            .await
+            .map_err(AppError::Http)?;
+
+        info!("Template sent via Twilio: {}", twilio_response.sid);
+
+        Ok(twilio_response)
+    }
+
+    /// Send a notification the user did not ask for. Twilio WhatsApp keeps
+    /// Meta's 24-hour window, so once it has closed the notification goes
+    /// out as its Twilio Content template, or by SMS when the template has
+    /// no Content SID.
+    pub async fn send_proactive(&self, to: &str, message: &ProactiveMessage, window_open: bool) -> Result<TwilioMessageResponse> {
+        if window_open {
+            return self.send_message(to, &message.text).await;
+        }
+        if let Some(content) = self.templates.render_twilio(&message.template)? {
+            return self.send_content_message(to, &content).await;
+        }
+        if !self.can_send_sms() {
+            return Err(AppError::ServiceWindowClosed(to.to_string()));
+        }
+        info!("Template {} has no Twilio Content SID; sending it to {} by SMS", message.template.name, to);
+        self.send_sms(to, &message.text).await
+    }
+
+    /// Send a plain SMS from the configured SMS number
+    pub async fn send_sms(&self, to: &str, message: &str) -> Result<TwilioMessageResponse> {
+        let url = format!(
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            self.config.twilio_api_base_url, self.config.twilio_account_sid
+        );
+
+        let mut form_data = HashMap::new();
+        form_data.insert("To", to.to_string());
+        form_data.insert("From", self.config.twilio_sms_number.clone());
+        form_data.insert("Body", message.to_string());
+
+        let response = self
+            .client
+            .post(&url)
+            .basic_auth(&self.config.twilio_account_sid, Some(&self.config.twilio_auth_token))
+            .form(&form_data)
+            .send()
+            .await
+            .map_err(|e| AppError::Network(format!("Failed to send Twilio SMS: {}", e)))?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            error!("Twilio SMS API error: {}", error_text);
+            return Err(AppError::WhatsApp(format!("Twilio SMS API error: {}", error_text)));
+        }
+
+        let twilio_response: TwilioMessageResponse = response
+            .json()
+            .await
+            .map_err(AppError::Http)?;
+
+        info!("SMS sent via Twilio: {}", twilio_response.sid);
+
+        Ok(twilio_response)
     }
 
     /// Send a media message via Twilio WhatsApp
@@ -121,10 +248,10 @@ impl TwilioService {
         to: &str,
         message: &str,
         media_url: &str,
-    ) -> Result<WhatsAppSendResponse> {
+    ) -> Result<TwilioMessageResponse> {
         let url = format!(
-            "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
-            self.config.twilio_account_sid
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            self.config.twilio_api_base_url, self.config.twilio_account_sid
         );
 
         let mut form_data = HashMap::new();
@@ -151,50 +278,54 @@ impl TwilioService {
         let twilio_response: TwilioMessageResponse = response
             .json()
             .await
-            .map_err(|e| AppError::Http(e))?;
+            .map_err(AppError::Http)?;
 
         info!("Media message sent via Twilio: {}", twilio_response.sid);
 
-        Ok(WhatsAppSendResponse {
-            messaging_product: "whatsapp".to_string(),
-            contacts: vec![],
-            messages: vec![crate::types::WhatsAppMessageResponse {
-                id: twilio_response.sid,
-            }],
-        })
+        Ok(twilio_response)
     }
 
-    /// Verify Twilio webhook signature
-    pub fn verify_webhook_signature(
-        &self,
-        signature: &str,
-        url: &str,
-        payload: &str,
-    ) -> Result<bool> {
-        // Twilio webhook signature verification
-        // For production, implement proper signature verification using Twilio's auth token
-        // This is a simplified version - in production, use Twilio's official signature verification
-        
-        if signature.is_empty() {
-            warn!("Empty Twilio webhook signature");
-            return Ok(false);
+    /// Verify the `X-Twilio-Signature` header of a webhook posted to `url`
+    /// against its raw form-encoded body. Webhooks are refused outright when
+    /// no webhook URL or auth token is configured.
+    pub fn verify_webhook_signature(&self, url: &str, payload: &[u8], signature: &str) -> Result<()> {
+        if url.is_empty() || self.config.twilio_auth_token.is_empty() {
+            warn!("Twilio webhook received but no webhook URL or auth token is configured");
+            return Err(AppError::Unauthorized);
+        }
+        let Ok(provided_bytes) = BASE64.decode(signature.trim()) else {
+            warn!("Twilio webhook signature is not valid base64");
+            return Err(AppError::Unauthorized);
+        };
+
+        let mut params: Vec<(String, String)> = serde_urlencoded::from_bytes(payload)
+            .map_err(|e| AppError::Validation(format!("Invalid webhook payload: {}", e)))?;
+        params.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut signed = url.to_string();
+        for (name, value) in &params {
+            signed.push_str(name);
+            signed.push_str(value);
         }
 
-        // Basic validation - in production, implement proper HMAC verification
-        Ok(true)
+        // Twilio still signs webhooks with SHA-1
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, self.config.twilio_auth_token.as_bytes());
+        hmac::verify(&key, signed.as_bytes(), &provided_bytes).map_err(|_| {
+            warn!("Twilio webhook signature verification failed");
+            AppError::Unauthorized
+        })
     }
 
-    /// Parse Twilio webhook payload
-    pub fn parse_webhook_payload(&self, payload: &str) -> Result<TwilioWebhookPayload> {
-        serde_json::from_str(payload)
-            .map_err(|e| AppError::Json(e))
+    /// Parse a form-encoded Twilio webhook body
+    pub fn parse_webhook_payload(&self, payload: &[u8]) -> Result<TwilioWebhookPayload> {
+        serde_urlencoded::from_bytes(payload)
+            .map_err(|e| AppError::Validation(format!("Invalid webhook payload: {}", e)))
     }
 
     /// Get message status from Twilio
     pub async fn get_message_status(&self, message_sid: &str) -> Result<String> {
         let url = format!(
-            "https://api.twilio.com/2010-04-01/Accounts/{}/Messages/{}.json",
-            self.config.twilio_account_sid, message_sid
+            "{}/2010-04-01/Accounts/{}/Messages/{}.json",
+            self.config.twilio_api_base_url, self.config.twilio_account_sid, message_sid
         );
 
         let response = self
@@ -214,7 +345,7 @@ impl TwilioService {
         let twilio_response: TwilioMessageResponse = response
             .json()
             .await
-            .map_err(|e| AppError::Http(e))?;
+            .map_err(AppError::Http)?;
 
         Ok(twilio_response.status)
     }
@@ -222,8 +353,8 @@ impl TwilioService {
     /// Health check for Twilio service
     pub async fn health_check(&self) -> Result<()> {
         let url = format!(
-            "https://api.twilio.com/2010-04-01/Accounts/{}.json",
-            self.config.twilio_account_sid
+            "{}/2010-04-01/Accounts/{}.json",
+            self.config.twilio_api_base_url, self.config.twilio_account_sid
         );
 
         let response = self
@@ -248,6 +379,86 @@ impl TwilioService {
             && !self.config.twilio_auth_token.is_empty()
             && !self.config.twilio_whatsapp_number.is_empty()
     }
+
+    /// Check if Twilio can send SMS
+    pub fn can_send_sms(&self) -> bool {
+        !self.config.twilio_account_sid.is_empty()
+            && !self.config.twilio_auth_token.is_empty()
+            && !self.config.twilio_sms_number.is_empty()
+    }
+}
+
+fn sent_message(response: TwilioMessageResponse) -> SentMessage {
+    SentMessage {
+        channel: CHANNEL,
+        id: response.sid,
+    }
+}
+
+impl MessagingChannel for TwilioService {
+    fn name(&self) -> &'static str {
+        CHANNEL
+    }
+
+    fn send_text<'a>(&'a self, to: &'a str, text: &'a str) -> SendFuture<'a> {
+        Box::pin(async move { self.send_message(to, text).await.map(sent_message) })
+    }
+
+    /// Twilio fetches media from a public URL, and the bot does not host
+    /// any, so media goes out as its caption
+    fn send_media<'a>(&'a self, to: &'a str, media: OutboundMedia) -> SendFuture<'a> {
+        Box::pin(async move {
+            let Some(caption) = media.caption else {
+                return Err(AppError::Validation("Twilio can only send media hosted at a URL".to_string()));
+            };
+            warn!("Sending {} to {} as its caption", media.file_name, to);
+            self.send_message(to, &caption).await.map(sent_message)
+        })
+    }
+
+    fn send_interactive<'a>(&'a self, to: &'a str, message: &'a InteractiveMessage) -> SendFuture<'a> {
+        Box::pin(async move { TwilioService::send_interactive(self, to, message).await.map(sent_message) })
+    }
+
+    fn send_proactive<'a>(&'a self, to: &'a str, message: &'a ProactiveMessage, window_open: bool) -> SendFuture<'a> {
+        Box::pin(async move { TwilioService::send_proactive(self, to, message, window_open).await.map(sent_message) })
+    }
+
+    fn verify_inbound(&self, request: &InboundRequest<'_>) -> Result<()> {
+        let Some(signature) = request
+            .headers
+            .get("x-twilio-signature")
+            .and_then(|value| value.to_str().ok())
+        else {
+            warn!("Twilio webhook has a missing or unreadable signature header");
+            return Err(AppError::Unauthorized);
+        };
+        self.verify_webhook_signature(request.url, request.body, signature)
+    }
+
+    fn normalize_inbound(&self, body: &[u8]) -> Result<Inbound> {
+        let payload = self.parse_webhook_payload(body)?;
+        let has_media = payload.num_media.as_deref().is_some_and(|n| n != "0");
+        let content = match payload.button_payload {
+            Some(reply) => InboundContent::Reply(reply),
+            None if !payload.body.is_empty() => InboundContent::Text(payload.body),
+            // Twilio does not say what kind of message it was; the media type is the best hint
+            None if has_media => {
+                InboundContent::Unsupported(payload.media_content_type.unwrap_or_else(|| "media".to_string()))
+            }
+            None => InboundContent::Unsupported("empty".to_string()),
+        };
+        Ok(Inbound {
+            messages: vec![InboundMessage {
+                id: payload.message_sid,
+                from: normalize_twilio_address(&payload.from),
+                // Twilio webhooks carry no send time
+                sent_at: chrono::Utc::now(),
+                content,
+            }],
+            statuses: Vec::new(),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -260,17 +471,37 @@ mod tests {
             whatsapp_access_token: "test_token".to_string(),
             whatsapp_phone_number_id: "test_phone_id".to_string(),
             whatsapp_webhook_verify_token: "test_verify_token".to_string(),
+            whatsapp_app_secret: "test_app_secret".to_string(),
             whatsapp_api_base_url: "https://graph.facebook.com/v18.0".to_string(),
             whatsapp_media_base_url: "https://graph.facebook.com/v18.0".to_string(),
             twilio_account_sid: "test_account_sid".to_string(),
             twilio_auth_token: "test_auth_token".to_string(),
             twilio_whatsapp_number: "+1234567890".to_string(),
+            twilio_api_base_url: "https://api.twilio.com".to_string(),
+            twilio_sms_number: "".to_string(),
+            messaging_provider: "meta".to_string(),
+            twilio_webhook_url: "".to_string(),
             bitsacco_api_base_url: "https://api.bitsacco.com".to_string(),
             bitsacco_api_token: "test_bitsacco_token".to_string(),
+            bitsacco_callback_secret: "test_callback_secret".to_string(),
             btc_api_base_url: "https://api.coinbase.com/v2".to_string(),
             btc_api_key: Some("test_btc_key".to_string()),
+            coinbase_api_base_url: "https://api.coinbase.com/v2".to_string(),
+            kraken_api_base_url: "https://api.kraken.com".to_string(),
+            binance_api_base_url: "https://api.binance.com".to_string(),
+            btc_price_sources: vec!["coingecko".to_string()],
+            invoice_store_path: "".to_string(),
+            mpesa_status_query_delay_secs: 60,
+            limits_config_path: "".to_string(),
+            templates_config_path: "".to_string(),
+            pin_store_path: "".to_string(),
+            bitsacco_web_app_url: "https://app.bitsacco.com".to_string(),
+            message_dedup_retention_secs: 86400,
+            admin_api_token: "".to_string(),
+            outbound_queue_path: "".to_string(),
             server_port: 8080,
             rate_limit_requests_per_minute: 60,
+            rate_limit_money_commands_per_minute: 5,
             max_message_length: 4096,
             server_host: "0.0.0.0".to_string(),
             rust_log: "info".to_string(),
@@ -282,6 +513,7 @@ mod tests {
         let config = create_test_config();
         let service = TwilioService::new(config);
         assert!(service.is_configured());
+        assert!(!service.can_send_sms());
     }
 
     #[test]
@@ -296,32 +528,43 @@ mod tests {
     fn test_parse_webhook_payload() {
         let config = create_test_config();
         let service = TwilioService::new(config);
-        
-        let payload = r#"{
-            "message_sid": "SM1234567890",
-            "account_sid": "AC1234567890",
-            "from": "whatsapp:+1234567890",
-            "to": "whatsapp:+0987654321",
-            "body": "Hello World",
-            "status": "received",
-            "api_version": "2010-04-01"
-        }"#;
-
-        let result = service.parse_webhook_payload(payload);
-        assert!(result.is_ok());
-        
-        let webhook = result.unwrap();
+
+        let payload = "MessageSid=SM1234567890&AccountSid=AC1234567890&From=whatsapp%3A%2B254712345678\
+                       &To=whatsapp%3A%2B1234567890&Body=Hello+World&NumMedia=0&SmsStatus=received&ApiVersion=2010-04-01";
+
+        let webhook = service.parse_webhook_payload(payload.as_bytes()).unwrap();
         assert_eq!(webhook.message_sid, "SM1234567890");
+        assert_eq!(webhook.from, "whatsapp:+254712345678");
         assert_eq!(webhook.body, "Hello World");
+        assert_eq!(webhook.status.as_deref(), Some("received"));
+
+        let inbound = service.normalize_inbound(payload.as_bytes()).unwrap();
+        assert_eq!(inbound.messages[0].from, "+254712345678");
+        assert!(matches!(&inbound.messages[0].content, InboundContent::Text(text) if text == "Hello World"));
     }
 
     #[test]
     fn test_verify_webhook_signature() {
-        let config = create_test_config();
+        // Example from Twilio's webhook security documentation
+        let mut config = create_test_config();
+        config.twilio_auth_token = "12345".to_string();
         let service = TwilioService::new(config);
-        
-        let result = service.verify_webhook_signature("test_signature", "test_url", "test_payload");
-        assert!(result.is_ok());
-        assert!(result.unwrap());
+        let url = "https://mycompany.com/myapp.php?foo=1&bar=2";
+        let payload = b"To=%2B18005551212&From=%2B12349013030&Digits=1234&Caller=%2B12349013030&CallSid=CA1234567890ABCDE";
+
+        assert!(service.verify_webhook_signature(url, payload, "0/KCTR6DLpKmkAf8muzZqo1nDgQ=").is_ok());
+        assert!(service.verify_webhook_signature(url, b"To=%2B18005551212", "0/KCTR6DLpKmkAf8muzZqo1nDgQ=").is_err());
+        assert!(service.verify_webhook_signature("https://other.example", payload, "0/KCTR6DLpKmkAf8muzZqo1nDgQ=").is_err());
+        assert!(service.verify_webhook_signature(url, payload, "not base64!").is_err());
+        assert!(service.verify_webhook_signature("", payload, "0/KCTR6DLpKmkAf8muzZqo1nDgQ=").is_err());
+
+        // Requests without a readable signature are unauthorized, not malformed
+        let mut headers = axum::http::HeaderMap::new();
+        let verify = |headers: &axum::http::HeaderMap| service.verify_inbound(&InboundRequest { url, headers, body: payload });
+        assert!(matches!(verify(&headers), Err(AppError::Unauthorized)));
+        headers.insert("x-twilio-signature", axum::http::HeaderValue::from_bytes(b"\xff").unwrap());
+        assert!(matches!(verify(&headers), Err(AppError::Unauthorized)));
+        headers.insert("x-twilio-signature", "0/KCTR6DLpKmkAf8muzZqo1nDgQ=".parse().unwrap());
+        assert!(verify(&headers).is_ok());
     }
 }
@@ -141,7 +141,7 @@ impl VoiceService {
         // Mock implementation - in production, replace with actual STT service
         let mock_transcript = self.generate_mock_transcript(audio_path).await?;
         
-        info!("Speech-to-text result: {}", mock_transcript);
+        info!("Speech-to-text produced {} characters", mock_transcript.chars().count());
         Ok(mock_transcript)
     }
 
@@ -212,7 +212,7 @@ impl VoiceService {
             .as_str()
             .ok_or_else(|| AppError::Internal("No transcript in Whisper API response".to_string()))?;
         
-        info!("OpenAI Whisper transcription completed: {}", transcript);
+        info!("OpenAI Whisper transcription completed ({} characters)", transcript.chars().count());
         Ok(transcript.to_string())
     }
 
@@ -296,7 +296,7 @@ impl VoiceService {
         let sample_rate: u32 = 16000;
         let channels: u16 = 1;
         let bits_per_sample: u16 = 16;
-        let samples = (sample_rate * duration_ms / 1000) as u32;
+        let samples = sample_rate * duration_ms / 1000;
         let data_size = samples * channels as u32 * (bits_per_sample as u32 / 8);
         let file_size = 44 + data_size; // WAV header is 44 bytes
         
@@ -357,7 +357,7 @@ impl VoiceService {
         }
         
         // Test creating a temporary file
-        let temp_file = NamedTempFile::new_in(&self.temp_dir)
+        let _temp_file = NamedTempFile::new_in(&self.temp_dir)
             .map_err(|e| AppError::Internal(format!("Failed to create test temp file: {}", e)))?;
         
         info!("Voice service health check passed");
@@ -376,16 +376,36 @@ mod tests {
             whatsapp_access_token: "test_token".to_string(),
             whatsapp_phone_number_id: "test_phone_id".to_string(),
             whatsapp_webhook_verify_token: "test_verify_token".to_string(),
+            whatsapp_app_secret: "test_app_secret".to_string(),
             whatsapp_api_base_url: "https://graph.facebook.com/v18.0".to_string(),
             whatsapp_media_base_url: "https://graph.facebook.com/v18.0".to_string(),
             twilio_account_sid: "test_account_sid".to_string(),
             twilio_auth_token: "test_auth_token".to_string(),
             twilio_whatsapp_number: "+1234567890".to_string(),
+            twilio_api_base_url: "https://api.twilio.com".to_string(),
+            twilio_sms_number: "".to_string(),
+            messaging_provider: "meta".to_string(),
+            twilio_webhook_url: "".to_string(),
             bitsacco_api_base_url: "https://api.bitsacco.com".to_string(),
             bitsacco_api_token: "test_bitsacco_token".to_string(),
+            bitsacco_callback_secret: "test_callback_secret".to_string(),
             btc_api_base_url: "https://api.coingecko.com/api/v3".to_string(),
             btc_api_key: None,
+            coinbase_api_base_url: "https://api.coinbase.com/v2".to_string(),
+            kraken_api_base_url: "https://api.kraken.com".to_string(),
+            binance_api_base_url: "https://api.binance.com".to_string(),
+            btc_price_sources: vec!["coingecko".to_string()],
+            invoice_store_path: "".to_string(),
+            mpesa_status_query_delay_secs: 60,
+            limits_config_path: "".to_string(),
+            templates_config_path: "".to_string(),
+            pin_store_path: "".to_string(),
+            bitsacco_web_app_url: "https://app.bitsacco.com".to_string(),
+            message_dedup_retention_secs: 86400,
+            admin_api_token: "".to_string(),
+            outbound_queue_path: "".to_string(),
             rate_limit_requests_per_minute: 60,
+            rate_limit_money_commands_per_minute: 5,
             max_message_length: 4096,
             server_host: "0.0.0.0".to_string(),
             server_port: 8080,
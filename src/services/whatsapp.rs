@@ -1,19 +1,38 @@
 use crate::{
+    channel::{Inbound, InboundContent, InboundMessage, InboundRequest, MessagingChannel, OutboundMedia, SendFuture, SentMessage},
     config::AppConfig,
+    delivery::DeliveryTracker,
     error::{AppError, Result},
-    types::{WhatsAppSendRequest, WhatsAppSendResponse, WhatsAppTextContent, WhatsAppAudioContent},
+    interactive::InteractiveMessage,
+    money::{Money, Sats},
+    outbound::{classify_response, queued_or, FailureKind, Outbox, SendFailure},
+    service_window::inbound_time,
+    services::conversion::{format_rates, rate_for, ExchangeRate},
+    templates::{ProactiveMessage, TemplateMessage, TemplateRegistry},
+    types::{WhatsAppSendRequest, WhatsAppSendResponse, WhatsAppTextContent, WhatsAppAudioContent, WhatsAppImageContent, WhatsAppWebhook},
+    validation::normalize_wa_id,
 };
 use reqwest::Client;
 use ring::hmac;
 use tracing::{error, info, warn};
 
+/// Name of the Cloud API channel, as used in `MESSAGING_PROVIDER`
+pub const CHANNEL: &str = "meta";
+
 #[derive(Debug, Clone)]
 pub struct WhatsAppService {
     client: Client,
     access_token: String,
     phone_number_id: String,
     webhook_verify_token: String,
+    app_secret: String,
     api_base_url: String,
+    /// Where failed text messages are queued for retry, if anywhere
+    outbox: Option<Outbox>,
+    /// Where the IDs of sent messages are recorded for status callbacks
+    deliveries: Option<DeliveryTracker>,
+    /// Templates approved for the account
+    templates: TemplateRegistry,
 }
 
 impl WhatsAppService {
@@ -31,10 +50,33 @@ impl WhatsAppService {
             access_token: config.whatsapp_access_token.clone(),
             phone_number_id: config.whatsapp_phone_number_id.clone(),
             webhook_verify_token: config.whatsapp_webhook_verify_token.clone(),
+            app_secret: config.whatsapp_app_secret.clone(),
             api_base_url: config.whatsapp_api_base_url.clone(),
+            outbox: None,
+            deliveries: None,
+            templates: TemplateRegistry::default(),
         })
     }
 
+    /// Queue text messages that fail to send in `outbox` for retry
+    pub fn with_outbox(mut self, outbox: Outbox) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Record the ID of every sent message in `deliveries` so status
+    /// callbacks can be matched to it
+    pub fn with_delivery_tracker(mut self, deliveries: DeliveryTracker) -> Self {
+        self.deliveries = Some(deliveries);
+        self
+    }
+
+    /// Use `templates` instead of the built-in templates
+    pub fn with_templates(mut self, templates: TemplateRegistry) -> Self {
+        self.templates = templates;
+        self
+    }
+
     pub fn verify_webhook(&self, mode: &str, token: &str, challenge: &str) -> Result<String> {
         if mode == "subscribe" && token == self.webhook_verify_token {
             info!("Webhook verification successful");
@@ -48,41 +90,89 @@ impl WhatsAppService {
         }
     }
 
-    pub fn verify_webhook_signature(&self, payload: &str, signature: &str) -> Result<()> {
-        // WhatsApp uses HMAC-SHA256 for webhook signature verification
-        let key = hmac::Key::new(hmac::HMAC_SHA256, self.webhook_verify_token.as_bytes());
-        let expected_signature = hmac::sign(&key, payload.as_bytes());
-        let expected_hex = hex::encode(expected_signature.as_ref());
-        
+    /// Verify the `X-Hub-Signature-256` header against the raw request body.
+    ///
+    /// Meta signs the exact bytes it sends with the app secret, so `payload`
+    /// must be the untouched body; re-serialized JSON will not match.
+    pub fn verify_webhook_signature(&self, payload: &[u8], signature: &str) -> Result<()> {
         // Remove 'sha256=' prefix if present
         let provided_signature = signature.strip_prefix("sha256=").unwrap_or(signature);
-        
-        // Use constant-time comparison to prevent timing attacks
-        if expected_hex.len() == provided_signature.len() {
-            let expected_bytes = expected_hex.as_bytes();
-            let provided_bytes = provided_signature.as_bytes();
-            
-            let mut result = 0u8;
-            for (a, b) in expected_bytes.iter().zip(provided_bytes.iter()) {
-                result |= a ^ b;
-            }
-            
-            if result == 0 {
+
+        let Ok(provided_bytes) = hex::decode(provided_signature) else {
+            warn!("Webhook signature is not valid hex");
+            return Err(AppError::Unauthorized);
+        };
+
+        // ring compares the tags in constant time
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.app_secret.as_bytes());
+        match hmac::verify(&key, payload, &provided_bytes) {
+            Ok(()) => {
                 info!("Webhook signature verification successful");
-                return Ok(());
+                Ok(())
+            }
+            Err(_) => {
+                warn!("Webhook signature verification failed");
+                Err(AppError::Unauthorized)
             }
         }
-        
-        warn!("Webhook signature verification failed");
-        Err(AppError::Unauthorized)
     }
 
+    /// Send a text message. A failed send is queued for retry, or
+    /// dead-lettered, when the service has an outbox; the error from the
+    /// first attempt is still returned.
     pub async fn send_message(&self, to: &str, message: &str) -> Result<WhatsAppSendResponse> {
-        if message.len() > 4096 {
-            return Err(AppError::Validation("Message too long".to_string()));
+        self.deliver(to, message, false).await
+    }
+
+    /// Send a notification the user must receive, such as a deposit
+    /// confirmation. If WhatsApp later reports it as failed it is sent again
+    /// through Twilio.
+    pub async fn send_critical_message(&self, to: &str, message: &str) -> Result<WhatsAppSendResponse> {
+        self.deliver(to, message, true).await
+    }
+
+    async fn deliver(&self, to: &str, message: &str, critical: bool) -> Result<WhatsAppSendResponse> {
+        match self.attempt_text(to, message).await {
+            Ok(response) => {
+                self.track_sent(to, &response, critical.then_some(message)).await;
+                Ok(response)
+            }
+            Err(failure) => {
+                // Messages rejected before sending would only fail again
+                let rejected = matches!(failure.error, AppError::Validation(_));
+                let queued = match (&self.outbox, rejected) {
+                    (Some(outbox), false) => Some(
+                        outbox
+                            .record_first_failure(to, message, critical, &failure, chrono::Utc::now())
+                            .await,
+                    ),
+                    _ => None,
+                };
+                Err(queued_or(queued, failure.error))
+            }
         }
+    }
 
-        let url = format!("{}/{}/messages", self.api_base_url, self.phone_number_id);
+    /// Record a sent message for delivery tracking. `critical_body` is kept
+    /// for messages that need a fallback if delivery fails.
+    pub async fn track_sent(&self, to: &str, response: &WhatsAppSendResponse, critical_body: Option<&str>) {
+        let Some(deliveries) = &self.deliveries else {
+            return;
+        };
+        for sent in &response.messages {
+            deliveries.record_sent(&sent.id, to, critical_body, chrono::Utc::now()).await;
+        }
+    }
+
+    /// Make a single attempt at sending a text message, reporting whether a
+    /// failure is worth retrying
+    pub async fn attempt_text(&self, to: &str, message: &str) -> std::result::Result<WhatsAppSendResponse, SendFailure> {
+        if message.len() > 4096 {
+            return Err(SendFailure {
+                error: AppError::Validation("Message too long".to_string()),
+                kind: FailureKind::Permanent,
+            });
+        }
 
         let request = WhatsAppSendRequest {
             messaging_product: "whatsapp".to_string(),
@@ -92,7 +182,126 @@ impl WhatsAppService {
                 body: message.to_string(),
             }),
             audio: None,
+            image: None,
+            interactive: None,
+            template: None,
+        };
+        self.attempt(&request).await
+    }
+
+    /// Send reply buttons or a list. Messages that do not fit WhatsApp's
+    /// limits are sent as their text version instead, as are retries of a
+    /// failed send.
+    pub async fn send_interactive(&self, to: &str, message: &InteractiveMessage) -> Result<WhatsAppSendResponse> {
+        if let Err(e) = message.validate() {
+            warn!("Sending interactive message to {} as text: {}", to, e);
+            return self.send_message(to, &message.fallback_text).await;
+        }
+
+        let request = WhatsAppSendRequest {
+            messaging_product: "whatsapp".to_string(),
+            to: to.to_string(),
+            r#type: "interactive".to_string(),
+            text: None,
+            audio: None,
+            image: None,
+            interactive: Some(message.to_content()),
+            template: None,
+        };
+        match self.attempt(&request).await {
+            Ok(response) => {
+                self.track_sent(to, &response, None).await;
+                Ok(response)
+            }
+            Err(failure) => {
+                let queued = match &self.outbox {
+                    Some(outbox) => Some(
+                        outbox
+                            .record_first_failure(to, &message.fallback_text, false, &failure, chrono::Utc::now())
+                            .await,
+                    ),
+                    None => None,
+                };
+                Err(queued_or(queued, failure.error))
+            }
+        }
+    }
+
+    /// Send an approved template. Its parameters are checked against the
+    /// registry before anything is sent.
+    pub async fn send_template(&self, to: &str, template: &TemplateMessage) -> Result<WhatsAppSendResponse> {
+        self.deliver_template(to, template, None, false).await
+    }
+
+    /// Send a notification the user did not ask for: as text while their
+    /// service window is open, otherwise as its template
+    pub async fn send_proactive(&self, to: &str, message: &ProactiveMessage, window_open: bool) -> Result<WhatsAppSendResponse> {
+        if window_open {
+            return self.deliver(to, &message.text, message.critical).await;
+        }
+        self.deliver_template(to, &message.template, Some(&message.text), message.critical)
+            .await
+    }
+
+    /// Send a template, queueing a failed send for retry like a text
+    /// message. `text` is its free-form version, if it has one, kept for
+    /// admins and for the fallback of a `critical` message.
+    async fn deliver_template(
+        &self,
+        to: &str,
+        template: &TemplateMessage,
+        text: Option<&str>,
+        critical: bool,
+    ) -> Result<WhatsAppSendResponse> {
+        match self.attempt_template(to, template).await {
+            Ok(response) => {
+                self.track_sent(to, &response, text.filter(|_| critical)).await;
+                Ok(response)
+            }
+            Err(failure) => {
+                // Templates the registry refuses would only fail again
+                let rejected = matches!(failure.error, AppError::Validation(_));
+                let queued = match (&self.outbox, rejected) {
+                    (Some(outbox), false) => Some(
+                        outbox
+                            .record_first_template_failure(to, template, text, critical, &failure, chrono::Utc::now())
+                            .await,
+                    ),
+                    _ => None,
+                };
+                Err(queued_or(queued, failure.error))
+            }
+        }
+    }
+
+    /// Make a single attempt at sending a template, reporting whether a
+    /// failure is worth retrying
+    pub async fn attempt_template(
+        &self,
+        to: &str,
+        template: &TemplateMessage,
+    ) -> std::result::Result<WhatsAppSendResponse, SendFailure> {
+        let rendered = self.templates.render(template).map_err(|error| SendFailure {
+            error,
+            kind: FailureKind::Permanent,
+        })?;
+        let request = WhatsAppSendRequest {
+            messaging_product: "whatsapp".to_string(),
+            to: to.to_string(),
+            r#type: "template".to_string(),
+            text: None,
+            audio: None,
+            image: None,
+            interactive: None,
+            template: Some(rendered),
         };
+        self.attempt(&request).await
+    }
+
+    /// Make a single attempt at a Cloud API send request
+    async fn attempt(&self, request: &WhatsAppSendRequest) -> std::result::Result<WhatsAppSendResponse, SendFailure> {
+        let url = format!("{}/{}/messages", self.api_base_url, self.phone_number_id);
+        let to = &request.to;
 
         info!("Sending WhatsApp message to: {}", to);
 
@@ -101,29 +310,45 @@ impl WhatsAppService {
             .post(&url)
             .header("Authorization", format!("Bearer {}", self.access_token))
             .header("Content-Type", "application/json")
-            .json(&request)
+            .json(request)
             .send()
             .await
-            .map_err(|e| AppError::WhatsApp(format!("Failed to send message: {}", e)))?;
+            .map_err(|e| SendFailure::network(AppError::WhatsApp(format!("Failed to send message: {}", e))))?;
 
         if !response.status().is_success() {
             let status = response.status();
+            let retry_after = response
+                .headers()
+                .get("retry-after")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(std::time::Duration::from_secs);
             let error_text = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
 
             error!("WhatsApp API error: {} - {}", status, error_text);
-            return Err(AppError::WhatsApp(format!(
-                "API error {}: {}",
-                status, error_text
-            )));
+            return Err(SendFailure {
+                kind: classify_response(status.as_u16(), &error_text, retry_after),
+                error: AppError::WhatsApp(format!("API error {}: {}", status, error_text)),
+            });
         }
 
-        let send_response: WhatsAppSendResponse = response
-            .json()
-            .await
-            .map_err(|e| AppError::WhatsApp(format!("Failed to parse response: {}", e)))?;
+        // A 2xx means the message went out, so a body that cannot be read
+        // still counts as sent; only its ID, and so delivery tracking, is lost
+        let body = response.text().await.unwrap_or_default();
+        let send_response = match serde_json::from_str::<WhatsAppSendResponse>(&body) {
+            Ok(send_response) => send_response,
+            Err(e) => {
+                warn!("Message to {} was sent but its response could not be parsed: {}", to, e);
+                WhatsAppSendResponse {
+                    messaging_product: "whatsapp".to_string(),
+                    contacts: Vec::new(),
+                    messages: Vec::new(),
+                }
+            }
+        };
 
         info!(
             "Message sent successfully with ID: {:?}",
@@ -132,158 +357,6 @@ impl WhatsAppService {
         Ok(send_response)
     }
 
-    pub async fn send_help_message(&self, to: &str) -> Result<()> {
-        let help_text = r#"🤖 *BitSacco WhatsApp Bot Help*
-
-*Basic Commands:*
-• `help` - Show this help message
-• `balance` - Check your total balance in sats
-• `savings` - View your savings details
-• `bitcoin` - Get current Bitcoin price
-
-*Personal Savings:*
-• `deposit <amount> KES [mpesa|lightning]` - Make a deposit
-• `withdraw <amount> KES [mpesa|lightning]` - Make a withdrawal
-• `history` - View transaction history
-
-*Chama Management:*
-• `chama` - View your chama groups
-• `create chama <name>` - Create a new chama
-• `contribute chama <id> <amount> <currency>` - Contribute to chama
-• `shares balance` - View your chama shares
-
-*Membership Shares:*
-• `membership` - View your BitSacco membership shares
-• `buy shares <count> [mpesa|lightning]` - Purchase membership shares
-• `share history` - View share purchase history
-
-*Lightning Network:*
-• `lightning deposit <amount> KES` - Deposit via Lightning
-• `lightning withdraw <amount> KES` - Withdraw via Lightning
-
-*Examples:*
-• `deposit 100 KES mpesa`
-• `deposit 50 KES lightning`
-• `buy shares 10 mpesa`
-• `create chama Investment Club`
-• `contribute chama CH123 50 USD`
-
-*Note:* All balances are displayed in Satoshis (sats) for Bitcoin precision.
-
-*Voice Commands:*
-🎤 You can also send voice messages with commands like:
-• "Help" - Get help
-• "Balance" - Check balance
-• "Bitcoin price" - Get BTC price
-• "Deposit 100 dollars" - Make a deposit
-• "Create chama My Group" - Create a chama
-
-*Examples:*
-• `deposit 100 USD`
-• `withdraw 50 KES`
-• `transfer 25 USD +254712345678`
-• `create chama Investment Group`
-• `contribute chama CH123 50 USD`
-• `shares balance`
-
-*Security Note:*
-All transactions are secure and encrypted. Your data is protected by BitSacco's enterprise-grade security.
-
-Need more help? Visit https://bitsacco.com or contact support."#;
-
-        self.send_message(to, help_text).await?;
-        Ok(())
-    }
-
-    pub async fn send_balance_message(
-        &self,
-        to: &str,
-        savings_balance: f64,
-        btc_balance: f64,
-        currency: &str,
-    ) -> Result<()> {
-        // Convert total balance to sats (assuming 1 KES = 1 sat for simplicity)
-        // In real implementation, this would use current BTC price to convert KES to sats
-        let total_kes = savings_balance + btc_balance;
-        let total_sats = (total_kes * 100_000_000.0) as u64;
-        
-        let balance_text = format!(
-            r#"💰 *Your BitSacco Balance*
-
-*Total Balance:* {} sats ({:.2} {})
-
-Last updated: {}"#,
-            total_sats,
-            total_kes,
-            currency,
-            chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
-        );
-
-        self.send_message(to, &balance_text).await?;
-        Ok(())
-    }
-
-    pub async fn send_error_message(&self, to: &str, error: &str) -> Result<()> {
-        let error_text = format!(
-            r#"❌ *Error*
-
-{}
-
-Please try again or contact support if the problem persists.
-
-For help, send `help`"#,
-            error
-        );
-
-        self.send_message(to, &error_text).await?;
-        Ok(())
-    }
-
-    pub async fn send_success_message(&self, to: &str, message: &str) -> Result<()> {
-        let success_text = format!(
-            r#"✅ *Success*
-
-{}
-
-Thank you for using BitSacco!"#,
-            message
-        );
-
-        self.send_message(to, &success_text).await?;
-        Ok(())
-    }
-
-    pub async fn send_btc_price_message(
-        &self,
-        to: &str,
-        price: f64,
-        change_24h: f64,
-        currency: &str,
-    ) -> Result<()> {
-        let change_emoji = if change_24h >= 0.0 { "📈" } else { "📉" };
-        let change_sign = if change_24h >= 0.0 { "+" } else { "" };
-
-        let price_text = format!(
-            r#"₿ *Bitcoin Price Update*
-
-*Current Price:* {:.2} {}
-*24h Change:* {} {}{:.2}%
-
-*Last Updated:* {}
-
-Data provided by BitSacco API"#,
-            price,
-            currency,
-            change_emoji,
-            change_sign,
-            change_24h,
-            chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
-        );
-
-        self.send_message(to, &price_text).await?;
-        Ok(())
-    }
-
     /// Send a voice message (audio file)
     pub async fn send_voice_message(&self, to: &str, audio_file_path: &str) -> Result<()> {
         // First, upload the audio file to WhatsApp
@@ -298,6 +371,9 @@ Data provided by BitSacco API"#,
             audio: Some(WhatsAppAudioContent {
                 id: media_id.clone(),
             }),
+            image: None,
+            interactive: None,
+            template: None,
         };
 
         let url = format!("{}/{}/messages", self.api_base_url, self.phone_number_id);
@@ -332,6 +408,72 @@ Data provided by BitSacco API"#,
         }
     }
 
+    /// Send a PNG image with an optional caption
+    pub async fn send_image_message(&self, to: &str, png_data: Vec<u8>, caption: Option<&str>) -> Result<()> {
+        self.send_media_message(to, OutboundMedia::png(png_data, "chart.png", caption))
+            .await
+            .map(|_| ())
+    }
+
+    /// Upload an image or audio clip and send it
+    pub async fn send_media_message(&self, to: &str, media: OutboundMedia) -> Result<WhatsAppSendResponse> {
+        let audio = media.is_audio();
+        let kind = if audio { "audio" } else { "image" };
+        let media_id = self
+            .upload_media_bytes(media.data, &media.file_name, &media.mime_type)
+            .await?;
+
+        let mut request = WhatsAppSendRequest {
+            messaging_product: "whatsapp".to_string(),
+            to: to.to_string(),
+            r#type: kind.to_string(),
+            text: None,
+            audio: None,
+            image: None,
+            interactive: None,
+            template: None,
+        };
+        if audio {
+            request.audio = Some(WhatsAppAudioContent { id: media_id.clone() });
+        } else {
+            request.image = Some(WhatsAppImageContent {
+                id: media_id.clone(),
+                caption: media.caption,
+            });
+        }
+
+        let url = format!("{}/{}/messages", self.api_base_url, self.phone_number_id);
+
+        info!("Sending {} message to {} with media ID: {}", kind, to, media_id);
+
+        let response = self
+            .client
+            .post(&url)
+            .bearer_auth(&self.access_token)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| AppError::WhatsApp(format!("Failed to send {} message: {}", kind, e)))?;
+
+        if response.status().is_success() {
+            let response_data: WhatsAppSendResponse = response
+                .json()
+                .await
+                .map_err(|e| AppError::WhatsApp(format!("Failed to parse response: {}", e)))?;
+
+            info!("{} message sent successfully: {:?}", kind, response_data);
+            Ok(response_data)
+        } else {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            error!("Failed to send {} message: status={}, body={}", kind, status, body);
+            Err(AppError::WhatsApp(format!(
+                "Failed to send {} message: HTTP {} - {}",
+                kind, status, body
+            )))
+        }
+    }
+
     /// Upload media file to WhatsApp and return media ID
     async fn upload_media(&self, file_path: &str) -> Result<String> {
         use std::fs;
@@ -349,16 +491,25 @@ Data provided by BitSacco API"#,
             .and_then(|n| n.to_str())
             .unwrap_or("audio.wav");
 
+        self.upload_media_bytes(file_data, file_name, "audio/wav").await
+    }
+
+    /// Upload a PNG image held in memory and return its media ID
+    pub async fn upload_image(&self, png_data: Vec<u8>, file_name: &str) -> Result<String> {
+        self.upload_media_bytes(png_data, file_name, "image/png").await
+    }
+
+    async fn upload_media_bytes(&self, data: Vec<u8>, file_name: &str, mime_type: &str) -> Result<String> {
         let form = reqwest::multipart::Form::new()
-            .part("file", reqwest::multipart::Part::bytes(file_data)
+            .part("file", reqwest::multipart::Part::bytes(data)
                 .file_name(file_name.to_string())
-                .mime_str("audio/wav")?)
-            .part("type", reqwest::multipart::Part::text("audio/wav"))
+                .mime_str(mime_type)?)
+            .part("type", reqwest::multipart::Part::text(mime_type.to_string()))
             .part("messaging_product", reqwest::multipart::Part::text("whatsapp"));
 
         let url = format!("{}/{}/media", self.api_base_url, self.phone_number_id);
         
-        info!("Uploading media file: {}", file_path);
+        info!("Uploading {} media: {}", mime_type, file_name);
 
         let response = self
             .client
@@ -392,3 +543,147 @@ Data provided by BitSacco API"#,
         }
     }
 }
+
+fn sent_message(response: WhatsAppSendResponse) -> SentMessage {
+    SentMessage {
+        channel: CHANNEL,
+        id: response.messages.into_iter().next().map(|m| m.id).unwrap_or_default(),
+    }
+}
+
+impl MessagingChannel for WhatsAppService {
+    fn name(&self) -> &'static str {
+        CHANNEL
+    }
+
+    fn send_text<'a>(&'a self, to: &'a str, text: &'a str) -> SendFuture<'a> {
+        Box::pin(async move { self.send_message(to, text).await.map(sent_message) })
+    }
+
+    fn send_media<'a>(&'a self, to: &'a str, media: OutboundMedia) -> SendFuture<'a> {
+        Box::pin(async move { self.send_media_message(to, media).await.map(sent_message) })
+    }
+
+    fn send_interactive<'a>(&'a self, to: &'a str, message: &'a InteractiveMessage) -> SendFuture<'a> {
+        Box::pin(async move { WhatsAppService::send_interactive(self, to, message).await.map(sent_message) })
+    }
+
+    fn send_proactive<'a>(&'a self, to: &'a str, message: &'a ProactiveMessage, window_open: bool) -> SendFuture<'a> {
+        Box::pin(async move { WhatsAppService::send_proactive(self, to, message, window_open).await.map(sent_message) })
+    }
+
+    fn verify_inbound(&self, request: &InboundRequest<'_>) -> Result<()> {
+        let signature = request
+            .headers
+            .get("x-hub-signature-256")
+            .ok_or_else(|| AppError::Validation("Missing webhook signature".to_string()))?
+            .to_str()
+            .map_err(|_| AppError::Validation("Invalid signature header".to_string()))?;
+        self.verify_webhook_signature(request.body, signature)
+    }
+
+    fn normalize_inbound(&self, body: &[u8]) -> Result<Inbound> {
+        let webhook: WhatsAppWebhook = serde_json::from_slice(body)
+            .map_err(|e| AppError::Validation(format!("Invalid webhook payload: {}", e)))?;
+        let now = chrono::Utc::now();
+
+        let mut inbound = Inbound::default();
+        for change in webhook.entry.into_iter().flat_map(|entry| entry.changes) {
+            for message in change.value.messages.unwrap_or_default() {
+                let reply = message.interactive.as_ref().and_then(|i| i.reply_id()).map(str::to_string);
+                let content = match (message.text, reply, message.voice, message.audio) {
+                    (Some(text), ..) => InboundContent::Text(text.body),
+                    (None, Some(reply), ..) => InboundContent::Reply(reply),
+                    (None, None, Some(voice), _) => InboundContent::Voice(voice),
+                    (None, None, None, Some(audio)) => InboundContent::Audio(audio),
+                    (None, None, None, None) => InboundContent::Unsupported(message.r#type),
+                };
+                inbound.messages.push(InboundMessage {
+                    // Meta sends the wa_id without the leading '+'
+                    from: normalize_wa_id(&message.from),
+                    sent_at: inbound_time(&message.timestamp, now),
+                    id: message.id,
+                    content,
+                });
+            }
+            inbound.statuses.extend(change.value.statuses.unwrap_or_default());
+        }
+        Ok(inbound)
+    }
+}
+
+/// Balance reply: each fiat savings total and the bitcoin balance with their
+/// equivalents at `rates`, and the combined total in sats.
+///
+/// `rates` must cover every fiat currency in `savings`; the first rate is used
+/// to show the fiat value of the bitcoin balance.
+pub fn format_balance(savings: &[Money], btc_balance: Money, rates: &[ExchangeRate]) -> Result<String> {
+    let mut total = Sats::try_from(btc_balance)?;
+    let mut savings_lines = Vec::new();
+    for amount in savings {
+        let rate = rate_for(rates, amount.currency()).ok_or_else(|| {
+            AppError::Internal(format!("No BTC/{} rate to value savings", amount.currency()))
+        })?;
+        let sats = rate.to_sats(*amount)?;
+        total = total
+            .checked_add(sats)
+            .ok_or_else(|| AppError::Internal("Balance total overflowed".to_string()))?;
+        savings_lines.push(format!("{} ≈ {}", amount, sats));
+    }
+    if savings_lines.is_empty() {
+        savings_lines.push("None yet".to_string());
+    }
+
+    let btc_sats = Sats::try_from(btc_balance)?;
+    let btc_line = match rates.first() {
+        Some(rate) => format!("{} (≈ {})", btc_sats, rate.to_fiat(btc_sats)?),
+        None => btc_sats.to_string(),
+    };
+
+    Ok(format!(
+        r#"💰 *Your BitSacco Balance*
+
+*Savings:*
+{}
+*Bitcoin:* {}
+*Total:* {}
+
+{}"#,
+        savings_lines.join("\n"),
+        btc_line,
+        total,
+        format_rates(rates)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Currency;
+
+    #[test]
+    fn test_format_balance_uses_rates() {
+        let as_of = chrono::DateTime::parse_from_rfc3339("2026-10-16T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let rates = vec![
+            ExchangeRate::new(Money::from_major(8_000_000, Currency::Kes).unwrap(), as_of).unwrap(),
+            ExchangeRate::new(Money::from_major(60_000, Currency::Usd).unwrap(), as_of).unwrap(),
+        ];
+        let savings = vec![
+            Money::from_major(800, Currency::Kes).unwrap(),
+            Money::from_major(6, Currency::Usd).unwrap(),
+        ];
+        let btc = Money::from_minor(5_000, Currency::Btc);
+
+        let text = format_balance(&savings, btc, &rates).unwrap();
+        assert!(text.contains("800.00 KES ≈ 10000 sats"));
+        assert!(text.contains("6.00 USD ≈ 10000 sats"));
+        assert!(text.contains("*Bitcoin:* 5000 sats (≈ 400.00 KES)"));
+        assert!(text.contains("*Total:* 25000 sats"));
+        assert!(text.contains("Rate: 1 BTC = 8000000.00 KES (as of 2026-10-16 12:00:00 UTC)"));
+
+        // A savings currency without a rate is an error, not a wrong figure
+        assert!(format_balance(&savings, btc, &rates[..1]).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+//! Lightning deposit settlement tracking
+//!
+//! Every deposit invoice the bot hands out is recorded here by payment hash
+//! until it is paid or expires. A background watcher polls BitSacco for each
+//! outstanding invoice, and BitSacco can also push status changes to
+//! `/callbacks/lightning`. Whichever sees the final state first removes the
+//! invoice and messages the user, so each deposit is announced exactly once.
+//! Outstanding invoices are written to a JSON file so a restart does not lose
+//! track of them.
+
+use crate::{
+    channel::Messenger,
+    error::{AppError, Result},
+    json_store,
+    lightning::Bolt11Invoice,
+    money::{Money, Sats},
+    outbound::sent_or_queued,
+    services::bitsacco::BitSaccoService,
+    templates::{ProactiveMessage, TemplateMessage, TemplateParameter},
+    types::{LightningPaymentResponse, LightningPaymentStatus},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+/// How often the background watcher polls BitSacco
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long past expiry a pending invoice is still checked, for payments
+/// that raced the deadline
+const EXPIRY_GRACE: chrono::Duration = chrono::Duration::minutes(2);
+
+/// Invoices whose status cannot be fetched for this long are dropped
+const GIVE_UP_AFTER: chrono::Duration = chrono::Duration::hours(24);
+
+/// Lifetime assumed when neither BitSacco nor the invoice gives an expiry
+const DEFAULT_INVOICE_TTL: chrono::Duration = chrono::Duration::hours(1);
+
+/// A deposit invoice waiting to be paid
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutstandingInvoice {
+    pub payment_hash: String,
+    pub phone_number: String,
+    /// Amount the user asked to deposit
+    pub amount: Money,
+    /// Amount the invoice is for, when it could be decoded
+    pub amount_sats: Option<Sats>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OutstandingInvoice {
+    /// Record an invoice BitSacco issued for a deposit of `amount`
+    pub fn new(phone_number: &str, amount: Money, response: &LightningPaymentResponse) -> Self {
+        let now = Utc::now();
+        let decoded = Bolt11Invoice::parse(&response.payment_request).ok();
+        let expires_at = DateTime::parse_from_rfc3339(&response.expires_at)
+            .map(|time| time.with_timezone(&Utc))
+            .ok()
+            .or_else(|| decoded.as_ref().map(Bolt11Invoice::expires_at))
+            .unwrap_or(now + DEFAULT_INVOICE_TTL);
+        Self {
+            payment_hash: response.payment_hash.clone(),
+            phone_number: phone_number.to_string(),
+            amount,
+            amount_sats: decoded.and_then(|invoice| invoice.amount().ok().flatten()),
+            expires_at,
+            created_at: now,
+        }
+    }
+
+    fn is_past_grace(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires_at + EXPIRY_GRACE
+    }
+}
+
+/// Where a payment stands, from BitSacco's status string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentState {
+    Pending,
+    Settled,
+    Expired,
+}
+
+impl PaymentState {
+    pub fn from_status(status: &str) -> Self {
+        match status.to_lowercase().as_str() {
+            "paid" | "settled" | "completed" | "succeeded" => PaymentState::Settled,
+            "expired" | "cancelled" | "canceled" | "failed" => PaymentState::Expired,
+            _ => PaymentState::Pending,
+        }
+    }
+}
+
+/// Outstanding invoices by payment hash, optionally mirrored to a JSON file
+#[derive(Debug, Clone, Default)]
+pub struct InvoiceTracker {
+    invoices: Arc<RwLock<HashMap<String, OutstandingInvoice>>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+impl InvoiceTracker {
+    /// Tracker backed by `path`, picking up invoices left by a previous run.
+    /// An empty path keeps invoices in memory only.
+    pub async fn load(path: &str) -> Self {
+        if path.is_empty() {
+            return Self::default();
+        }
+        let path = PathBuf::from(path);
+        let invoices: Vec<OutstandingInvoice> = json_store::read_or_default(&path, "Invoice store").await;
+        if !invoices.is_empty() {
+            info!("Resuming {} outstanding Lightning invoices", invoices.len());
+        }
+
+        Self {
+            invoices: Arc::new(RwLock::new(
+                invoices.into_iter().map(|invoice| (invoice.payment_hash.clone(), invoice)).collect(),
+            )),
+            path: Some(Arc::new(path)),
+        }
+    }
+
+    pub async fn track(&self, invoice: OutstandingInvoice) {
+        let mut invoices = self.invoices.write().await;
+        invoices.insert(invoice.payment_hash.clone(), invoice);
+        self.persist(&invoices).await;
+    }
+
+    pub async fn outstanding(&self) -> Vec<OutstandingInvoice> {
+        self.invoices.read().await.values().cloned().collect()
+    }
+
+    /// Stop tracking an invoice. Only the first caller gets it back, which is
+    /// what makes each settlement notification go out once.
+    pub async fn take(&self, payment_hash: &str) -> Option<OutstandingInvoice> {
+        let mut invoices = self.invoices.write().await;
+        let invoice = invoices.remove(payment_hash)?;
+        self.persist(&invoices).await;
+        Some(invoice)
+    }
+
+    async fn persist(&self, invoices: &HashMap<String, OutstandingInvoice>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = json_store::write(path, &invoices.values().collect::<Vec<_>>()).await {
+            error!("Failed to save outstanding invoices to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Act on a payment status from a poll or a callback. Returns whether the
+/// invoice reached a final state and the user was told.
+pub async fn apply_status(
+    tracker: &InvoiceTracker,
+    messenger: &Messenger,
+    status: &LightningPaymentStatus,
+) -> Result<bool> {
+    let state = PaymentState::from_status(&status.status);
+    if state == PaymentState::Pending {
+        return Ok(false);
+    }
+    let Some(invoice) = tracker.take(&status.payment_hash).await else {
+        return Ok(false);
+    };
+
+    let message = match state {
+        PaymentState::Settled => {
+            info!("Lightning invoice {} settled", invoice.payment_hash);
+            settled_notification(&invoice, status.amount_sats.map(Sats))
+        }
+        _ => {
+            info!("Lightning invoice {} expired", invoice.payment_hash);
+            expired_notification(&invoice)
+        }
+    };
+    if let Err(e) = sent_or_queued(messenger.send_proactive(&invoice.phone_number, &message.critical()).await) {
+        // Track it again so the next poll or callback retries the message
+        tracker.track(invoice).await;
+        return Err(e);
+    }
+    Ok(true)
+}
+
+pub fn settled_message(invoice: &OutstandingInvoice, received: Option<Sats>) -> String {
+    match received.or(invoice.amount_sats) {
+        Some(sats) => format!(
+            "⚡ Received {}\n\nYour Lightning deposit of {} has arrived. Send `balance` to see your updated balance.",
+            sats.grouped(),
+            invoice.amount
+        ),
+        None => format!(
+            "⚡ Received {}\n\nYour Lightning deposit has arrived. Send `balance` to see your updated balance.",
+            invoice.amount
+        ),
+    }
+}
+
+pub fn expired_message(invoice: &OutstandingInvoice) -> String {
+    format!(
+        "⌛ Your Lightning invoice for {} expired without being paid.\n\nSend `lightning deposit {}` for a new one.",
+        invoice.amount,
+        invoice.amount
+    )
+}
+
+fn settled_notification(invoice: &OutstandingInvoice, received: Option<Sats>) -> ProactiveMessage {
+    let amount = match received.or(invoice.amount_sats) {
+        Some(sats) => sats.grouped(),
+        None => invoice.amount.to_string(),
+    };
+    ProactiveMessage::new(
+        settled_message(invoice, received),
+        TemplateMessage::new("lightning_deposit_received").body(vec![TemplateParameter::text(amount)]),
+    )
+}
+
+fn expired_notification(invoice: &OutstandingInvoice) -> ProactiveMessage {
+    ProactiveMessage::new(
+        expired_message(invoice),
+        TemplateMessage::new("lightning_invoice_expired").body(vec![TemplateParameter::money(invoice.amount)]),
+    )
+}
+
+/// Poll BitSacco for every outstanding invoice until the process exits
+pub async fn run_settlement_watcher(
+    tracker: InvoiceTracker,
+    bitsacco_service: BitSaccoService,
+    messenger: Messenger,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        for invoice in tracker.outstanding().await {
+            if let Err(e) = check_invoice(&tracker, &bitsacco_service, &messenger, &invoice).await {
+                error!("Failed to update Lightning invoice {}: {}", invoice.payment_hash, e);
+            }
+        }
+    }
+}
+
+async fn check_invoice(
+    tracker: &InvoiceTracker,
+    bitsacco_service: &BitSaccoService,
+    messenger: &Messenger,
+    invoice: &OutstandingInvoice,
+) -> Result<()> {
+    let now = Utc::now();
+    let mut status = match bitsacco_service.get_lightning_payment_status(&invoice.payment_hash).await {
+        Ok(status) => status,
+        Err(e) if now >= invoice.expires_at + GIVE_UP_AFTER => {
+            warn!("Giving up on Lightning invoice {}: {}", invoice.payment_hash, e);
+            tracker.take(&invoice.payment_hash).await;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if status.payment_hash.is_empty() {
+        status.payment_hash = invoice.payment_hash.clone();
+    } else if status.payment_hash != invoice.payment_hash {
+        return Err(AppError::BitSacco(format!(
+            "Status for {} returned for payment {}",
+            status.payment_hash, invoice.payment_hash
+        )));
+    }
+
+    // BitSacco may leave an unpaid invoice pending; the deadline is ours to enforce
+    if PaymentState::from_status(&status.status) == PaymentState::Pending && invoice.is_past_grace(now) {
+        status.status = "expired".to_string();
+    }
+    apply_status(tracker, messenger, &status).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Currency;
+
+    fn invoice(hash: &str) -> OutstandingInvoice {
+        OutstandingInvoice {
+            payment_hash: hash.to_string(),
+            phone_number: "+254712345678".to_string(),
+            amount: Money::from_major(500, Currency::Kes).unwrap(),
+            amount_sats: Some(Sats(5_000)),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_payment_state() {
+        assert_eq!(PaymentState::from_status("PAID"), PaymentState::Settled);
+        assert_eq!(PaymentState::from_status("expired"), PaymentState::Expired);
+        assert_eq!(PaymentState::from_status("pending"), PaymentState::Pending);
+        assert_eq!(PaymentState::from_status("something new"), PaymentState::Pending);
+    }
+
+    #[test]
+    fn test_messages() {
+        let invoice = invoice("abc");
+        assert!(settled_message(&invoice, None).starts_with("⚡ Received 5,000 sats"));
+        assert!(settled_message(&invoice, Some(Sats(5_100))).starts_with("⚡ Received 5,100 sats"));
+        assert!(expired_message(&invoice).contains("expired"));
+    }
+
+    #[test]
+    fn test_expiry_from_response_or_invoice() {
+        let response = LightningPaymentResponse {
+            payment_request: "lnbc-not-decodable".to_string(),
+            payment_hash: "abc".to_string(),
+            expires_at: "2026-10-16T12:00:00Z".to_string(),
+            status: "pending".to_string(),
+        };
+        let amount = Money::from_major(500, Currency::Kes).unwrap();
+        let invoice = OutstandingInvoice::new("+254712345678", amount, &response);
+        assert_eq!(invoice.expires_at.to_rfc3339(), "2026-10-16T12:00:00+00:00");
+        assert_eq!(invoice.amount_sats, None);
+    }
+
+    #[tokio::test]
+    async fn test_take_only_once() {
+        let tracker = InvoiceTracker::default();
+        tracker.track(invoice("abc")).await;
+        assert_eq!(tracker.outstanding().await.len(), 1);
+        assert!(tracker.take("abc").await.is_some());
+        assert!(tracker.take("abc").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_persists_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invoices.json");
+        let path = path.to_str().unwrap();
+
+        let tracker = InvoiceTracker::load(path).await;
+        tracker.track(invoice("abc")).await;
+        tracker.track(invoice("def")).await;
+        tracker.take("abc").await;
+
+        let reloaded = InvoiceTracker::load(path).await;
+        let outstanding = reloaded.outstanding().await;
+        assert_eq!(outstanding.len(), 1);
+        assert_eq!(outstanding[0].payment_hash, "def");
+        assert_eq!(outstanding[0].amount_sats, Some(Sats(5_000)));
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_store_is_set_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invoices.json");
+        std::fs::write(&path, "not json").unwrap();
+
+        let tracker = InvoiceTracker::load(path.to_str().unwrap()).await;
+        assert!(tracker.outstanding().await.is_empty());
+        assert!(dir.path().join("invoices.json.corrupt").exists());
+    }
+}
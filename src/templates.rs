@@ -0,0 +1,439 @@
+//! Pre-approved WhatsApp message templates
+//!
+//! Outside the 24-hour customer service window Meta only delivers templates
+//! approved in WhatsApp Manager. The registry lists the templates approved for
+//! this account, with their language and the parameters each component takes,
+//! so a template sent with the wrong parameters is refused here rather than by
+//! Meta. Proactive notifications carry both their free-form text and a
+//! template; the template is used once the user's window has closed. Users
+//! reached through Twilio get the same template approved as Twilio Content,
+//! when a definition names its Content SID.
+
+use crate::{
+    error::{AppError, Result},
+    money::{Currency, Money},
+    types::{
+        WhatsAppTemplateComponent, WhatsAppTemplateContent, WhatsAppTemplateCurrency, WhatsAppTemplateDateTime,
+        WhatsAppTemplateLanguage, WhatsAppTemplateParameter,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
+
+/// Language the built-in templates are approved in
+const DEFAULT_LANGUAGE: &str = "en";
+
+/// Type of a template parameter, as declared when the template was approved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParameterKind {
+    Text,
+    Currency,
+    DateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterSpec {
+    pub name: String,
+    pub kind: ParameterKind,
+}
+
+/// An approved template and the parameters its header and body take, in order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateDefinition {
+    pub name: String,
+    #[serde(default = "default_language")]
+    pub language: String,
+    #[serde(default)]
+    pub header: Vec<ParameterSpec>,
+    #[serde(default)]
+    pub body: Vec<ParameterSpec>,
+    /// SID of the same template approved through Twilio Content, if it is
+    #[serde(default)]
+    pub twilio_content_sid: Option<String>,
+}
+
+fn default_language() -> String {
+    DEFAULT_LANGUAGE.to_string()
+}
+
+/// A value for one template parameter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TemplateParameter {
+    Text(String),
+    /// Localised by WhatsApp; `fallback` is shown where it cannot be
+    Currency { fallback: String, code: String, amount_1000: i64 },
+    DateTime { fallback: String },
+}
+
+impl TemplateParameter {
+    pub fn text(value: impl ToString) -> Self {
+        TemplateParameter::Text(value.to_string())
+    }
+
+    /// A currency parameter for fiat amounts. WhatsApp only knows ISO 4217
+    /// currencies, so BTC amounts are sent as text.
+    pub fn money(amount: Money) -> Self {
+        match amount.currency() {
+            Currency::Btc => TemplateParameter::Text(amount.to_string()),
+            currency => match amount.minor().checked_mul(1000) {
+                Some(scaled) => TemplateParameter::Currency {
+                    fallback: amount.to_string(),
+                    code: currency.code().to_string(),
+                    amount_1000: scaled / 10_i64.pow(currency.minor_units()),
+                },
+                // Too large to scale; the formatted amount still reads right
+                None => TemplateParameter::Text(amount.to_string()),
+            },
+        }
+    }
+
+    /// Kind this value can fill; BTC amounts sent as text fill currency parameters
+    fn fills(&self, kind: ParameterKind) -> bool {
+        matches!(
+            (self, kind),
+            (TemplateParameter::Text(_), ParameterKind::Text | ParameterKind::Currency)
+                | (TemplateParameter::Currency { .. }, ParameterKind::Currency)
+                | (TemplateParameter::DateTime { .. }, ParameterKind::DateTime)
+        )
+    }
+
+    /// The value as plain text, for providers without typed parameters
+    fn as_text(&self) -> &str {
+        match self {
+            TemplateParameter::Text(text) => text,
+            TemplateParameter::Currency { fallback, .. } | TemplateParameter::DateTime { fallback } => fallback,
+        }
+    }
+
+    fn to_wire(&self) -> WhatsAppTemplateParameter {
+        let mut parameter = WhatsAppTemplateParameter {
+            r#type: String::new(),
+            text: None,
+            currency: None,
+            date_time: None,
+        };
+        match self {
+            TemplateParameter::Text(text) => {
+                parameter.r#type = "text".to_string();
+                parameter.text = Some(text.clone());
+            }
+            TemplateParameter::Currency { fallback, code, amount_1000 } => {
+                parameter.r#type = "currency".to_string();
+                parameter.currency = Some(WhatsAppTemplateCurrency {
+                    fallback_value: fallback.clone(),
+                    code: code.clone(),
+                    amount_1000: *amount_1000,
+                });
+            }
+            TemplateParameter::DateTime { fallback } => {
+                parameter.r#type = "date_time".to_string();
+                parameter.date_time = Some(WhatsAppTemplateDateTime {
+                    fallback_value: fallback.clone(),
+                });
+            }
+        }
+        parameter
+    }
+}
+
+/// A template to send, with values for its parameters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateMessage {
+    pub name: String,
+    pub header: Vec<TemplateParameter>,
+    pub body: Vec<TemplateParameter>,
+}
+
+impl TemplateMessage {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            header: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, parameters: Vec<TemplateParameter>) -> Self {
+        self.header = parameters;
+        self
+    }
+
+    pub fn body(mut self, parameters: Vec<TemplateParameter>) -> Self {
+        self.body = parameters;
+        self
+    }
+}
+
+/// A notification the user did not ask for: sent as free-form text while
+/// their service window is open, and as `template` once it has closed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProactiveMessage {
+    pub text: String,
+    pub template: TemplateMessage,
+    /// Whether to fall back to SMS if WhatsApp fails to deliver it
+    pub critical: bool,
+}
+
+impl ProactiveMessage {
+    pub fn new(text: impl Into<String>, template: TemplateMessage) -> Self {
+        Self {
+            text: text.into(),
+            template,
+            critical: false,
+        }
+    }
+
+    pub fn critical(mut self) -> Self {
+        self.critical = true;
+        self
+    }
+}
+
+/// A template to send through Twilio: its Content SID, and its parameters as
+/// the JSON object of numbered variables Twilio expects
+#[derive(Debug, Clone, PartialEq)]
+pub struct TwilioContent {
+    pub sid: String,
+    pub variables: String,
+}
+
+/// Templates approved for this WhatsApp Business account
+#[derive(Debug, Clone)]
+pub struct TemplateRegistry {
+    templates: HashMap<String, TemplateDefinition>,
+}
+
+impl TemplateRegistry {
+    pub fn new(definitions: Vec<TemplateDefinition>) -> Self {
+        Self {
+            templates: definitions
+                .into_iter()
+                .map(|definition| (definition.name.clone(), definition))
+                .collect(),
+        }
+    }
+
+    /// The built-in templates, with any in the JSON list at `path` added or
+    /// replacing the built-in ones of the same name
+    pub async fn load(path: &str) -> Result<Self> {
+        let mut registry = Self::default();
+        if path.is_empty() {
+            return Ok(registry);
+        }
+        let data = tokio::fs::read(path).await?;
+        let definitions: Vec<TemplateDefinition> = serde_json::from_slice(&data)?;
+        info!("Loaded {} message templates from {}", definitions.len(), path);
+        for definition in definitions {
+            registry.templates.insert(definition.name.clone(), definition);
+        }
+        Ok(registry)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TemplateDefinition> {
+        self.templates.get(name)
+    }
+
+    /// Check `message` against its definition and build the `template`
+    /// object of a Cloud API send request
+    pub fn render(&self, message: &TemplateMessage) -> Result<WhatsAppTemplateContent> {
+        let definition = self.check(message)?;
+        let components = [("header", &message.header), ("body", &message.body)]
+            .into_iter()
+            .filter(|(_, values)| !values.is_empty())
+            .map(|(kind, values)| WhatsAppTemplateComponent {
+                r#type: kind.to_string(),
+                parameters: values.iter().map(TemplateParameter::to_wire).collect(),
+            })
+            .collect();
+
+        Ok(WhatsAppTemplateContent {
+            name: definition.name.clone(),
+            language: WhatsAppTemplateLanguage {
+                code: definition.language.clone(),
+            },
+            components,
+        })
+    }
+
+    /// Check `message` against its definition and build the Twilio Content
+    /// to send, or `None` when the template has no Content SID. Header and
+    /// body parameters are numbered from 1 in order.
+    pub fn render_twilio(&self, message: &TemplateMessage) -> Result<Option<TwilioContent>> {
+        let definition = self.check(message)?;
+        let Some(sid) = &definition.twilio_content_sid else {
+            return Ok(None);
+        };
+        let variables: serde_json::Map<String, serde_json::Value> = message
+            .header
+            .iter()
+            .chain(&message.body)
+            .enumerate()
+            .map(|(index, value)| ((index + 1).to_string(), value.as_text().into()))
+            .collect();
+        Ok(Some(TwilioContent {
+            sid: sid.clone(),
+            variables: serde_json::Value::Object(variables).to_string(),
+        }))
+    }
+
+    /// The definition of `message`, if its parameters match it
+    fn check(&self, message: &TemplateMessage) -> Result<&TemplateDefinition> {
+        let definition = self
+            .get(&message.name)
+            .ok_or_else(|| AppError::Validation(format!("Unknown message template {}", message.name)))?;
+
+        for (kind, specs, values) in [
+            ("header", &definition.header, &message.header),
+            ("body", &definition.body, &message.body),
+        ] {
+            if specs.len() != values.len() {
+                return Err(AppError::Validation(format!(
+                    "Template {} takes {} {} parameters, got {}",
+                    definition.name,
+                    specs.len(),
+                    kind,
+                    values.len()
+                )));
+            }
+            if let Some((spec, _)) = specs.iter().zip(values).find(|(spec, value)| !value.fills(spec.kind)) {
+                return Err(AppError::Validation(format!(
+                    "Template {} {} parameter {} must be {:?}",
+                    definition.name, kind, spec.name, spec.kind
+                )));
+            }
+        }
+        Ok(definition)
+    }
+}
+
+impl Default for TemplateRegistry {
+    /// Templates for the bot's own notifications. They must be approved in
+    /// WhatsApp Manager under these names before they can be delivered.
+    fn default() -> Self {
+        let spec = |name: &str, kind| ParameterSpec { name: name.to_string(), kind };
+        let body = |name: &str, body: Vec<ParameterSpec>| TemplateDefinition {
+            name: name.to_string(),
+            language: default_language(),
+            header: Vec::new(),
+            body,
+            twilio_content_sid: None,
+        };
+        Self::new(vec![
+            body("lightning_deposit_received", vec![spec("amount", ParameterKind::Text)]),
+            body("lightning_invoice_expired", vec![spec("amount", ParameterKind::Currency)]),
+            body(
+                "mpesa_deposit_received",
+                vec![spec("amount", ParameterKind::Currency), spec("transaction_id", ParameterKind::Text)],
+            ),
+            body(
+                "mpesa_deposit_failed",
+                vec![spec("amount", ParameterKind::Currency), spec("reason", ParameterKind::Text)],
+            ),
+            body(
+                "mpesa_deposit_unconfirmed",
+                vec![spec("amount", ParameterKind::Currency), spec("transaction_id", ParameterKind::Text)],
+            ),
+            body("pin_reset", Vec::new()),
+            body(
+                "btc_price_alert",
+                vec![
+                    spec("price", ParameterKind::Currency),
+                    spec("alert_id", ParameterKind::Text),
+                    spec("direction", ParameterKind::Text),
+                    spec("threshold", ParameterKind::Currency),
+                ],
+            ),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kes(major: i64) -> Money {
+        Money::from_major(major, Currency::Kes).unwrap()
+    }
+
+    #[test]
+    fn test_render_typed_parameters() {
+        let registry = TemplateRegistry::default();
+        let message = TemplateMessage::new("mpesa_deposit_received")
+            .body(vec![TemplateParameter::money(kes(500)), TemplateParameter::text("tx123")]);
+
+        let json = serde_json::to_value(registry.render(&message).unwrap()).unwrap();
+        assert_eq!(json["name"], "mpesa_deposit_received");
+        assert_eq!(json["language"]["code"], "en");
+        assert_eq!(json["components"].as_array().unwrap().len(), 1);
+        let parameters = &json["components"][0]["parameters"];
+        assert_eq!(json["components"][0]["type"], "body");
+        assert_eq!(parameters[0]["type"], "currency");
+        assert_eq!(parameters[0]["currency"]["code"], "KES");
+        assert_eq!(parameters[0]["currency"]["amount_1000"], 500_000);
+        assert_eq!(parameters[0]["currency"]["fallback_value"], kes(500).to_string());
+        assert_eq!(parameters[1], serde_json::json!({"type": "text", "text": "tx123"}));
+    }
+
+    #[test]
+    fn test_render_checks_schema() {
+        let registry = TemplateRegistry::default();
+        assert!(registry.render(&TemplateMessage::new("no_such_template")).is_err());
+
+        let too_few = TemplateMessage::new("mpesa_deposit_received").body(vec![TemplateParameter::money(kes(500))]);
+        assert!(registry.render(&too_few).is_err());
+
+        let wrong_kind = TemplateMessage::new("mpesa_deposit_received").body(vec![
+            TemplateParameter::DateTime { fallback: "today".to_string() },
+            TemplateParameter::text("tx123"),
+        ]);
+        assert!(registry.render(&wrong_kind).is_err());
+
+        // BTC amounts go out as text
+        let btc = Money::from_minor(50_000, Currency::Btc);
+        assert_eq!(TemplateParameter::money(btc), TemplateParameter::Text(btc.to_string()));
+        let expired = TemplateMessage::new("lightning_invoice_expired").body(vec![TemplateParameter::money(btc)]);
+        assert!(registry.render(&expired).is_ok());
+
+        // So do amounts too large to scale to thousandths
+        let huge = Money::from_minor(i64::MAX, Currency::Kes);
+        assert_eq!(TemplateParameter::money(huge), TemplateParameter::Text(huge.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_load_definitions() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            r#"[{"name": "savings_reminder", "language": "sw", "body": [{"name": "name", "kind": "text"}]}]"#,
+        )
+        .unwrap();
+
+        let registry = TemplateRegistry::load(file.path().to_str().unwrap()).await.unwrap();
+        // Built-in templates the file does not mention are kept
+        assert!(registry.get("mpesa_deposit_received").is_some());
+        let content = registry
+            .render(&TemplateMessage::new("savings_reminder").body(vec![TemplateParameter::text("Wanjiku")]))
+            .unwrap();
+        assert_eq!(content.language.code, "sw");
+    }
+
+    #[test]
+    fn test_render_twilio_content() {
+        let registry = TemplateRegistry::default();
+        let message = TemplateMessage::new("mpesa_deposit_received")
+            .body(vec![TemplateParameter::money(kes(500)), TemplateParameter::text("tx123")]);
+        // Built-in templates have no Twilio Content SID
+        assert_eq!(registry.render_twilio(&message).unwrap(), None);
+
+        let mut definition = registry.get("mpesa_deposit_received").unwrap().clone();
+        definition.twilio_content_sid = Some("HX123".to_string());
+        let registry = TemplateRegistry::new(vec![definition]);
+        let content = registry.render_twilio(&message).unwrap().unwrap();
+        assert_eq!(content.sid, "HX123");
+        let variables: serde_json::Value = serde_json::from_str(&content.variables).unwrap();
+        assert_eq!(variables, serde_json::json!({"1": kes(500).to_string(), "2": "tx123"}));
+        assert!(registry.render_twilio(&TemplateMessage::new("mpesa_deposit_received")).is_err());
+    }
+}
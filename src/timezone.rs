@@ -0,0 +1,75 @@
+//! Local time for users, inferred from their phone number
+//!
+//! WhatsApp does not tell us a user's time zone, but the country calling code
+//! is a good guide for the fixed-offset African markets BitSacco serves.
+//! Numbers from elsewhere fall back to UTC.
+
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+
+/// Calling code prefixes (without `+`) and their zone, longest prefixes first
+const ZONES: &[(&str, i32, &str)] = &[
+    ("254", 3, "EAT"), // Kenya
+    ("255", 3, "EAT"), // Tanzania
+    ("256", 3, "EAT"), // Uganda
+    ("251", 3, "EAT"), // Ethiopia
+    ("252", 3, "EAT"), // Somalia
+    ("250", 2, "CAT"), // Rwanda
+    ("257", 2, "CAT"), // Burundi
+    ("260", 2, "CAT"), // Zambia
+    ("263", 2, "CAT"), // Zimbabwe
+    ("265", 2, "CAT"), // Malawi
+    ("234", 1, "WAT"), // Nigeria
+    ("233", 0, "GMT"), // Ghana
+    ("27", 2, "SAST"), // South Africa
+];
+
+/// The user's UTC offset and zone abbreviation, e.g. `(+03:00, "EAT")`
+pub fn zone_for_phone(phone_number: &str) -> (FixedOffset, &'static str) {
+    let digits = phone_number.trim().trim_start_matches('+');
+    ZONES
+        .iter()
+        .find(|(prefix, _, _)| digits.starts_with(prefix))
+        .and_then(|(_, hours, name)| FixedOffset::east_opt(hours * 3600).map(|offset| (offset, *name)))
+        .unwrap_or_else(|| (FixedOffset::east_opt(0).expect("zero offset is valid"), "UTC"))
+}
+
+/// Format a UTC instant in the user's local time, e.g. `2026-10-16 15:30 EAT`
+pub fn format_local_time(time: DateTime<Utc>, phone_number: &str) -> String {
+    let (offset, name) = zone_for_phone(phone_number);
+    format!("{} {}", offset.from_utc_datetime(&time.naive_utc()).format("%Y-%m-%d %H:%M"), name)
+}
+
+/// Format an RFC 3339 timestamp from an API in the user's local time,
+/// returning it unchanged if it cannot be parsed
+pub fn format_local_timestamp(timestamp: &str, phone_number: &str) -> String {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(time) => format_local_time(time.with_timezone(&Utc), phone_number),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zone_for_phone() {
+        assert_eq!(zone_for_phone("+254712345678").1, "EAT");
+        assert_eq!(zone_for_phone("254712345678").0.local_minus_utc(), 3 * 3600);
+        assert_eq!(zone_for_phone("+27821234567").1, "SAST");
+        assert_eq!(zone_for_phone("+14155550123").1, "UTC");
+    }
+
+    #[test]
+    fn test_format_local_timestamp() {
+        assert_eq!(
+            format_local_timestamp("2026-10-16T12:30:00Z", "+254712345678"),
+            "2026-10-16 15:30 EAT"
+        );
+        assert_eq!(
+            format_local_timestamp("2026-10-16T23:30:00+00:00", "+250788123456"),
+            "2026-10-17 01:30 CAT"
+        );
+        assert_eq!(format_local_timestamp("soon", "+254712345678"), "soon");
+    }
+}
@@ -2,9 +2,24 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 use crate::{
+    alerts::{AlertDirection, AlertStore},
     cache::AppCache,
+    channel::Messenger,
     config::AppConfig,
-    services::{bitsacco::BitSaccoService, btc::BtcService, twilio::TwilioService, voice::VoiceService, whatsapp::WhatsAppService},
+    confirmation::PendingActionStore,
+    conversation::ConversationStore,
+    dedup::MessageDeduplicator,
+    delivery::DeliveryTracker,
+    lightning::LightningDestination,
+    limits::LimitsEngine,
+    money::{Currency, Money},
+    mpesa::StkPushTracker,
+    outbound::Outbox,
+    pin::{Pin, PinStore},
+    rate_limit::RateLimiter,
+    service_window::ServiceWindows,
+    settlement::InvoiceTracker,
+    services::{bitsacco::BitSaccoService, btc::BtcService, conversion::ConversionService, twilio::TwilioService, voice::VoiceService, whatsapp::WhatsAppService},
 };
 
 #[derive(Debug, Clone)]
@@ -14,9 +29,24 @@ pub struct AppState {
     pub whatsapp_service: WhatsAppService,
     pub bitsacco_service: BitSaccoService,
     pub btc_service: BtcService,
+    pub conversion_service: ConversionService,
     pub voice_service: VoiceService,
     pub cache: AppCache,
     pub twilio_service: TwilioService,
+    /// Sends to each user through the provider they use
+    pub messenger: Messenger,
+    pub pending_actions: PendingActionStore,
+    pub conversations: ConversationStore,
+    pub price_alerts: AlertStore,
+    pub lightning_invoices: InvoiceTracker,
+    pub mpesa_deposits: StkPushTracker,
+    pub limits: LimitsEngine,
+    pub pins: PinStore,
+    pub processed_messages: MessageDeduplicator,
+    pub rate_limiter: RateLimiter,
+    pub outbox: Outbox,
+    pub deliveries: DeliveryTracker,
+    pub service_windows: ServiceWindows,
 }
 
 // WhatsApp API Types
@@ -73,6 +103,8 @@ pub struct WhatsAppMessage {
     pub voice: Option<WhatsAppVoice>,
     pub audio: Option<WhatsAppAudio>,
     pub context: Option<WhatsAppContext>,
+    /// A tapped reply button or list row
+    pub interactive: Option<WhatsAppInteractive>,
     pub r#type: String,
 }
 
@@ -95,6 +127,24 @@ pub struct WhatsAppAudio {
     pub sha256: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WhatsAppInteractive {
+    pub r#type: String,
+    pub button_reply: Option<WhatsAppReplyOption>,
+    pub list_reply: Option<WhatsAppListRow>,
+}
+
+impl WhatsAppInteractive {
+    /// ID of the button or row the user tapped
+    pub fn reply_id(&self) -> Option<&str> {
+        match (&self.button_reply, &self.list_reply) {
+            (Some(button), _) => Some(&button.id),
+            (None, Some(row)) => Some(&row.id),
+            (None, None) => None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct WhatsAppContext {
     pub from: String,
@@ -107,6 +157,15 @@ pub struct WhatsAppStatus {
     pub status: String,
     pub timestamp: String,
     pub recipient_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<WhatsAppStatusError>>,
+}
+
+/// Why WhatsApp could not deliver a message
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WhatsAppStatusError {
+    pub code: u32,
+    pub title: String,
 }
 
 // WhatsApp Send Message Types
@@ -117,6 +176,12 @@ pub struct WhatsAppSendRequest {
     pub r#type: String,
     pub text: Option<WhatsAppTextContent>,
     pub audio: Option<WhatsAppAudioContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<WhatsAppImageContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interactive: Option<WhatsAppInteractiveContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<WhatsAppTemplateContent>,
 }
 
 #[derive(Debug, Serialize)]
@@ -129,6 +194,104 @@ pub struct WhatsAppAudioContent {
     pub id: String,
 }
 
+#[derive(Debug, Serialize)]
+pub struct WhatsAppImageContent {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppInteractiveContent {
+    pub r#type: String,
+    pub body: WhatsAppInteractiveBody,
+    pub action: WhatsAppInteractiveAction,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppInteractiveBody {
+    pub text: String,
+}
+
+/// Reply buttons, or the button that opens a list and the list's sections
+#[derive(Debug, Serialize)]
+pub struct WhatsAppInteractiveAction {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub button: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buttons: Option<Vec<WhatsAppReplyButton>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sections: Option<Vec<WhatsAppListSection>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppReplyButton {
+    pub r#type: String,
+    pub reply: WhatsAppReplyOption,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WhatsAppReplyOption {
+    pub id: String,
+    pub title: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppListSection {
+    pub title: String,
+    pub rows: Vec<WhatsAppListRow>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WhatsAppListRow {
+    pub id: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppTemplateContent {
+    pub name: String,
+    pub language: WhatsAppTemplateLanguage,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<WhatsAppTemplateComponent>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppTemplateLanguage {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppTemplateComponent {
+    pub r#type: String,
+    pub parameters: Vec<WhatsAppTemplateParameter>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppTemplateParameter {
+    pub r#type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<WhatsAppTemplateCurrency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_time: Option<WhatsAppTemplateDateTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppTemplateCurrency {
+    pub fallback_value: String,
+    pub code: String,
+    pub amount_1000: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhatsAppTemplateDateTime {
+    pub fallback_value: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct WhatsAppSendResponse {
     pub messaging_product: String,
@@ -163,8 +326,8 @@ pub struct BitSaccoUser {
 pub struct BitSaccoSavings {
     pub id: String,
     pub user_id: String,
-    pub amount: f64,
-    pub currency: String,
+    #[serde(flatten)]
+    pub amount: Money,
     pub chama_id: Option<String>,
     pub created_at: String,
     pub updated_at: String,
@@ -176,8 +339,8 @@ pub struct BitSaccoChama {
     pub name: String,
     pub description: Option<String>,
     pub members: Vec<BitSaccoUser>,
-    pub total_savings: f64,
-    pub currency: String,
+    #[serde(flatten, with = "crate::money::fields::total_savings")]
+    pub total_savings: Money,
     pub created_at: String,
     pub updated_at: String,
 }
@@ -188,8 +351,8 @@ pub struct BitSaccoChamaShare {
     pub chama_id: String,
     pub user_id: String,
     pub shares_count: i32,
-    pub total_contribution: f64,
-    pub currency: String,
+    #[serde(flatten, with = "crate::money::fields::total_contribution")]
+    pub total_contribution: Money,
     pub created_at: String,
     pub updated_at: String,
 }
@@ -199,8 +362,8 @@ pub struct BitSaccoChamaContribution {
     pub id: String,
     pub chama_id: String,
     pub user_id: String,
-    pub amount: f64,
-    pub currency: String,
+    #[serde(flatten)]
+    pub amount: Money,
     pub shares_purchased: i32,
     pub status: String, // "pending", "completed", "failed"
     pub created_at: String,
@@ -210,8 +373,8 @@ pub struct BitSaccoChamaContribution {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BitSaccoBtcBalance {
     pub user_id: String,
-    pub balance: f64,
-    pub currency: String,
+    #[serde(flatten, with = "crate::money::fields::balance")]
+    pub balance: Money,
     pub last_updated: String,
 }
 
@@ -220,8 +383,8 @@ pub struct BitSaccoTransaction {
     pub id: String,
     pub user_id: String,
     pub r#type: String, // "deposit", "withdrawal", "transfer", "chama_contribution", "share_purchase"
-    pub amount: f64,
-    pub currency: String,
+    #[serde(flatten)]
+    pub amount: Money,
     pub status: String, // "pending", "completed", "failed"
     pub payment_method: Option<String>, // "mpesa", "lightning", "internal"
     pub external_reference: Option<String>,
@@ -234,8 +397,8 @@ pub struct BitSaccoTransaction {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MpesaStkPushRequest {
     pub phone_number: String,
-    pub amount: f64,
-    pub currency: String,
+    #[serde(flatten)]
+    pub amount: Money,
     pub account_reference: String,
     pub transaction_desc: String,
 }
@@ -249,14 +412,81 @@ pub struct MpesaStkPushResponse {
     pub customer_message: String,
 }
 
+// M-Pesa Daraja STK Push result callback
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MpesaCallback {
+    #[serde(rename = "Body")]
+    pub body: MpesaCallbackBody,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MpesaCallbackBody {
+    #[serde(rename = "stkCallback")]
+    pub stk_callback: MpesaStkCallback,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MpesaStkCallback {
+    #[serde(rename = "MerchantRequestID")]
+    pub merchant_request_id: String,
+    #[serde(rename = "CheckoutRequestID")]
+    pub checkout_request_id: String,
+    #[serde(rename = "ResultCode")]
+    pub result_code: i64,
+    #[serde(rename = "ResultDesc")]
+    pub result_desc: String,
+    /// Only present when the payment succeeded
+    #[serde(rename = "CallbackMetadata")]
+    pub callback_metadata: Option<MpesaCallbackMetadata>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MpesaCallbackMetadata {
+    #[serde(rename = "Item")]
+    pub items: Vec<MpesaCallbackItem>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MpesaCallbackItem {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Value", default)]
+    pub value: Option<serde_json::Value>,
+}
+
+/// PIN reset made in the BitSacco web app, pushed to `/callbacks/pin-reset`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PinResetNotification {
+    pub phone_number: String,
+}
+
+/// STK Push status query result, as relayed from Daraja. `ResultCode` is
+/// absent while M-Pesa is still processing, and Daraja sends it as a string.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MpesaStkQueryResponse {
+    #[serde(rename = "CheckoutRequestID", default)]
+    pub checkout_request_id: String,
+    #[serde(rename = "ResultCode", default)]
+    pub result_code: Option<serde_json::Value>,
+    #[serde(rename = "ResultDesc", default)]
+    pub result_desc: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TransactionStatusUpdate {
+    pub status: String, // "completed" or "failed"
+    pub external_receipt: Option<String>,
+    pub failure_reason: Option<String>,
+}
+
 // BitSacco Membership Shares
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BitSaccoMembershipShare {
     pub id: String,
     pub user_id: String,
     pub shares_count: u32,
-    pub total_investment: f64,
-    pub currency: String,
+    #[serde(flatten, with = "crate::money::fields::total_investment")]
+    pub total_investment: Money,
     pub created_at: String,
     pub updated_at: String,
 }
@@ -266,8 +496,8 @@ pub struct BitSaccoSharePurchase {
     pub id: String,
     pub user_id: String,
     pub shares_count: u32,
-    pub amount: f64,
-    pub currency: String,
+    #[serde(flatten)]
+    pub amount: Money,
     pub payment_method: String, // "mpesa" or "lightning"
     pub status: String,
     pub created_at: String,
@@ -279,8 +509,8 @@ pub struct BitSaccoSharePurchase {
 // Lightning Network Payment
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LightningPaymentRequest {
-    pub amount: f64,
-    pub currency: String,
+    #[serde(flatten)]
+    pub amount: Money,
     pub description: String,
     pub user_id: String,
 }
@@ -293,17 +523,40 @@ pub struct LightningPaymentResponse {
     pub status: String,
 }
 
+/// Status of a Lightning payment, as polled from BitSacco or pushed to
+/// `/callbacks/lightning`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LightningPaymentStatus {
+    pub payment_hash: String,
+    pub status: String, // "pending", "paid", "expired", ...
+    #[serde(default)]
+    pub amount_sats: Option<u64>,
+    #[serde(default)]
+    pub settled_at: Option<String>,
+}
+
 // Withdrawal Request
 #[derive(Debug, Deserialize, Serialize)]
 pub struct WithdrawalRequest {
     pub user_id: String,
-    pub amount: f64,
-    pub currency: String,
+    #[serde(flatten)]
+    pub amount: Money,
     pub payment_method: String, // "mpesa" or "lightning"
     pub phone_number: Option<String>,
     pub description: Option<String>,
 }
 
+// Lightning Withdrawal Request
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LightningWithdrawalRequest {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub amount: Money,
+    pub destination_type: String, // "bolt11", "lightning_address" or "lnurl"
+    pub destination: String,
+    pub payment_hash: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct WithdrawalResponse {
     pub id: String,
@@ -312,6 +565,13 @@ pub struct WithdrawalResponse {
     pub message: String,
 }
 
+// Fee BitSacco charges for a withdrawal, quoted before it is confirmed
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WithdrawalQuote {
+    #[serde(flatten, with = "crate::money::fields::fee")]
+    pub fee: Money,
+}
+
 // BTC Service Types
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BtcPrice {
@@ -319,6 +579,91 @@ pub struct BtcPrice {
     pub price: f64,
     pub change_24h: f64,
     pub last_updated: String,
+    /// Price sources whose quotes made up `price`
+    #[serde(default)]
+    pub sources: Vec<String>,
+}
+
+/// Time window for historical BTC prices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PriceRange {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl PriceRange {
+    /// Parse the suffix of `bitcoin 7d`; accepts `24h`/`1d`, `7d`/`1w`, `30d`/`1m` and `1y`/`365d`
+    pub fn parse(range: &str) -> Option<Self> {
+        match range.trim().to_lowercase().as_str() {
+            "24h" | "1d" => Some(PriceRange::Day),
+            "7d" | "1w" => Some(PriceRange::Week),
+            "30d" | "1m" => Some(PriceRange::Month),
+            "1y" | "365d" | "12m" => Some(PriceRange::Year),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PriceRange::Day => "24h",
+            PriceRange::Week => "7d",
+            PriceRange::Month => "30d",
+            PriceRange::Year => "1y",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            PriceRange::Day => "last 24 hours",
+            PriceRange::Week => "last 7 days",
+            PriceRange::Month => "last 30 days",
+            PriceRange::Year => "last year",
+        }
+    }
+
+    pub fn days(&self) -> u32 {
+        match self {
+            PriceRange::Day => 1,
+            PriceRange::Week => 7,
+            PriceRange::Month => 30,
+            PriceRange::Year => 365,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PricePoint {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub price: f64,
+}
+
+/// BTC prices over a `PriceRange`, oldest first
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PriceHistory {
+    pub currency: String,
+    pub range: String,
+    pub points: Vec<PricePoint>,
+    /// Price source the series came from
+    pub source: String,
+}
+
+impl PriceHistory {
+    pub fn high(&self) -> Option<f64> {
+        self.points.iter().map(|p| p.price).reduce(f64::max)
+    }
+
+    pub fn low(&self) -> Option<f64> {
+        self.points.iter().map(|p| p.price).reduce(f64::min)
+    }
+
+    /// Percent change from the first to the last point
+    pub fn change_percent(&self) -> Option<f64> {
+        let first = self.points.first()?.price;
+        let last = self.points.last()?.price;
+        (first > 0.0).then(|| (last - first) / first * 100.0)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -337,19 +682,23 @@ pub enum BotCommand {
     Savings,
     Chama,
     BtcPrice,
+    BtcPriceHistory {
+        range: PriceRange,
+        currency: Currency,
+    },
     Deposit {
-        amount: f64,
-        currency: String,
+        amount: Money,
         method: Option<String>,
     },
+    /// `destination` is an M-Pesa number or a Lightning invoice, address or
+    /// LNURL, depending on `method`
     Withdraw {
-        amount: f64,
-        currency: String,
+        amount: Money,
         method: Option<String>,
+        destination: Option<String>,
     },
     Transfer {
-        amount: f64,
-        currency: String,
+        amount: Money,
         recipient: String,
     },
     CreateChama {
@@ -358,8 +707,7 @@ pub enum BotCommand {
     },
     ContributeChama {
         chama_id: String,
-        amount: f64,
-        currency: String,
+        amount: Money,
     },
     SharesBalance {
         chama_id: Option<String>,
@@ -375,16 +723,40 @@ pub enum BotCommand {
     History,
     // Lightning Network
     LightningDeposit { 
-        amount: f64, 
-        currency: String 
+        amount: Money 
     },
-    LightningWithdraw { 
-        amount: f64, 
-        currency: String 
+    /// `amount` is `None` when paying an invoice for the amount it carries
+    LightningWithdraw {
+        amount: Option<Money>,
+        destination: String,
     },
     VoiceCommand {
         transcript: String,
     },
+    // Bitcoin price alerts
+    AddPriceAlert {
+        direction: AlertDirection,
+        threshold: Money,
+    },
+    PriceAlerts,
+    RemovePriceAlert {
+        id: u64,
+    },
+    // Transaction PIN, set through the guided flows
+    SetPin {
+        pin: Pin,
+    },
+    ChangePin {
+        current: Pin,
+        pin: Pin,
+    },
+    RemovePin {
+        current: Pin,
+    },
+    ResetPin,
+    // Replies to a pending confirmation
+    Confirm,
+    Cancel,
     Unknown(String),
 }
 
@@ -402,39 +774,39 @@ impl BotCommand {
             BotCommand::Chama
         } else if message == "btc" || message == "bitcoin" || message == "bitcoin price" || message == "/btc" {
             BotCommand::BtcPrice
+        } else if let Some(command) = Self::parse_price_history(&message) {
+            command
+        } else if message == "yes" || message == "y" || message == "confirm" {
+            BotCommand::Confirm
+        } else if message == "no" || message == "n" || message == "cancel" {
+            BotCommand::Cancel
         } else if message.starts_with("deposit ") {
             // Parse deposit command: "deposit 100 KES mpesa" or "deposit 100 KES lightning"
             let parts: Vec<&str> = message.split_whitespace().collect();
             if parts.len() >= 3 {
-                if let Ok(amount) = parts[1].parse::<f64>() {
+                if let Ok(amount) = Money::parse_with_code(parts[1], parts[2]) {
                     let method = if parts.len() > 3 {
                         Some(parts[3].to_lowercase())
                     } else {
                         None
                     };
-                    return BotCommand::Deposit {
-                        amount,
-                        currency: parts[2].to_uppercase(),
-                        method,
-                    };
+                    return BotCommand::Deposit { amount, method };
                 }
             }
             BotCommand::Unknown(message)
         } else if message.starts_with("withdraw ") {
-            // Parse withdraw command: "withdraw 50 KES mpesa" or "withdraw 50 KES lightning"
+            // Parse withdraw command: "withdraw 50 KES", "withdraw 50 KES mpesa +254712345678"
+            // or "withdraw 50 KES lightning <invoice|address|lnurl>"
             let parts: Vec<&str> = message.split_whitespace().collect();
             if parts.len() >= 3 {
-                if let Ok(amount) = parts[1].parse::<f64>() {
-                    let method = if parts.len() > 3 {
-                        Some(parts[3].to_lowercase())
-                    } else {
-                        None
-                    };
-                    return BotCommand::Withdraw {
-                        amount,
-                        currency: parts[2].to_uppercase(),
-                        method,
-                    };
+                if let Ok(amount) = Money::parse_with_code(parts[1], parts[2]) {
+                    let method = parts.get(3).map(|method| method.to_lowercase());
+                    let destination = parts.get(4).map(|destination| destination.to_string());
+                    // Lightning needs somewhere to send to; the guided flow asks for it
+                    if method.as_deref() == Some("lightning") && destination.is_none() {
+                        return BotCommand::Unknown(message);
+                    }
+                    return BotCommand::Withdraw { amount, method, destination };
                 }
             }
             BotCommand::Unknown(message)
@@ -442,10 +814,9 @@ impl BotCommand {
             // Parse transfer command: "transfer 25 USD +254712345678"
             let parts: Vec<&str> = message.split_whitespace().collect();
             if parts.len() >= 4 {
-                if let Ok(amount) = parts[1].parse::<f64>() {
+                if let Ok(amount) = Money::parse_with_code(parts[1], parts[2]) {
                     return BotCommand::Transfer {
                         amount,
-                        currency: parts[2].to_uppercase(),
                         recipient: parts[3].to_string(),
                     };
                 }
@@ -465,11 +836,10 @@ impl BotCommand {
             // Parse contribute chama command: "contribute chama <chama_id> 100 USD"
             let parts: Vec<&str> = message.split_whitespace().collect();
             if parts.len() >= 5 {
-                if let Ok(amount) = parts[3].parse::<f64>() {
+                if let Ok(amount) = Money::parse_with_code(parts[3], parts[4]) {
                     return BotCommand::ContributeChama {
                         chama_id: parts[2].to_string(),
                         amount,
-                        currency: parts[4].to_uppercase(),
                     };
                 }
             }
@@ -506,29 +876,121 @@ impl BotCommand {
         } else if message.starts_with("lightning deposit ") {
             let parts: Vec<&str> = message.split_whitespace().collect();
             if parts.len() >= 4 {
-                if let Ok(amount) = parts[2].parse::<f64>() {
-                    return BotCommand::LightningDeposit {
-                        amount,
-                        currency: parts[3].to_uppercase(),
-                    };
+                if let Ok(amount) = Money::parse_with_code(parts[2], parts[3]) {
+                    return BotCommand::LightningDeposit { amount };
                 }
             }
             BotCommand::Unknown(message)
         } else if message.starts_with("lightning withdraw ") {
+            // Parse "lightning withdraw <invoice>" or
+            // "lightning withdraw 500 KES <invoice|address|lnurl>"
             let parts: Vec<&str> = message.split_whitespace().collect();
-            if parts.len() >= 4 {
-                if let Ok(amount) = parts[2].parse::<f64>() {
+            match parts.as_slice() {
+                // Destinations without an amount fall through to the guided flow
+                [_, _, destination]
+                    if LightningDestination::looks_like(destination)
+                        && LightningDestination::parse(destination).map_or(true, |d| !d.needs_amount()) =>
+                {
                     return BotCommand::LightningWithdraw {
-                        amount,
-                        currency: parts[3].to_uppercase(),
+                        amount: None,
+                        destination: destination.to_string(),
                     };
                 }
+                [_, _, amount, currency, destination] => {
+                    if let Ok(amount) = Money::parse_with_code(amount, currency) {
+                        return BotCommand::LightningWithdraw {
+                            amount: Some(amount),
+                            destination: destination.to_string(),
+                        };
+                    }
+                }
+                _ => {}
+            }
+            BotCommand::Unknown(message)
+        } else if message == "reset pin" || message == "forgot pin" {
+            BotCommand::ResetPin
+        } else if message == "alerts" || message == "/alerts" {
+            BotCommand::PriceAlerts
+        } else if message.starts_with("alert remove ") {
+            // Parse alert removal: "alert remove 3" or "alert remove #3"
+            let id = message
+                .strip_prefix("alert remove ")
+                .map(|id| id.trim().trim_start_matches('#'))
+                .and_then(|id| id.parse::<u64>().ok());
+            match id {
+                Some(id) => BotCommand::RemovePriceAlert { id },
+                None => BotCommand::Unknown(message),
+            }
+        } else if message.starts_with("alert ") {
+            // Parse price alert: "alert btc above 10000000 KES" ("btc" is optional)
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let parts = match parts.get(1) {
+                Some(&"btc") | Some(&"bitcoin") => &parts[2..],
+                _ => &parts[1..],
+            };
+            if parts.len() == 3 {
+                let direction = match parts[0] {
+                    "above" => Some(AlertDirection::Above),
+                    "below" => Some(AlertDirection::Below),
+                    _ => None,
+                };
+                if let (Some(direction), Ok(threshold)) =
+                    (direction, Money::parse_with_code(parts[1], parts[2]))
+                {
+                    return BotCommand::AddPriceAlert { direction, threshold };
+                }
             }
             BotCommand::Unknown(message)
         } else {
             BotCommand::Unknown(message)
         }
     }
+
+    /// Parse `bitcoin 7d`, `btc 30d kes` or `bitcoin price 1y usd`
+    fn parse_price_history(message: &str) -> Option<Self> {
+        let parts: Vec<&str> = message.split_whitespace().collect();
+        let rest = match parts.as_slice() {
+            ["bitcoin" | "btc" | "/btc", "price", rest @ ..] => rest,
+            ["bitcoin" | "btc" | "/btc", rest @ ..] => rest,
+            _ => return None,
+        };
+        match rest {
+            [range] => Some(BotCommand::BtcPriceHistory {
+                range: PriceRange::parse(range)?,
+                currency: Currency::Usd,
+            }),
+            [range, currency] => Some(BotCommand::BtcPriceHistory {
+                range: PriceRange::parse(range)?,
+                currency: currency.parse().ok().filter(|c| *c != Currency::Btc)?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Commands that move money out of the user's account and must be
+    /// confirmed with an explicit `YES` before they run
+    pub fn requires_confirmation(&self) -> bool {
+        matches!(
+            self,
+            BotCommand::Withdraw { .. }
+                | BotCommand::LightningWithdraw { .. }
+                | BotCommand::Transfer { .. }
+                | BotCommand::BuyShares { .. }
+                | BotCommand::ContributeChama { .. }
+        )
+    }
+
+    /// Commands that must be confirmed with the user's PIN, when they have
+    /// set one, instead of `YES`
+    pub fn requires_pin(&self) -> bool {
+        matches!(
+            self,
+            BotCommand::Withdraw { .. }
+                | BotCommand::LightningWithdraw { .. }
+                | BotCommand::Transfer { .. }
+                | BotCommand::BuyShares { .. }
+        )
+    }
 }
 
 // Health Check Response
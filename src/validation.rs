@@ -1,6 +1,6 @@
 use crate::error::{AppError, Result};
+use crate::money::{Currency, Money};
 use regex::Regex;
-use std::str::FromStr;
 
 /// Validates phone number format (supports international format)
 pub fn validate_phone_number(phone: &str) -> Result<()> {
@@ -22,6 +22,22 @@ pub fn validate_phone_number(phone: &str) -> Result<()> {
     Ok(())
 }
 
+/// Normalizes a WhatsApp ID (digits only, as Meta sends it) to E.164 with a leading '+'
+pub fn normalize_wa_id(wa_id: &str) -> String {
+    let trimmed = wa_id.trim();
+    if trimmed.starts_with('+') {
+        trimmed.to_string()
+    } else {
+        format!("+{}", trimmed)
+    }
+}
+
+/// Normalizes a Twilio WhatsApp address such as `whatsapp:+254712345678` to E.164
+pub fn normalize_twilio_address(address: &str) -> String {
+    let trimmed = address.trim();
+    normalize_wa_id(trimmed.strip_prefix("whatsapp:").unwrap_or(trimmed))
+}
+
 /// Validates currency code (ISO 4217 format)
 pub fn validate_currency(currency: &str) -> Result<()> {
     let currency_regex = Regex::new(r"^[A-Z]{3}$").map_err(|e| {
@@ -39,26 +55,22 @@ pub fn validate_currency(currency: &str) -> Result<()> {
 }
 
 /// Validates amount (positive number with reasonable limits)
-pub fn validate_amount(amount: f64) -> Result<()> {
-    if amount <= 0.0 {
+///
+/// Decimal places are already bounded by the currency when the amount is parsed.
+pub fn validate_amount(amount: &Money) -> Result<()> {
+    if !amount.is_positive() {
         return Err(AppError::Validation(
             "Amount must be greater than 0".to_string()
         ));
     }
     
-    if amount > 1_000_000.0 {
+    let limit = Money::from_major(1_000_000, amount.currency())?;
+    if amount.minor() > limit.minor() {
         return Err(AppError::Validation(
             "Amount exceeds maximum limit of 1,000,000".to_string()
         ));
     }
     
-    // Check for reasonable decimal places (max 2 for most currencies)
-    if (amount * 100.0).fract() != 0.0 {
-        return Err(AppError::Validation(
-            "Amount cannot have more than 2 decimal places".to_string()
-        ));
-    }
-    
     Ok(())
 }
 
@@ -129,12 +141,10 @@ pub fn sanitize_input(input: &str) -> String {
 }
 
 /// Validates and parses amount from string
-pub fn parse_and_validate_amount(amount_str: &str) -> Result<f64> {
-    let amount = f64::from_str(amount_str).map_err(|_| {
-        AppError::Validation(format!("Invalid amount format: {}", amount_str))
-    })?;
+pub fn parse_and_validate_amount(amount_str: &str, currency: Currency) -> Result<Money> {
+    let amount = Money::parse(amount_str, currency)?;
     
-    validate_amount(amount)?;
+    validate_amount(&amount)?;
     Ok(amount)
 }
 
@@ -151,6 +161,15 @@ mod tests {
         assert!(validate_phone_number("invalid").is_err());
     }
 
+    #[test]
+    fn test_normalize_wa_id() {
+        assert_eq!(normalize_wa_id("254712345678"), "+254712345678");
+        assert_eq!(normalize_wa_id("+254712345678"), "+254712345678");
+        assert!(validate_phone_number(&normalize_wa_id("254712345678")).is_ok());
+        assert_eq!(normalize_twilio_address("whatsapp:+254712345678"), "+254712345678");
+        assert_eq!(normalize_twilio_address("+254712345678"), "+254712345678");
+    }
+
     #[test]
     fn test_validate_currency() {
         assert!(validate_currency("USD").is_ok());
@@ -162,12 +181,14 @@ mod tests {
 
     #[test]
     fn test_validate_amount() {
-        assert!(validate_amount(100.0).is_ok());
-        assert!(validate_amount(0.01).is_ok());
-        assert!(validate_amount(0.0).is_err());
-        assert!(validate_amount(-10.0).is_err());
-        assert!(validate_amount(1_000_001.0).is_err());
-        assert!(validate_amount(100.123).is_err());
+        let kes = |s: &str| parse_and_validate_amount(s, Currency::Kes);
+        assert!(kes("100").is_ok());
+        assert!(kes("0.01").is_ok());
+        assert!(kes("0").is_err());
+        assert!(kes("-10").is_err());
+        assert!(kes("1000001").is_err());
+        assert!(kes("100.123").is_err());
+        assert!(parse_and_validate_amount("0.00000001", Currency::Btc).is_ok());
     }
 
     #[test]
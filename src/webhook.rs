@@ -1,11 +1,12 @@
 use axum::{
+    body::Bytes,
     extract::{Query, State},
-    http::HeaderMap,
+    http::{header, HeaderMap},
     response::Json,
 };
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 // Webhook handling module for the BitSacco WhatsApp Bot
 // 
@@ -17,11 +18,33 @@ use tracing::{error, info};
 // - Message sending functionality
 
 use crate::{
+    channel::{Inbound, InboundContent, InboundRequest, MessagingChannel, OutboundMedia},
+    confirmation::{describe_action, describe_costs, BeginOutcome, ConfirmOutcome},
+    conversation::{ChamaOption, ConversationSession, ConversationStep, GuidedFlow, Slot},
+    delivery::send_fallback,
     error::{AppError, Result},
-    monitoring::{HealthStatus, SystemMetrics},
-    // Rate limiting removed - using simple validation instead
-    types::{AppState, BotCommand, HealthResponse, WhatsAppSendResponse, WhatsAppWebhook},
-    validation::{validate_message, validate_phone_number, validate_amount, validate_currency},
+    interactive::InteractiveMessage,
+    lightning::LightningWithdrawal,
+    limits::LimitKind,
+    money::{format_totals, Currency, Money, Sats},
+    mpesa::{complete_deposit, validate_outcome, PendingStkPush, StkOutcome},
+    outbound::sent_or_queued,
+    pin::{Pin, PinCheck},
+    services::{
+        conversion::{format_rates, rate_for, ExchangeRate},
+        whatsapp,
+    },
+    qr::render_invoice_qr,
+    rate_limit::CommandClass,
+    settlement::{apply_status, OutstandingInvoice},
+    templates::{ProactiveMessage, TemplateMessage},
+    timezone::format_local_timestamp,
+    types::{
+        AppState, BotCommand, HealthResponse, LightningPaymentResponse, LightningPaymentStatus, MpesaCallback, PinResetNotification,
+        PriceHistory, PriceRange, WhatsAppSendResponse, WhatsAppStatus,
+    },
+    validation::{normalize_wa_id, validate_message, validate_phone_number, validate_amount},
+    withdrawal::{WithdrawalMethod, WithdrawalPlan, WithdrawalRoute},
 };
 
 #[derive(Debug, Deserialize)]
@@ -47,19 +70,18 @@ pub struct SendMessageRequest {
 /// 
 /// # Arguments
 /// * `state` - Application state containing services and configuration
-/// * `headers` - HTTP headers for webhook signature verification
 /// * `query` - Query parameters for webhook verification
-/// * `payload` - JSON payload containing the webhook data
+/// * `headers` - Request headers, including `X-Hub-Signature-256`
+/// * `body` - Raw webhook body, verified before it is parsed
 /// 
 /// # Returns
 /// * `Result<String>` - Success response or error
 pub async fn handle_webhook(
     State(state): State<AppState>,
-    headers: HeaderMap,
     Query(query): Query<WebhookQuery>,
-    Json(payload): Json<serde_json::Value>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<String> {
-    // Rate limiting simplified - using basic validation
     // Handle webhook verification
     if let (Some(mode), Some(challenge), Some(token)) = (
         &query.hub_mode,
@@ -71,92 +93,267 @@ pub async fn handle_webhook(
             .verify_webhook(mode, token, challenge);
     }
 
-    // Verify webhook signature for incoming messages
-    if let Some(signature) = headers.get("x-hub-signature-256") {
-        let signature_str = signature.to_str().map_err(|_| {
-            AppError::Validation("Invalid signature header".to_string())
-        })?;
-        
-        let payload_str = serde_json::to_string(&payload)?;
-        state.whatsapp_service.verify_webhook_signature(&payload_str, signature_str)?;
-    } else {
-        return Err(AppError::Validation("Missing webhook signature".to_string()));
-    }
+    let channel = &state.whatsapp_service;
+    channel.verify_inbound(&InboundRequest {
+        url: "",
+        headers: &headers,
+        body: &body,
+    })?;
 
-    // Handle incoming messages
-    info!(
-        "Received webhook payload: {}",
-        serde_json::to_string(&payload)?
-    );
+    // Handle incoming messages. Bodies carry PINs, so only their size is logged
+    info!("Received webhook payload of {} bytes", body.len());
+    let inbound = channel.normalize_inbound(&body)?;
+    process_inbound(&state, channel.name(), inbound).await?;
 
-    let webhook: WhatsAppWebhook = serde_json::from_value(payload)
-        .map_err(|e| AppError::Validation(format!("Invalid webhook payload: {}", e)))?;
-
-    for entry in webhook.entry {
-        for change in entry.changes {
-            if let Some(messages) = change.value.messages {
-                for message in messages {
-                    let phone_number = &message.from;
-                    
-                    // Validate phone number
-                    validate_phone_number(phone_number)?;
-
-                    // Process text messages
-                    if let Some(text) = message.text {
-                        let message_text = &text.body;
-                        validate_message(message_text)?;
-
-                        info!("Processing text message from {}: {}", phone_number, message_text);
-
-                        let state_clone = state.clone();
-                        let phone_clone = phone_number.clone();
-                        let message_clone = message_text.clone();
-
-                        tokio::spawn(async move {
-                            if let Err(e) =
-                                process_text_message(state_clone, phone_clone, message_clone).await
-                            {
-                                error!("Error processing text message: {}", e);
-                            }
-                        });
+    Ok("OK".to_string())
+}
+
+/// Handle a message posted by Twilio.
+///
+/// Twilio signs the URL it was configured with, so the signature is checked
+/// against `TWILIO_WEBHOOK_URL` rather than the URL the request arrived on,
+/// which differs behind a proxy. Replies to the sender go out through Twilio.
+/// The empty TwiML response tells Twilio not to reply itself.
+pub async fn handle_twilio_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<([(header::HeaderName, &'static str); 1], &'static str)> {
+    let channel = &state.twilio_service;
+    channel.verify_inbound(&InboundRequest {
+        url: &state.config.twilio_webhook_url,
+        headers: &headers,
+        body: &body,
+    })?;
+
+    info!("Received Twilio webhook payload of {} bytes", body.len());
+    let inbound = channel.normalize_inbound(&body)?;
+    process_inbound(&state, channel.name(), inbound).await?;
+
+    Ok(([(header::CONTENT_TYPE, "text/xml")], "<Response></Response>"))
+}
+
+/// Run the messages from a verified webhook through the command pipeline
+/// and record its delivery reports. Replies go out on `channel`.
+pub async fn process_inbound(state: &AppState, channel: &'static str, inbound: Inbound) -> Result<()> {
+    for message in inbound.messages {
+        let phone_number = &message.from;
+
+        // Validate phone number
+        validate_phone_number(phone_number)?;
+
+        // Providers redeliver messages they think we missed
+        if !state.processed_messages.first_delivery(&message.id).await {
+            info!("Skipping redelivered message {} from {}", message.id, phone_number);
+            continue;
+        }
+        state.messenger.record_inbound(phone_number, channel).await;
+        // Replies are free-form for 24 hours after the user sent this
+        state.service_windows.record_inbound(channel, phone_number, message.sent_at).await;
+        if !within_rate_limit(state, phone_number, CommandClass::Query).await {
+            continue;
+        }
+        // BitSacco calls made for this message carry keys derived from its ID
+        let mut state = state.clone();
+        state.bitsacco_service = state.bitsacco_service.for_message(&message.id);
+        let phone_number = phone_number.clone();
+
+        match message.content {
+            InboundContent::Text(text) => {
+                validate_message(&text)?;
+                info!("Processing text message {} from {}", message.id, phone_number);
+
+                tokio::spawn(async move {
+                    if let Err(e) = process_text_message(state, phone_number, text).await {
+                        error!("Error processing text message: {}", e);
                     }
-                    // Process voice messages
-                    else if let Some(voice) = message.voice {
-                        info!("Processing voice message from {}: {}", phone_number, voice.id);
-
-                        let state_clone = state.clone();
-                        let phone_clone = phone_number.clone();
-                        let voice_clone = voice.clone();
-
-                        tokio::spawn(async move {
-                            if let Err(e) =
-                                process_voice_message(state_clone, phone_clone, voice_clone).await
-                            {
-                                error!("Error processing voice message: {}", e);
-                            }
-                        });
+                });
+            }
+            // Tapped buttons and list rows reply with the text they stand for
+            InboundContent::Reply(reply) => {
+                info!("Processing interactive reply {} from {}", message.id, phone_number);
+
+                tokio::spawn(async move {
+                    if let Err(e) = process_text_message(state, phone_number, reply).await {
+                        error!("Error processing interactive reply: {}", e);
                     }
-                    // Process audio messages
-                    else if let Some(audio) = message.audio {
-                        info!("Processing audio message from {}: {}", phone_number, audio.id);
-
-                        let state_clone = state.clone();
-                        let phone_clone = phone_number.clone();
-                        let audio_clone = audio.clone();
-
-                        tokio::spawn(async move {
-                            if let Err(e) =
-                                process_audio_message(state_clone, phone_clone, audio_clone).await
-                            {
-                                error!("Error processing audio message: {}", e);
-                            }
-                        });
+                });
+            }
+            InboundContent::Voice(voice) => {
+                info!("Processing voice message {} from {}", message.id, phone_number);
+
+                tokio::spawn(async move {
+                    if let Err(e) = process_voice_message(state, phone_number, voice).await {
+                        error!("Error processing voice message: {}", e);
                     }
-                }
+                });
+            }
+            InboundContent::Audio(audio) => {
+                info!("Processing audio message {} from {}", message.id, phone_number);
+
+                tokio::spawn(async move {
+                    if let Err(e) = process_audio_message(state, phone_number, audio).await {
+                        error!("Error processing audio message: {}", e);
+                    }
+                });
+            }
+            InboundContent::Unsupported(kind) => {
+                info!("Ignoring {} message {} from {}", kind, message.id, phone_number);
             }
         }
     }
 
+    // Delivery reports for messages we sent
+    for status in &inbound.statuses {
+        track_delivery_status(state, status).await;
+    }
+    Ok(())
+}
+
+/// Record a delivery report, sending a failed critical notification again
+/// through Twilio
+async fn track_delivery_status(state: &AppState, status: &WhatsAppStatus) {
+    let Some(failed) = state.deliveries.apply_status(status).await else {
+        return;
+    };
+    let deliveries = state.deliveries.clone();
+    let twilio_service = state.twilio_service.clone();
+    tokio::spawn(async move {
+        if let Err(e) = send_fallback(&deliveries, &twilio_service, &failed).await {
+            error!("Failed to send fallback for message {}: {}", failed.message_id, e);
+        }
+    });
+}
+
+/// Handle a Lightning payment status pushed by BitSacco.
+///
+/// The body must carry a valid `X-BitSacco-Signature`. Statuses for unknown
+/// or already announced invoices are acknowledged and ignored, so BitSacco
+/// can safely retry.
+pub async fn handle_lightning_callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<String> {
+    let signature = headers
+        .get("x-bitsacco-signature")
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AppError::Validation("Missing callback signature".to_string()))?;
+    state
+        .bitsacco_service
+        .verify_callback_signature(&body, signature)?;
+
+    let status: LightningPaymentStatus = serde_json::from_slice(&body)
+        .map_err(|e| AppError::Validation(format!("Invalid callback payload: {}", e)))?;
+    info!("Lightning payment {} is {}", status.payment_hash, status.status);
+
+    apply_status(&state.lightning_invoices, &state.messenger, &status).await?;
+    Ok("OK".to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MpesaCallbackQuery {
+    pub token: Option<String>,
+}
+
+/// Handle an M-Pesa STK Push result from Daraja.
+///
+/// Daraja does not sign callbacks, so the registered callback URL carries the
+/// callback secret as `?token=`. Request logging leaves query strings out so
+/// the secret stays out of the logs. The result is checked against the
+/// pending deposit with the same `CheckoutRequestID`; deposits that are no
+/// longer pending are acknowledged without changes, so repeated callbacks are
+/// safe.
+pub async fn handle_mpesa_callback(
+    State(state): State<AppState>,
+    Query(query): Query<MpesaCallbackQuery>,
+    body: Bytes,
+) -> Result<Json<serde_json::Value>> {
+    let token = query
+        .token
+        .ok_or_else(|| AppError::Validation("Missing callback token".to_string()))?;
+    state.bitsacco_service.verify_callback_token(&token)?;
+
+    let callback: MpesaCallback = serde_json::from_slice(&body)
+        .map_err(|e| AppError::Validation(format!("Invalid callback payload: {}", e)))?;
+    let callback = callback.body.stk_callback;
+    info!(
+        "M-Pesa checkout {} finished with result code {}",
+        callback.checkout_request_id, callback.result_code
+    );
+
+    let transaction = state
+        .bitsacco_service
+        .get_transaction_by_reference(&callback.checkout_request_id)
+        .await?;
+    let accepted = Json(serde_json::json!({"ResultCode": 0, "ResultDesc": "Accepted"}));
+    if transaction.status != "pending" {
+        info!(
+            "Ignoring M-Pesa callback for transaction {} which is already {}",
+            transaction.id, transaction.status
+        );
+        state.mpesa_deposits.take(&callback.checkout_request_id).await;
+        return Ok(accepted);
+    }
+
+    let outcome = StkOutcome::from_callback(&callback)?;
+    validate_outcome(&transaction, &callback.checkout_request_id, &outcome)?;
+    let user = state.bitsacco_service.get_user_by_id(&transaction.user_id).await?;
+
+    // Stop querying the push while its callback is applied. Daraja does not
+    // send callbacks again, so if applying it fails the status queries are
+    // left to resolve the deposit.
+    let claimed = state.mpesa_deposits.take(&callback.checkout_request_id).await;
+    if let Err(e) = complete_deposit(
+        &state.bitsacco_service.for_stk_push(&callback.checkout_request_id),
+        &state.messenger,
+        &state.cache,
+        &user.phone_number,
+        &transaction,
+        &outcome,
+    )
+    .await
+    {
+        if let Some(push) = claimed.or_else(|| PendingStkPush::new(&user.phone_number, &transaction)) {
+            state.mpesa_deposits.track(push).await;
+        }
+        return Err(e);
+    }
+
+    Ok(accepted)
+}
+
+/// Handle a PIN reset made in the BitSacco web app.
+///
+/// Signed like the Lightning callback. The user has already proven who they
+/// are to the web app, so the bot simply clears the PIN and tells them.
+pub async fn handle_pin_reset_callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<String> {
+    let signature = headers
+        .get("x-bitsacco-signature")
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AppError::Validation("Missing callback signature".to_string()))?;
+    state
+        .bitsacco_service
+        .verify_callback_signature(&body, signature)?;
+
+    let reset: PinResetNotification = serde_json::from_slice(&body)
+        .map_err(|e| AppError::Validation(format!("Invalid callback payload: {}", e)))?;
+    let phone_number = normalize_wa_id(&reset.phone_number);
+
+    if state.pins.remove(&phone_number).await {
+        info!("PIN for {} reset from the web app", phone_number);
+        // Anything waiting for the old PIN has to be started again
+        state.pending_actions.cancel(&phone_number).await;
+        let notice = ProactiveMessage::new(
+            "🔓 *PIN Reset*\n\nYour WhatsApp PIN was reset from the BitSacco web app. Send `set pin` to choose a new one.",
+            TemplateMessage::new("pin_reset"),
+        );
+        sent_or_queued(state.messenger.send_proactive(&phone_number, &notice).await)?;
+    }
     Ok("OK".to_string())
 }
 
@@ -166,53 +363,472 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
     
     let command = BotCommand::parse(&message);
 
+    // Replies to an open guided conversation fill its next slot; any other
+    // recognised command means the user has moved on
+    if state.conversations.get(&phone_number).await.is_some() {
+        if matches!(command, BotCommand::Unknown(_) | BotCommand::Cancel) {
+            return continue_conversation(state, phone_number, message).await;
+        }
+        state.conversations.end(&phone_number).await;
+    }
+
+    if let BotCommand::Unknown(_) = command {
+        if Pin::looks_like(&message) && awaiting_pin(&state, &phone_number).await {
+            return confirm_with_pin(state, phone_number, Pin::parse(&message)?).await;
+        }
+        if let Some(session) = ConversationSession::start(&message) {
+            if let Some(refusal) = refuse_pin_flow(&state, &phone_number, session.flow).await {
+                state
+                    .messenger
+                    .send_message(&phone_number, &refusal)
+                    .await?;
+                return Ok(());
+            }
+            return advance_conversation(state, phone_number, session).await;
+        }
+    }
+
+    dispatch_command(state, phone_number, command).await
+}
+
+/// Charge a message to the sender's budget, telling them to slow down the
+/// first time it is exceeded. Returns `false` when the message should be
+/// dropped.
+async fn within_rate_limit(state: &AppState, phone_number: &str, class: CommandClass) -> bool {
+    let Err(e) = state
+        .rate_limiter
+        .check(phone_number, class, chrono::Utc::now())
+        .await
+    else {
+        return true;
+    };
+    info!("Dropping message from {}: {}", phone_number, e);
+    if state.rate_limiter.take_notice(phone_number).await {
+        let notice = "🐢 *Slow Down*\n\nYou're sending messages faster than we can handle them. Please wait a minute before trying again; messages sent in the meantime will be ignored.";
+        if let Err(e) = state.messenger.send_message(phone_number, notice).await {
+            error!("Failed to send rate limit notice to {}: {}", phone_number, e);
+        }
+    }
+    false
+}
+
+/// Route a complete command through confirmation or straight to execution
+async fn dispatch_command(state: AppState, phone_number: String, command: BotCommand) -> Result<()> {
+    if CommandClass::of(&command) == CommandClass::Money
+        && !within_rate_limit(&state, &phone_number, CommandClass::Money).await
+    {
+        return Ok(());
+    }
+    match command {
+        BotCommand::Confirm if awaiting_pin(&state, &phone_number).await => {
+            state
+                .messenger
+                .send_message(&phone_number, "🔐 Please reply with your *PIN* to confirm, or *NO* to cancel.")
+                .await?;
+            Ok(())
+        }
+        BotCommand::Confirm => confirm_pending_action(state, phone_number).await,
+        BotCommand::Cancel => cancel_pending_action(&state, &phone_number).await,
+        command if command.requires_confirmation() => {
+            request_confirmation(&state, &phone_number, command).await
+        }
+        command => {
+            if reject_over_limit(&state, &phone_number, &command).await? {
+                return Ok(());
+            }
+            execute_command(state, phone_number, command).await
+        }
+    }
+}
+
+/// Check a command against the user's transaction limits, telling them and
+/// returning `true` when it goes over or its amount cannot be worked out
+async fn reject_over_limit(state: &AppState, phone_number: &str, command: &BotCommand) -> Result<bool> {
+    let (kind, amount) = match LimitKind::of(command) {
+        Ok(Some(limited)) => limited,
+        Ok(None) => return Ok(false),
+        Err(e) => {
+            info!("Rejected unmeasurable command for {}: {}", phone_number, e);
+            state
+                .messenger
+                .send_error_message(phone_number, &e.to_string())
+                .await?;
+            return Ok(true);
+        }
+    };
+    let result = async {
+        let user = state
+            .bitsacco_service
+            .get_user_by_phone(phone_number, &state.cache)
+            .await?;
+        let history = state.bitsacco_service.get_user_transactions(&user.id).await?;
+        state.limits.check(kind, amount, &history, chrono::Utc::now())
+    }
+    .await;
+
+    let Err(e) = result else {
+        return Ok(false);
+    };
+    info!("Rejected {} of {} for {}: {}", kind, amount, phone_number, e);
+    state
+        .messenger
+        .send_error_message(phone_number, &e.to_string())
+        .await?;
+    Ok(true)
+}
+
+/// Whether the user's pending action has to be confirmed with their PIN
+async fn awaiting_pin(state: &AppState, phone_number: &str) -> bool {
+    match state.pending_actions.get(phone_number).await {
+        Some(action) => action.command.requires_pin() && state.pins.has_pin(phone_number).await,
+        None => false,
+    }
+}
+
+/// Confirm the pending action with a PIN reply. Locking the PIN also
+/// discards the action.
+async fn confirm_with_pin(state: AppState, phone_number: String, pin: Pin) -> Result<()> {
+    let message = match state.pins.verify(&phone_number, &pin, chrono::Utc::now()).await {
+        PinCheck::Correct | PinCheck::NotSet => return confirm_pending_action(state, phone_number).await,
+        PinCheck::Incorrect { attempts_left } => format!(
+            "❌ Incorrect PIN. {} attempt{} left before your PIN is locked.",
+            attempts_left,
+            if attempts_left == 1 { "" } else { "s" }
+        ),
+        PinCheck::LockedOut { until } => {
+            let discarded = state
+                .pending_actions
+                .cancel(&phone_number)
+                .await
+                .map(|action| format!("\n\n{} was cancelled.", action.summary))
+                .unwrap_or_default();
+            format!("{}{}", pin_locked_message(until), discarded)
+        }
+    };
+    state
+        .messenger
+        .send_message(&phone_number, &message)
+        .await?;
+    Ok(())
+}
+
+fn pin_locked_message(until: chrono::DateTime<chrono::Utc>) -> String {
+    let minutes = (until - chrono::Utc::now()).num_minutes().max(0) + 1;
+    format!(
+        "🔒 *PIN Locked*\n\nToo many wrong attempts. Please try again in {} minutes, or send `reset pin` if you have forgotten it.",
+        minutes
+    )
+}
+
+/// Why a PIN flow cannot start for this user, if it cannot
+async fn refuse_pin_flow(state: &AppState, phone_number: &str, flow: GuidedFlow) -> Option<String> {
+    let has_pin = state.pins.has_pin(phone_number).await;
+    match flow {
+        GuidedFlow::SetPin if has_pin => Some(
+            "You already have a PIN. Send `change pin` to change it or `remove pin` to turn it off.".to_string(),
+        ),
+        GuidedFlow::ChangePin | GuidedFlow::RemovePin if !has_pin => {
+            Some("You have not set a PIN yet. Send `set pin` to set one.".to_string())
+        }
+        GuidedFlow::ChangePin | GuidedFlow::RemovePin => state
+            .pins
+            .locked_until(phone_number, chrono::Utc::now())
+            .await
+            .map(pin_locked_message),
+        _ => None,
+    }
+}
+
+/// Check the current PIN for a change or removal, telling the user when it
+/// is wrong. Returns `true` when it matched.
+async fn check_current_pin(state: &AppState, phone_number: &str, current: &Pin) -> Result<bool> {
+    let message = match state.pins.verify(phone_number, current, chrono::Utc::now()).await {
+        PinCheck::Correct => return Ok(true),
+        PinCheck::NotSet => "You have not set a PIN yet. Send `set pin` to set one.".to_string(),
+        PinCheck::Incorrect { attempts_left } => format!(
+            "Incorrect PIN, nothing was changed. {} attempt{} left before your PIN is locked.",
+            attempts_left,
+            if attempts_left == 1 { "" } else { "s" }
+        ),
+        PinCheck::LockedOut { until } => pin_locked_message(until),
+    };
+    state
+        .messenger
+        .send_error_message(phone_number, &message)
+        .await?;
+    Ok(false)
+}
+
+/// Handle a reply while a guided conversation is open
+async fn continue_conversation(state: AppState, phone_number: String, message: String) -> Result<()> {
+    let Some(mut session) = state.conversations.get(&phone_number).await else {
+        return dispatch_command(state, phone_number, BotCommand::parse(&message)).await;
+    };
+
+    match message.trim().to_lowercase().as_str() {
+        "cancel" | "stop" | "no" | "n" => {
+            state.conversations.end(&phone_number).await;
+            let response = format!("🚫 *{} Cancelled*\n\nNothing was sent.", session.flow.title());
+            state
+                .messenger
+                .send_message(&phone_number, &response)
+                .await?;
+            return Ok(());
+        }
+        "back" => {
+            if !session.back() {
+                state
+                    .messenger
+                    .send_message(&phone_number, "You are already at the first step.")
+                    .await?;
+            }
+        }
+        _ => {
+            if let Err(hint) = session.answer(&message) {
+                state.conversations.save(&phone_number, session).await;
+                state
+                    .messenger
+                    .send_message(&phone_number, &format!("⚠️ {}", hint))
+                    .await?;
+                return Ok(());
+            }
+        }
+    }
+
+    advance_conversation(state, phone_number, session).await
+}
+
+/// Ask for the next missing slot, or resume the command once complete
+async fn advance_conversation(
+    state: AppState,
+    phone_number: String,
+    mut session: ConversationSession,
+) -> Result<()> {
+    match session.next_step() {
+        ConversationStep::Ask(slot) => {
+            if slot == Slot::Chama && session.chama_options.is_empty() {
+                let chamas = get_user_chamas(&state, &phone_number).await?;
+                if chamas.is_empty() {
+                    state.conversations.end(&phone_number).await;
+                    state
+                        .messenger
+                        .send_message(&phone_number, "You are not part of any chama groups yet.")
+                        .await?;
+                    return Ok(());
+                }
+                session.chama_options = chamas
+                    .into_iter()
+                    .map(|c| ChamaOption { id: c.id, name: c.name })
+                    .collect();
+            }
+
+            let prompt = session.prompt(slot);
+            let choices = session.interactive_prompt(slot);
+            state.conversations.save(&phone_number, session).await;
+            match choices {
+                Some(choices) => state.messenger.send_interactive(&phone_number, &choices).await?,
+                None => state.messenger.send_message(&phone_number, &prompt).await?,
+            };
+            Ok(())
+        }
+        ConversationStep::Complete(command) => {
+            state.conversations.end(&phone_number).await;
+            dispatch_command(state, phone_number, command).await
+        }
+    }
+}
+
+/// Park a money-moving command and ask the user to confirm it
+async fn request_confirmation(state: &AppState, phone_number: &str, command: BotCommand) -> Result<()> {
+    if let Err(e) = validate_command_inputs(&command) {
+        state
+            .messenger
+            .send_error_message(phone_number, &e.to_string())
+            .await?;
+        return Ok(());
+    }
+    if reject_over_limit(state, phone_number, &command).await? {
+        return Ok(());
+    }
+
+    let user = state
+        .bitsacco_service
+        .get_user_by_phone(phone_number, &state.cache)
+        .await?;
+    let mpesa_phone = user.mpesa_phone.as_deref().unwrap_or(phone_number);
+
+    let Some(summary) = describe_action(&command, mpesa_phone) else {
+        return execute_command(state.clone(), phone_number.to_string(), command).await;
+    };
+    let fee = quote_fee(state, &user.id, &command).await;
+    let costs = describe_costs(&command, fee)
+        .map(|costs| format!("\n\n{}", costs))
+        .unwrap_or_default();
+
+    let has_pin = state.pins.has_pin(phone_number).await;
+    let reply = |pin_required: bool| if pin_required { "Reply with your *PIN*" } else { "Reply *YES*" };
+
+    // The prompt is for whichever action is pending, which may not be this one
+    let (message, pin_required) = match state
+        .pending_actions
+        .begin(phone_number, command, summary)
+        .await
+    {
+        BeginOutcome::Started(action) => {
+            let pin_required = action.command.requires_pin() && has_pin;
+            let message = format!(
+                "🔐 *Please Confirm*\n\n{}?{}\n\n{} within {} minutes to continue, or *NO* to cancel.",
+                action.summary,
+                costs,
+                reply(pin_required),
+                state.pending_actions.ttl_minutes()
+            );
+            (message, pin_required)
+        }
+        BeginOutcome::AlreadyPending(existing) => {
+            let pin_required = existing.command.requires_pin() && has_pin;
+            let message = format!(
+                "⏳ *Action Already Pending*\n\nYou still have a request waiting for confirmation:\n{}\n\n{} to confirm it or *NO* to cancel it before starting another.",
+                existing.summary,
+                reply(pin_required)
+            );
+            (message, pin_required)
+        }
+    };
+
+    // A PIN has to be typed, so there is nothing to tap
+    if pin_required {
+        state
+            .messenger
+            .send_message(phone_number, &message)
+            .await?;
+    } else {
+        state
+            .messenger
+            .send_interactive(phone_number, &InteractiveMessage::yes_no(message))
+            .await?;
+    }
+    Ok(())
+}
+
+/// BitSacco's fee for a withdrawal, for the confirmation prompt. A failed
+/// quote is logged and the prompt says no fee could be quoted.
+async fn quote_fee(state: &AppState, user_id: &str, command: &BotCommand) -> Option<Money> {
+    let BotCommand::Withdraw { amount, method, .. } = command else {
+        return None;
+    };
+    let method = WithdrawalMethod::parse(method.as_deref()).ok()?;
+    match state.bitsacco_service.quote_withdrawal(user_id, *amount, method.code()).await {
+        Ok(quote) => Some(quote.fee),
+        Err(e) => {
+            warn!("Failed to quote a {} withdrawal fee for {}: {}", method, user_id, e);
+            None
+        }
+    }
+}
+
+/// Run the user's pending action after a `YES` reply
+async fn confirm_pending_action(state: AppState, phone_number: String) -> Result<()> {
+    match state.pending_actions.confirm(&phone_number).await {
+        ConfirmOutcome::Confirmed(action) => {
+            // Checked again: other transactions may have used the allowance meanwhile
+            if reject_over_limit(&state, &phone_number, &action.command).await? {
+                return Ok(());
+            }
+            info!("Executing confirmed action for {}: {}", phone_number, action.summary);
+            execute_command(state, phone_number, action.command).await
+        }
+        ConfirmOutcome::Expired(action) => {
+            let message = format!(
+                "⌛ *Confirmation Expired*\n\n{} was not confirmed in time and has been discarded.\n\nPlease send the command again.",
+                action.summary
+            );
+            state
+                .messenger
+                .send_message(&phone_number, &message)
+                .await?;
+            Ok(())
+        }
+        ConfirmOutcome::NothingPending => {
+            state
+                .messenger
+                .send_message(&phone_number, "There is nothing waiting for confirmation.")
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Discard the user's pending action after a `NO` reply
+async fn cancel_pending_action(state: &AppState, phone_number: &str) -> Result<()> {
+    let message = match state.pending_actions.cancel(phone_number).await {
+        Some(action) => format!("🚫 *Cancelled*\n\n{} will not be carried out.", action.summary),
+        None => "There is nothing waiting for confirmation.".to_string(),
+    };
+    state
+        .messenger
+        .send_message(phone_number, &message)
+        .await?;
+    Ok(())
+}
+
+/// Check amounts, currencies and recipients before asking for confirmation,
+/// so users are never asked to confirm something that will be rejected
+fn validate_command_inputs(command: &BotCommand) -> Result<()> {
+    match command {
+        BotCommand::Withdraw { amount, method, destination } => {
+            validate_amount(amount)?;
+            WithdrawalPlan::resolve(*amount, method.as_deref(), destination.as_deref(), chrono::Utc::now())?;
+            Ok(())
+        }
+        BotCommand::ContributeChama { amount, .. } => validate_amount(amount),
+        BotCommand::LightningWithdraw { amount, destination } => {
+            let withdrawal = LightningWithdrawal::resolve(destination, *amount, chrono::Utc::now())?;
+            validate_amount(&withdrawal.amount)
+        }
+        BotCommand::Transfer { amount, recipient } => {
+            validate_amount(amount)?;
+            validate_phone_number(recipient)
+        }
+        BotCommand::BuyShares { count, .. } if *count == 0 => Err(AppError::Validation(
+            "Share count must be greater than 0".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+async fn execute_command(state: AppState, phone_number: String, command: BotCommand) -> Result<()> {
     match command {
         BotCommand::Help => {
             state
-                .whatsapp_service
+                .messenger
                 .send_help_message(&phone_number)
                 .await?;
         }
         BotCommand::Balance => match get_user_balance(&state, &phone_number).await {
-            Ok((savings, btc_balance, currency)) => {
+            Ok((savings, btc_balance, rates)) => {
                 state
-                    .whatsapp_service
-                    .send_balance_message(&phone_number, savings, btc_balance, &currency)
+                    .messenger
+                    .send_balance_message(&phone_number, &savings, btc_balance, &rates)
                     .await?;
             }
             Err(e) => {
                 state
-                    .whatsapp_service
+                    .messenger
                     .send_error_message(&phone_number, &e.to_string())
                     .await?;
             }
         },
-        BotCommand::Savings => match get_user_savings(&state, &phone_number).await {
-            Ok(savings) => {
-                let total_kes: f64 = savings.iter().map(|s| s.amount).sum();
-                let total_sats = (total_kes * 100_000_000.0) as u64; // Convert KES to sats
-                
-                let message = format!(
-                    "💰 *Your Savings*\n\nTotal: {} sats ({:.2} KES)\n\nDetails:\n{}",
-                    total_sats,
-                    total_kes,
-                    savings
-                        .iter()
-                        .map(|s| {
-                            let sats = (s.amount * 100_000_000.0) as u64;
-                            format!("• {} sats ({:.2} {}) - {}", sats, s.amount, s.currency, s.id)
-                        })
-                        .collect::<Vec<_>>()
-                        .join("\n")
-                );
+        BotCommand::Savings => match get_savings_summary(&state, &phone_number).await {
+            Ok(message) => {
                 state
-                    .whatsapp_service
+                    .messenger
                     .send_message(&phone_number, &message)
                     .await?;
             }
             Err(e) => {
                 state
-                    .whatsapp_service
+                    .messenger
                     .send_error_message(&phone_number, &e.to_string())
                     .await?;
             }
@@ -221,7 +837,7 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
             Ok(chamas) => {
                 if chamas.is_empty() {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_message(&phone_number, "You are not part of any chama groups yet.")
                         .await?;
                 } else {
@@ -230,24 +846,23 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                         chamas
                             .iter()
                             .map(|c| format!(
-                                "• {} - {:.2} {} ({} members)",
+                                "• {} - {} ({} members)",
                                 c.name,
                                 c.total_savings,
-                                c.currency,
                                 c.members.len()
                             ))
                             .collect::<Vec<_>>()
                             .join("\n")
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_message(&phone_number, &message)
                         .await?;
                 }
             }
             Err(e) => {
                 state
-                    .whatsapp_service
+                    .messenger
                     .send_error_message(&phone_number, &e.to_string())
                     .await?;
             }
@@ -255,32 +870,68 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
         BotCommand::BtcPrice => match state.btc_service.get_btc_price_usd(&state.cache).await {
             Ok(price) => {
                 state
-                    .whatsapp_service
+                    .messenger
                     .send_btc_price_message(
                         &phone_number,
                         price.price,
                         price.change_24h,
                         &price.currency,
+                        &price.sources,
                     )
                     .await?;
             }
             Err(e) => {
                 state
-                    .whatsapp_service
+                    .messenger
                     .send_error_message(&phone_number, &e.to_string())
                     .await?;
             }
         },
-        BotCommand::Deposit { amount, currency, method } => {
-            validate_amount(amount)?;
-            validate_currency(&currency)?;
+        BotCommand::BtcPriceHistory { range, currency } => {
+            match state
+                .btc_service
+                .get_price_history(currency.code(), range, &state.cache)
+                .await
+            {
+                Ok(history) => {
+                    let summary = price_history_summary(&history, range);
+                    // Fall back to the text summary if the chart can't be drawn or sent
+                    let sent = match crate::chart::render_price_chart(&history) {
+                        Ok(png_data) => state
+                            .messenger
+                            .send_media(&phone_number, OutboundMedia::png(png_data, "chart.png", Some(&summary)))
+                            .await
+                            .map_err(|e| error!("Failed to send price chart: {}", e))
+                            .is_ok(),
+                        Err(e) => {
+                            error!("Failed to render price chart: {}", e);
+                            false
+                        }
+                    };
+                    if !sent {
+                        state
+                            .messenger
+                            .send_message(&phone_number, &summary)
+                            .await?;
+                    }
+                }
+                Err(e) => {
+                    state
+                        .messenger
+                        .send_error_message(&phone_number, &e.to_string())
+                        .await?;
+                }
+            }
+        }
+        BotCommand::Deposit { amount, method } => {
+            validate_amount(&amount)?;
             
             // Restrict deposits to KES only
-            if currency.to_uppercase() != "KES" {
+            if amount.currency() != Currency::Kes {
                 let error_message = "❌ *Deposit Error*\n\nOnly KES deposits are supported. Please use KES currency for deposits.\n\nExample: `deposit 100 KES`";
                 state
-                    .whatsapp_service
-                    .send_error_message(&phone_number, &error_message)
+                    .messenger
+                    .send_error_message(&phone_number, error_message)
                     .await?;
                 return Ok(());
             }
@@ -289,40 +940,37 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
             
             match payment_method {
                 "lightning" => {
-                    match create_lightning_deposit(&state, &phone_number, amount, &currency).await {
+                    match create_lightning_deposit(&state, &phone_number, amount).await {
                         Ok(lightning_response) => {
-                            let message = format!(
-                                "⚡ *Lightning Deposit Initiated!*\n\nAmount: {:.2} KES\nPayment Request: {}\n\n📱 *Scan the QR code or copy the payment request to your Lightning wallet to complete the deposit.*",
-                                amount, lightning_response.payment_request
-                            );
-                            state
-                                .whatsapp_service
-                                .send_success_message(&phone_number, &message)
-                                .await?;
+                            send_lightning_invoice(&state, &phone_number, amount, &lightning_response).await?;
                         }
                         Err(e) => {
                             state
-                                .whatsapp_service
+                                .messenger
                                 .send_error_message(&phone_number, &e.to_string())
                                 .await?;
                         }
                     }
                 }
                 _ => {
-                    match create_deposit(&state, &phone_number, amount, &currency).await {
+                    match create_deposit(&state, &phone_number, amount).await {
                         Ok(transaction) => {
+                            // Query the STK Push ourselves if its callback never arrives
+                            if let Some(push) = PendingStkPush::new(&phone_number, &transaction) {
+                                state.mpesa_deposits.track(push).await;
+                            }
                             let message = format!(
-                                "💰 *M-Pesa Deposit Initiated!*\n\nAmount: {:.2} KES\nTransaction ID: {}\nStatus: {}\n\n📱 *M-Pesa STK Push sent to your phone!*\n\nPlease check your phone and enter your M-Pesa PIN to complete the deposit.",
+                                "💰 *M-Pesa Deposit Initiated!*\n\nAmount: {}\nTransaction ID: {}\nStatus: {}\n\n📱 *M-Pesa STK Push sent to your phone!*\n\nPlease check your phone and enter your M-Pesa PIN to complete the deposit.",
                                 amount, transaction.id, transaction.status
                             );
                             state
-                                .whatsapp_service
+                                .messenger
                                 .send_success_message(&phone_number, &message)
                                 .await?;
                         }
                         Err(e) => {
                             state
-                                .whatsapp_service
+                                .messenger
                                 .send_error_message(&phone_number, &e.to_string())
                                 .await?;
                         }
@@ -330,53 +978,46 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                 }
             }
         }
-        BotCommand::Withdraw { amount, currency, method } => {
-            validate_amount(amount)?;
-            validate_currency(&currency)?;
-            
-            let payment_method = method.as_deref().unwrap_or("mpesa");
-            
-            match create_withdrawal(&state, &phone_number, amount, &currency).await {
-                Ok(transaction) => {
+        BotCommand::Withdraw { amount, method, destination } => {
+            validate_amount(&amount)?;
+
+            // Resolved again here: an invoice may have expired while the user confirmed
+            match create_withdrawal(&state, &phone_number, amount, method.as_deref(), destination.as_deref()).await {
+                Ok((plan, to, response)) => {
                     let message = format!(
-                        "💰 *Withdrawal Initiated!*\n\nAmount: {:.2} {}\nTransaction ID: {}\nStatus: {}\n\n📱 *Withdrawal will be processed.*",
-                        amount, currency, transaction.id, transaction.status
+                        "💰 *Withdrawal Initiated!*\n\nAmount: {}\nVia: {}\nTo: {}\nTransaction ID: {}\nStatus: {}\n\n📱 *Withdrawal will be processed.*",
+                        plan.amount, plan.method(), to, response.transaction_id, response.status
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_success_message(&phone_number, &message)
                         .await?;
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
             }
         }
-        BotCommand::Transfer {
-            amount,
-            currency,
-            recipient,
-        } => {
-            validate_amount(amount)?;
-            validate_currency(&currency)?;
+        BotCommand::Transfer { amount, recipient } => {
+            validate_amount(&amount)?;
             validate_phone_number(&recipient)?;
-            match create_transfer(&state, &phone_number, amount, &currency, &recipient).await {
+            match create_transfer(&state, &phone_number, amount, &recipient).await {
                 Ok(transaction) => {
                     let message = format!(
-                        "Transfer of {:.2} {} to {} created successfully. Transaction ID: {}",
-                        amount, currency, recipient, transaction.id
+                        "Transfer of {} to {} created successfully. Transaction ID: {}",
+                        amount, recipient, transaction.id
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_success_message(&phone_number, &message)
                         .await?;
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
@@ -393,35 +1034,34 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                         chama.id
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_success_message(&phone_number, &message)
                         .await?;
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
             }
         },
-        BotCommand::ContributeChama { chama_id, amount, currency } => {
-            validate_amount(amount)?;
-            validate_currency(&currency)?;
-            match contribute_to_chama(&state, &phone_number, &chama_id, amount, &currency).await {
+        BotCommand::ContributeChama { chama_id, amount } => {
+            validate_amount(&amount)?;
+            match contribute_to_chama(&state, &phone_number, &chama_id, amount).await {
                 Ok(contribution) => {
                     let message = format!(
-                        "💰 *Chama Contribution Successful!*\n\nAmount: {:.2} {}\nShares Purchased: {}\nChama ID: {}\nTransaction ID: {}",
-                        amount, currency, contribution.shares_purchased, chama_id, contribution.id
+                        "💰 *Chama Contribution Successful!*\n\nAmount: {}\nShares Purchased: {}\nChama ID: {}\nTransaction ID: {}",
+                        amount, contribution.shares_purchased, chama_id, contribution.id
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_success_message(&phone_number, &message)
                         .await?;
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
@@ -437,7 +1077,7 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                             "You don't have any chama shares yet.".to_string()
                         };
                         state
-                            .whatsapp_service
+                            .messenger
                             .send_message(&phone_number, &message)
                             .await?;
                     } else {
@@ -446,21 +1086,21 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                             shares
                                 .iter()
                                 .map(|s| format!(
-                                    "• Chama: {}\n  Shares: {}\n  Total Contribution: {:.2} {}\n  Last Updated: {}",
-                                    s.chama_id, s.shares_count, s.total_contribution, s.currency, s.updated_at
+                                    "• Chama: {}\n  Shares: {}\n  Total Contribution: {}\n  Last Updated: {}",
+                                    s.chama_id, s.shares_count, s.total_contribution, s.updated_at
                                 ))
                                 .collect::<Vec<_>>()
                                 .join("\n\n")
                         );
                         state
-                            .whatsapp_service
+                            .messenger
                             .send_message(&phone_number, &message)
                             .await?;
                     }
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
@@ -470,17 +1110,17 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
             match get_membership_shares(&state, &phone_number).await {
                 Ok(shares) => {
                     let message = format!(
-                        "🏛️ *BitSacco Membership*\n\nShares Owned: {}\nTotal Investment: {:.2} {}\n\nUse `buy shares <count>` to purchase more shares.\nUse `share history` to view your purchase history.",
-                        shares.shares_count, shares.total_investment, shares.currency
+                        "🏛️ *BitSacco Membership*\n\nShares Owned: {}\nTotal Investment: {}\n\nUse `buy shares <count>` to purchase more shares.\nUse `share history` to view your purchase history.",
+                        shares.shares_count, shares.total_investment
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_message(&phone_number, &message)
                         .await?;
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
@@ -491,17 +1131,17 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
             match buy_membership_shares(&state, &phone_number, count, payment_method).await {
                 Ok(purchase) => {
                     let message = format!(
-                        "🎯 *Share Purchase Initiated!*\n\nShares: {}\nAmount: {:.2} {}\nPayment Method: {}\nTransaction ID: {}\nStatus: {}\n\nYour shares will be added to your account once payment is confirmed.",
-                        count, purchase.amount, purchase.currency, payment_method, purchase.id, purchase.status
+                        "🎯 *Share Purchase Initiated!*\n\nShares: {}\nAmount: {}\nPayment Method: {}\nTransaction ID: {}\nStatus: {}\n\nYour shares will be added to your account once payment is confirmed.",
+                        count, purchase.amount, payment_method, purchase.id, purchase.status
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_success_message(&phone_number, &message)
                         .await?;
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
@@ -513,8 +1153,8 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                     if history.is_empty() {
                         let message = "📊 *Share History*\n\nNo share purchases found.";
                         state
-                            .whatsapp_service
-                            .send_message(&phone_number, &message)
+                            .messenger
+                            .send_message(&phone_number, message)
                             .await?;
                     } else {
                         let message = format!(
@@ -522,22 +1162,22 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                             history
                                 .iter()
                                 .map(|p| format!(
-                                    "• {} shares - {:.2} {} ({})\n  Date: {}\n  Status: {}",
-                                    p.shares_count, p.amount, p.currency, p.payment_method, 
+                                    "• {} shares - {} ({})\n  Date: {}\n  Status: {}",
+                                    p.shares_count, p.amount, p.payment_method, 
                                     p.created_at, p.status
                                 ))
                                 .collect::<Vec<_>>()
                                 .join("\n\n")
                         );
                         state
-                            .whatsapp_service
+                            .messenger
                             .send_message(&phone_number, &message)
                             .await?;
                     }
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
@@ -549,8 +1189,8 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                     if transactions.is_empty() {
                         let message = "📋 *Transaction History*\n\nNo transactions found.";
                         state
-                            .whatsapp_service
-                            .send_message(&phone_number, &message)
+                            .messenger
+                            .send_message(&phone_number, message)
                             .await?;
                     } else {
                         let recent_transactions: Vec<_> = transactions.iter().take(5).collect();
@@ -559,8 +1199,8 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                             recent_transactions
                                 .iter()
                                 .map(|t| format!(
-                                    "• {} - {:.2} {} ({})\n  Type: {}\n  Status: {}\n  Date: {}",
-                                    t.id, t.amount, t.currency, 
+                                    "• {} - {} ({})\n  Type: {}\n  Status: {}\n  Date: {}",
+                                    t.id, t.amount,
                                     t.payment_method.as_deref().unwrap_or("internal"), 
                                     t.r#type, t.status, t.created_at
                                 ))
@@ -568,63 +1208,164 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                                 .join("\n\n")
                         );
                         state
-                            .whatsapp_service
+                            .messenger
                             .send_message(&phone_number, &message)
                             .await?;
                     }
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
             }
         },
-        BotCommand::LightningDeposit { amount, currency } => {
-            validate_amount(amount)?;
-            validate_currency(&currency)?;
-            match create_lightning_deposit(&state, &phone_number, amount, &currency).await {
+        BotCommand::LightningDeposit { amount } => {
+            validate_amount(&amount)?;
+            match create_lightning_deposit(&state, &phone_number, amount).await {
                 Ok(lightning_response) => {
+                    send_lightning_invoice(&state, &phone_number, amount, &lightning_response).await?;
+                }
+                Err(e) => {
+                    state
+                        .messenger
+                        .send_error_message(&phone_number, &e.to_string())
+                        .await?;
+                }
+            }
+        },
+        BotCommand::LightningWithdraw { amount, destination } => {
+            // Checked again here: the invoice may have expired while the user confirmed
+            match create_lightning_withdrawal(&state, &phone_number, amount, &destination).await {
+                Ok((withdrawal, response)) => {
                     let message = format!(
-                        "⚡ *Lightning Deposit Initiated!*\n\nAmount: {:.2} {}\nPayment Request: {}\n\n📱 *Scan the QR code or copy the payment request to your Lightning wallet to complete the deposit.*",
-                        amount, currency, lightning_response.payment_request
+                        "⚡ *Lightning Withdrawal Initiated!*\n\nAmount: {}\nTo: {}\nTransaction ID: {}\nStatus: {}\n\n📱 *Withdrawal will be processed via Lightning Network.*",
+                        withdrawal.amount, withdrawal.destination, response.transaction_id, response.status
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_success_message(&phone_number, &message)
                         .await?;
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
             }
         },
-        BotCommand::LightningWithdraw { amount, currency } => {
-            validate_amount(amount)?;
-            validate_currency(&currency)?;
-            match create_withdrawal(&state, &phone_number, amount, &currency).await {
-                Ok(transaction) => {
+        BotCommand::AddPriceAlert { direction, threshold } => {
+            match state.price_alerts.add(&phone_number, direction, threshold).await {
+                Ok(alert) => {
                     let message = format!(
-                        "⚡ *Lightning Withdrawal Initiated!*\n\nAmount: {:.2} {}\nTransaction ID: {}\nStatus: {}\n\n📱 *Withdrawal will be processed via Lightning Network.*",
-                        amount, currency, transaction.id, transaction.status
+                        "🔔 Alert {} set.\n\nYou'll get a message when Bitcoin goes {} {}. Send `alerts` to see your alerts or `alert remove {}` to remove this one.",
+                        alert, alert.direction, alert.threshold, alert.id
                     );
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_success_message(&phone_number, &message)
                         .await?;
                 }
                 Err(e) => {
                     state
-                        .whatsapp_service
+                        .messenger
                         .send_error_message(&phone_number, &e.to_string())
                         .await?;
                 }
             }
-        },
+        }
+        BotCommand::PriceAlerts => {
+            let alerts = state.price_alerts.list(&phone_number).await;
+            let message = if alerts.is_empty() {
+                "You have no price alerts.\n\nExample: `alert btc above 10000000 KES`".to_string()
+            } else {
+                format!(
+                    "🔔 *Your Price Alerts*\n\n{}\n\nSend `alert remove <id>` to remove one.",
+                    alerts
+                        .iter()
+                        .map(|a| format!(
+                            "• {}{}",
+                            a,
+                            if a.armed { "" } else { " (fired, waiting for the price to move back)" }
+                        ))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                )
+            };
+            state
+                .messenger
+                .send_message(&phone_number, &message)
+                .await?;
+        }
+        BotCommand::RemovePriceAlert { id } => {
+            match state.price_alerts.remove(&phone_number, id).await {
+                Some(alert) => {
+                    state
+                        .messenger
+                        .send_success_message(&phone_number, &format!("Alert {} removed.", alert))
+                        .await?;
+                }
+                None => {
+                    state
+                        .messenger
+                        .send_error_message(&phone_number, &format!("You have no alert #{}", id))
+                        .await?;
+                }
+            }
+        }
+        BotCommand::SetPin { pin } => {
+            if state.pins.has_pin(&phone_number).await {
+                state
+                    .messenger
+                    .send_error_message(&phone_number, "You already have a PIN. Send `change pin` to change it.")
+                    .await?;
+                return Ok(());
+            }
+            state.pins.set(&phone_number, &pin).await?;
+            info!("PIN set for {}", phone_number);
+            state
+                .messenger
+                .send_success_message(
+                    &phone_number,
+                    "PIN set. You'll be asked for it before withdrawals, transfers and share purchases.",
+                )
+                .await?;
+        }
+        BotCommand::ChangePin { current, pin } => {
+            if check_current_pin(&state, &phone_number, &current).await? {
+                state.pins.set(&phone_number, &pin).await?;
+                info!("PIN changed for {}", phone_number);
+                state
+                    .messenger
+                    .send_success_message(&phone_number, "PIN changed.")
+                    .await?;
+            }
+        }
+        BotCommand::RemovePin { current } => {
+            if check_current_pin(&state, &phone_number, &current).await? {
+                state.pins.remove(&phone_number).await;
+                info!("PIN removed for {}", phone_number);
+                state
+                    .messenger
+                    .send_success_message(
+                        &phone_number,
+                        "PIN removed. Withdrawals, transfers and share purchases will be confirmed with YES again.",
+                    )
+                    .await?;
+            }
+        }
+        BotCommand::ResetPin => {
+            let message = format!(
+                "🔑 *Reset Your PIN*\n\nFor your security, PINs can only be reset from the BitSacco web app. Sign in at {} and reset your WhatsApp PIN under your security settings.\n\nYou'll get a message here once it is done.",
+                state.config.bitsacco_web_app_url
+            );
+            state
+                .messenger
+                .send_message(&phone_number, &message)
+                .await?;
+        }
         BotCommand::VoiceCommand { transcript } => {
             // This should not happen in text processing, but handle it gracefully
             let response = format!(
@@ -632,17 +1373,30 @@ async fn process_text_message(state: AppState, phone_number: String, message: St
                 transcript
             );
             state
-                .whatsapp_service
+                .messenger
                 .send_message(&phone_number, &response)
                 .await?;
         }
+        BotCommand::Confirm | BotCommand::Cancel => {
+            // Confirmation replies are handled before dispatch
+        }
+        // A PIN sent after its action expired is never quoted back into the chat
+        BotCommand::Unknown(message) if Pin::looks_like(&message) => {
+            state
+                .messenger
+                .send_message(
+                    &phone_number,
+                    "🔐 No action is waiting for a PIN. It may have expired; send the command again to start over.",
+                )
+                .await?;
+        }
         BotCommand::Unknown(message) => {
             let response = format!(
                 "I didn't understand: \"{}\"\n\nSend `help` to see available commands.",
                 message
             );
             state
-                .whatsapp_service
+                .messenger
                 .send_message(&phone_number, &response)
                 .await?;
         }
@@ -664,7 +1418,7 @@ async fn process_voice_message(
     // Convert speech to text
     let transcript = state.voice_service.speech_to_text(&audio_path).await?;
     
-    info!("Voice transcript: {}", transcript);
+    info!("Transcribed voice message from {} ({} characters)", phone_number, transcript.chars().count());
 
     // Process the transcript as a command
     let command = BotCommand::parse(&transcript);
@@ -699,7 +1453,7 @@ async fn process_audio_message(
     // Convert speech to text
     let transcript = state.voice_service.speech_to_text(&audio_path).await?;
     
-    info!("Audio transcript: {}", transcript);
+    info!("Transcribed audio message from {} ({} characters)", phone_number, transcript.chars().count());
 
     // Process the transcript as a command
     let command = BotCommand::parse(&transcript);
@@ -726,7 +1480,7 @@ async fn process_voice_command(
     phone_number: &str,
     transcript: &str,
 ) -> Result<()> {
-    info!("Processing voice command: {}", transcript);
+    info!("Processing voice command from {}", phone_number);
 
     // For now, we'll respond with a text message acknowledging the voice command
     // In the future, we could respond with a voice message using text-to-speech
@@ -736,7 +1490,7 @@ async fn process_voice_command(
     );
 
     state
-        .whatsapp_service
+        .messenger
         .send_message(phone_number, &response)
         .await?;
 
@@ -763,7 +1517,76 @@ async fn validate_registered_user(
     }
 }
 
-async fn get_user_balance(state: &AppState, phone_number: &str) -> Result<(f64, f64, String)> {
+/// Send a Lightning deposit invoice as a QR code image captioned with the
+/// BOLT11 string, falling back to text if the image can't be sent
+async fn send_lightning_invoice(
+    state: &AppState,
+    phone_number: &str,
+    amount: Money,
+    invoice: &LightningPaymentResponse,
+) -> Result<()> {
+    state
+        .lightning_invoices
+        .track(OutstandingInvoice::new(phone_number, amount, invoice))
+        .await;
+    let expires = format_local_timestamp(&invoice.expires_at, phone_number);
+
+    let sent = match render_invoice_qr(&invoice.payment_request) {
+        Ok(png_data) => {
+            let message = format!(
+                "⚡ *Lightning Deposit Initiated!*\n\nAmount: {}\nExpires: {}\n\n📱 *Scan the QR code below with your Lightning wallet, or copy the invoice from its caption.*",
+                amount, expires
+            );
+            state
+                .messenger
+                .send_success_message(phone_number, &message)
+                .await?;
+            state
+                .messenger
+                .send_media(phone_number, OutboundMedia::png(png_data, "invoice.png", Some(&invoice.payment_request)))
+                .await
+                .map_err(|e| error!("Failed to send invoice QR code: {}", e))
+                .is_ok()
+        }
+        Err(e) => {
+            error!("Failed to render invoice QR code: {}", e);
+            false
+        }
+    };
+
+    if !sent {
+        let message = format!(
+            "⚡ *Lightning Deposit Initiated!*\n\nAmount: {}\nExpires: {}\nPayment Request: {}\n\n📱 *Copy the payment request to your Lightning wallet to complete the deposit.*",
+            amount, expires, invoice.payment_request
+        );
+        state
+            .messenger
+            .send_success_message(phone_number, &message)
+            .await?;
+    }
+    Ok(())
+}
+
+fn price_history_summary(history: &PriceHistory, range: PriceRange) -> String {
+    let change = history.change_percent().unwrap_or(0.0);
+    format!(
+        "₿ *Bitcoin Price, {}*\n\n*High:* {:.2} {}\n*Low:* {:.2} {}\n*Change:* {} {}{:.2}%\n\nSource: {}",
+        range.description(),
+        history.high().unwrap_or_default(),
+        history.currency,
+        history.low().unwrap_or_default(),
+        history.currency,
+        if change >= 0.0 { "📈" } else { "📉" },
+        if change >= 0.0 { "+" } else { "" },
+        change,
+        history.source
+    )
+}
+
+async fn get_user_balance(
+    state: &AppState,
+    phone_number: &str,
+) -> Result<(Vec<Money>, Money, Vec<ExchangeRate>)> {
     let user = state
         .bitsacco_service
         .get_user_by_phone(phone_number, &state.cache)
@@ -776,7 +1599,47 @@ async fn get_user_balance(state: &AppState, phone_number: &str) -> Result<(f64,
         .get_user_btc_balance(&user.id, &state.cache)
         .await?;
 
-    Ok((savings, btc_balance.balance, btc_balance.currency))
+    // KES first so the bitcoin balance is always valued in shillings
+    let currencies = std::iter::once(Currency::Kes).chain(savings.iter().map(Money::currency));
+    let rates = state.conversion_service.rates(currencies, &state.cache).await?;
+
+    Ok((savings, btc_balance.balance, rates))
+}
+
+async fn get_savings_summary(state: &AppState, phone_number: &str) -> Result<String> {
+    let savings = get_user_savings(state, phone_number).await?;
+    let totals = Money::totals_by_currency(savings.iter().map(|s| s.amount))?;
+    let rates = state
+        .conversion_service
+        .rates(totals.iter().map(Money::currency), &state.cache)
+        .await?;
+
+    let mut total_sats = Sats::default();
+    for total in &totals {
+        let sats = match rate_for(&rates, total.currency()) {
+            Some(rate) => rate.to_sats(*total)?,
+            None => Sats::try_from(*total)?,
+        };
+        total_sats = total_sats
+            .checked_add(sats)
+            .ok_or_else(|| AppError::Internal("Savings total overflowed".to_string()))?;
+    }
+
+    let mut message = format!(
+        "💰 *Your Savings*\n\nTotal: {} ≈ {}\n\nDetails:\n{}",
+        format_totals(&totals),
+        total_sats,
+        savings
+            .iter()
+            .map(|s| format!("• {} - {}", s.amount, s.id))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    if !rates.is_empty() {
+        message.push_str("\n\n");
+        message.push_str(&format_rates(&rates));
+    }
+    Ok(message)
 }
 
 async fn get_user_savings(
@@ -806,8 +1669,7 @@ async fn get_user_chamas(
 async fn create_deposit(
     state: &AppState,
     phone_number: &str,
-    amount: f64,
-    currency: &str,
+    amount: Money,
 ) -> Result<crate::types::BitSaccoTransaction> {
     let user = state
         .bitsacco_service
@@ -816,15 +1678,14 @@ async fn create_deposit(
 
     state
         .bitsacco_service
-        .create_deposit(&user.id, amount, currency)
+        .create_deposit(&user.id, amount)
         .await
 }
 
 async fn create_transfer(
     state: &AppState,
     phone_number: &str,
-    amount: f64,
-    currency: &str,
+    amount: Money,
     recipient: &str,
 ) -> Result<crate::types::BitSaccoTransaction> {
     let user = state
@@ -834,7 +1695,7 @@ async fn create_transfer(
 
     state
         .bitsacco_service
-        .create_transfer(&user.id, amount, currency, recipient)
+        .create_transfer(&user.id, amount, recipient)
         .await
 }
 
@@ -843,6 +1704,7 @@ pub async fn send_message(
     State(state): State<AppState>,
     Json(request): Json<SendMessageRequest>,
 ) -> Result<Json<WhatsAppSendResponse>> {
+    state.messenger.check_window(whatsapp::CHANNEL, &request.to).await?;
     let response = state
         .whatsapp_service
         .send_message(&request.to, &request.message)
@@ -872,8 +1734,7 @@ async fn contribute_to_chama(
     state: &AppState,
     phone_number: &str,
     chama_id: &str,
-    amount: f64,
-    currency: &str,
+    amount: Money,
 ) -> Result<crate::types::BitSaccoChamaContribution> {
     let user = state
         .bitsacco_service
@@ -882,7 +1743,7 @@ async fn contribute_to_chama(
 
     state
         .bitsacco_service
-        .contribute_to_chama(&user.id, chama_id, amount, currency)
+        .contribute_to_chama(&user.id, chama_id, amount)
         .await
 }
 
@@ -967,8 +1828,7 @@ async fn get_transaction_history(
 async fn create_lightning_deposit(
     state: &AppState,
     phone_number: &str,
-    amount: f64,
-    currency: &str,
+    amount: Money,
 ) -> Result<crate::types::LightningPaymentResponse> {
     let user = state
         .bitsacco_service
@@ -977,25 +1837,69 @@ async fn create_lightning_deposit(
 
     state
         .bitsacco_service
-        .create_lightning_deposit(&user.id, amount, currency)
+        .create_lightning_deposit(&user.id, amount)
         .await
 }
 
+/// Send a withdrawal by its method: M-Pesa B2C to the chosen or registered
+/// M-Pesa number, or Lightning to the given destination. Also returns where
+/// the money went, for the reply.
 async fn create_withdrawal(
     state: &AppState,
     phone_number: &str,
-    amount: f64,
-    currency: &str,
-) -> Result<crate::types::BitSaccoTransaction> {
+    amount: Money,
+    method: Option<&str>,
+    destination: Option<&str>,
+) -> Result<(WithdrawalPlan, String, crate::types::WithdrawalResponse)> {
+    let plan = WithdrawalPlan::resolve(amount, method, destination, chrono::Utc::now())?;
     let user = state
         .bitsacco_service
         .get_user_by_phone(phone_number, &state.cache)
         .await?;
 
-    state
+    let (to, response) = match &plan.route {
+        WithdrawalRoute::Mpesa { phone } => {
+            let mpesa_phone = phone
+                .as_deref()
+                .or(user.mpesa_phone.as_deref())
+                .unwrap_or(phone_number)
+                .to_string();
+            let response = state
+                .bitsacco_service
+                .create_withdrawal_enhanced(&user.id, plan.amount, plan.method().code(), Some(&mpesa_phone))
+                .await?;
+            (mpesa_phone, response)
+        }
+        WithdrawalRoute::Lightning(withdrawal) => {
+            let response = state
+                .bitsacco_service
+                .create_lightning_withdrawal(&user.id, withdrawal)
+                .await?;
+            (withdrawal.destination.to_string(), response)
+        }
+    };
+    Ok((plan, to, response))
+}
+
+async fn create_lightning_withdrawal(
+    state: &AppState,
+    phone_number: &str,
+    amount: Option<Money>,
+    destination: &str,
+) -> Result<(LightningWithdrawal, crate::types::WithdrawalResponse)> {
+    let withdrawal = LightningWithdrawal::resolve(destination, amount, chrono::Utc::now())?;
+    validate_amount(&withdrawal.amount)?;
+
+    let user = state
         .bitsacco_service
-        .create_withdrawal(&user.id, amount, currency)
-        .await
+        .get_user_by_phone(phone_number, &state.cache)
+        .await?;
+
+    let response = state
+        .bitsacco_service
+        .create_lightning_withdrawal(&user.id, &withdrawal)
+        .await?;
+    Ok((withdrawal, response))
 }
 
 pub async fn health_check(State(state): State<AppState>) -> Result<Json<HealthResponse>> {
@@ -1015,6 +1919,11 @@ pub async fn health_check(State(state): State<AppState>) -> Result<Json<HealthRe
         Ok(_) => services.insert("btc".to_string(), "healthy".to_string()),
         Err(_) => services.insert("btc".to_string(), "unhealthy".to_string()),
     };
+    let now = chrono::Utc::now();
+    for (source, health) in state.btc_service.source_health().await {
+        let status = if health.is_available(now) { "healthy" } else { "unhealthy" };
+        services.insert(format!("btc:{}", source), status.to_string());
+    }
 
     // Check Voice service
     match state.voice_service.health_check().await {
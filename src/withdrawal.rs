@@ -0,0 +1,237 @@
+//! Routing withdrawals by payment method
+//!
+//! `withdraw <amount> <currency> [mpesa|lightning] [destination]` pays out
+//! either through M-Pesa B2C, to the user's registered M-Pesa number or one
+//! they name, or over Lightning to an invoice, Lightning address or LNURL.
+//! Each method has its own per-withdrawal limits, which are shown in the
+//! confirmation prompt before anything is sent, with the fee BitSacco quotes
+//! for the payout. The fee is never estimated here: when BitSacco cannot
+//! quote one the prompt says so.
+
+use crate::{
+    error::{AppError, Result},
+    lightning::LightningWithdrawal,
+    money::{Currency, Money},
+    validation::validate_phone_number,
+};
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/// Smallest M-Pesa B2C payout, in KES
+const MPESA_MIN_KES: i64 = 10;
+
+/// Largest M-Pesa B2C payout, in KES
+const MPESA_MAX_KES: i64 = 250_000;
+
+/// How a withdrawal is paid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawalMethod {
+    Mpesa,
+    Lightning,
+}
+
+impl WithdrawalMethod {
+    /// Method named in a command, M-Pesa when none was given
+    pub fn parse(method: Option<&str>) -> Result<Self> {
+        match method.map(str::to_lowercase).as_deref() {
+            None | Some("mpesa") | Some("m-pesa") => Ok(WithdrawalMethod::Mpesa),
+            Some("lightning") | Some("ln") => Ok(WithdrawalMethod::Lightning),
+            Some(other) => Err(AppError::Validation(format!(
+                "Unsupported withdrawal method: {}. Use `mpesa` or `lightning`.",
+                other
+            ))),
+        }
+    }
+
+    /// Method code BitSacco expects
+    pub fn code(&self) -> &'static str {
+        match self {
+            WithdrawalMethod::Mpesa => "mpesa",
+            WithdrawalMethod::Lightning => "lightning",
+        }
+    }
+}
+
+impl fmt::Display for WithdrawalMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WithdrawalMethod::Mpesa => write!(f, "M-Pesa"),
+            WithdrawalMethod::Lightning => write!(f, "Lightning"),
+        }
+    }
+}
+
+/// Where the money goes
+#[derive(Debug, Clone, PartialEq)]
+pub enum WithdrawalRoute {
+    /// M-Pesa B2C; `None` pays the user's registered M-Pesa number
+    Mpesa { phone: Option<String> },
+    Lightning(LightningWithdrawal),
+}
+
+/// A checked withdrawal, ready to preview or send
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithdrawalPlan {
+    pub amount: Money,
+    pub route: WithdrawalRoute,
+}
+
+impl WithdrawalPlan {
+    /// Check a `withdraw` command against its method's rules
+    pub fn resolve(
+        amount: Money,
+        method: Option<&str>,
+        destination: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<Self> {
+        match WithdrawalMethod::parse(method)? {
+            WithdrawalMethod::Mpesa => {
+                if amount.currency() != Currency::Kes {
+                    return Err(AppError::Validation(format!(
+                        "M-Pesa withdrawals must be in KES, got {}",
+                        amount.currency()
+                    )));
+                }
+                let (min, max) = mpesa_limits()?;
+                if amount.minor() < min.minor() || amount.minor() > max.minor() {
+                    return Err(AppError::Validation(format!(
+                        "M-Pesa withdrawals must be between {} and {}",
+                        min, max
+                    )));
+                }
+                let phone = destination.map(validate_mpesa_phone).transpose()?;
+                Ok(Self {
+                    amount,
+                    route: WithdrawalRoute::Mpesa { phone },
+                })
+            }
+            WithdrawalMethod::Lightning => {
+                let destination = destination.ok_or_else(|| {
+                    AppError::Validation(
+                        "Lightning withdrawals need a destination: `withdraw 500 KES lightning <invoice|address|lnurl>`"
+                            .to_string(),
+                    )
+                })?;
+                let withdrawal = LightningWithdrawal::resolve(destination, Some(amount), now)?;
+                Ok(Self {
+                    amount: withdrawal.amount,
+                    route: WithdrawalRoute::Lightning(withdrawal),
+                })
+            }
+        }
+    }
+
+    pub fn method(&self) -> WithdrawalMethod {
+        match self.route {
+            WithdrawalRoute::Mpesa { .. } => WithdrawalMethod::Mpesa,
+            WithdrawalRoute::Lightning(_) => WithdrawalMethod::Lightning,
+        }
+    }
+
+    /// One-line description for the confirmation prompt. `mpesa_phone` is the
+    /// user's registered M-Pesa number, used when no other was given.
+    pub fn summary(&self, mpesa_phone: &str, now: DateTime<Utc>) -> String {
+        match &self.route {
+            WithdrawalRoute::Mpesa { phone } => format!(
+                "Withdraw {} via M-Pesa to {}",
+                self.amount,
+                phone.as_deref().unwrap_or(mpesa_phone)
+            ),
+            WithdrawalRoute::Lightning(withdrawal) => withdrawal.summary(now),
+        }
+    }
+
+    /// Fee and limit lines shown under the summary before confirmation.
+    /// `fee` is BitSacco's quote, if it gave one.
+    pub fn cost_preview(&self, fee: Option<Money>) -> Result<String> {
+        let fee = match fee {
+            Some(fee) => format!("Fee: {}", fee),
+            None => "Fee: BitSacco could not quote one right now".to_string(),
+        };
+        let limits = match self.route {
+            WithdrawalRoute::Mpesa { .. } => {
+                let (min, max) = mpesa_limits()?;
+                format!("M-Pesa limits: {} to {} per withdrawal", min, max)
+            }
+            WithdrawalRoute::Lightning(_) => "Limit: your available balance".to_string(),
+        };
+        Ok(format!("{}\n{}", fee, limits))
+    }
+}
+
+/// Smallest and largest M-Pesa payout
+pub fn mpesa_limits() -> Result<(Money, Money)> {
+    Ok((
+        Money::from_major(MPESA_MIN_KES, Currency::Kes)?,
+        Money::from_major(MPESA_MAX_KES, Currency::Kes)?,
+    ))
+}
+
+/// M-Pesa only pays out to Kenyan numbers
+fn validate_mpesa_phone(phone: &str) -> Result<String> {
+    validate_phone_number(phone)?;
+    if !phone.starts_with("+254") {
+        return Err(AppError::Validation(format!(
+            "M-Pesa withdrawals can only go to Kenyan numbers (+254...), got {}",
+            phone
+        )));
+    }
+    Ok(phone.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kes(major: i64) -> Money {
+        Money::from_major(major, Currency::Kes).unwrap()
+    }
+
+    #[test]
+    fn test_method_parsing() {
+        assert_eq!(WithdrawalMethod::parse(None).unwrap(), WithdrawalMethod::Mpesa);
+        assert_eq!(WithdrawalMethod::parse(Some("M-Pesa")).unwrap(), WithdrawalMethod::Mpesa);
+        assert_eq!(WithdrawalMethod::parse(Some("ln")).unwrap(), WithdrawalMethod::Lightning);
+        assert!(WithdrawalMethod::parse(Some("paypal")).is_err());
+    }
+
+    #[test]
+    fn test_mpesa_plan() {
+        let now = Utc::now();
+        let plan = WithdrawalPlan::resolve(kes(500), None, None, now).unwrap();
+        assert_eq!(plan.method(), WithdrawalMethod::Mpesa);
+        assert_eq!(plan.route, WithdrawalRoute::Mpesa { phone: None });
+        assert_eq!(plan.summary("+254712345678", now), "Withdraw 500.00 KES via M-Pesa to +254712345678");
+        assert_eq!(
+            plan.cost_preview(Some(kes(15))).unwrap(),
+            "Fee: 15.00 KES\nM-Pesa limits: 10.00 KES to 250000.00 KES per withdrawal"
+        );
+        assert!(plan.cost_preview(None).unwrap().starts_with("Fee: BitSacco could not quote"));
+
+        let chosen = WithdrawalPlan::resolve(kes(500), Some("mpesa"), Some("+254700000001"), now).unwrap();
+        assert_eq!(chosen.summary("+254712345678", now), "Withdraw 500.00 KES via M-Pesa to +254700000001");
+    }
+
+    #[test]
+    fn test_mpesa_rules() {
+        let now = Utc::now();
+        assert!(WithdrawalPlan::resolve(kes(5), None, None, now).is_err());
+        assert!(WithdrawalPlan::resolve(kes(300_000), None, None, now).is_err());
+        let usd = Money::from_major(50, Currency::Usd).unwrap();
+        assert!(WithdrawalPlan::resolve(usd, Some("mpesa"), None, now).is_err());
+        assert!(WithdrawalPlan::resolve(kes(500), None, Some("+14155550100"), now).is_err());
+        assert!(WithdrawalPlan::resolve(kes(500), None, Some("not-a-phone"), now).is_err());
+    }
+
+    #[test]
+    fn test_lightning_plan() {
+        let now = Utc::now();
+        let plan = WithdrawalPlan::resolve(kes(500), Some("lightning"), Some("alice@wallet.com"), now).unwrap();
+        assert_eq!(plan.method(), WithdrawalMethod::Lightning);
+        assert_eq!(plan.summary("+254712345678", now), "Withdraw 500.00 KES via Lightning to alice@wallet.com");
+        assert_eq!(plan.cost_preview(Some(kes(2))).unwrap(), "Fee: 2.00 KES\nLimit: your available balance");
+
+        assert!(WithdrawalPlan::resolve(kes(500), Some("lightning"), None, now).is_err());
+        assert!(WithdrawalPlan::resolve(kes(500), Some("lightning"), Some("+254712345678"), now).is_err());
+    }
+}
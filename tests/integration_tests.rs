@@ -1,10 +1,18 @@
 use bitsacco_whatsapp_bot::{
+    alerts::AlertDirection,
     config::AppConfig,
+    lightning::LightningWithdrawal,
+    money::{Currency, Money},
     services::{bitsacco::BitSaccoService, btc::BtcService, voice::VoiceService, whatsapp::WhatsAppService},
-    types::BotCommand,
+    types::{BotCommand, PriceRange},
 };
+use axum::{body::Body, http::Request, response::Response, routing::post, Router};
 use mockito::{Server, ServerGuard};
+use ring::hmac;
 use serde_json::json;
+use tower::ServiceExt;
+
+const TEST_PHONE: &str = "+254712345678";
 
 // Helper function to create test config
 async fn create_test_config() -> (AppConfig, ServerGuard) {
@@ -15,20 +23,43 @@ async fn create_test_config() -> (AppConfig, ServerGuard) {
         whatsapp_access_token: "test_token".to_string(),
         whatsapp_phone_number_id: "test_phone_id".to_string(),
         whatsapp_webhook_verify_token: "test_verify_token".to_string(),
+        whatsapp_app_secret: "test_app_secret".to_string(),
         whatsapp_api_base_url: url.clone(),
         whatsapp_media_base_url: url.clone(),
         twilio_account_sid: "test_account_sid".to_string(),
         twilio_auth_token: "test_auth_token".to_string(),
         twilio_whatsapp_number: "+1234567890".to_string(),
+        twilio_api_base_url: "https://api.twilio.com".to_string(),
+        twilio_sms_number: "".to_string(),
+        messaging_provider: "meta".to_string(),
+        twilio_webhook_url: "".to_string(),
         bitsacco_api_base_url: url.clone(),
         bitsacco_api_token: "test_bitsacco_token".to_string(),
+        bitsacco_callback_secret: "test_callback_secret".to_string(),
         server_host: "127.0.0.1".to_string(),
         server_port: 8080,
         rust_log: "debug".to_string(),
         rate_limit_requests_per_minute: 60,
+        rate_limit_money_commands_per_minute: 5,
         max_message_length: 4096,
         btc_api_base_url: url.clone(),
         btc_api_key: Some("test_btc_key".to_string()),
+        coinbase_api_base_url: url.clone(),
+        kraken_api_base_url: url.clone(),
+        binance_api_base_url: url.clone(),
+        btc_price_sources: ["coingecko", "coinbase", "kraken", "binance"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        invoice_store_path: "".to_string(),
+        mpesa_status_query_delay_secs: 60,
+        limits_config_path: "".to_string(),
+        templates_config_path: "".to_string(),
+        pin_store_path: "".to_string(),
+        bitsacco_web_app_url: "https://app.bitsacco.com".to_string(),
+        message_dedup_retention_secs: 86400,
+        admin_api_token: "test_admin_token".to_string(),
+        outbound_queue_path: "".to_string(),
     };
 
     (config, server)
@@ -74,8 +105,7 @@ async fn test_bot_command_parsing() {
     assert_eq!(
         BotCommand::parse("deposit 100 USD"),
         BotCommand::Deposit {
-            amount: 100.0,
-            currency: "USD".to_string(),
+            amount: Money::from_major(100, Currency::Usd).unwrap(),
             method: None
         }
     );
@@ -84,22 +114,87 @@ async fn test_bot_command_parsing() {
     assert_eq!(
         BotCommand::parse("withdraw 50 KES"),
         BotCommand::Withdraw {
-            amount: 50.0,
-            currency: "KES".to_string(),
-            method: None
+            amount: Money::from_major(50, Currency::Kes).unwrap(),
+            method: None,
+            destination: None
+        }
+    );
+    assert_eq!(
+        BotCommand::parse("withdraw 50 KES lightning alice@wallet.com"),
+        BotCommand::Withdraw {
+            amount: Money::from_major(50, Currency::Kes).unwrap(),
+            method: Some("lightning".to_string()),
+            destination: Some("alice@wallet.com".to_string())
         }
     );
+    assert!(matches!(BotCommand::parse("withdraw 50 KES lightning"), BotCommand::Unknown(_)));
 
     // Test transfer command
     assert_eq!(
         BotCommand::parse("transfer 25 USD +254712345678"),
         BotCommand::Transfer {
-            amount: 25.0,
-            currency: "USD".to_string(),
+            amount: Money::from_major(25, Currency::Usd).unwrap(),
             recipient: "+254712345678".to_string()
         }
     );
 
+    // Amounts are exact decimals; sub-cent precision is not a valid command
+    assert_eq!(
+        BotCommand::parse("deposit 0.30 KES"),
+        BotCommand::Deposit {
+            amount: Money::parse("0.3", Currency::Kes).unwrap(),
+            method: None
+        }
+    );
+    assert!(matches!(BotCommand::parse("deposit 10.001 KES"), BotCommand::Unknown(_)));
+
+    // Test price alert commands
+    assert_eq!(
+        BotCommand::parse("alert btc above 10000000 KES"),
+        BotCommand::AddPriceAlert {
+            direction: AlertDirection::Above,
+            threshold: Money::from_major(10_000_000, Currency::Kes).unwrap()
+        }
+    );
+    assert_eq!(
+        BotCommand::parse("alert below 60000 USD"),
+        BotCommand::AddPriceAlert {
+            direction: AlertDirection::Below,
+            threshold: Money::from_major(60_000, Currency::Usd).unwrap()
+        }
+    );
+    assert_eq!(BotCommand::parse("alerts"), BotCommand::PriceAlerts);
+
+    // Test price history commands
+    assert_eq!(
+        BotCommand::parse("bitcoin 7d"),
+        BotCommand::BtcPriceHistory { range: PriceRange::Week, currency: Currency::Usd }
+    );
+    assert_eq!(
+        BotCommand::parse("btc price 1y KES"),
+        BotCommand::BtcPriceHistory { range: PriceRange::Year, currency: Currency::Kes }
+    );
+    assert_eq!(BotCommand::parse("bitcoin price"), BotCommand::BtcPrice);
+    assert!(matches!(BotCommand::parse("bitcoin 3d"), BotCommand::Unknown(_)));
+    assert_eq!(BotCommand::parse("alert remove #3"), BotCommand::RemovePriceAlert { id: 3 });
+    assert!(matches!(BotCommand::parse("alert btc sideways 100 KES"), BotCommand::Unknown(_)));
+
+    // Lightning withdrawals name a destination; an amount is optional for invoices
+    assert_eq!(
+        BotCommand::parse("lightning withdraw 500 KES Alice@Wallet.com"),
+        BotCommand::LightningWithdraw {
+            amount: Some(Money::from_major(500, Currency::Kes).unwrap()),
+            destination: "alice@wallet.com".to_string(),
+        }
+    );
+    assert_eq!(
+        BotCommand::parse("lightning withdraw lnbc1garbled"),
+        BotCommand::LightningWithdraw { amount: None, destination: "lnbc1garbled".to_string() }
+    );
+    // No destination, or no amount for an address: finished by the guided flow
+    assert!(matches!(BotCommand::parse("lightning withdraw 500 KES"), BotCommand::Unknown(_)));
+    assert!(matches!(BotCommand::parse("lightning withdraw alice@wallet.com"), BotCommand::Unknown(_)));
+
     // Test unknown command
     assert_eq!(
         BotCommand::parse("unknown command"),
@@ -107,6 +202,28 @@ async fn test_bot_command_parsing() {
     );
 }
 
+#[tokio::test]
+async fn test_confirmation_command_parsing() {
+    assert_eq!(BotCommand::parse("YES"), BotCommand::Confirm);
+    assert_eq!(BotCommand::parse(" yes "), BotCommand::Confirm);
+    assert_eq!(BotCommand::parse("no"), BotCommand::Cancel);
+    assert_eq!(BotCommand::parse("cancel"), BotCommand::Cancel);
+
+    // Money-moving commands must be confirmed, queries must not
+    assert!(BotCommand::parse("withdraw 500 KES mpesa").requires_confirmation());
+    assert!(BotCommand::parse("transfer 25 USD +254712345678").requires_confirmation());
+    assert!(BotCommand::parse("buy shares 10 mpesa").requires_confirmation());
+    assert!(BotCommand::parse("contribute chama CH123 50 USD").requires_confirmation());
+    assert!(BotCommand::parse("lightning withdraw 50 KES alice@wallet.com").requires_confirmation());
+    assert!(!BotCommand::parse("balance").requires_confirmation());
+    assert!(!BotCommand::parse("deposit 100 KES").requires_confirmation());
+
+    // Chama contributions are confirmed with YES even when a PIN is set
+    assert!(BotCommand::parse("withdraw 500 KES mpesa").requires_pin());
+    assert!(!BotCommand::parse("contribute chama CH123 50 USD").requires_pin());
+    assert_eq!(BotCommand::parse("forgot pin"), BotCommand::ResetPin);
+}
+
 #[tokio::test]
 async fn test_bitsacco_service_user_lookup() {
     let (config, mut server) = create_test_config().await;
@@ -180,8 +297,143 @@ async fn test_bitsacco_service_savings() {
     let savings = bitsacco_service.get_user_savings("user123", &cache).await.unwrap();
 
     assert_eq!(savings.len(), 2);
-    assert_eq!(savings[0].amount, 1000.0);
-    assert_eq!(savings[1].amount, 500.0);
+    assert_eq!(savings[0].amount, Money::from_major(1000, Currency::Kes).unwrap());
+    assert_eq!(savings[1].amount, Money::from_major(500, Currency::Usd).unwrap());
+}
+
+#[tokio::test]
+async fn test_bitsacco_lightning_withdrawal() {
+    let (config, mut server) = create_test_config().await;
+    let bitsacco_service = BitSaccoService::new(&config).unwrap();
+
+    let _m = server
+        .mock("POST", "/lightning/withdraw")
+        .match_body(mockito::Matcher::PartialJson(json!({
+            "user_id": "user123",
+            "amount": 500.0,
+            "currency": "KES",
+            "destination_type": "lightning_address",
+            "destination": "alice@wallet.com"
+        })))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "id": "wd1",
+                "status": "pending",
+                "transaction_id": "tx1",
+                "message": "Withdrawal queued"
+            })
+            .to_string(),
+        )
+        .create();
+
+    let withdrawal = LightningWithdrawal::resolve(
+        "alice@wallet.com",
+        Some(Money::from_major(500, Currency::Kes).unwrap()),
+        chrono::Utc::now(),
+    )
+    .unwrap();
+    let response = bitsacco_service
+        .create_lightning_withdrawal("user123", &withdrawal)
+        .await
+        .unwrap();
+
+    assert_eq!(response.transaction_id, "tx1");
+    assert_eq!(response.status, "pending");
+}
+
+#[tokio::test]
+async fn test_bitsacco_mpesa_withdrawal() {
+    use bitsacco_whatsapp_bot::withdrawal::{WithdrawalPlan, WithdrawalRoute};
+
+    let (config, mut server) = create_test_config().await;
+    let bitsacco_service = BitSaccoService::new(&config).unwrap();
+
+    let _m = server
+        .mock("POST", "/withdrawals")
+        .match_body(mockito::Matcher::PartialJson(json!({
+            "user_id": "user123",
+            "amount": 500.0,
+            "currency": "KES",
+            "payment_method": "mpesa",
+            "phone_number": "+254700000001"
+        })))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "id": "wd2",
+                "status": "pending",
+                "transaction_id": "tx2",
+                "message": "Withdrawal queued"
+            })
+            .to_string(),
+        )
+        .create();
+
+    let plan = WithdrawalPlan::resolve(
+        Money::from_major(500, Currency::Kes).unwrap(),
+        Some("mpesa"),
+        Some("+254700000001"),
+        chrono::Utc::now(),
+    )
+    .unwrap();
+    let WithdrawalRoute::Mpesa { phone } = &plan.route else {
+        panic!("expected an M-Pesa route, got {:?}", plan.route);
+    };
+    let response = bitsacco_service
+        .create_withdrawal_enhanced("user123", plan.amount, plan.method().code(), phone.as_deref())
+        .await
+        .unwrap();
+
+    assert_eq!(response.transaction_id, "tx2");
+}
+
+#[tokio::test]
+async fn test_withdrawal_limits_from_history() {
+    use bitsacco_whatsapp_bot::{
+        error::AppError,
+        limits::{LimitKind, LimitsEngine},
+    };
+
+    let (config, mut server) = create_test_config().await;
+    let bitsacco_service = BitSaccoService::new(&config).unwrap();
+
+    let now = chrono::Utc::now();
+    let withdrawal = |amount: f64, hours_ago: i64| {
+        let at = (now - chrono::Duration::hours(hours_ago)).to_rfc3339();
+        json!({
+            "id": format!("tx{}", hours_ago),
+            "user_id": "user123",
+            "type": "withdrawal",
+            "amount": amount,
+            "currency": "KES",
+            "status": "completed",
+            "payment_method": "mpesa",
+            "external_reference": null,
+            "chama_id": null,
+            "description": null,
+            "created_at": at,
+            "updated_at": at
+        })
+    };
+    let _m = server
+        .mock("GET", "/users/user123/transactions")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!([withdrawal(150_000.0, 2), withdrawal(100_000.0, 6)]).to_string())
+        .create();
+
+    let history = bitsacco_service.get_user_transactions("user123").await.unwrap();
+    let engine = LimitsEngine::default();
+    let kes = |major| Money::from_major(major, Currency::Kes).unwrap();
+
+    assert!(engine.check(LimitKind::Withdrawal, kes(50_000), &history, now).is_ok());
+    match engine.check(LimitKind::Withdrawal, kes(60_000), &history, now) {
+        Err(AppError::LimitExceeded(message)) => assert!(message.contains("50000.00 KES left"), "{}", message),
+        other => panic!("expected a limit error, got {:?}", other),
+    }
 }
 
 #[tokio::test]
@@ -215,6 +467,91 @@ async fn test_btc_service_price() {
     assert_eq!(price.currency, "USD");
     assert_eq!(price.price, 50000.0);
     assert_eq!(price.change_24h, 0.0); // Coinbase API doesn't provide 24h change in basic endpoint
+    // The other sources are unmocked and fail over to Coinbase alone
+    assert_eq!(price.sources, vec!["coinbase"]);
+}
+
+#[tokio::test]
+async fn test_btc_price_history() {
+    let (config, mut server) = create_test_config().await;
+    let btc_service = BtcService::new(&config).unwrap();
+
+    // Mock the CoinGecko market chart; the exchanges fail and are skipped
+    let _m = server
+        .mock("GET", "/coins/bitcoin/market_chart")
+        .match_query(mockito::Matcher::AllOf(vec![
+            mockito::Matcher::UrlEncoded("vs_currency".into(), "kes".into()),
+            mockito::Matcher::UrlEncoded("days".into(), "7".into()),
+        ]))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "prices": [
+                    [1_760_000_000_000_i64, 8_000_000.0],
+                    [1_760_086_400_000_i64, 8_400_000.0],
+                    [1_760_172_800_000_i64, 7_900_000.0],
+                    [1_760_259_200_000_i64, 8_200_000.0]
+                ]
+            })
+            .to_string(),
+        )
+        .create();
+
+    let cache = bitsacco_whatsapp_bot::cache::AppCache::new(bitsacco_whatsapp_bot::cache::CacheConfig::default());
+    let history = btc_service
+        .get_price_history("kes", PriceRange::Week, &cache)
+        .await
+        .unwrap();
+
+    assert_eq!(history.currency, "KES");
+    assert_eq!(history.source, "coingecko");
+    assert_eq!(history.points.len(), 4);
+    assert_eq!(history.high(), Some(8_400_000.0));
+    assert_eq!(history.low(), Some(7_900_000.0));
+    assert!((history.change_percent().unwrap() - 2.5).abs() < 1e-9);
+
+    let png_data = bitsacco_whatsapp_bot::chart::render_price_chart(&history).unwrap();
+    assert_eq!(&png_data[..4], b"\x89PNG");
+}
+
+#[tokio::test]
+async fn test_whatsapp_send_image_message() {
+    let (config, mut server) = create_test_config().await;
+    let whatsapp_service = WhatsAppService::new(&config).unwrap();
+
+    let upload = server
+        .mock("POST", "/test_phone_id/media")
+        .match_body(mockito::Matcher::Regex("image/png".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"id": "media123"}).to_string())
+        .create();
+    let send = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::PartialJson(json!({
+            "type": "image",
+            "image": {"id": "media123", "caption": "BTC 7d"}
+        })))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "messaging_product": "whatsapp",
+                "contacts": [{"input": "+254712345678", "wa_id": "+254712345678"}],
+                "messages": [{"id": "wamid.123456789"}]
+            })
+            .to_string(),
+        )
+        .create();
+
+    whatsapp_service
+        .send_image_message("+254712345678", b"\x89PNG fake".to_vec(), Some("BTC 7d"))
+        .await
+        .unwrap();
+
+    upload.assert();
+    send.assert();
 }
 
 #[tokio::test]
@@ -325,3 +662,1546 @@ async fn test_voice_command_parsing() {
     let command = BotCommand::parse("bitcoin");
     assert!(matches!(command, BotCommand::BtcPrice));
 }
+
+// Webhook bodies in Meta's wire format: compact JSON with \uXXXX and \/
+// escapes, as Meta serialises them. They are written by hand from Meta's
+// documented payloads, not captured from a live app; replace them with real
+// captures, re-signed with the test secret, when one is available. Each .sig
+// holds the X-Hub-Signature-256 value for the body, signed with the test app
+// secret ("test_app_secret").
+const TEXT_MESSAGE_BODY: &[u8] = include_bytes!("fixtures/whatsapp/text_message.json");
+const TEXT_MESSAGE_SIG: &str = include_str!("fixtures/whatsapp/text_message.sig");
+const STATUS_UPDATE_BODY: &[u8] = include_bytes!("fixtures/whatsapp/status_update.json");
+const STATUS_UPDATE_SIG: &str = include_str!("fixtures/whatsapp/status_update.sig");
+
+#[tokio::test]
+async fn test_webhook_signature_over_raw_body() {
+    let (config, _server) = create_test_config().await;
+    let whatsapp_service = WhatsAppService::new(&config).unwrap();
+
+    for (body, signature) in [
+        (TEXT_MESSAGE_BODY, TEXT_MESSAGE_SIG.trim()),
+        (STATUS_UPDATE_BODY, STATUS_UPDATE_SIG.trim()),
+    ] {
+        assert!(whatsapp_service.verify_webhook_signature(body, signature).is_ok());
+
+        // Re-serializing the parsed payload changes escapes and whitespace,
+        // so the signature must no longer match
+        let reserialized =
+            serde_json::to_vec(&serde_json::from_slice::<serde_json::Value>(body).unwrap()).unwrap();
+        assert_ne!(reserialized.as_slice(), body);
+        assert!(whatsapp_service.verify_webhook_signature(&reserialized, signature).is_err());
+    }
+
+    // The verify token is not the signing key
+    let mut wrong_key_config = config.clone();
+    wrong_key_config.whatsapp_app_secret = config.whatsapp_webhook_verify_token.clone();
+    let wrong_key_service = WhatsAppService::new(&wrong_key_config).unwrap();
+    assert!(wrong_key_service
+        .verify_webhook_signature(TEXT_MESSAGE_BODY, TEXT_MESSAGE_SIG.trim())
+        .is_err());
+
+    // Tampered bodies and malformed signatures are rejected
+    let mut tampered = TEXT_MESSAGE_BODY.to_vec();
+    tampered[0] = b' ';
+    assert!(whatsapp_service.verify_webhook_signature(&tampered, TEXT_MESSAGE_SIG.trim()).is_err());
+    assert!(whatsapp_service.verify_webhook_signature(TEXT_MESSAGE_BODY, "sha256=not-hex").is_err());
+}
+
+fn create_test_state(config: AppConfig) -> bitsacco_whatsapp_bot::types::AppState {
+    use bitsacco_whatsapp_bot::{
+        alerts::AlertStore,
+        cache::{AppCache, CacheConfig},
+        confirmation::PendingActionStore,
+        conversation::ConversationStore,
+        dedup::MessageDeduplicator,
+        delivery::DeliveryTracker,
+        limits::LimitsEngine,
+        mpesa::StkPushTracker,
+        outbound::Outbox,
+        pin::PinStore,
+        rate_limit::{RateLimitPolicy, RateLimiter},
+        channel::Messenger,
+        service_window::ServiceWindows,
+        services::{conversion::ConversionService, twilio::TwilioService},
+        settlement::InvoiceTracker,
+        types::AppState,
+    };
+    use std::time::Duration;
+
+    let outbox = Outbox::default();
+    let deliveries = DeliveryTracker::default();
+    let service_windows = ServiceWindows::default();
+    let whatsapp_service = WhatsAppService::new(&config)
+        .unwrap()
+        .with_outbox(outbox.clone())
+        .with_delivery_tracker(deliveries.clone());
+    let twilio_service = TwilioService::new(config.clone());
+    AppState {
+        messenger: Messenger::from_config(&config, whatsapp_service.clone(), twilio_service.clone())
+            .unwrap()
+            .with_service_windows(service_windows.clone()),
+        whatsapp_service,
+        bitsacco_service: BitSaccoService::new(&config).unwrap(),
+        btc_service: BtcService::new(&config).unwrap(),
+        conversion_service: ConversionService::new(BtcService::new(&config).unwrap()),
+        voice_service: VoiceService::new(&config).unwrap(),
+        cache: AppCache::new(CacheConfig::default()),
+        twilio_service,
+        pending_actions: PendingActionStore::default(),
+        conversations: ConversationStore::default(),
+        price_alerts: AlertStore::default(),
+        lightning_invoices: InvoiceTracker::default(),
+        mpesa_deposits: StkPushTracker::new(Duration::from_secs(config.mpesa_status_query_delay_secs)),
+        limits: LimitsEngine::default(),
+        pins: PinStore::default(),
+        processed_messages: MessageDeduplicator::default(),
+        rate_limiter: RateLimiter::new(RateLimitPolicy {
+            messages_per_minute: config.rate_limit_requests_per_minute,
+            money_commands_per_minute: config.rate_limit_money_commands_per_minute,
+        }),
+        outbox,
+        deliveries,
+        service_windows,
+        config,
+    }
+}
+
+/// Mock BitSacco's lookups of the test user, by phone number and by ID
+async fn mock_user(server: &mut ServerGuard) -> mockito::Mock {
+    server
+        .mock("GET", mockito::Matcher::Regex(r"^/users/(phone/\+254712345678|user123)$".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "id": "user123", "phone_number": TEST_PHONE, "name": "Test User", "email": "test@example.com",
+                "created_at": "2023-01-01T00:00:00Z", "updated_at": "2023-01-01T00:00:00Z"
+            })
+            .to_string(),
+        )
+        .create_async()
+        .await
+}
+
+/// A Meta webhook body delivering one message from the test user. `content`
+/// holds the message's `type` and the field named by it.
+fn meta_message(id: &str, content: serde_json::Value) -> String {
+    let mut message = json!({"from": "254712345678", "id": id, "timestamp": chrono::Utc::now().timestamp().to_string()});
+    message.as_object_mut().unwrap().extend(content.as_object().unwrap().clone());
+    meta_webhook(json!({"messages": [message]}))
+}
+
+/// A Meta webhook body carrying `value`'s messages or statuses
+fn meta_webhook(value: serde_json::Value) -> String {
+    let mut change = json!({
+        "messaging_product": "whatsapp",
+        "metadata": {"display_phone_number": "15550783881", "phone_number_id": "106540352242922"}
+    });
+    change.as_object_mut().unwrap().extend(value.as_object().unwrap().clone());
+    json!({
+        "object": "whatsapp_business_account",
+        "entry": [{"id": "102290129340398", "changes": [{"field": "messages", "value": change}]}]
+    })
+    .to_string()
+}
+
+/// A POST of `body` to `/webhook`, signed with the test app secret
+fn signed_meta_request(body: &str) -> Request<Body> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, b"test_app_secret");
+    let signature = format!("sha256={}", hex::encode(hmac::sign(&key, body.as_bytes()).as_ref()));
+    Request::post("/webhook")
+        .header("content-type", "application/json")
+        .header("x-hub-signature-256", signature)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// The Meta webhook route, serving `state`
+fn webhook_app(state: &bitsacco_whatsapp_bot::types::AppState) -> Router {
+    use bitsacco_whatsapp_bot::webhook::handle_webhook;
+
+    Router::new().route("/webhook", post(handle_webhook)).with_state(state.clone())
+}
+
+/// Run `request` through `app`
+async fn send(app: &Router, request: Request<Body>) -> Response {
+    app.clone().oneshot(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_webhook_route_verifies_raw_body() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::post, Router};
+    use bitsacco_whatsapp_bot::webhook::handle_webhook;
+    use tower::ServiceExt;
+
+    let (config, _server) = create_test_config().await;
+    let state = create_test_state(config);
+    let app = Router::new()
+        .route("/webhook", post(handle_webhook))
+        .with_state(state);
+
+    let request = |body: Vec<u8>, signature: Option<&str>| {
+        let mut builder = Request::post("/webhook").header("content-type", "application/json");
+        if let Some(signature) = signature {
+            builder = builder.header("x-hub-signature-256", signature);
+        }
+        builder.body(Body::from(body)).unwrap()
+    };
+
+    let response = app
+        .clone()
+        .oneshot(request(STATUS_UPDATE_BODY.to_vec(), Some(STATUS_UPDATE_SIG.trim())))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .clone()
+        .oneshot(request(TEXT_MESSAGE_BODY.to_vec(), Some(STATUS_UPDATE_SIG.trim())))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .oneshot(request(STATUS_UPDATE_BODY.to_vec(), None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_webhook_drops_redelivered_messages() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::post, Router};
+    use bitsacco_whatsapp_bot::webhook::handle_webhook;
+    use tower::ServiceExt;
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    // Handling the message starts with looking up the sender
+    let lookup = server
+        .mock("GET", mockito::Matcher::Regex(r"^/users/phone/.*$".to_string()))
+        .with_status(404)
+        .expect(1)
+        .create_async()
+        .await;
+
+    let app = Router::new()
+        .route("/webhook", post(handle_webhook))
+        .with_state(state);
+    for _ in 0..2 {
+        let request = Request::post("/webhook")
+            .header("content-type", "application/json")
+            .header("x-hub-signature-256", TEXT_MESSAGE_SIG.trim())
+            .body(Body::from(TEXT_MESSAGE_BODY.to_vec()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Messages are handled on their own tasks
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    lookup.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_meta_webhook_normalized() {
+    use bitsacco_whatsapp_bot::channel::{InboundContent, MessagingChannel};
+
+    let (config, _server) = create_test_config().await;
+    let whatsapp_service = WhatsAppService::new(&config).unwrap();
+    let body = json!({
+        "object": "whatsapp_business_account",
+        "entry": [{"id": "102290129340398", "changes": [{"field": "messages", "value": {
+            "messaging_product": "whatsapp",
+            "metadata": {"display_phone_number": "15550783881", "phone_number_id": "106540352242922"},
+            "messages": [
+                {"from": "254712345678", "id": "wamid.1", "timestamp": "1718362915", "type": "text", "text": {"body": "balance"}},
+                {"from": "254712345678", "id": "wamid.2", "timestamp": "1718362916", "type": "interactive",
+                 "interactive": {"type": "button_reply", "button_reply": {"id": "yes", "title": "Yes"}}},
+                {"from": "254712345678", "id": "wamid.3", "timestamp": "1718362917", "type": "sticker", "sticker": {"id": "s1"}}
+            ],
+            "statuses": [{"id": "wamid.OUT", "status": "read", "timestamp": "1718362918", "recipient_id": "254712345678"}]
+        }}]}]
+    })
+    .to_string();
+
+    let inbound = whatsapp_service.normalize_inbound(body.as_bytes()).unwrap();
+    assert_eq!(inbound.messages.len(), 3);
+    assert_eq!(inbound.messages[0].from, "+254712345678");
+    assert_eq!(inbound.messages[0].sent_at.timestamp(), 1718362915);
+    assert!(matches!(&inbound.messages[0].content, InboundContent::Text(text) if text == "balance"));
+    assert!(matches!(&inbound.messages[1].content, InboundContent::Reply(reply) if reply == "yes"));
+    assert!(matches!(&inbound.messages[2].content, InboundContent::Unsupported(kind) if kind == "sticker"));
+    assert_eq!(inbound.statuses.len(), 1);
+}
+
+#[tokio::test]
+async fn test_twilio_webhook_replies_through_twilio() {
+    use axum::http::StatusCode;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use bitsacco_whatsapp_bot::webhook::handle_twilio_webhook;
+
+    let (mut config, mut server) = create_test_config().await;
+    config.twilio_api_base_url = server.url();
+    config.twilio_webhook_url = "https://bot.example.com/webhook/twilio".to_string();
+    let state = create_test_state(config);
+    let _user = mock_user(&mut server).await;
+    let twilio = server
+        .mock("POST", "/2010-04-01/Accounts/test_account_sid/Messages.json")
+        .match_body(mockito::Matcher::AllOf(vec![
+            mockito::Matcher::Regex("To=whatsapp%3A%2B254712345678".to_string()),
+            mockito::Matcher::Regex("BitSacco\\+WhatsApp\\+Bot\\+Help".to_string()),
+        ]))
+        .with_status(201)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "sid": "SM456", "status": "queued", "to": "whatsapp:+254712345678", "from": "whatsapp:+1234567890",
+                "body": "help", "error_code": null, "error_message": null
+            })
+            .to_string(),
+        )
+        .expect(1)
+        .create_async()
+        .await;
+    let meta = server
+        .mock("POST", "/test_phone_id/messages")
+        .expect(0)
+        .create_async()
+        .await;
+
+    let app = Router::new()
+        .route("/webhook/twilio", post(handle_twilio_webhook))
+        .with_state(state.clone());
+    let body = "MessageSid=SM123&AccountSid=test_account_sid&From=whatsapp%3A%2B254712345678\
+                &To=whatsapp%3A%2B1234567890&Body=help&NumMedia=0";
+    // The URL, then each parameter name and value sorted by name
+    let signed = "https://bot.example.com/webhook/twilioAccountSidtest_account_sidBodyhelp\
+                  Fromwhatsapp:+254712345678MessageSidSM123NumMedia0Towhatsapp:+1234567890";
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, b"test_auth_token");
+    let signature = BASE64.encode(hmac::sign(&key, signed.as_bytes()).as_ref());
+    let request = |signature: &str| {
+        Request::post("/webhook/twilio")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("x-twilio-signature", signature)
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let response = send(&app, request("bm90IHRoZSBzaWduYXR1cmU=")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&app, request(&signature)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/xml");
+
+    // Messages are handled on their own tasks
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    twilio.assert_async().await;
+    meta.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_twilio_proactive_messages_outside_window() {
+    use bitsacco_whatsapp_bot::{
+        channel::{Messenger, MessagingChannel},
+        service_window::ServiceWindows,
+        services::twilio::TwilioService,
+        templates::{ProactiveMessage, TemplateMessage, TemplateParameter, TemplateRegistry},
+    };
+    use std::sync::Arc;
+
+    let (mut config, mut server) = create_test_config().await;
+    config.twilio_api_base_url = server.url();
+    config.twilio_sms_number = "+15005550006".to_string();
+    let windows = ServiceWindows::default();
+    let twilio_service = TwilioService::new(config.clone());
+    let messenger = |twilio_service: TwilioService| {
+        let twilio: Arc<dyn MessagingChannel> = Arc::new(twilio_service);
+        Messenger::new(twilio).with_service_windows(windows.clone())
+    };
+    let notification = ProactiveMessage::new(
+        "✅ *M-Pesa Deposit Received!*",
+        TemplateMessage::new("mpesa_deposit_received").body(vec![
+            TemplateParameter::money(Money::from_major(500, Currency::Kes).unwrap()),
+            TemplateParameter::text("tx123"),
+        ]),
+    );
+    let mut sent = |matcher: mockito::Matcher| {
+        server
+            .mock("POST", "/2010-04-01/Accounts/test_account_sid/Messages.json")
+            .match_body(matcher)
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "sid": "SM1", "status": "queued", "to": "+254712345678", "from": "+15005550006",
+                    "body": "", "error_code": null, "error_message": null
+                })
+                .to_string(),
+            )
+            .expect(1)
+    };
+
+    // Without a Content template the notice goes out by SMS
+    let sms = sent(mockito::Matcher::AllOf(vec![
+        mockito::Matcher::Regex("To=%2B254712345678".to_string()),
+        mockito::Matcher::Regex("From=%2B15005550006".to_string()),
+    ]))
+    .create_async()
+    .await;
+    messenger(twilio_service.clone()).send_proactive("+254712345678", &notification).await.unwrap();
+    sms.assert_async().await;
+    sms.remove_async().await;
+
+    // With one it goes out as the template
+    let mut definition = TemplateRegistry::default().get("mpesa_deposit_received").unwrap().clone();
+    definition.twilio_content_sid = Some("HX123".to_string());
+    let with_content = twilio_service.with_templates(TemplateRegistry::new(vec![definition]));
+    let template = sent(mockito::Matcher::AllOf(vec![
+        mockito::Matcher::Regex("To=whatsapp%3A%2B254712345678".to_string()),
+        mockito::Matcher::Regex("ContentSid=HX123".to_string()),
+        mockito::Matcher::Regex("ContentVariables=".to_string()),
+    ]))
+    .create_async()
+    .await;
+    messenger(with_content).send_proactive("+254712345678", &notification).await.unwrap();
+    template.assert_async().await;
+    assert_eq!(windows.metrics(chrono::Utc::now()).await.template_sends, 2);
+}
+
+#[tokio::test]
+async fn test_admin_lists_rate_limit_bans() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::get, Router};
+    use bitsacco_whatsapp_bot::{admin::list_rate_limit_bans, rate_limit::CommandClass};
+    use tower::ServiceExt;
+
+    let (config, _server) = create_test_config().await;
+    let state = create_test_state(config);
+    let now = chrono::Utc::now();
+    // Keep messaging well past the limit
+    for _ in 0..100 {
+        let _ = state.rate_limiter.check("+254712345678", CommandClass::Query, now).await;
+    }
+    assert!(state.rate_limiter.check("+254700000001", CommandClass::Query, now).await.is_ok());
+
+    let app = Router::new()
+        .route("/admin/rate-limits/bans", get(list_rate_limit_bans))
+        .with_state(state);
+    let request = |token: &str| {
+        Request::get("/admin/rate-limits/bans")
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request("wrong_token")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.oneshot(request("test_admin_token")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let bans: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(bans.as_array().unwrap().len(), 1);
+    assert_eq!(bans[0]["wa_id"], "+254712345678");
+}
+
+#[tokio::test]
+async fn test_outbound_retry_and_dead_letter_replay() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::{get, post}, Router};
+    use bitsacco_whatsapp_bot::{
+        admin::{list_dead_letters, replay_dead_letter},
+        outbound::flush_outbox,
+    };
+    use tower::ServiceExt;
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    state.service_windows.record_inbound("meta", "+254712345678", chrono::Utc::now()).await;
+
+    // Throttled by Meta: queued for a retry
+    let throttled = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("Deposit received".to_string()))
+        .with_status(400)
+        .with_header("retry-after", "0")
+        .with_body(json!({"error": {"message": "Pair rate limit hit", "type": "OAuthException", "code": 131056}}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+    assert!(state.whatsapp_service.send_message("+254712345678", "Deposit received").await.is_err());
+    throttled.assert_async().await;
+    let queued = state.outbox.pending().await;
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].attempts, 1);
+
+    // The retry goes through once it is due
+    throttled.remove_async().await;
+    let delivered = server
+        .mock("POST", "/test_phone_id/messages")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.1"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+    let due = chrono::Utc::now() + chrono::Duration::minutes(10);
+    flush_outbox(&state.outbox, &state.whatsapp_service, &state.service_windows, due).await;
+    assert!(state.outbox.pending().await.is_empty());
+    delivered.assert_async().await;
+    delivered.remove_async().await;
+
+    // An undeliverable message is dead-lettered straight away
+    let undeliverable = server
+        .mock("POST", "/test_phone_id/messages")
+        .with_status(400)
+        .with_body(json!({"error": {"message": "Message undeliverable", "type": "OAuthException", "code": 131026}}).to_string())
+        .create_async()
+        .await;
+    assert!(state.whatsapp_service.send_message("+254712345678", "Share purchase complete").await.is_err());
+    assert!(state.outbox.pending().await.is_empty());
+    undeliverable.remove_async().await;
+
+    let app = Router::new()
+        .route("/admin/outbound/dead-letters", get(list_dead_letters))
+        .route("/admin/outbound/dead-letters/{id}/replay", post(replay_dead_letter))
+        .with_state(state.clone());
+    let request = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("authorization", "Bearer test_admin_token")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request("GET", "/admin/outbound/dead-letters")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let dead: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(dead.as_array().unwrap().len(), 1);
+    assert_eq!(dead[0]["body"], "Share purchase complete");
+    let id = dead[0]["id"].as_u64().unwrap();
+
+    let response = app.clone().oneshot(request("POST", "/admin/outbound/dead-letters/999/replay")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let replay = format!("/admin/outbound/dead-letters/{}/replay", id);
+    let response = app.oneshot(request("POST", &replay)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(state.outbox.dead_letters().await.is_empty());
+
+    let resent = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("Share purchase complete".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.2"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+    flush_outbox(&state.outbox, &state.whatsapp_service, &state.service_windows, chrono::Utc::now()).await;
+    resent.assert_async().await;
+    assert!(state.outbox.pending().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_outbound_template_retry_outside_window() {
+    use bitsacco_whatsapp_bot::{
+        outbound::flush_outbox,
+        templates::{TemplateMessage, TemplateParameter},
+    };
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    let template = TemplateMessage::new("mpesa_deposit_received").body(vec![
+        TemplateParameter::money(Money::from_major(500, Currency::Kes).unwrap()),
+        TemplateParameter::text("tx123"),
+    ]);
+
+    // No open window: the failed template is queued and retried as a template
+    let unavailable = server
+        .mock("POST", "/test_phone_id/messages")
+        .with_status(503)
+        .expect(1)
+        .create_async()
+        .await;
+    assert!(state.whatsapp_service.send_template("+254712345678", &template).await.is_err());
+    unavailable.assert_async().await;
+    unavailable.remove_async().await;
+    let queued = state.outbox.pending().await;
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].template.as_ref(), Some(&template));
+
+    // A 2xx whose body cannot be read still counts as sent
+    let sent = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex(r#""type":"template""#.to_string()))
+        .with_status(200)
+        .with_body("not json")
+        .expect(1)
+        .create_async()
+        .await;
+    let due = chrono::Utc::now() + chrono::Duration::minutes(10);
+    flush_outbox(&state.outbox, &state.whatsapp_service, &state.service_windows, due).await;
+    sent.assert_async().await;
+    assert!(state.outbox.pending().await.is_empty());
+    assert!(state.outbox.dead_letters().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_failed_critical_message_falls_back_to_sms() {
+    use axum::{http::StatusCode, routing::get};
+    use bitsacco_whatsapp_bot::admin::{get_delivery, list_deliveries};
+
+    let (mut config, mut server) = create_test_config().await;
+    config.twilio_api_base_url = server.url();
+    config.twilio_sms_number = "+15005550006".to_string();
+    let state = create_test_state(config);
+    state.service_windows.record_inbound("meta", "+254712345678", chrono::Utc::now()).await;
+
+    for (text, id) in [("Deposit received", "wamid.CRIT"), ("Hello", "wamid.HELLO")] {
+        let sent = server
+            .mock("POST", "/test_phone_id/messages")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": id}]}).to_string())
+            .create_async()
+            .await;
+        if id == "wamid.CRIT" {
+            state.whatsapp_service.send_critical_message("+254712345678", text).await.unwrap();
+        } else {
+            state.whatsapp_service.send_message("+254712345678", text).await.unwrap();
+        }
+        sent.remove_async().await;
+    }
+
+    let sms = server
+        .mock("POST", "/2010-04-01/Accounts/test_account_sid/Messages.json")
+        .match_body(mockito::Matcher::AllOf(vec![
+            mockito::Matcher::Regex("To=%2B254712345678".to_string()),
+            mockito::Matcher::Regex("From=%2B15005550006".to_string()),
+            mockito::Matcher::Regex("Body=Deposit\\+received".to_string()),
+        ]))
+        .with_status(201)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "sid": "SM123", "status": "queued", "to": "+254712345678", "from": "+15005550006",
+                "body": "Deposit received", "error_code": null, "error_message": null
+            })
+            .to_string(),
+        )
+        .expect(1)
+        .create_async()
+        .await;
+
+    let app = webhook_app(&state)
+        .merge(
+            Router::new()
+                .route("/admin/deliveries", get(list_deliveries))
+                .route("/admin/deliveries/{message_id}", get(get_delivery))
+                .with_state(state.clone()),
+        );
+    let body = meta_webhook(json!({"statuses": [
+        {"id": "wamid.CRIT", "status": "sent", "timestamp": "1718362920", "recipient_id": "254712345678"},
+        {"id": "wamid.CRIT", "status": "failed", "timestamp": "1718362921", "recipient_id": "254712345678",
+         "errors": [{"code": 131026, "title": "Message undeliverable"}]}
+    ]}));
+    // Repeated failure reports only fall back once
+    for _ in 0..2 {
+        let response = send(&app, signed_meta_request(&body)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // The fallback is sent on its own task
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    sms.assert_async().await;
+
+    let get = |uri: &str| {
+        Request::get(uri)
+            .header("authorization", "Bearer test_admin_token")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = send(&app, get("/admin/deliveries/wamid.CRIT")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let record: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(record["status"], "failed");
+    assert_eq!(record["history"].as_array().unwrap().len(), 5);
+    assert_eq!(record["history"][2]["error"], "Message undeliverable (131026)");
+    assert_eq!(record["fallback"]["channel"], "sms");
+    assert_eq!(record["fallback"]["message_id"], "SM123");
+
+    let response = send(&app, get("/admin/deliveries?to=%2B254712345678")).await;
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let records: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(records.as_array().unwrap().len(), 2);
+
+    let response = send(&app, get("/admin/deliveries/wamid.unknown")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_interactive_replies_drive_confirmation() {
+    use axum::http::StatusCode;
+    use bitsacco_whatsapp_bot::conversation::ConversationSession;
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    let _user = mock_user(&mut server).await;
+    let _history = server
+        .mock("GET", "/users/user123/transactions")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body("[]")
+        .create_async()
+        .await;
+    let quote = server
+        .mock("GET", "/withdrawals/quote")
+        .match_query(mockito::Matcher::AllOf(vec![
+            mockito::Matcher::UrlEncoded("amount".to_string(), "500.00".to_string()),
+            mockito::Matcher::UrlEncoded("currency".to_string(), "KES".to_string()),
+            mockito::Matcher::UrlEncoded("payment_method".to_string(), "mpesa".to_string()),
+        ]))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"fee": 15.0, "currency": "KES"}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+    let buttons = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::AllOf(vec![
+            mockito::Matcher::Regex(r#""type":"interactive""#.to_string()),
+            mockito::Matcher::Regex(r#"Please Confirm"#.to_string()),
+            mockito::Matcher::Regex(r#"Fee: 15.00 KES"#.to_string()),
+            mockito::Matcher::Regex(r#""reply":\{"id":"yes","title":"Yes"\}"#.to_string()),
+        ]))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.OUT"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+    let other_replies = server
+        .mock("POST", "/test_phone_id/messages")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.OUT2"}]}).to_string())
+        .expect(2)
+        .create_async()
+        .await;
+
+    // The user was asked "M-Pesa or Lightning?" for a withdrawal of 500
+    state
+        .conversations
+        .save(TEST_PHONE, ConversationSession::start("withdraw 500").unwrap())
+        .await;
+
+    let app = webhook_app(&state);
+    let tap = |id: &str, button: &str, title: &str| {
+        signed_meta_request(&meta_message(
+            id,
+            json!({"type": "interactive", "interactive": {"type": "button_reply", "button_reply": {"id": button, "title": title}}}),
+        ))
+    };
+
+    let response = send(&app, tap("wamid.IN1", "mpesa", "M-Pesa")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    quote.assert_async().await;
+    buttons.assert_async().await;
+    let pending = state.pending_actions.get(TEST_PHONE).await.unwrap();
+    assert!(matches!(pending.command, BotCommand::Withdraw { .. }));
+
+    // Tapping "No" cancels it
+    let response = send(&app, tap("wamid.IN2", "no", "No")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(state.pending_actions.get(TEST_PHONE).await.is_none());
+
+    // "No" in the middle of a guided conversation cancels it too
+    state
+        .conversations
+        .save(TEST_PHONE, ConversationSession::start("withdraw 500").unwrap())
+        .await;
+    let response = send(&app, tap("wamid.IN3", "no", "No")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(state.conversations.get(TEST_PHONE).await.is_none());
+    other_replies.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_already_pending_prompt_asks_for_its_pin() {
+    use axum::http::StatusCode;
+    use bitsacco_whatsapp_bot::pin::Pin;
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    let _user = mock_user(&mut server).await;
+    let _history = server
+        .mock("GET", "/users/user123/transactions")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body("[]")
+        .create_async()
+        .await;
+    // The pending withdrawal needs the PIN, so there are no buttons to tap
+    let prompt = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::AllOf(vec![
+            mockito::Matcher::Regex(r#""type":"text""#.to_string()),
+            mockito::Matcher::Regex(r"Action Already Pending".to_string()),
+            mockito::Matcher::Regex(r"Reply with your \*PIN\* to confirm it".to_string()),
+        ]))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.OUT"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+
+    state.pins.set(TEST_PHONE, &Pin::parse("2580").unwrap()).await.unwrap();
+    state
+        .pending_actions
+        .begin(
+            TEST_PHONE,
+            BotCommand::parse("withdraw 500 KES mpesa"),
+            "Withdraw KSh 500.00 to M-Pesa".to_string(),
+        )
+        .await;
+
+    let message = meta_message("wamid.IN1", json!({"type": "text", "text": {"body": "contribute chama chama1 100 KES"}}));
+    let response = send(&webhook_app(&state), signed_meta_request(&message)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    prompt.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_stray_pin_is_not_echoed() {
+    use axum::http::StatusCode;
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    let _user = mock_user(&mut server).await;
+    let reply = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("No action is waiting for a PIN".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.OUT"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+    let echo = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("2580".to_string()))
+        .expect(0)
+        .create_async()
+        .await;
+
+    // The confirmation this PIN was meant for has already expired
+    let message = meta_message("wamid.IN1", json!({"type": "text", "text": {"body": "2580"}}));
+    let response = send(&webhook_app(&state), signed_meta_request(&message)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    reply.assert_async().await;
+    echo.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_proactive_messages_use_templates_outside_window() {
+    use bitsacco_whatsapp_bot::templates::{ProactiveMessage, TemplateMessage, TemplateParameter};
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    let notification = ProactiveMessage::new(
+        "✅ *M-Pesa Deposit Received!*",
+        TemplateMessage::new("mpesa_deposit_received").body(vec![
+            TemplateParameter::money(Money::from_major(500, Currency::Kes).unwrap()),
+            TemplateParameter::text("tx123"),
+        ]),
+    );
+    let sent = |mock: mockito::Mock| {
+        mock.with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.1"}]}).to_string())
+            .expect(1)
+    };
+
+    // Never heard from the user: only the template can be delivered
+    let template = sent(server.mock("POST", "/test_phone_id/messages").match_body(mockito::Matcher::PartialJson(json!({
+        "type": "template",
+        "template": {
+            "name": "mpesa_deposit_received",
+            "language": {"code": "en"},
+            "components": [{"type": "body", "parameters": [
+                {"type": "currency", "currency": {"code": "KES", "amount_1000": 500000}},
+                {"type": "text", "text": "tx123"}
+            ]}]
+        }
+    }))))
+    .create_async()
+    .await;
+    state.messenger.send_proactive("+254712345678", &notification).await.unwrap();
+    template.assert_async().await;
+    template.remove_async().await;
+
+    // Within 24 hours of their last message it goes out as text
+    state.service_windows.record_inbound("meta", "+254712345678", chrono::Utc::now()).await;
+    let text = sent(server.mock("POST", "/test_phone_id/messages").match_body(mockito::Matcher::PartialJson(
+        json!({"type": "text", "text": {"body": "✅ *M-Pesa Deposit Received!*"}}),
+    )))
+    .create_async()
+    .await;
+    state.messenger.send_proactive("+254712345678", &notification).await.unwrap();
+    text.assert_async().await;
+
+    // Templates that do not match the registry are refused before sending
+    let unknown = TemplateMessage::new("not_approved");
+    assert!(matches!(
+        state.whatsapp_service.send_template("+254712345678", &unknown).await,
+        Err(bitsacco_whatsapp_bot::error::AppError::Validation(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_free_form_sends_blocked_outside_window() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::get, Router};
+    use bitsacco_whatsapp_bot::{admin::service_window_metrics, error::AppError};
+    use tower::ServiceExt;
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    let sent = server
+        .mock("POST", "/test_phone_id/messages")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.1"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+
+    // Meta dropped the window when the user's last message is over a day old
+    let stale = chrono::Utc::now() - chrono::Duration::hours(25);
+    state.service_windows.record_inbound("meta", "+254712345678", stale).await;
+    assert!(matches!(
+        state.messenger.send_message("+254712345678", "Hello").await,
+        Err(AppError::ServiceWindowClosed(_))
+    ));
+    // Blocked sends are not queued for a retry
+    assert!(state.outbox.pending().await.is_empty());
+
+    state.service_windows.record_inbound("meta", "+254712345678", chrono::Utc::now()).await;
+    state.messenger.send_message("+254712345678", "Hello").await.unwrap();
+    sent.assert_async().await;
+
+    let app = Router::new()
+        .route("/admin/service-windows/metrics", get(service_window_metrics))
+        .with_state(state.clone());
+    let response = app
+        .oneshot(
+            Request::get("/admin/service-windows/metrics")
+                .header("authorization", "Bearer test_admin_token")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let metrics: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        metrics,
+        json!({"open_windows": 1, "free_form_sends": 1, "template_sends": 0, "blocked_sends": 1})
+    );
+}
+
+#[tokio::test]
+async fn test_bitsacco_idempotency_keys() {
+    let (config, mut server) = create_test_config().await;
+    let bitsacco_service = BitSaccoService::new(&config).unwrap();
+
+    let key = |input: &str| hex::encode(ring::digest::digest(&ring::digest::SHA256, input.as_bytes()).as_ref());
+    let response = json!({"CheckoutRequestID": "ws_CO_1", "ResultCode": "0", "ResultDesc": "Processed"}).to_string();
+    let mut query = |key: mockito::Matcher| {
+        server
+            .mock("POST", "/mpesa/stk-query")
+            .match_header("idempotency-key", key)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(response.clone())
+    };
+    let first = query(mockito::Matcher::Exact(key("wamid.A:0:mpesa/stk-query")))
+        .expect(2)
+        .create_async()
+        .await;
+    let second = query(mockito::Matcher::Exact(key("wamid.A:1:mpesa/stk-query")))
+        .expect(1)
+        .create_async()
+        .await;
+    let unkeyed = query(mockito::Matcher::Missing).expect(1).create_async().await;
+
+    // Handling a redelivered message repeats the same keys in the same order
+    let for_message = bitsacco_service.for_message("wamid.A");
+    for_message.query_mpesa_stk_status("ws_CO_1").await.unwrap();
+    for_message.query_mpesa_stk_status("ws_CO_1").await.unwrap();
+    bitsacco_service
+        .for_message("wamid.A")
+        .query_mpesa_stk_status("ws_CO_1")
+        .await
+        .unwrap();
+
+    // Background calls made outside any message are not keyed
+    bitsacco_service.query_mpesa_stk_status("ws_CO_1").await.unwrap();
+
+    first.assert_async().await;
+    second.assert_async().await;
+    unkeyed.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_lightning_settlement_callback() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::post, Router};
+    use bitsacco_whatsapp_bot::{
+        settlement::OutstandingInvoice,
+        types::LightningPaymentResponse,
+        webhook::handle_lightning_callback,
+    };
+    use ring::hmac;
+    use tower::ServiceExt;
+
+    let (config, mut server) = create_test_config().await;
+    let secret = config.bitsacco_callback_secret.clone();
+    let state = create_test_state(config);
+    // The user asked for the invoice a moment ago
+    state.service_windows.record_inbound("meta", "+254712345678", chrono::Utc::now()).await;
+
+    let deposit = LightningPaymentResponse {
+        payment_request: "lnbc50u1pexample".to_string(),
+        payment_hash: "hash123".to_string(),
+        expires_at: "2099-01-01T00:00:00Z".to_string(),
+        status: "pending".to_string(),
+    };
+    state
+        .lightning_invoices
+        .track(OutstandingInvoice::new(
+            "+254712345678",
+            Money::from_major(500, Currency::Kes).unwrap(),
+            &deposit,
+        ))
+        .await;
+
+    let notification = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("Received 5,000 sats".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.1"}]}).to_string())
+        .expect(1)
+        .create();
+
+    let app = Router::new()
+        .route("/callbacks/lightning", post(handle_lightning_callback))
+        .with_state(state.clone());
+    let body = json!({"payment_hash": "hash123", "status": "paid", "amount_sats": 5000}).to_string();
+    let sign = |body: &str| {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        format!("sha256={}", hex::encode(hmac::sign(&key, body.as_bytes()).as_ref()))
+    };
+    let request = |signature: String| {
+        Request::post("/callbacks/lightning")
+            .header("content-type", "application/json")
+            .header("x-bitsacco-signature", signature)
+            .body(Body::from(body.clone()))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request("sha256=00".to_string())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(state.lightning_invoices.outstanding().await.len(), 1);
+
+    let response = app.clone().oneshot(request(sign(&body))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(state.lightning_invoices.outstanding().await.is_empty());
+
+    // A retried callback is acknowledged without messaging the user again
+    let response = app.oneshot(request(sign(&body))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    notification.assert();
+}
+
+#[tokio::test]
+async fn test_queued_settlement_notice_sent_once() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::post, Router};
+    use bitsacco_whatsapp_bot::{
+        outbound::flush_outbox,
+        settlement::OutstandingInvoice,
+        types::LightningPaymentResponse,
+        webhook::handle_lightning_callback,
+    };
+    use ring::hmac;
+    use tower::ServiceExt;
+
+    let (config, mut server) = create_test_config().await;
+    let secret = config.bitsacco_callback_secret.clone();
+    let state = create_test_state(config);
+    state.service_windows.record_inbound("meta", TEST_PHONE, chrono::Utc::now()).await;
+
+    let deposit = LightningPaymentResponse {
+        payment_request: "lnbc50u1pexample".to_string(),
+        payment_hash: "hash123".to_string(),
+        expires_at: "2099-01-01T00:00:00Z".to_string(),
+        status: "pending".to_string(),
+    };
+    state
+        .lightning_invoices
+        .track(OutstandingInvoice::new(TEST_PHONE, Money::from_major(500, Currency::Kes).unwrap(), &deposit))
+        .await;
+
+    // WhatsApp is down when the payment lands: the notice goes to the outbox
+    let unavailable = server
+        .mock("POST", "/test_phone_id/messages")
+        .with_status(503)
+        .expect(1)
+        .create_async()
+        .await;
+
+    let app = Router::new()
+        .route("/callbacks/lightning", post(handle_lightning_callback))
+        .with_state(state.clone());
+    let body = json!({"payment_hash": "hash123", "status": "paid", "amount_sats": 5000}).to_string();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let signature = format!("sha256={}", hex::encode(hmac::sign(&key, body.as_bytes()).as_ref()));
+    let request = || {
+        Request::post("/callbacks/lightning")
+            .header("content-type", "application/json")
+            .header("x-bitsacco-signature", signature.clone())
+            .body(Body::from(body.clone()))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    unavailable.assert_async().await;
+    assert_eq!(state.outbox.pending().await.len(), 1);
+    // The outbox has it, so the invoice is not tracked for another try
+    assert!(state.lightning_invoices.outstanding().await.is_empty());
+
+    // A retried callback finds nothing to announce
+    let response = app.oneshot(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    unavailable.remove_async().await;
+
+    let delivered = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("Received 5,000 sats".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.1"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+    let due = chrono::Utc::now() + chrono::Duration::minutes(10);
+    flush_outbox(&state.outbox, &state.whatsapp_service, &state.service_windows, due).await;
+    flush_outbox(&state.outbox, &state.whatsapp_service, &state.service_windows, due).await;
+    assert!(state.outbox.pending().await.is_empty());
+    delivered.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_pin_reset_callback() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::post, Router};
+    use bitsacco_whatsapp_bot::{pin::Pin, webhook::handle_pin_reset_callback};
+    use ring::hmac;
+    use tower::ServiceExt;
+
+    let (config, mut server) = create_test_config().await;
+    let secret = config.bitsacco_callback_secret.clone();
+    let state = create_test_state(config);
+    state
+        .pins
+        .set("+254712345678", &Pin::parse("2580").unwrap())
+        .await
+        .unwrap();
+    state.service_windows.record_inbound("meta", "+254712345678", chrono::Utc::now()).await;
+
+    let notification = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("PIN Reset".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.1"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+
+    let app = Router::new()
+        .route("/callbacks/pin-reset", post(handle_pin_reset_callback))
+        .with_state(state.clone());
+    let body = json!({"phone_number": "254712345678"}).to_string();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let signed = format!("sha256={}", hex::encode(hmac::sign(&key, body.as_bytes()).as_ref()));
+    let request = |signature: &str| {
+        Request::post("/callbacks/pin-reset")
+            .header("content-type", "application/json")
+            .header("x-bitsacco-signature", signature)
+            .body(Body::from(body.clone()))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request("sha256=00")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(state.pins.has_pin("+254712345678").await);
+
+    let response = app.clone().oneshot(request(&signed)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!state.pins.has_pin("+254712345678").await);
+
+    // A repeated reset is acknowledged without messaging the user again
+    let response = app.oneshot(request(&signed)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    notification.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_mpesa_stk_callback() {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::post, Router};
+    use bitsacco_whatsapp_bot::webhook::handle_mpesa_callback;
+    use tower::ServiceExt;
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    state.service_windows.record_inbound("meta", "+254712345678", chrono::Utc::now()).await;
+
+    let transaction = |status: &str| {
+        json!({
+            "id": "tx123",
+            "user_id": "user123",
+            "type": "deposit",
+            "amount": 500.0,
+            "currency": "KES",
+            "status": status,
+            "payment_method": "mpesa",
+            "external_reference": "ws_CO_191220191020363925",
+            "chama_id": null,
+            "description": null,
+            "created_at": "2026-01-01T00:00:00Z",
+            "updated_at": "2026-01-01T00:00:00Z"
+        })
+        .to_string()
+    };
+    let pending = server
+        .mock("GET", "/transactions/reference/ws_CO_191220191020363925")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(transaction("pending"))
+        .create_async()
+        .await;
+    let update = server
+        .mock("POST", "/transactions/tx123/status")
+        .match_body(mockito::Matcher::PartialJson(
+            json!({"status": "completed", "external_receipt": "NLJ7RT61SV"}),
+        ))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(transaction("completed"))
+        .expect(1)
+        .create_async()
+        .await;
+    let _user = server
+        .mock("GET", "/users/user123")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "id": "user123",
+                "phone_number": "+254712345678",
+                "name": "Test User",
+                "email": null,
+                "created_at": "2026-01-01T00:00:00Z",
+                "updated_at": "2026-01-01T00:00:00Z"
+            })
+            .to_string(),
+        )
+        .create_async()
+        .await;
+    let notification = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("NLJ7RT61SV".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.1"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+
+    let app = Router::new()
+        .route("/callbacks/mpesa", post(handle_mpesa_callback))
+        .with_state(state);
+    let body = json!({
+        "Body": {"stkCallback": {
+            "MerchantRequestID": "29115-34620561-1",
+            "CheckoutRequestID": "ws_CO_191220191020363925",
+            "ResultCode": 0,
+            "ResultDesc": "The service request is processed successfully.",
+            "CallbackMetadata": {"Item": [
+                {"Name": "Amount", "Value": 500.00},
+                {"Name": "MpesaReceiptNumber", "Value": "NLJ7RT61SV"},
+                {"Name": "TransactionDate", "Value": 20191219102115u64},
+                {"Name": "PhoneNumber", "Value": 254712345678u64}
+            ]}
+        }}
+    })
+    .to_string();
+    let request = |token: &str| {
+        Request::post(format!("/callbacks/mpesa?token={}", token))
+            .header("content-type", "application/json")
+            .body(Body::from(body.clone()))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request("wrong")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.clone().oneshot(request("test_callback_secret")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let ack: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(ack["ResultCode"], 0);
+
+    // Once the deposit is completed a repeated callback changes nothing
+    pending.remove_async().await;
+    let _completed = server
+        .mock("GET", "/transactions/reference/ws_CO_191220191020363925")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(transaction("completed"))
+        .create_async()
+        .await;
+    let response = app.oneshot(request("test_callback_secret")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    update.assert_async().await;
+    notification.assert_async().await;
+}
+
+#[tokio::test]
+async fn test_mpesa_callback_failure_keeps_querying() {
+    use axum::{body::Body, http::Request, routing::post, Router};
+    use bitsacco_whatsapp_bot::{
+        mpesa::PendingStkPush,
+        types::BitSaccoTransaction,
+        webhook::handle_mpesa_callback,
+    };
+    use tower::ServiceExt;
+
+    let (config, mut server) = create_test_config().await;
+    let state = create_test_state(config);
+    let transaction = json!({
+        "id": "tx123",
+        "user_id": "user123",
+        "type": "deposit",
+        "amount": 500.0,
+        "currency": "KES",
+        "status": "pending",
+        "payment_method": "mpesa",
+        "external_reference": "ws_CO_191220191020363925",
+        "chama_id": null,
+        "description": null,
+        "created_at": "2026-01-01T00:00:00Z",
+        "updated_at": "2026-01-01T00:00:00Z"
+    });
+    let push = PendingStkPush::new(
+        "+254712345678",
+        &serde_json::from_value::<BitSaccoTransaction>(transaction.clone()).unwrap(),
+    )
+    .unwrap();
+    state.mpesa_deposits.track(push.clone()).await;
+
+    let _lookup = server
+        .mock("GET", "/transactions/reference/ws_CO_191220191020363925")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(transaction.to_string())
+        .create_async()
+        .await;
+    let _user = server
+        .mock("GET", "/users/user123")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "id": "user123",
+                "phone_number": "+254712345678",
+                "name": "Test User",
+                "email": null,
+                "created_at": "2026-01-01T00:00:00Z",
+                "updated_at": "2026-01-01T00:00:00Z"
+            })
+            .to_string(),
+        )
+        .create_async()
+        .await;
+    // Applying the result is keyed by the checkout, whichever path applies it
+    let status_key = hex::encode(
+        ring::digest::digest(&ring::digest::SHA256, b"mpesa:ws_CO_191220191020363925:0:transactions/tx123/status").as_ref(),
+    );
+    let update = server
+        .mock("POST", "/transactions/tx123/status")
+        .match_header("idempotency-key", status_key.as_str())
+        .with_status(503)
+        .expect(1)
+        .create_async()
+        .await;
+
+    let app = Router::new()
+        .route("/callbacks/mpesa", post(handle_mpesa_callback))
+        .with_state(state.clone());
+    let body = json!({
+        "Body": {"stkCallback": {
+            "MerchantRequestID": "29115-34620561-1",
+            "CheckoutRequestID": "ws_CO_191220191020363925",
+            "ResultCode": 1032,
+            "ResultDesc": "Request cancelled by user"
+        }}
+    });
+    let response = app
+        .oneshot(
+            Request::post("/callbacks/mpesa?token=test_callback_secret")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(!response.status().is_success());
+    update.assert_async().await;
+
+    // Daraja will not send the callback again, so status queries take over
+    assert_eq!(state.mpesa_deposits.outstanding().await, vec![push]);
+}
+
+#[tokio::test]
+async fn test_mpesa_status_query_fallback() {
+    use bitsacco_whatsapp_bot::{
+        cache::{AppCache, CacheConfig},
+        channel::Messenger,
+        mpesa::{check_stk_push, PendingStkPush, StkPushTracker},
+        types::BitSaccoTransaction,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    let (config, mut server) = create_test_config().await;
+    let bitsacco_service = BitSaccoService::new(&config).unwrap();
+    let messenger = Messenger::new(Arc::new(WhatsAppService::new(&config).unwrap()));
+    let cache = AppCache::new(CacheConfig::default());
+    let tracker = StkPushTracker::new(Duration::from_secs(config.mpesa_status_query_delay_secs));
+
+    let transaction = json!({
+        "id": "tx123",
+        "user_id": "user123",
+        "type": "deposit",
+        "amount": 500.0,
+        "currency": "KES",
+        "status": "pending",
+        "payment_method": "mpesa",
+        "external_reference": "ws_CO_191220191020363925",
+        "chama_id": null,
+        "description": null,
+        "created_at": "2026-01-01T00:00:00Z",
+        "updated_at": "2026-01-01T00:00:00Z"
+    });
+    let push = PendingStkPush::new(
+        "+254712345678",
+        &serde_json::from_value::<BitSaccoTransaction>(transaction.clone()).unwrap(),
+    )
+    .unwrap();
+    tracker.track(push.clone()).await;
+
+    let _lookup = server
+        .mock("GET", "/transactions/reference/ws_CO_191220191020363925")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(transaction.to_string())
+        .create_async()
+        .await;
+    // Still processing: the push stays tracked for another query
+    let processing = server
+        .mock("POST", "/mpesa/stk-query")
+        .with_status(500)
+        .with_body(json!({"errorCode": "500.001.1001", "errorMessage": "The transaction is being processed"}).to_string())
+        .create_async()
+        .await;
+    check_stk_push(&tracker, &bitsacco_service, &messenger, &cache, &push).await.unwrap();
+    let outstanding = tracker.outstanding().await;
+    assert_eq!(outstanding.len(), 1);
+    assert_eq!(outstanding[0].attempts, 1);
+    processing.remove_async().await;
+
+    let _cancelled = server
+        .mock("POST", "/mpesa/stk-query")
+        .match_body(mockito::Matcher::PartialJson(json!({"checkout_request_id": "ws_CO_191220191020363925"})))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "ResponseCode": "0",
+                "CheckoutRequestID": "ws_CO_191220191020363925",
+                "ResultCode": "1032",
+                "ResultDesc": "Request cancelled by user"
+            })
+            .to_string(),
+        )
+        .create_async()
+        .await;
+    // Applying the result is keyed by the checkout, whichever path applies it
+    let status_key = hex::encode(
+        ring::digest::digest(&ring::digest::SHA256, b"mpesa:ws_CO_191220191020363925:0:transactions/tx123/status").as_ref(),
+    );
+    // A BitSacco error while completing leaves the push tracked for another query
+    let unavailable = server
+        .mock("POST", "/transactions/tx123/status")
+        .match_header("idempotency-key", status_key.as_str())
+        .with_status(503)
+        .expect(1)
+        .create_async()
+        .await;
+    let push = tracker.outstanding().await.remove(0);
+    assert!(check_stk_push(&tracker, &bitsacco_service, &messenger, &cache, &push).await.is_err());
+    let outstanding = tracker.outstanding().await;
+    assert_eq!(outstanding.len(), 1);
+    assert_eq!(outstanding[0].attempts, 2);
+    unavailable.assert_async().await;
+    unavailable.remove_async().await;
+
+    let update = server
+        .mock("POST", "/transactions/tx123/status")
+        .match_body(mockito::Matcher::PartialJson(json!({"status": "failed"})))
+        .match_header("idempotency-key", status_key.as_str())
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(transaction.to_string())
+        .expect(1)
+        .create_async()
+        .await;
+    let notification = server
+        .mock("POST", "/test_phone_id/messages")
+        .match_body(mockito::Matcher::Regex("You cancelled the M-Pesa prompt".to_string()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(json!({"messaging_product": "whatsapp", "contacts": [], "messages": [{"id": "wamid.1"}]}).to_string())
+        .expect(1)
+        .create_async()
+        .await;
+
+    let push = tracker.outstanding().await.remove(0);
+    check_stk_push(&tracker, &bitsacco_service, &messenger, &cache, &push).await.unwrap();
+    assert!(tracker.outstanding().await.is_empty());
+    update.assert_async().await;
+    notification.assert_async().await;
+}